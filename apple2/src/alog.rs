@@ -1,10 +1,10 @@
 use std::time::Instant;
 use tracing::info;
-use crate::constants::{CYCLES, PC, START};
+use crate::constants::{PC, START};
 
 //#[cfg(test)]
-pub fn alog(s: &str) {
+pub fn alog(cycles: u64, s: &str) {
     let elapsed = (Instant::now() - *START.get().unwrap()).as_millis();
-    info!("cycles:{} ms:{} PC:{:04X}| {}", *CYCLES.read().unwrap(), elapsed,
+    info!("cycles:{} ms:{} PC:{:04X}| {}", cycles, elapsed,
         *PC.read().unwrap(), s);
 }
\ No newline at end of file
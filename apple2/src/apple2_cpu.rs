@@ -8,14 +8,17 @@ use cpu::config::Config;
 use cpu::cpu::{Cpu, RunStatus, StopReason};
 use cpu::memory::Memory;
 use crate::memory::Apple2Memory;
+use crate::mockingboard::mockingboard::Mockingboard;
 use crate::messages::{CpuDumpMsg, CpuStateMsg, ToCpu, ToUi};
 use crate::messages::ToUi::{EmulatorSpeed};
-use crate::misc::increase_cycles;
 use crate::rolling_times::RollingTimes;
 use crate::{send_message, ui_log};
 use crate::config_file::ConfigFile;
-use crate::constants::{CPU_REFRESH_MS, DIVIDER, PC, START};
-use crate::ui::iced::shared::Shared;
+use crate::constants::{CPU_REFRESH_MS, DIVIDER, PC, REWIND_MAX_BYTES, REWIND_POINTS_PER_SECOND,
+    SOUND_BUFFER_TARGET, SOUND_FRAME_CYCLES, START};
+use crate::rewind::RewindBuffer;
+use crate::snapshot::{load_snapshot, load_snapshot_from_file, save_snapshot, save_snapshot_to_file};
+use crate::context::{EmulatorEvent, RewindStatus};
 
 #[derive(Clone, Debug, Default)]
 pub struct EmulatorConfigMsg {
@@ -64,12 +67,24 @@ pub struct AppleCpu {
     started: bool,
     // handle: Option<Handle>,
     previous_slice_start: u128,
+
+    /// Snapshots taken every [AppleCpu::rewind_interval_cycles], only kept once [AppleCpu::enable_rewind]
+    /// was called since they cost a snapshot and its compression each time
+    rewind: Option<RewindBuffer>,
+    next_rewind_cycle: u64,
+    /// The snapshot currently restored, if the user is going back in time
+    rewind_position: Option<usize>,
 }
 
 impl AppleCpu {
-    pub fn new(cpu: Cpu<Apple2Memory>, config: Box<EmulatorConfigMsg>,
+    pub fn new(mut cpu: Cpu<Apple2Memory>, config: Box<EmulatorConfigMsg>,
         sender: Option<Sender<ToUi>>,
             receiver: Option<Receiver<ToCpu>>) -> Self {
+        // The speaker samples are played in real time, at the speed the emulator runs
+        cpu.memory.speaker.set_cpu_frequency(config.config.emulator_speed_hz);
+        if let Some(mockingboard) = cpu.memory.slots.find_mut::<Mockingboard>() {
+            mockingboard.set_cpu_frequency(config.config.emulator_speed_hz);
+        }
         Self { cpu, sender, receiver,
             last_memory_sent: Instant::now(),
             last_cpu_run: Instant::now(),
@@ -82,6 +97,9 @@ impl AppleCpu {
             started: false,
             config,
             previous_slice_start: START.get().unwrap().elapsed().as_millis(),
+            rewind: None,
+            next_rewind_cycle: 0,
+            rewind_position: None,
         }
     }
 
@@ -96,17 +114,97 @@ impl AppleCpu {
         }
 
         if ! matches!(self.cpu.run_status, RunStatus::Stop(_, _)) {
-            self.cpu.memory.disk_controller.step();
+            self.cpu.memory.slots.step();
             self.advance_cpu();
+            // Only used by the logs
             *PC.write().unwrap() = self.cpu.pc;
-            self.cpu.memory.disk_controller.step();
+            self.cpu.memory.slots.step();
+            for (slot, irq) in self.cpu.memory.slots.irq_lines().into_iter().enumerate() {
+                self.cpu.set_irq(slot as u8, irq);
+            }
 
             self.cycles += self.cpu.run_status.cycles();
 
-            increase_cycles(1);
+            self.cpu.memory.cycles += 1;
+            let cycles = self.cpu.memory.cycles;
+            if cycles.is_multiple_of(SOUND_FRAME_CYCLES) {
+                self.render_sound(cycles);
+            }
+            // Only between two instructions, the snapshots don't contain the instruction in progress
+            if self.wait == 0 && self.rewind.is_some() && cycles >= self.next_rewind_cycle {
+                self.take_rewind_snapshot(cycles);
+            }
+        }
+    }
+
+    /// Take the rewind snapshots from now on, used by the frontend for its rewind slider
+    pub fn enable_rewind(&mut self) {
+        if self.rewind.is_none() {
+            self.rewind = Some(RewindBuffer::new(REWIND_MAX_BYTES));
+            self.next_rewind_cycle = self.cpu.memory.cycles;
+        }
+    }
+
+    fn take_rewind_snapshot(&mut self, cycle: u64) {
+        self.discard_rewound_snapshots();
+        let snapshot = save_snapshot(&self.cpu);
+        let interval = self.rewind_interval_cycles();
+        if let Some(rewind) = &mut self.rewind {
+            rewind.push(cycle, &snapshot);
+            self.next_rewind_cycle = cycle + interval;
+            let count = rewind.len();
+            self.notify(EmulatorEvent::RewindChanged(RewindStatus { count, position: None }));
+        }
+    }
+
+    /// The snapshots are 1 / REWIND_POINTS_PER_SECOND second apart at the speed of the emulator
+    fn rewind_interval_cycles(&self) -> u64 {
+        (self.config.config.emulator_speed_hz / REWIND_POINTS_PER_SECOND as u64).max(1)
+    }
+
+    fn notify(&self, event: EmulatorEvent) {
+        self.cpu.memory.context.notify(event);
+    }
+
+    /// Pause the machine and restore the rewind snapshot `index`
+    fn rewind_to(&mut self, index: usize) {
+        if let Some(snapshot) = self.rewind.as_ref().and_then(|rewind| rewind.get(index)) {
+            match load_snapshot(&mut self.cpu, &snapshot) {
+                Ok(_) => {
+                    self.wait = 0;
+                    self.cpu.run_status = RunStatus::Stop(StopReason::Ok, 0);
+                    self.rewind_position = Some(index);
+                    let count = self.rewind.as_ref().map_or(0, |rewind| rewind.len());
+                    self.notify(EmulatorEvent::RewindChanged(RewindStatus { count, position: Some(index) }));
+                    self.update_context();
+                }
+                Err(e) => { ui_log(&format!("Couldn't rewind: {e}")); }
+            }
+        }
+    }
+
+    /// Once the machine runs again from a rewound snapshot, the more recent ones are obsolete
+    fn discard_rewound_snapshots(&mut self) {
+        let interval = self.rewind_interval_cycles();
+        if let (Some(position), Some(rewind)) = (self.rewind_position.take(), &mut self.rewind) {
+            rewind.truncate(position + 1);
+            if let Some(cycle) = rewind.cycle(position) {
+                self.next_rewind_cycle = cycle + interval;
+            }
+            let count = rewind.len();
+            self.notify(EmulatorEvent::RewindChanged(RewindStatus { count, position: None }));
         }
     }
 
+    /// Turn the speaker toggles of the last frame into samples for the sound thread
+    fn render_sound(&mut self, cycle: u64) {
+        let memory = &mut self.cpu.memory;
+        memory.speaker.adjust_rate(memory.context.sound_sample_count(), SOUND_BUFFER_TARGET);
+        let mut samples = Vec::new();
+        memory.speaker.render(cycle, &mut samples);
+        memory.context.add_sound_samples(&samples);
+    }
+
     fn advance_cpu(&mut self) {
         if self.wait == 0 {
             self.cpu.step(&self.config.config, &self.config.config_file.breakpoints_hash);
@@ -117,7 +215,7 @@ impl AppleCpu {
                         ui_log(&format!("Sending message to UI: BreakpointWasHit: {:04X}",
                                         self.cpu.pc));
                         send_message!(&self.sender, ToUi::BreakpointWasHit(0));
-                        Shared::set_breakpoint_was_hit(true);
+                        self.notify(EmulatorEvent::BreakpointHit(true));
                     }
                 }
             };
@@ -167,7 +265,7 @@ impl AppleCpu {
 
         let mut status = CpuStateMsg::Running;
         while status == CpuStateMsg::Running {
-            if let Some(receiver) = &self.receiver {
+            if let Some(receiver) = self.receiver.clone() {
                 while status == CpuStateMsg::Running && ! receiver.is_empty() {
                     if let Ok(message) = receiver.recv() {
                         match message {
@@ -194,11 +292,12 @@ impl AppleCpu {
                                 }
                             }
                             SwapDisks => {
-                                self.cpu.memory.disk_controller.swap_disks();
-                                let paths = &self.cpu.memory.disk_controller.disks()
-                                    .map(|disk| disk.map(|di| di.path));
-                                self.config.config_file.set_drive(false, 0, paths[0].clone());
-                                self.config.config_file.set_drive(false, 1, paths[1].clone());
+                                if let Some(dc) = self.cpu.memory.disk_controller_mut() {
+                                    dc.swap_disks();
+                                    let paths = &dc.disks().map(|disk| disk.map(|di| di.path));
+                                    self.config.config_file.set_drive(false, 0, paths[0].clone());
+                                    self.config.config_file.set_drive(false, 1, paths[1].clone());
+                                }
                             }
                             Reboot => {
                                 status = CpuStateMsg::Rebooting;
@@ -209,17 +308,66 @@ impl AppleCpu {
                             SaveGraphics => {
                                 save_graphic_memory(&mut self.cpu.memory);
                             }
+                            EjectDisk(is_hard_drive, drive_number) => {
+                                self.cpu.memory.eject_disk(is_hard_drive, drive_number);
+                            }
                             LoadDisk(is_hard_drive, drive_number, disk_info) => {
                                 ui_log(&format!("Loading {} in {} drive {drive_number}",
                                     disk_info.path(),
                                     if is_hard_drive { "hard" } else { "" }
                                 ));
-                                self.cpu.memory.load_disk_from_file(is_hard_drive, drive_number,
-                                    disk_info);
+                                if let Err(e) = self.cpu.memory.load_disk_from_file(is_hard_drive,
+                                        drive_number, disk_info) {
+                                    ui_log(&e);
+                                }
+                            }
+                            SaveState(path) => {
+                                match save_snapshot_to_file(&self.cpu, &path) {
+                                    Ok(_) => { ui_log(&format!("Saved state to {path}")); }
+                                    Err(e) => { ui_log(&e); }
+                                }
+                            }
+                            LoadState(path) => {
+                                match load_snapshot_from_file(&mut self.cpu, &path) {
+                                    Ok(_) => {
+                                        ui_log(&format!("Loaded state from {path}"));
+                                        self.update_context();
+                                    }
+                                    Err(e) => { ui_log(&format!("Couldn't load state: {e}")); }
+                                }
+                            }
+                            Rewind(index) => {
+                                self.rewind_to(index);
+                            }
+                            ResumeRewind => {
+                                self.discard_rewound_snapshots();
+                                self.cpu.run_status = RunStatus::Continue(0);
+                                self.notify(EmulatorEvent::RunStatusChanged(self.cpu.run_status));
                             }
                             LockDisk(drive_number) => {
+                                if let Some(dc) = self.cpu.memory.disk_controller_mut() {
+                                    dc.set_write_protected(drive_number, true);
+                                }
                             }
                             UnlockDisk(drive_number) => {
+                                if let Some(dc) = self.cpu.memory.disk_controller_mut() {
+                                    dc.set_write_protected(drive_number, false);
+                                }
+                            }
+                            EnableDiskOverlay(drive_number) => {
+                                if let Some(dc) = self.cpu.memory.disk_controller_mut() {
+                                    dc.enable_overlay(drive_number);
+                                }
+                            }
+                            CommitDiskOverlay(drive_number) => {
+                                if let Some(dc) = self.cpu.memory.disk_controller_mut() {
+                                    dc.commit_overlay(drive_number);
+                                }
+                            }
+                            DiscardDiskOverlay(drive_number) => {
+                                if let Some(dc) = self.cpu.memory.disk_controller_mut() {
+                                    dc.discard_overlay(drive_number);
+                                }
                             }
                             CpuState(state) => {
                                 match state {
@@ -230,7 +378,7 @@ impl AppleCpu {
                                     }
                                     CpuStateMsg::Running => {
                                         self.cpu.run_status = RunStatus::Continue(0);
-                                        Shared::set_breakpoint_was_hit(false);
+                                        self.notify(EmulatorEvent::BreakpointHit(false));
                                         status = CpuStateMsg::Running;
                                     }
                                     CpuStateMsg::Paused => {
@@ -245,7 +393,7 @@ impl AppleCpu {
                                     _ => {}
                                 };
 
-                                Shared::get_cpu().run_status = self.cpu.run_status;
+                                self.notify(EmulatorEvent::RunStatusChanged(self.cpu.run_status));
                             }
                             TraceStatus(trace_status) => {
                                 let mut remove = false;
@@ -278,7 +426,7 @@ impl AppleCpu {
                                 d.generate(&self.cpu.memory.memories[0], &self.cpu.operands);
                             }
                             Debug => {
-                                if let Some(disk) = self.cpu.memory.disk_controller.left_disk() {
+                                if let Some(Some(disk)) = self.cpu.memory.disk_controller().map(|dc| dc.left_disk()) {
                                     for i in (0..20).step_by(4) {
                                         // disk.bit_streams.dump(i);
                                     }
@@ -346,8 +494,10 @@ impl AppleCpu {
         status
     }
 
+    /// Send the registers and memory to the observers
     fn update_context(&mut self) {
-        Shared::set_cpu(create_dump_msg(&mut self.cpu));
+        let dump = create_dump_msg(&mut self.cpu);
+        self.notify(EmulatorEvent::Cpu(dump));
     }
 }
 
@@ -0,0 +1,51 @@
+//! The headless runner, built without the gui: `maple2-headless --disk files/master.dsk --until "]"`
+
+use std::process::exit;
+use clap::Parser;
+use maple_2::headless::{run_headless, HeadlessOptions};
+
+/// Run the emulator without any window, sound or joystick (see [maple_2::headless])
+#[derive(Parser)]
+#[command(name = "maple2-headless")]
+struct Args {
+    /// Disk to insert, can be repeated for drive 2
+    #[arg(long)]
+    disk: Vec<String>,
+
+    /// Boot from this hard drive
+    #[arg(long)]
+    hard_drive: Option<String>,
+
+    /// Maximum number of cycles to run
+    #[arg(long, default_value_t = 50_000_000)]
+    cycles: u64,
+
+    /// Stop as soon as this text appears on the screen, fail if it doesn't
+    #[arg(long)]
+    until: Option<String>,
+
+    /// Save the screen to this PNG file
+    #[arg(long)]
+    png: Option<String>,
+
+    /// Print the hash of the screen
+    #[arg(long)]
+    hash: bool,
+
+    /// Fail if the hash of the screen is different
+    #[arg(long)]
+    expect_hash: Option<String>,
+}
+
+fn main() {
+    let args = Args::parse();
+    exit(run_headless(&HeadlessOptions {
+        disks: args.disk,
+        hard_drive: args.hard_drive,
+        max_cycles: args.cycles,
+        until: args.until,
+        png: args.png,
+        hash: args.hash,
+        expected_hash: args.expect_hash,
+    }));
+}
@@ -0,0 +1,236 @@
+use std::any::Any;
+use serde::{Deserialize, Serialize};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+/// Number of slots on the motherboard. Slot 0 is the language card, which is built in.
+pub const SLOT_COUNT: usize = 8;
+
+/// Size of the $C800-$CFFF expansion ROM
+pub const EXPANSION_ROM_SIZE: usize = 0x800;
+
+/// The cards that can be put in a slot, as saved in the config file
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, Hash, PartialEq)]
+pub enum CardType {
+    Disk2,
+    /// Disk ][ with the 13 sector boot PROM, to boot DOS 3.1 and 3.2 disks
+    Disk2Sector13,
+    SmartPort,
+    /// Two 6522 VIAs and two AY-3-8910
+    Mockingboard,
+    /// Mockingboard with an SSI-263 speech chip
+    MockingboardC,
+}
+
+/// Default slot configuration: Disk ][ in slot 6, SmartPort in slot 7. The config file can put
+/// a Mockingboard in slot 4.
+pub fn default_slots() -> [Option<CardType>; SLOT_COUNT] {
+    let mut result = [None; SLOT_COUNT];
+    result[6] = Some(CardType::Disk2);
+    result[7] = Some(CardType::SmartPort);
+    result
+}
+
+/// The memory as the CPU currently sees it, for the cards that transfer data to and from it
+/// (DMA). The accesses go through the soft switches: RAMRD, RAMWRT, 80STORE, ALTZP and the
+/// language card select the bank that is read or written.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+}
+
+/// A peripheral card. A card owns three address ranges for its slot `n`:
+/// - $C0n0-$C0nF (with n = slot + 8): the I/O locations, see [Card::io]
+/// - $Cn00-$CnFF: the card ROM, see [Card::rom] and [Card::page]
+/// - $C800-$CFFF: the expansion ROM, mapped in after the card ROM has been accessed and
+///   until $CFFF is accessed, see [Card::expansion_rom]
+pub trait Card {
+    fn name(&self) -> String;
+
+    /// Invoked on every access to $C0n0-$C0nF. `offset` is 0..=0xf.
+    /// `bus` is the memory as the CPU sees it, see [Bus].
+    /// Return the value read (ignored for writes).
+    fn io(&mut self, offset: u8, value: u8, read: bool, bus: &mut dyn Bus) -> u8;
+
+    /// The content of the $Cn00-$CnFF page, if the card has one
+    fn rom(&self) -> Option<&[u8]> { None }
+
+    /// The content of $C800-$CFFF, if the card has one
+    fn expansion_rom(&self) -> Option<&[u8]> { None }
+
+    /// Invoked on every access to $Cn00-$CnFF. `offset` is 0..=0xff. The default implementation
+    /// returns the content of [Card::rom]. Cards that map registers in that page
+    /// (e.g. the Mockingboard) override it.
+    fn page(&mut self, offset: u8, _value: u8, read: bool) -> Option<u8> {
+        if read {
+            self.rom().and_then(|rom| rom.get(offset as usize).cloned())
+        } else {
+            None
+        }
+    }
+
+    /// Level of the IRQ line of the card, sampled after each cycle
+    fn irq(&self) -> bool { false }
+
+    /// Invoked twice per CPU cycle, before and after the CPU runs (2 Mhz)
+    fn step(&mut self) {}
+
+    /// Invoked when the machine is rebooted
+    fn reset(&mut self) {}
+
+    /// Write the state of the card in a snapshot, see [crate::snapshot]
+    fn save_state(&self, _w: &mut SnapshotWriter) {}
+
+    /// Read the state written by [Card::save_state] and keep it for [Card::load_state], without
+    /// changing the card. Everything that can fail (e.g. reloading a disk) happens here: all the
+    /// cards are prepared before any of them is restored, see [Slots::prepare_state].
+    fn prepare_state(&mut self, _r: &mut SnapshotReader) -> Result<(), String> { Ok(()) }
+
+    /// Restore the state kept by [Card::prepare_state]
+    fn load_state(&mut self) {}
+
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// The cards in the slots, and the state of the $C800-$CFFF expansion ROM
+#[derive(Default)]
+pub struct Slots {
+    cards: [Option<Box<dyn Card>>; SLOT_COUNT],
+
+    /// The slot whose expansion ROM is currently mapped in $C800-$CFFF, if any.
+    /// Set when a $Cn00 page is accessed, reset when $CFFF is accessed (Sather 5-28).
+    expansion_slot: Option<usize>,
+}
+
+impl Slots {
+    pub fn insert(&mut self, slot: usize, card: Box<dyn Card>) {
+        assert!((1..SLOT_COUNT).contains(&slot), "Invalid slot: {slot}");
+        self.cards[slot] = Some(card);
+    }
+
+    pub fn card(&self, slot: usize) -> Option<&dyn Card> {
+        self.cards[slot].as_deref()
+    }
+
+    /// Return the first card of type `T`, e.g. `slots.find::<DiskController>()`
+    pub fn find<T: 'static>(&self) -> Option<&T> {
+        self.cards.iter().flatten().find_map(|c| c.as_any().downcast_ref::<T>())
+    }
+
+    pub fn find_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.cards.iter_mut().flatten().find_map(|c| c.as_any_mut().downcast_mut::<T>())
+    }
+
+    /// The slot of the first card of type `T`
+    pub fn slot_of<T: 'static>(&self) -> Option<usize> {
+        self.cards.iter().position(|c| c.as_ref().is_some_and(|c| c.as_any().is::<T>()))
+    }
+
+    /// Access to $C090-$C0FF
+    pub fn io(&mut self, address: u16, value: u8, read: bool, bus: &mut dyn Bus) -> Option<u8> {
+        let slot = ((address >> 4) & 7) as usize;
+        self.cards[slot].as_mut().map(|card| card.io((address & 0xf) as u8, value, read, bus))
+    }
+
+    /// Access $C100-$C7FF. Return `None` if there is no card or if the card doesn't map
+    /// anything there. Accessing a slot page also maps the expansion ROM of that card
+    /// in $C800-$CFFF.
+    pub fn page(&mut self, address: u16, value: u8, read: bool) -> Option<u8> {
+        let slot = ((address >> 8) & 7) as usize;
+        match self.cards[slot].as_mut() {
+            Some(card) => {
+                self.expansion_slot = Some(slot);
+                card.page((address & 0xff) as u8, value, read)
+            }
+            None => None,
+        }
+    }
+
+    /// Same as [Slots::page] but without side effects, used by the debugger
+    pub fn peek_rom(&self, address: u16) -> Option<u8> {
+        let slot = ((address >> 8) & 7) as usize;
+        self.cards[slot].as_ref()
+            .and_then(|card| card.rom())
+            .and_then(|rom| rom.get((address & 0xff) as usize).cloned())
+    }
+
+    /// Read $C800-$CFFE from the card that currently owns the expansion ROM, if any
+    pub fn read_expansion_rom(&self, address: u16) -> Option<u8> {
+        self.expansion_slot
+            .and_then(|slot| self.cards[slot].as_ref())
+            .and_then(|card| card.expansion_rom())
+            .and_then(|rom| rom.get((address - 0xc800) as usize).cloned())
+    }
+
+    /// Invoked on $CFFF: all the cards release $C800-$CFFF
+    pub fn reset_expansion_rom(&mut self) {
+        self.expansion_slot = None;
+    }
+
+    pub fn step(&mut self) {
+        for card in self.cards.iter_mut().flatten() {
+            card.step();
+        }
+    }
+
+    /// The IRQ line of each slot
+    pub fn irq_lines(&self) -> [bool; SLOT_COUNT] {
+        let mut result = [false; SLOT_COUNT];
+        for (slot, card) in self.cards.iter().enumerate() {
+            result[slot] = card.as_ref().is_some_and(|c| c.irq());
+        }
+        result
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.cards.iter().map(|card| card.as_ref().map_or(String::new(), |c| c.name())).collect()
+    }
+
+    /// The names of the cards, then the state of each card. A snapshot can only be loaded
+    /// if the cards are the same, which is verified before any card is modified.
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        for name in self.names() {
+            w.string(&name);
+        }
+        w.u8(self.expansion_slot.map_or(0xff, |slot| slot as u8));
+        for card in &self.cards {
+            w.section(|w| {
+                if let Some(c) = card { c.save_state(w); }
+            });
+        }
+    }
+
+    /// Read the state written by [Slots::save_state] without changing the cards (see
+    /// [Card::prepare_state]) and return the expansion slot to pass to [Slots::load_state]
+    pub fn prepare_state(&mut self, r: &mut SnapshotReader) -> Result<Option<usize>, String> {
+        for (slot, current) in self.names().iter().enumerate() {
+            let name = r.string()?;
+            if name != *current {
+                return Err(format!("Slot {slot} contains \"{current}\", the snapshot expects \"{name}\""));
+            }
+        }
+        let expansion_slot = r.u8()?;
+        for card in self.cards.iter_mut() {
+            let mut section = r.section()?;
+            if let Some(c) = card {
+                c.prepare_state(&mut section)?;
+            }
+        }
+        Ok(if expansion_slot == 0xff { None } else { Some(expansion_slot as usize) })
+    }
+
+    /// Restore the cards prepared by [Slots::prepare_state]
+    pub fn load_state(&mut self, expansion_slot: Option<usize>) {
+        self.expansion_slot = expansion_slot;
+        for card in self.cards.iter_mut().flatten() {
+            card.load_state();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.expansion_slot = None;
+        for card in self.cards.iter_mut().flatten() {
+            card.reset();
+        }
+    }
+}
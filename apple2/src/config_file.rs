@@ -8,7 +8,10 @@ use serde::{Deserialize, Serialize};
 
 use cpu::constants::DEFAULT_EMULATOR_SPEED_HZ;
 
-use crate::constants::{DEFAULT_DISKS_DIRECTORIES, DEFAULT_MAGNIFICATION, DEFAULT_SPEED_HZ};
+use crate::card::{CardType, default_slots, SLOT_COUNT};
+use crate::constants::{HARD_DRIVE_COUNT, DEFAULT_DISKS_DIRECTORIES, DEFAULT_MAGNIFICATION, DEFAULT_SPEED_HZ};
+use crate::disk::hard_drive::is_hard_drive_image;
+use crate::snapshot::SNAPSHOT_EXTENSION;
 use crate::roms::RomType;
 use crate::ui_log;
 
@@ -34,6 +37,10 @@ pub struct ConfigFile {
     drive_2: Option<String>,
     hard_drive_1: Option<String>,
     hard_drive_2: Option<String>,
+    #[serde(default)]
+    hard_drive_3: Option<String>,
+    #[serde(default)]
+    hard_drive_4: Option<String>,
     // The tab to start in (ordinal position of the enum [MainTab]
     pub(crate) tab: usize,
     pub(crate) magnification: Option<u16>,
@@ -47,6 +54,10 @@ pub struct ConfigFile {
 
     /// If true, show hard drives, if false, show drives
     pub(crate) show_hard_drive: bool,
+
+    /// The card in each slot (index 0 is ignored, the language card is built in)
+    #[serde(default = "default_slots")]
+    slots: [Option<CardType>; SLOT_COUNT],
 }
 
 impl Default for ConfigFile {
@@ -59,16 +70,29 @@ impl Default for ConfigFile {
             drive_2: None,
             hard_drive_1: None,
             hard_drive_2: None,
+            hard_drive_3: None,
+            hard_drive_4: None,
             tab: 0,
             breakpoints: Vec::new(),
             breakpoints_hash: HashSet::new(),
             rom_type: Some(RomType::Apple2Enhanced),
             show_hard_drive: false,
+            slots: default_slots(),
         }
     }
 }
 
 impl ConfigFile {
+    /// The default settings, optionally booting from a hard drive. Used by the headless runner,
+    /// never saved.
+    pub fn headless(hard_drive: Option<String>) -> ConfigFile {
+        Self {
+            show_hard_drive: hard_drive.is_some(),
+            hard_drive_1: hard_drive,
+            ..Default::default()
+        }
+    }
+
     pub fn magnification(&self) -> u16 { self.magnification.unwrap_or_else(|| DEFAULT_MAGNIFICATION)}
 
     /// If a ConfigFile already exists, read it, if not create it with defaults, then return it
@@ -102,6 +126,10 @@ impl ConfigFile {
         }
     }
 
+    pub fn slots(&self) -> [Option<CardType>; SLOT_COUNT] {
+        self.slots
+    }
+
     pub fn hard_drive_1(&self) -> Option<String> {
         self.hard_drive_1.clone()
     }
@@ -110,6 +138,12 @@ impl ConfigFile {
         self.hard_drive_2.clone()
     }
 
+    /// The images of the SmartPort units
+    pub fn hard_drives(&self) -> [Option<String>; HARD_DRIVE_COUNT] {
+        [self.hard_drive_1.clone(), self.hard_drive_2.clone(), self.hard_drive_3.clone(),
+            self.hard_drive_4.clone()]
+    }
+
     pub fn breakpoints(&self) -> &Vec<Breakpoint> {
         &self.breakpoints
     }
@@ -140,18 +174,20 @@ impl ConfigFile {
 
     pub(crate) fn disk_directories(&self) -> &Vec<String> { &self.disk_directories }
     pub fn emulator_speed_hz(&self) -> u64 { self.emulator_speed_hz }
-    pub(crate) fn drive_1(&self) -> Option<String> { self.drive_1.clone() }
-    pub(crate) fn drive_2(&self) -> Option<String> { self.drive_2.clone() }
+    pub fn drive_1(&self) -> Option<String> { self.drive_1.clone() }
+    pub fn drive_2(&self) -> Option<String> { self.drive_2.clone() }
 
     pub fn set_drive(&mut self, is_hard_drive: bool, drive_number: usize, path: Option<String>) {
         if let Some(p) = &path {
-            if p.ends_with("hdv") && ! is_hard_drive {
+            if is_hard_drive_image(p) && ! is_hard_drive {
                 println!("BUG HARD DRIVE");
             }
         }
         match (is_hard_drive, drive_number) {
             (true, 0) => { self.hard_drive_1 = path }
             (true, 1) => { self.hard_drive_2 = path }
+            (true, 2) => { self.hard_drive_3 = path }
+            (true, 3) => { self.hard_drive_4 = path }
             (false, 0) => { self.drive_1 = path }
             (false, 1) => { self.drive_2 = path }
             _ => { panic!("Should never happen"); }
@@ -202,6 +238,13 @@ impl ConfigFile {
         }
     }
 
+    /// The file used by the "Save state" and "Load state" buttons, next to the config file
+    pub(crate) fn quick_save_path() -> Option<String> {
+        Self::config_file_path()
+            .map(|p| p.with_file_name(format!("quick_save.{SNAPSHOT_EXTENSION}")))
+            .and_then(|p| p.to_str().map(|s| s.to_string()))
+    }
+
     /// Return the fully qualified path + file name of the config file
     fn config_file_path() -> Option<PathBuf> {
         let mut result: Option<PathBuf> = None;
@@ -231,12 +274,15 @@ impl ConfigFile {
                     drive_1: None, drive_2: None,
                     hard_drive_1: None,
                     hard_drive_2: None,
+                    hard_drive_3: None,
+                    hard_drive_4: None,
                     tab: 0,
                     magnification: Some(DEFAULT_MAGNIFICATION),
                     breakpoints: Vec::new(),
                     breakpoints_hash: HashSet::new(),
                     rom_type: Some(RomType::Apple2Enhanced),
                     show_hard_drive: false,
+                    slots: default_slots(),
                 };
                 user_config.save();
             }
@@ -245,6 +291,8 @@ impl ConfigFile {
         }
     }
 
+    pub fn show_hard_drive(&self) -> bool { self.show_hard_drive }
+
     pub(crate) fn set_show_hard_drive(&mut self, b: bool) {
         self.show_hard_drive = b;
         self.save();
@@ -27,7 +27,8 @@ pub(crate) const HIRES_HEIGHT: u16 = 192;
 
 pub const _FRAMES_PER_SECOND: usize = 50;
 pub const _CYCLES_PER_LINE: usize = 65;
-pub const _CYCLES_PER_FRAME: usize = 20280;
+/// 65 cycles per line, 262 lines (NTSC)
+pub const CYCLES_PER_FRAME: u64 = 17_030;
 
 
 ///
@@ -57,17 +58,39 @@ pub(crate) const DIVIDER: f32 = 1.7;
 
 /// Sound
 pub const SAMPLE_RATE: u32 = 48_000;
+/// Frequency used to turn cycles into sound samples
+pub(crate) const CPU_FREQUENCY_HZ: u32 = 1_000_000;
+/// The speaker goes between -SPEAKER_VOLUME and SPEAKER_VOLUME
+pub(crate) const SPEAKER_VOLUME: f32 = 0.1;
+/// Volume of the Mockingboard relative to the speaker
+pub(crate) const MOCKINGBOARD_VOLUME: f32 = 0.2;
+/// Maximum number of samples waiting to be played (0.25s). Older ones are dropped
+/// if the emulator produces them faster than the sound card consumes them.
+pub(crate) const MAX_QUEUED_SAMPLES: usize = SAMPLE_RATE as usize / 4;
+/// The speaker toggles are turned into samples every SOUND_FRAME_CYCLES cycles
+pub(crate) const SOUND_FRAME_CYCLES: u64 = 4_096;
+/// Number of speaker samples we try to keep queued for the sound thread (50ms), see
+/// [crate::speaker::SpeakerSynth::adjust_rate]
+pub(crate) const SOUND_BUFFER_TARGET: usize = SAMPLE_RATE as usize / 20;
+
+/// Rewind: REWIND_POINTS_PER_SECOND snapshots are taken per second at the speed of the emulator
+/// (every 0.1s) and the compressed snapshots use at most REWIND_MAX_BYTES, see
+/// [crate::rewind::RewindBuffer]
+pub(crate) const REWIND_POINTS_PER_SECOND: usize = 10;
+pub(crate) const REWIND_MAX_BYTES: usize = 64 * 1024 * 1024;
 
 /// How many cycles to wait between the time when the motor is turned off
 /// and when it actually turns off
 pub(crate) const SPINNING_DOWN_CYCLES: u64 = 1_200_000;
 
+/// Number of hard drives, which are the units of the SmartPort card
+pub const HARD_DRIVE_COUNT: usize = 4;
+
 /// Globals mostly used for debugging and getting access to the current PC and the start time from anywhere
 /// Wish I had some decent Dependency Injection instead of having to declare globals :-(
-pub(crate) static START: OnceLock<Instant> = OnceLock::new();
-pub(crate) static SENDER_TO_UI: OnceLock<Sender<ToUi>> = OnceLock::new();
+pub static START: OnceLock<Instant> = OnceLock::new();
+pub static SENDER_TO_UI: OnceLock<Sender<ToUi>> = OnceLock::new();
 pub(crate) static PC: RwLock<u16> = RwLock::new(0);
-pub(crate) static CYCLES: RwLock<u64> = RwLock::new(0);
 pub(crate) fn pc() -> String {
     format!("{:04X}", *PC.read().unwrap())
 }
@@ -94,8 +117,9 @@ lazy_static! {
 
     pub static ref DEFAULT_DISKS_DIRECTORIES: Vec<String> = vec![];
 
-    pub static ref DISKS_SUFFIXES: [String; 3] = [
-        "woz".to_string(), "dsk".to_string(), "hdv".to_string()
+    pub static ref DISKS_SUFFIXES: [String; 9] = [
+        "woz".to_string(), "dsk".to_string(), "do".to_string(), "d13".to_string(), "nib".to_string(),
+        "a2r".to_string(), "hdv".to_string(), "po".to_string(), "2mg".to_string()
     ];
 
     pub static ref WATCHED_FILES: Vec<WatchedFileMsg> = vec![
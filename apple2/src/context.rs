@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use cpu::cpu::RunStatus;
+use crate::constants::{HARD_DRIVE_COUNT, MAX_QUEUED_SAMPLES};
+use crate::disk::disk_info::DiskInfo;
+use crate::messages::CpuDumpMsg;
+
+/// The state of a machine that its frontends need to see or modify while it runs: the disks in
+/// the drives and where their heads are, the joystick, the sound samples to play.
+///
+/// Each machine owns its context (see [crate::memory::Apple2Memory::context]), which is shared
+/// with the frontend through an `Arc`. The frontend pushes its input (joystick, which drive to
+/// boot from) with the setters, pulls the sound samples, and gets notified of everything else
+/// by subscribing an [EmulatorObserver].
+#[derive(Default)]
+pub struct EmulatorContext {
+    drives: RwLock<[DriveState; 2]>,
+    hard_drives: RwLock<[HardDriveState; HARD_DRIVE_COUNT]>,
+    /// If true, the SmartPort card is inserted when the machine is created
+    boot_from_hard_drive: RwLock<bool>,
+    controller: RwLock<ControllerState>,
+    sound_samples: Mutex<VecDeque<f32>>,
+    mockingboard_samples: Mutex<VecDeque<f32>>,
+    observers: RwLock<Vec<Arc<dyn EmulatorObserver>>>,
+}
+
+#[derive(Clone, Default)]
+struct DriveState {
+    disk_info: Option<DiskInfo>,
+    sector: u8,
+    phase_160: u8,
+}
+
+#[derive(Clone, Default)]
+struct HardDriveState {
+    disk_info: Option<DiskInfo>,
+    block_number: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ControllerState {
+    /// Paddles 0-3, 0-255
+    pub values: [u8; 4],
+    pub buttons: [bool; 4],
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RewindStatus {
+    /// Number of snapshots in the rewind buffer
+    pub count: usize,
+    /// The snapshot currently displayed if the machine is being rewound, None if it's running
+    pub position: Option<usize>,
+}
+
+/// Sent to the observers each time the state of the machine changes
+#[derive(Clone, Debug)]
+pub enum EmulatorEvent {
+    /// Drive number, None if the disk was ejected
+    DiskChanged(usize, Option<DiskInfo>),
+    HardDriveChanged(usize, Option<DiskInfo>),
+    /// Drive number, phase (0-159)
+    PhaseChanged(usize, u8),
+    /// Drive number, sector whose address field was just read
+    SectorChanged(usize, u8),
+    /// Hard drive number, block
+    BlockChanged(usize, u16),
+    ControllerChanged(ControllerState),
+    /// Registers and memory, sent periodically and each time the machine is paused or restored
+    Cpu(CpuDumpMsg),
+    RunStatusChanged(RunStatus),
+    /// true when a breakpoint was hit, false when the machine resumes
+    BreakpointHit(bool),
+    RewindChanged(RewindStatus),
+}
+
+pub trait EmulatorObserver: Send + Sync {
+    /// Invoked on the emulator thread, so it should return quickly
+    fn on_event(&self, event: &EmulatorEvent);
+}
+
+impl EmulatorContext {
+    pub fn subscribe(&self, observer: Arc<dyn EmulatorObserver>) {
+        self.observers.write().unwrap().push(observer);
+    }
+
+    pub fn notify(&self, event: EmulatorEvent) {
+        for observer in self.observers.read().unwrap().iter() {
+            observer.on_event(&event);
+        }
+    }
+
+    pub fn drive(&self, drive_index: usize) -> Option<DiskInfo> {
+        self.drives.read().unwrap()[drive_index].disk_info.clone()
+    }
+
+    pub fn set_drive(&self, drive_index: usize, disk_info: Option<DiskInfo>) {
+        self.drives.write().unwrap()[drive_index].disk_info = disk_info.clone();
+        self.notify(EmulatorEvent::DiskChanged(drive_index, disk_info));
+    }
+
+    pub fn phase_160(&self, drive_index: usize) -> u8 {
+        self.drives.read().unwrap()[drive_index].phase_160
+    }
+
+    pub fn set_phase_160(&self, drive_index: usize, phase_160: u8) {
+        self.drives.write().unwrap()[drive_index].phase_160 = phase_160;
+        self.notify(EmulatorEvent::PhaseChanged(drive_index, phase_160));
+    }
+
+    pub fn sector(&self, drive_index: usize) -> u8 {
+        self.drives.read().unwrap()[drive_index].sector
+    }
+
+    pub fn set_sector(&self, drive_index: usize, sector: u8) {
+        self.drives.write().unwrap()[drive_index].sector = sector;
+        self.notify(EmulatorEvent::SectorChanged(drive_index, sector));
+    }
+
+    pub fn hard_drive(&self, drive_index: usize) -> Option<DiskInfo> {
+        self.hard_drives.read().unwrap()[drive_index].disk_info.clone()
+    }
+
+    pub fn set_hard_drive(&self, drive_index: usize, disk_info: Option<DiskInfo>) {
+        self.hard_drives.write().unwrap()[drive_index].disk_info = disk_info.clone();
+        self.notify(EmulatorEvent::HardDriveChanged(drive_index, disk_info));
+    }
+
+    pub fn block_number(&self, drive_index: usize) -> u16 {
+        self.hard_drives.read().unwrap()[drive_index].block_number
+    }
+
+    pub fn set_block_number(&self, drive_index: usize, block_number: u16) {
+        self.hard_drives.write().unwrap()[drive_index].block_number = block_number;
+        self.notify(EmulatorEvent::BlockChanged(drive_index, block_number));
+    }
+
+    pub fn boot_from_hard_drive(&self) -> bool {
+        *self.boot_from_hard_drive.read().unwrap()
+    }
+
+    /// Takes effect the next time the machine is created
+    pub fn set_boot_from_hard_drive(&self, v: bool) {
+        *self.boot_from_hard_drive.write().unwrap() = v;
+    }
+
+    pub fn controller(&self) -> ControllerState {
+        *self.controller.read().unwrap()
+    }
+
+    pub fn set_controller_values(&self, values: [u8; 4]) {
+        let state = {
+            let mut controller = self.controller.write().unwrap();
+            controller.values = values;
+            *controller
+        };
+        self.notify(EmulatorEvent::ControllerChanged(state));
+    }
+
+    pub fn set_controller_button(&self, index: usize, pressed: bool) {
+        let state = {
+            let mut controller = self.controller.write().unwrap();
+            controller.buttons[index] = pressed;
+            *controller
+        };
+        self.notify(EmulatorEvent::ControllerChanged(state));
+    }
+
+    pub fn add_sound_samples(&self, samples: &[f32]) {
+        add_samples(&self.sound_samples, samples);
+    }
+
+    pub fn next_sound_sample(&self) -> Option<f32> {
+        self.sound_samples.lock().unwrap().pop_front()
+    }
+
+    /// Number of speaker samples waiting to be played
+    pub fn sound_sample_count(&self) -> usize {
+        self.sound_samples.lock().unwrap().len()
+    }
+
+    pub fn add_mockingboard_samples(&self, samples: &[f32]) {
+        add_samples(&self.mockingboard_samples, samples);
+    }
+
+    pub fn next_mockingboard_sample(&self) -> Option<f32> {
+        self.mockingboard_samples.lock().unwrap().pop_front()
+    }
+}
+
+/// Append to a sample queue, dropping the oldest samples if it gets too long
+fn add_samples(queue: &Mutex<VecDeque<f32>>, samples: &[f32]) {
+    let mut queue = queue.lock().unwrap();
+    queue.extend(samples);
+    let len = queue.len();
+    if len > MAX_QUEUED_SAMPLES {
+        queue.drain(0..len - MAX_QUEUED_SAMPLES);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
 #[derive(Default)]
 pub struct Actions {
     pub actions: Vec<ActionWrapper>,
@@ -14,6 +16,43 @@ impl /* <T: Clone + 'static> */ Actions {
         });
     }
 
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.usize(self.actions.len());
+        for wrapper in &self.actions {
+            w.u64(wrapper.wait);
+            w.bool(wrapper.has_run);
+            match &wrapper.action {
+                CycleAction::UpdatePhase(a) => {
+                    w.u8(0);
+                    w.usize(a.drive_index);
+                    w.usize(a.phase_160);
+                }
+                CycleAction::MotorOff(a) => {
+                    w.u8(1);
+                    w.usize(a.drive_index);
+                }
+            }
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.actions.clear();
+        for _ in 0..r.usize()? {
+            let wait = r.u64()?;
+            let has_run = r.bool()?;
+            let action = match r.u8()? {
+                0 => CycleAction::UpdatePhase(UpdatePhaseAction {
+                    drive_index: r.usize()?,
+                    phase_160: r.usize()?,
+                }),
+                1 => CycleAction::MotorOff(MotorOffAction { drive_index: r.usize()? }),
+                t => { return Err(format!("Unknown cycle action: {t}")); }
+            };
+            self.actions.push(ActionWrapper { wait, has_run, action });
+        }
+        Ok(())
+    }
+
     pub fn remove_motor_off_actions(&mut self) {
         for mut action in &mut self.actions {
             match action.action {
@@ -1,5 +1,5 @@
 use std::ops::Range;
-use crate::constants::{CYCLES, PC};
+use crate::constants::PC;
 use crate::disk::bit_stream::Nibble;
 
 pub const DEBUG_DISASM: Option<Range<i32>> = Some(0xc65e..0xc6b8);
@@ -36,6 +36,6 @@ pub fn hex_dump_fn<T>(b: &[T], f: fn(&T) -> String) {
 }
 
 #[allow(unused)]
-pub(crate) fn log_emulator(s: &str) {
-    println!("{:8} | {:04X} | {}", *CYCLES.read().unwrap(), *PC.read().unwrap(), s);
+pub(crate) fn log_emulator(cycles: u64, s: &str) {
+    println!("{:8} | {:04X} | {}", cycles, *PC.read().unwrap(), s);
 }
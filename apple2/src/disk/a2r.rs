@@ -0,0 +1,323 @@
+//! A2R images (Applesauce raw flux captures, versions 2 and 3). Each quarter-track can have
+//! several captures, usually covering more than one revolution of the disk. The revolution
+//! of each capture is found by looking for the point where the flux transitions start
+//! repeating themselves, and becomes a flux track (see [BitStream::new_flux]).
+//! The tracks whose loop couldn't be found are listed in [A2r::unresolved].
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use crate::disk::archive;
+use crate::disk::bit_stream::{BitStream, BitStreams, DEFAULT_BIT_TIMING};
+use crate::disk::disk::PDisk;
+use crate::disk::disk_controller::MAX_PHASE;
+use crate::disk::disk_info::{DiskInfo, WozVersion};
+use crate::disk::woz::{InfoChunk, Woz};
+use crate::ui_log;
+
+/// Resolution of the WOZ flux tracks, in picoseconds
+const WOZ_RESOLUTION: u64 = 125_000;
+/// A revolution takes 200ms at 300 RPM, in 125ns ticks
+const NOMINAL_REVOLUTION: u64 = 1_600_000;
+/// How many transitions are compared to decide that a track loops
+const LOOP_WINDOW: usize = 4096;
+/// Fraction of these transitions that need to match
+const LOOP_MATCH: f32 = 0.9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptureType {
+    /// One revolution or a bit more
+    Timing,
+    Bits,
+    /// Extended timing: at least two revolutions, to find the loop point
+    XTiming,
+}
+
+#[derive(Clone)]
+pub struct Capture {
+    /// Quarter-track, i.e. the phase_160 of the head
+    pub location: usize,
+    pub capture_type: CaptureType,
+    /// Delays between the flux transitions, in 125ns ticks
+    pub delays: Vec<u32>,
+    /// Length of a revolution in 125ns ticks, 0 if unknown
+    pub estimated_loop: u64,
+}
+
+#[derive(Clone)]
+pub struct A2r {
+    disk_info: DiskInfo,
+    bit_streams: BitStreams,
+    pub meta: HashMap<String, String>,
+    pub captures: Vec<Capture>,
+    /// Quarter-tracks that were captured but whose revolution couldn't be found
+    pub unresolved: Vec<usize>,
+}
+
+impl PDisk for A2r {
+    fn bit_streams(&self) -> &BitStreams {
+        &self.bit_streams
+    }
+
+    fn bit_streams_mut(&mut self) -> &mut BitStreams {
+        &mut self.bit_streams
+    }
+
+    fn save(&mut self) {
+        ui_log(&format!("A2R images are read only, not saving {}", self.disk_info.path));
+    }
+
+    fn disk_info(&self) -> &DiskInfo {
+        &self.disk_info
+    }
+
+    fn disk_info_mut(&mut self) -> &mut DiskInfo {
+        &mut self.disk_info
+    }
+}
+
+impl A2r {
+    pub fn new_with_file(filename: &str, quick: bool) -> Result<A2r, String> {
+        let bytes = archive::read(filename).map_err(|e| e.to_string())?;
+        let mut result = Self::new(&bytes, filename)?;
+        if ! quick {
+            result.resolve_tracks();
+            if ! result.unresolved.is_empty() {
+                ui_log(&format!("{filename}: couldn't find the revolution of tracks {}",
+                    Self::track_names(&result.unresolved)));
+            }
+        }
+        Ok(result)
+    }
+
+    fn new(bytes: &[u8], filename: &str) -> Result<A2r, String> {
+        if bytes.len() < 8 || &bytes[0..3] != b"A2R" || bytes[4..8] != [0xff, 0xa, 0xd, 0xa] {
+            return Err(format!("{filename} is not an A2R file"));
+        }
+        let version = bytes[3];
+        if version != b'2' && version != b'3' {
+            return Err(format!("Unsupported A2R version {}", version as char));
+        }
+
+        let mut meta = HashMap::new();
+        let mut captures = Vec::new();
+        let mut write_protected = true;
+        let mut i = 8;
+        while i + 8 <= bytes.len() {
+            let name = &bytes[i..i + 4];
+            let size = read32(bytes, i + 4) as usize;
+            i += 8;
+            let chunk = bytes.get(i..i + size).ok_or(format!("Truncated chunk in {filename}"))?;
+            match name {
+                b"INFO" => {
+                    // Version, creator (32 bytes), drive type, write protected, synchronized
+                    if chunk.len() < 36 { return Err("Invalid INFO chunk".into()); }
+                    if chunk[33] != 1 {
+                        return Err(format!("Drive type {} not supported, only 5.25 disks are", chunk[33]));
+                    }
+                    write_protected = chunk[34] == 1;
+                }
+                b"STRM" => { captures.extend(Self::read_strm(chunk)?); }
+                b"RWCP" => { captures.extend(Self::read_rwcp(chunk)?); }
+                b"META" => {
+                    for line in String::from_utf8_lossy(chunk).split('\n') {
+                        let mut sp = line.split('\t');
+                        if let (Some(key), Some(value)) = (sp.next(), sp.next()) {
+                            meta.insert(key.to_string(), value.to_string());
+                        }
+                    }
+                }
+                _ => {}
+            }
+            i += size;
+        }
+
+        let woz_version = if version == b'2' { WozVersion::A2r2 } else { WozVersion::A2r3 };
+        let disk_info = DiskInfo::new2(meta.get("title").cloned(), filename, meta.clone(),
+            woz_version, write_protected);
+        Ok(A2r { disk_info, bit_streams: Default::default(), meta, captures, unresolved: Vec::new() })
+    }
+
+    fn capture_type(t: u8) -> Result<CaptureType, String> {
+        match t {
+            1 => Ok(CaptureType::Timing),
+            2 => Ok(CaptureType::Bits),
+            3 => Ok(CaptureType::XTiming),
+            _ => Err(format!("Unknown capture type {t}")),
+        }
+    }
+
+    /// A2R 2: location, capture type, data size, estimated loop point, data. Ends with $FF
+    fn read_strm(chunk: &[u8]) -> Result<Vec<Capture>, String> {
+        let mut result = Vec::new();
+        let mut i = 0;
+        while i + 10 <= chunk.len() && chunk[i] != 0xff {
+            let size = read32(chunk, i + 2) as usize;
+            let data = chunk.get(i + 10..i + 10 + size).ok_or("Truncated STRM capture")?;
+            result.push(Capture {
+                location: chunk[i] as usize,
+                capture_type: Self::capture_type(chunk[i + 1])?,
+                delays: Woz::bytes_to_delays(data),
+                estimated_loop: read32(chunk, i + 6) as u64,
+            });
+            i += 10 + size;
+        }
+        Ok(result)
+    }
+
+    /// A2R 3: version, resolution in picoseconds, 11 reserved bytes, then each capture starts
+    /// with 'C' (type, location, index signals, data size, data) and the list ends with 'X'
+    fn read_rwcp(chunk: &[u8]) -> Result<Vec<Capture>, String> {
+        if chunk.len() < 16 { return Err("Invalid RWCP chunk".into()); }
+        let resolution = read32(chunk, 1) as u64;
+        let mut result = Vec::new();
+        let mut i = 16;
+        while i < chunk.len() && chunk[i] == b'C' {
+            let header = chunk.get(i + 1..i + 5).ok_or("Truncated RWCP capture")?;
+            let capture_type = Self::capture_type(header[0])?;
+            let location = header[1] as usize | (header[2] as usize) << 8;
+            let index_count = header[3] as usize;
+            i += 5;
+            let indexes: Vec<u64> = (0..index_count).map(|k| read32(chunk, i + k * 4) as u64).collect();
+            i += index_count * 4;
+            let size = read32(chunk, i) as usize;
+            let data = chunk.get(i + 4..i + 4 + size).ok_or("Truncated RWCP capture")?;
+            i += 4 + size;
+
+            let estimated_loop = if indexes.len() >= 2 {
+                indexes[1].saturating_sub(indexes[0]) * resolution / WOZ_RESOLUTION
+            } else {
+                0
+            };
+            result.push(Capture {
+                location, capture_type,
+                delays: Self::convert_resolution(&Woz::bytes_to_delays(data), resolution),
+                estimated_loop,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Convert delays from `resolution` picoseconds to 125ns ticks, rounding the time of each
+    /// transition rather than each delay so that the errors don't accumulate
+    fn convert_resolution(delays: &[u32], resolution: u64) -> Vec<u32> {
+        if resolution == WOZ_RESOLUTION {
+            return delays.to_vec();
+        }
+        let mut result = Vec::with_capacity(delays.len());
+        let (mut time, mut previous) = (0_u64, 0_u64);
+        for delay in delays {
+            time += *delay as u64 * resolution;
+            let ticks = (time + WOZ_RESOLUTION / 2) / WOZ_RESOLUTION;
+            result.push((ticks - previous) as u32);
+            previous = ticks;
+        }
+        result
+    }
+
+    /// Number of delays making one revolution of the capture, None if the transitions
+    /// don't repeat themselves
+    pub fn find_loop(capture: &Capture) -> Option<usize> {
+        let delays = &capture.delays;
+        let expected = if capture.estimated_loop > 0 { capture.estimated_loop } else { NOMINAL_REVOLUTION };
+        let (low, high) = (expected * 97 / 100, expected * 103 / 100);
+        let matches = |a: u32, b: u32| a.abs_diff(b) <= 3 + a.max(b) / 16;
+
+        let mut best: Option<(usize, usize)> = None;
+        let mut time = 0_u64;
+        for (j, delay) in delays.iter().enumerate() {
+            if time > high { break; }
+            if time >= low {
+                let window = LOOP_WINDOW.min(delays.len() - j).min(j);
+                // Quickly discard the candidates whose first transitions don't match
+                let quick = window.min(64);
+                let quick_matches = (0..quick).filter(|k| matches(delays[*k], delays[j + k])).count();
+                if window > 0 && quick_matches as f32 >= quick as f32 * LOOP_MATCH {
+                    let count = (0..window).filter(|k| matches(delays[*k], delays[j + k])).count();
+                    if count as f32 >= window as f32 * LOOP_MATCH
+                            && best.is_none_or(|(_, c)| count > c) {
+                        best = Some((j, count));
+                    }
+                }
+            }
+            time += *delay as u64;
+        }
+
+        match best {
+            Some((j, _)) => Some(j),
+            // A single revolution captured with the loop point measured by Applesauce
+            None if capture.estimated_loop > 0 && time <= high => {
+                let mut time = 0;
+                delays.iter().position(|d| { time += *d as u64; time >= capture.estimated_loop })
+                    .map(|j| j + 1)
+            }
+            None => None,
+        }
+    }
+
+    /// Turn the captures into flux tracks. The quarter-tracks next to a track without their
+    /// own capture read that track, like the TMAP of WOZ files.
+    fn resolve_tracks(&mut self) {
+        let mut tracks: Vec<Option<BitStream>> = vec![None; MAX_PHASE];
+        let mut unresolved = Vec::new();
+        for (location, track) in tracks.iter_mut().enumerate() {
+            let mut captures: Vec<&Capture> = self.captures.iter()
+                .filter(|c| c.location == location && c.capture_type != CaptureType::Bits)
+                .collect();
+            if captures.is_empty() { continue; }
+            // The extended captures are the most likely to contain a full loop
+            captures.sort_by_key(|c| c.capture_type != CaptureType::XTiming);
+            let revolution = captures.iter()
+                .find_map(|c| Self::find_loop(c).map(|j| &c.delays[0..j]));
+            match revolution {
+                Some(delays) => {
+                    *track = Some(BitStream::new_flux(delays, None, DEFAULT_BIT_TIMING));
+                }
+                None => { unresolved.push(location); }
+            }
+        }
+
+        let mut tmap = [0xff_u8; MAX_PHASE];
+        let mut bit_streams = Vec::with_capacity(MAX_PHASE);
+        for (phase, t) in tmap.iter_mut().enumerate() {
+            let neighbour = [Some(phase), phase.checked_sub(1), Some(phase + 1)].into_iter().flatten()
+                .find(|p| *p < MAX_PHASE && tracks[*p].is_some());
+            match neighbour {
+                Some(p) => {
+                    *t = p as u8;
+                    bit_streams.push(tracks[p].clone().unwrap());
+                }
+                None => { bit_streams.push(BitStream::random()); }
+            }
+        }
+        self.bit_streams = BitStreams::new(bit_streams, tmap, self.disk_info.clone());
+        self.unresolved = unresolved;
+    }
+
+    /// "0, 3.25, 17.5"
+    pub fn track_names(phases: &[usize]) -> String {
+        phases.iter().map(|p| match p % 4 {
+            0 => format!("{}", p / 4),
+            q => format!("{}.{}", p / 4, q * 25),
+        }).collect::<Vec<_>>().join(", ")
+    }
+
+    /// A WOZ 2 file with the flux tracks, their decoding as bit tracks and the META of the capture
+    pub fn to_woz(&self) -> Result<Vec<u8>, String> {
+        let mut info = InfoChunk::default();
+        info.write_protected = self.disk_info.is_write_protected;
+        Woz::to_bytes(&info, &self.meta, &self.bit_streams)
+    }
+}
+
+/// Convert an .a2r file into a .woz file next to it, return the path of that file
+pub fn a2r_to_woz(path: &str) -> Result<String, String> {
+    let a2r = A2r::new_with_file(path, false)?;
+    let file_out = Path::new(path).with_extension("woz").to_str().unwrap().to_string();
+    fs::write(&file_out, a2r.to_woz()?).map_err(|e| e.to_string())?;
+    Ok(file_out)
+}
+
+fn read32(bytes: &[u8], i: usize) -> u32 {
+    bytes.get(i..i + 4).map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
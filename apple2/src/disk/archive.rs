@@ -0,0 +1,681 @@
+//! Disk images inside .zip, .gz and ShrinkIt (.shk, .sdk) archives.
+//!
+//! A disk inside an archive has the path of the archive followed by the name of the member,
+//! e.g. `disks/Games.zip/Games/Choplifter.woz` or `disks/Total Replay.hdv.gz/Total Replay.hdv`.
+//! Archives are never written to: the disks read from them save to an overlay next to the
+//! archive, see [sidecar_path].
+//!
+//! Only what disk images need is supported: deflated or stored zip members, gzip, and the
+//! uncompressed, LZW/1 and LZW/2 threads of ShrinkIt archives.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::disk::dsk_to_woz::crc32;
+
+pub const ARCHIVE_SUFFIXES: [&str; 4] = ["zip", "gz", "shk", "sdk"];
+
+/// A file of an archive
+#[derive(Clone, Debug, PartialEq)]
+pub struct Member {
+    /// The path of the file in the archive, with '/' separators
+    pub name: String,
+    /// Uncompressed
+    pub size: usize,
+}
+
+pub fn is_archive(path: &str) -> bool {
+    let extension = Path::new(path).extension().and_then(OsStr::to_str).unwrap_or_default().to_lowercase();
+    ARCHIVE_SUFFIXES.contains(&extension.as_str())
+}
+
+/// "disks/Games.zip/Games/Choplifter.woz" -> ("disks/Games.zip", "Games/Choplifter.woz"), None if
+/// the path is not inside an archive
+pub fn split_path(path: &str) -> Option<(String, String)> {
+    Path::new(path).ancestors().skip(1)
+        .find(|a| a.to_str().is_some_and(is_archive) && a.is_file())
+        .and_then(|archive| {
+            let member = Path::new(path).strip_prefix(archive).ok()?;
+            let member: Vec<&str> = member.iter().filter_map(OsStr::to_str).collect();
+            Some((archive.to_str()?.to_string(), member.join("/")))
+        })
+}
+
+/// Where the disk at `path` saves to if it's inside an archive: next to the archive, named
+/// after both, e.g. "disks/Games.zip.Games.Choplifter.woz"
+pub fn sidecar_path(path: &str) -> Option<String> {
+    split_path(path).map(|(archive, member)| format!("{archive}.{}", member.replace('/', ".")))
+}
+
+/// The files of the archive at `path`
+pub fn members(path: &str) -> Result<Vec<Member>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Couldn't read {path}: {e}"))?;
+    Ok(Archive::new(path, &bytes)?.members())
+}
+
+/// The content of the file at `path`, inside an archive or not
+pub fn read(path: &str) -> io::Result<Vec<u8>> {
+    match split_path(path) {
+        Some((archive, member)) => {
+            let bytes = fs::read(&archive)?;
+            Archive::new(&archive, &bytes).and_then(|a| a.extract(&member))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        None => fs::read(path),
+    }
+}
+
+/// The size of the file at `path`, inside an archive or not
+pub fn file_size(path: &str) -> Option<u64> {
+    match split_path(path) {
+        Some((archive, member)) => members(&archive).ok()?.into_iter()
+            .find(|m| m.name == member).map(|m| m.size as u64),
+        None => fs::metadata(path).ok().map(|m| m.len()),
+    }
+}
+
+enum Archive<'a> {
+    Zip(Vec<ZipEntry<'a>>),
+    /// The name of the file and the compressed stream
+    Gzip(String, &'a [u8]),
+    ShrinkIt(Vec<Record<'a>>),
+}
+
+impl<'a> Archive<'a> {
+    fn new(path: &str, bytes: &'a [u8]) -> Result<Archive<'a>, String> {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".zip") {
+            Ok(Archive::Zip(zip_entries(bytes)?))
+        } else if lower.ends_with(".gz") {
+            let name = Path::new(path).file_stem().and_then(OsStr::to_str).unwrap_or_default();
+            Ok(Archive::Gzip(name.to_string(), bytes))
+        } else if lower.ends_with(".shk") || lower.ends_with(".sdk") {
+            Ok(Archive::ShrinkIt(shrinkit_records(bytes)?))
+        } else {
+            Err(format!("{path} is not an archive"))
+        }
+    }
+
+    fn members(&self) -> Vec<Member> {
+        match self {
+            Archive::Zip(entries) => entries.iter()
+                .map(|e| Member { name: e.name.clone(), size: e.size }).collect(),
+            Archive::Gzip(name, bytes) => {
+                // The size modulo 2^32 is at the end of the file
+                let size = bytes.len().checked_sub(4).map_or(0, |i| u32_at(bytes, i) as usize);
+                vec![Member { name: name.clone(), size }]
+            }
+            Archive::ShrinkIt(records) => records.iter()
+                .map(|r| Member { name: r.name.clone(), size: r.size }).collect(),
+        }
+    }
+
+    fn extract(&self, name: &str) -> Result<Vec<u8>, String> {
+        let not_found = || format!("{name} is not in the archive");
+        match self {
+            Archive::Zip(entries) => entries.iter().find(|e| e.name == name).ok_or_else(not_found)?.extract(),
+            Archive::Gzip(member, bytes) if member == name => gunzip(bytes),
+            Archive::Gzip(_, _) => Err(not_found()),
+            Archive::ShrinkIt(records) => records.iter().find(|r| r.name == name).ok_or_else(not_found)?.extract(),
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// `bytes[start..start + length]`, or an error if the archive is too short
+fn slice(bytes: &[u8], start: usize, length: usize) -> Result<&[u8], String> {
+    bytes.get(start..start + length).ok_or_else(|| "The archive is truncated".to_string())
+}
+
+//
+// Zip
+//
+
+const ZIP_END_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP_ENTRY_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP_LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+
+struct ZipEntry<'a> {
+    name: String,
+    method: u16,
+    crc: u32,
+    size: usize,
+    /// The compressed data
+    data: &'a [u8],
+}
+
+impl ZipEntry<'_> {
+    fn extract(&self) -> Result<Vec<u8>, String> {
+        let result = match self.method {
+            ZIP_STORED => self.data.to_vec(),
+            ZIP_DEFLATED => inflate(self.data, self.size)?.0,
+            method => { return Err(format!("{}: unsupported compression method {method}", self.name)); }
+        };
+        if result.len() != self.size || crc32(0, &result) != self.crc {
+            return Err(format!("{} is corrupted", self.name));
+        }
+        Ok(result)
+    }
+}
+
+/// The files of the central directory, the directories are skipped
+fn zip_entries(bytes: &[u8]) -> Result<Vec<ZipEntry<'_>>, String> {
+    // The end of central directory record is followed by a comment of up to 64K
+    let end = (0..bytes.len().saturating_sub(21)).rev().take(0x10000 + 22)
+        .find(|&i| u32_at(bytes, i) == ZIP_END_SIGNATURE)
+        .ok_or_else(|| "Not a zip file".to_string())?;
+    let count = u16_at(bytes, end + 10) as usize;
+    let mut offset = u32_at(bytes, end + 16) as usize;
+    let mut result = Vec::new();
+    for _ in 0..count {
+        let header = slice(bytes, offset, 46)?;
+        if u32_at(header, 0) != ZIP_ENTRY_SIGNATURE {
+            return Err("Corrupted zip directory".to_string());
+        }
+        let (name_length, extra_length, comment_length) =
+            (u16_at(header, 28) as usize, u16_at(header, 30) as usize, u16_at(header, 32) as usize);
+        let name = String::from_utf8_lossy(slice(bytes, offset + 46, name_length)?).to_string();
+        let (compressed_size, size) = (u32_at(header, 20) as usize, u32_at(header, 24) as usize);
+        let local = u32_at(header, 42) as usize;
+        offset += 46 + name_length + extra_length + comment_length;
+        if name.ends_with('/') {
+            continue;
+        }
+        if compressed_size == 0xffff_ffff || size == 0xffff_ffff {
+            return Err(format!("{name}: zip64 archives are not supported"));
+        }
+        let local_header = slice(bytes, local, 30)?;
+        if u32_at(local_header, 0) != ZIP_LOCAL_SIGNATURE {
+            return Err(format!("{name}: corrupted zip entry"));
+        }
+        let start = local + 30 + u16_at(local_header, 26) as usize + u16_at(local_header, 28) as usize;
+        result.push(ZipEntry {
+            name, method: u16_at(header, 10), crc: u32_at(header, 16), size,
+            data: slice(bytes, start, compressed_size)?,
+        });
+    }
+    Ok(result)
+}
+
+//
+// Gzip
+//
+
+const GZIP_EXTRA: u8 = 4;
+const GZIP_NAME: u8 = 8;
+const GZIP_COMMENT: u8 = 16;
+const GZIP_HEADER_CRC: u8 = 2;
+
+fn gunzip(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    if bytes.len() < 18 || bytes[0..3] != [0x1f, 0x8b, 8] {
+        return Err("Not a gzip file".to_string());
+    }
+    let flags = bytes[3];
+    let mut offset = 10;
+    if flags & GZIP_EXTRA != 0 {
+        offset += 2 + u16_at(bytes, offset) as usize;
+    }
+    for flag in [GZIP_NAME, GZIP_COMMENT] {
+        if flags & flag != 0 {
+            offset += bytes.iter().skip(offset).position(|b| *b == 0).ok_or("Corrupted gzip header")? + 1;
+        }
+    }
+    if flags & GZIP_HEADER_CRC != 0 {
+        offset += 2;
+    }
+    // The size modulo 2^32 is at the end of the file
+    let size = u32_at(bytes, bytes.len() - 4) as usize;
+    let (result, length) = inflate(bytes.get(offset..).ok_or("Corrupted gzip header")?, size)?;
+    let trailer = slice(bytes, offset + length, 8)?;
+    if crc32(0, &result) != u32_at(trailer, 0) || result.len() as u32 != u32_at(trailer, 4) {
+        return Err("The gzip file is corrupted".to_string());
+    }
+    Ok(result)
+}
+
+//
+// Deflate (RFC 1951)
+//
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83,
+    99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769,
+    1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11,
+    12, 12, 13, 13];
+/// The order of the code lengths of the code length alphabet in dynamic blocks
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+const END_OF_BLOCK: u16 = 256;
+
+/// Reads the bits of a byte from the least significant one, like deflate and LZW streams
+/// store them
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn new(bytes: &[u8]) -> BitReader<'_> {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn bits(&mut self, count: u8) -> Result<u32, String> {
+        let mut result = 0;
+        for i in 0..count {
+            let byte = self.bytes.get(self.position / 8).ok_or("Compressed data is truncated")?;
+            result |= (((byte >> (self.position % 8)) & 1) as u32) << i;
+            self.position += 1;
+        }
+        Ok(result)
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+
+    /// The number of bytes read so far
+    fn byte_count(&self) -> usize {
+        self.position.div_ceil(8)
+    }
+}
+
+/// A canonical Huffman code: how many codes of each length, and the symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0; 16];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|s| lengths[*s as usize] != 0).collect();
+        symbols.sort_by_key(|s| lengths[*s as usize]);
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for length in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("Invalid Huffman code".to_string())
+    }
+}
+
+/// Return the inflated data and the number of bytes of `bytes` it used. Fails as soon as the
+/// data gets larger than the `size` declared by the archive.
+fn inflate(bytes: &[u8], size: usize) -> Result<(Vec<u8>, usize), String> {
+    let mut reader = BitReader::new(bytes);
+    let mut result: Vec<u8> = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let start = reader.byte_count();
+                let header = slice(bytes, start, 4)?;
+                let length = u16_at(header, 0) as usize;
+                if u16_at(header, 2) != ! (length as u16) {
+                    return Err("Corrupted stored block".to_string());
+                }
+                if result.len() + length > size {
+                    return Err(too_large());
+                }
+                result.extend_from_slice(slice(bytes, start + 4, length)?);
+                reader.position = (start + 4 + length) * 8;
+            }
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                inflate_block(&mut reader, &mut result, size, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut result, size, &literals, &distances)?;
+            }
+            _ => { return Err("Invalid deflate block".to_string()); }
+        }
+        if last {
+            return Ok((result, reader.byte_count()));
+        }
+    }
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    let mut code_lengths = [0; 19];
+    for i in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_lengths[*i] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+    let mut lengths: Vec<u8> = Vec::new();
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or("Invalid code lengths")?, 3 + reader.bits(2)?),
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() != literal_count + distance_count {
+        return Err("Invalid code lengths".to_string());
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn too_large() -> String {
+    "The inflated data is larger than its declared size".to_string()
+}
+
+fn inflate_block(reader: &mut BitReader, result: &mut Vec<u8>, size: usize, literals: &Huffman,
+    distances: &Huffman) -> Result<(), String>
+{
+    loop {
+        let symbol = literals.decode(reader)?;
+        if symbol < END_OF_BLOCK {
+            if result.len() >= size {
+                return Err(too_large());
+            }
+            result.push(symbol as u8);
+        } else if symbol == END_OF_BLOCK {
+            return Ok(());
+        } else {
+            let index = (symbol - 257) as usize;
+            if index >= LENGTH_BASE.len() {
+                return Err("Invalid length".to_string());
+            }
+            let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index])? as usize;
+            let index = distances.decode(reader)? as usize;
+            if index >= DISTANCE_BASE.len() {
+                return Err("Invalid distance".to_string());
+            }
+            let distance = DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index])? as usize;
+            let start = result.len().checked_sub(distance).ok_or("Distance is too far back")?;
+            if result.len() + length > size {
+                return Err(too_large());
+            }
+            // The copy can overlap what it writes
+            for i in 0..length {
+                result.push(result[start + i]);
+            }
+        }
+    }
+}
+
+//
+// ShrinkIt (NuFX)
+//
+
+/// "NuFile" and "NuFX" with every other byte in high ASCII
+const NUFX_MASTER_ID: [u8; 6] = [0x4e, 0xf5, 0x46, 0xe9, 0x6c, 0xe5];
+const NUFX_RECORD_ID: [u8; 4] = [0x4e, 0xf5, 0x46, 0xd8];
+const NUFX_MASTER_HEADER_SIZE: usize = 48;
+/// .bxy and some .shk files are wrapped in a Binary II header
+const BINARY_II_ID: [u8; 3] = [0x0a, 0x47, 0x4c];
+const BINARY_II_HEADER_SIZE: usize = 128;
+const THREAD_HEADER_SIZE: usize = 16;
+
+const DATA_CLASS: u16 = 2;
+const FILE_NAME_CLASS: u16 = 3;
+const DATA_FORK_KIND: u16 = 0;
+const DISK_IMAGE_KIND: u16 = 1;
+
+const UNCOMPRESSED: u16 = 0;
+const LZW_1: u16 = 2;
+const LZW_2: u16 = 3;
+
+/// A file or a disk image of a ShrinkIt archive
+struct Record<'a> {
+    /// Disk images are named with a .po suffix, since they hold ProDOS blocks
+    name: String,
+    size: usize,
+    format: u16,
+    data: &'a [u8],
+}
+
+impl Record<'_> {
+    fn extract(&self) -> Result<Vec<u8>, String> {
+        let mut result = match self.format {
+            UNCOMPRESSED => self.data.to_vec(),
+            LZW_1 | LZW_2 => expand_lzw(self.data, self.format == LZW_2, self.size)?,
+            format => { return Err(format!("{}: unsupported ShrinkIt compression {format}", self.name)); }
+        };
+        if result.len() < self.size {
+            return Err(format!("{} is truncated", self.name));
+        }
+        result.truncate(self.size);
+        Ok(result)
+    }
+}
+
+fn shrinkit_records(bytes: &[u8]) -> Result<Vec<Record<'_>>, String> {
+    let bytes = if bytes.starts_with(&BINARY_II_ID) { &bytes[BINARY_II_HEADER_SIZE.min(bytes.len())..] } else { bytes };
+    if ! bytes.starts_with(&NUFX_MASTER_ID) {
+        return Err("Not a ShrinkIt archive".to_string());
+    }
+    let count = u32_at(slice(bytes, 0, NUFX_MASTER_HEADER_SIZE)?, 8);
+    let mut offset = NUFX_MASTER_HEADER_SIZE;
+    let mut result = Vec::new();
+    for _ in 0..count {
+        let header = slice(bytes, offset, 58)?;
+        if ! header.starts_with(&NUFX_RECORD_ID) {
+            return Err("Corrupted ShrinkIt record".to_string());
+        }
+        // The attributes end with the length of the file name, which follows them
+        let attribute_count = u16_at(header, 6) as usize;
+        let thread_count = u32_at(header, 10) as usize;
+        let (extra_type, storage_type) = (u32_at(header, 26) as usize, u16_at(header, 30) as usize);
+        let name_length = u16_at(slice(bytes, offset + attribute_count - 2, 2)?, 0) as usize;
+        let mut name = String::from_utf8_lossy(slice(bytes, offset + attribute_count, name_length)?).to_string();
+        let threads = offset + attribute_count + name_length;
+        let mut data_offset = threads + thread_count * THREAD_HEADER_SIZE;
+        let mut contents = Vec::new();
+        for i in 0..thread_count {
+            let thread = slice(bytes, threads + i * THREAD_HEADER_SIZE, THREAD_HEADER_SIZE)?;
+            let (class, format, kind) = (u16_at(thread, 0), u16_at(thread, 2), u16_at(thread, 4));
+            let (length, compressed_length) = (u32_at(thread, 8) as usize, u32_at(thread, 12) as usize);
+            let data = slice(bytes, data_offset, compressed_length)?;
+            if class == FILE_NAME_CLASS {
+                name = String::from_utf8_lossy(&data[..length.min(data.len())]).to_string();
+            } else if class == DATA_CLASS && kind == DISK_IMAGE_KIND {
+                // The length of disk threads is often 0, the size of the disk is in the header
+                contents.push((kind, format, extra_type.saturating_mul(storage_type), data));
+            } else if class == DATA_CLASS && kind == DATA_FORK_KIND {
+                contents.push((kind, format, length, data));
+            }
+            data_offset += compressed_length;
+        }
+        // ProDOS names use '/' as separator, other file systems use the separator in the header
+        let separator = u16_at(header, 16) as u8 as char;
+        let name: String = name.split(separator).collect::<Vec<_>>().join("/");
+        for (kind, format, size, data) in contents {
+            let name = if kind == DISK_IMAGE_KIND { format!("{name}.po") } else { name.clone() };
+            result.push(Record { name, size, format, data });
+        }
+        offset = data_offset;
+    }
+    Ok(result)
+}
+
+//
+// ShrinkIt LZW: the data is cut in 4K chunks, each of them is run length encoded then
+// compressed with LZW if that makes it smaller. LZW/1 starts a new table for every chunk,
+// LZW/2 keeps it until a clear code.
+//
+
+const CHUNK_SIZE: usize = 4096;
+/// The largest ProDOS volume, 65535 blocks: nothing in a disk archive is larger
+const MAX_THREAD_SIZE: usize = 65535 * 512;
+const CLEAR_CODE: u16 = 0x100;
+const FIRST_CODE: u16 = 0x101;
+const MAX_CODE: u16 = 0x1000;
+
+struct Lzw {
+    prefixes: Vec<u16>,
+    suffixes: Vec<u8>,
+    next_code: u16,
+    previous: Option<u16>,
+    /// The first character of the previous string
+    first_char: u8,
+}
+
+impl Lzw {
+    fn new() -> Lzw {
+        Lzw { prefixes: vec![0; MAX_CODE as usize], suffixes: vec![0; MAX_CODE as usize], next_code: FIRST_CODE,
+            previous: None, first_char: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.next_code = FIRST_CODE;
+        self.previous = None;
+    }
+
+    /// Codes are 9 bits wide, then grow up to 12 bits as the table fills, one code early
+    fn code_width(&self) -> u8 {
+        match self.next_code + 1 {
+            0..=0x1ff => 9,
+            0x200..=0x3ff => 10,
+            0x400..=0x7ff => 11,
+            _ => 12,
+        }
+    }
+
+    fn expand(&mut self, reader: &mut BitReader, length: usize, lzw_2: bool) -> Result<Vec<u8>, String> {
+        let mut result = Vec::with_capacity(length);
+        let mut string = Vec::new();
+        while result.len() < length {
+            let code = reader.bits(self.code_width())? as u16;
+            if lzw_2 && code == CLEAR_CODE {
+                self.clear();
+                continue;
+            }
+            let Some(previous) = self.previous else {
+                if code > 0xff {
+                    return Err("Invalid LZW code".to_string());
+                }
+                result.push(code as u8);
+                (self.previous, self.first_char) = (Some(code), code as u8);
+                continue;
+            };
+            string.clear();
+            let mut c = code;
+            if code >= self.next_code {
+                if code > self.next_code {
+                    return Err("Invalid LZW code".to_string());
+                }
+                // The string being defined: the previous one followed by its first character
+                string.push(self.first_char);
+                c = previous;
+            }
+            while c > 0xff {
+                string.push(self.suffixes[c as usize]);
+                c = self.prefixes[c as usize];
+            }
+            string.push(c as u8);
+            result.extend(string.iter().rev());
+            if self.next_code < MAX_CODE {
+                self.prefixes[self.next_code as usize] = previous;
+                self.suffixes[self.next_code as usize] = c as u8;
+                self.next_code += 1;
+            }
+            (self.previous, self.first_char) = (Some(code), c as u8);
+        }
+        if result.len() != length {
+            return Err("Corrupted LZW chunk".to_string());
+        }
+        Ok(result)
+    }
+}
+
+/// Expand a chunk that was run length encoded: `delimiter`, byte, count - 1
+fn expand_rle(bytes: &[u8], delimiter: u8) -> Vec<u8> {
+    let mut result = Vec::with_capacity(CHUNK_SIZE);
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == delimiter && i + 2 < bytes.len() {
+            result.extend(std::iter::repeat_n(bytes[i + 1], bytes[i + 2] as usize + 1));
+            i += 3;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    result
+}
+
+fn expand_lzw(bytes: &[u8], lzw_2: bool, size: usize) -> Result<Vec<u8>, String> {
+    if size > MAX_THREAD_SIZE {
+        return Err(format!("{size} bytes is too large for a ShrinkIt thread"));
+    }
+    // LZW/1 starts with a CRC of the data, then both have the volume number and the RLE delimiter
+    let mut offset = if lzw_2 { 0 } else { 2 };
+    let delimiter = slice(bytes, offset, 2)?[1];
+    offset += 2;
+    let mut lzw = Lzw::new();
+    let mut result = Vec::with_capacity(size.div_ceil(CHUNK_SIZE) * CHUNK_SIZE);
+    while result.len() < size {
+        let (rle_length, compressed, chunk_end) = if lzw_2 {
+            let word = u16_at(slice(bytes, offset, 2)?, 0);
+            if word & 0x8000 != 0 {
+                // The length of the chunk includes these 4 bytes
+                let chunk_end = offset + u16_at(slice(bytes, offset + 2, 2)?, 0) as usize;
+                offset += 4;
+                ((word & 0x1fff) as usize, true, Some(chunk_end))
+            } else {
+                offset += 2;
+                ((word & 0x1fff) as usize, false, None)
+            }
+        } else {
+            let header = slice(bytes, offset, 3)?;
+            offset += 3;
+            (u16_at(header, 0) as usize, header[2] != 0, None)
+        };
+        let chunk = if compressed {
+            let mut reader = BitReader::new(bytes.get(offset..).unwrap_or_default());
+            if ! lzw_2 {
+                lzw.clear();
+            }
+            let chunk = lzw.expand(&mut reader, rle_length, lzw_2)?;
+            offset = chunk_end.unwrap_or(offset + reader.byte_count());
+            chunk
+        } else {
+            // A chunk stored as is starts the table over
+            lzw.clear();
+            let chunk = slice(bytes, offset, rle_length)?.to_vec();
+            offset += rle_length;
+            chunk
+        };
+        let chunk = if rle_length == CHUNK_SIZE { chunk } else { expand_rle(&chunk, delimiter) };
+        if chunk.len() != CHUNK_SIZE {
+            return Err("Corrupted ShrinkIt chunk".to_string());
+        }
+        result.extend(chunk);
+    }
+    Ok(result)
+}
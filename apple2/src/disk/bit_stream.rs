@@ -2,15 +2,30 @@ use std::fmt::{Debug, Display, Formatter};
 use rand::random;
 use crate::debug::{hex_dump_fn};
 use crate::disk::bit_stream::AreaType::Unknown;
-use crate::disk::disk_controller::{MAX_PHASE};
+use crate::disk::d13::{D13, DATA_FIELD_SIZE_13, WRITE_TABLE_53};
+use crate::disk::disk_controller::{DATA_FIELD_SIZE, MAX_PHASE, SECTOR_SIZE_BYTES};
+use crate::disk::dsk::{Dsk, WRITE_TABLE};
 use crate::disk::disk_info::DiskInfo;
 use crate::misc::bit;
 
+/// Bit cells last 4µs on 5.25 disks, in units of 125ns
+pub const DEFAULT_BIT_TIMING: u8 = 32;
+
 /// A stream of bit that contains the content of a track
 #[derive(Default, Clone)]
 pub struct BitStream {
     bits: Vec<u8>,
     pub(crate) random: bool,
+    /// WOZ 2.1 flux tracks: the time of each flux transition in 125ns ticks, sorted, and the
+    /// duration of one revolution. `bits` then only contains their decoding, for the UI and
+    /// the conversions, the LSS reads the transitions themselves
+    flux: Option<Flux>,
+}
+
+#[derive(Clone)]
+struct Flux {
+    transitions: Vec<u32>,
+    ticks: u32,
 }
 
 #[derive(Clone, Copy)]
@@ -79,86 +94,301 @@ pub enum TrackType {
 pub struct AnalyzedTrack {
     pub(crate) nibbles: Vec<Nibble>,
     pub(crate) track_type: TrackType,
+    /// The sectors in the order they are found on the track
+    pub(crate) sectors: Vec<AnalyzedSector>,
+}
+
+/// An address field, and the data field that follows it if there is one
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalyzedSector {
+    pub volume: u8,
+    pub track: u8,
+    pub sector: u8,
+    /// D5 AA B5 address prologue: the data field is 5 and 3 encoded
+    pub five_and_three: bool,
+    pub address_checksum_ok: bool,
+    /// The three nibbles following the address field, DE AA EB on standard disks
+    pub address_epilogue: [u8; 3],
+    /// Sync bits in the gap before the address field
+    pub address_sync_bits: u16,
+    pub data: Option<AnalyzedData>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalyzedData {
+    pub checksum_ok: bool,
+    /// The three nibbles following the data field, DE AA EB on standard disks
+    pub epilogue: [u8; 3],
+    /// Sync bits in the gap between the address field and the data field
+    pub sync_bits: u16,
+    /// The content of the sector, decoded even if the checksum is wrong
+    pub bytes: [u8; SECTOR_SIZE_BYTES],
+}
+
+impl AnalyzedSector {
+    /// Both checksums are valid and both epilogues start with DE AA
+    pub fn is_standard(&self) -> bool {
+        self.address_checksum_ok && self.address_epilogue.starts_with(&[0xde, 0xaa])
+            && self.data.as_ref().is_some_and(|data| {
+                data.checksum_ok && data.epilogue.starts_with(&[0xde, 0xaa])
+            })
+    }
+}
+
+/// The data fields are XORed with the previous value, the last nibble being the checksum: all
+/// the values XORed together give 0
+fn checksum_ok(nibbles: &[u8], write_table: &[u8]) -> bool {
+    let mut read_table: [Option<u8>; 256] = [None; 256];
+    for (i, nibble) in write_table.iter().enumerate() {
+        read_table[*nibble as usize] = Some(i as u8);
+    }
+    let mut checksum = 0;
+    for nibble in nibbles {
+        match read_table[*nibble as usize] {
+            Some(value) => { checksum ^= value; }
+            None => { return false; }
+        }
+    }
+    checksum == 0
 }
 
 impl BitStream {
     pub fn new(buffer: Vec<u8>) -> Self {
         Self {
-            bits: buffer, random: false,
+            bits: buffer, random: false, flux: None,
         }
     }
 
     pub fn random() -> Self {
         Self {
-            bits: vec![0], random: true
+            bits: vec![0], random: true, flux: None,
         }
     }
 
-    /// Analyze the track to find out if it's standard, nonstandard, or empty
-    ///
-    /// TODO: Do more checks, e.g.
-    /// - data field size is 343
-    /// - verify checksums
-    /// - maybe try to guess the markers based on 4-4 detection
-    pub fn analyze_track(&self) -> AnalyzedTrack {
-        // How many valid D5 AA 96 ... DE AA we found
-        let mut address_prologues_found = 0;
-        let mut address_epilogues_found = 0;
-        // How many valid D5 AA AD ... DE AA we found
-        let mut data_prologues_found = 0;
-        let mut data_epilogues_found = 0;
-
-        let nibbles = &self.find_nibble_areas();
-        let track_type = if self.random {
-            TrackType::Empty
-        } else {
-            for nibble in nibbles {
-                use AreaType::*;
-                match nibble.area_type {
-                    AddressPrologue if nibble.value == 0xd5 => { address_prologues_found += 1; }
-                    AddressContent => {}
-                    AddressEpilogue => if nibble.value == 0xde { address_epilogues_found += 1;},
-                    DataPrologue => if nibble.value == 0xd5 { data_prologues_found += 1; }
-                    DataContent => {}
-                    DataEpilogue => if nibble.value == 0xde { data_epilogues_found += 1; }
-                    Unknown => {}
-                    _ => {}
+    /// A flux track, made of the delays between two flux transitions in 125ns ticks.
+    /// `bits` is what these transitions read as with bit cells of `bit_timing` ticks, if the
+    /// image doesn't have a bit track for that phase.
+    pub fn new_flux(delays: &[u32], bits: Option<Vec<u8>>, bit_timing: u8) -> Self {
+        let mut transitions = Vec::with_capacity(delays.len());
+        let mut ticks = 0;
+        for delay in delays {
+            ticks += delay;
+            transitions.push(ticks);
+        }
+        let ticks = ticks.max(1);
+        // The last transition ends the revolution, it's also at the start of the next one
+        for t in transitions.iter_mut() { *t %= ticks; }
+        transitions.sort_unstable();
+
+        let bits = bits.unwrap_or_else(|| {
+            let timing = bit_timing.max(1) as u32;
+            let mut result = Vec::new();
+            for delay in delays {
+                let cells = ((delay + timing / 2) / timing).max(1);
+                result.extend(vec![0; cells as usize - 1]);
+                result.push(1);
+            }
+            result
+        });
+        Self { bits, random: false, flux: Some(Flux { transitions, ticks }) }
+    }
+
+    pub fn is_flux(&self) -> bool { self.flux.is_some() }
+
+    /// The delays between the flux transitions, in the format of the FLUX chunk of WOZ files
+    pub fn flux_delays(&self) -> Option<Vec<u32>> {
+        self.flux.as_ref().map(|flux| {
+            let mut result = Vec::with_capacity(flux.transitions.len());
+            let mut previous = 0;
+            for t in &flux.transitions {
+                result.push(t - previous);
+                previous = *t;
+            }
+            if let Some(first) = result.first_mut() {
+                // The transition at tick 0 is the one ending the revolution
+                if *first == 0 {
+                    result.remove(0);
+                    result.push(flux.ticks - previous);
+                } else {
+                    *first += flux.ticks - previous;
+                    result.rotate_left(1);
                 }
             }
+            result
+        })
+    }
+
+    /// Writing turns a flux track into a regular bit track
+    pub(crate) fn remove_flux(&mut self) {
+        self.flux = None;
+    }
+
+    /// The length of the track in the unit of the head position: ticks for flux tracks,
+    /// bits otherwise
+    pub fn position_len(&self) -> usize {
+        match &self.flux {
+            Some(flux) => flux.ticks as usize,
+            None => self.len(),
+        }
+    }
 
-            if address_prologues_found == 16 && address_epilogues_found == 16 &&
-                  data_prologues_found == 16 && data_epilogues_found == 16 {
+    /// 1 if a flux transition happens during the `ticks` following `tick`
+    pub fn flux_pulse(&self, tick: usize, ticks: usize) -> u8 {
+        let Some(flux) = &self.flux else { return 0 };
+        let in_window = |start: u32, end: u32| {
+            let i = flux.transitions.partition_point(|t| *t < start);
+            i < flux.transitions.len() && flux.transitions[i] < end
+        };
+        let (start, end) = (tick as u32, (tick + ticks) as u32);
+        let found = in_window(start, end.min(flux.ticks))
+            || (end > flux.ticks && in_window(0, end - flux.ticks));
+        if found { 1 } else { 0 }
+    }
+
+    /// Analyze the track to find out if it's standard, nonstandard, or empty, and decode its
+    /// sectors. A standard track has 16 (or 13) different sectors whose checksums are valid
+    /// and whose fields end with DE AA.
+    pub fn analyze_track(&self) -> AnalyzedTrack {
+        let nibbles = self.find_nibble_areas();
+        let (track_type, sectors) = if self.random {
+            (TrackType::Empty, Vec::new())
+        } else {
+            let sectors = self.find_sectors();
+            let mut numbers: Vec<u8> = sectors.iter().map(|s| s.sector).collect();
+            numbers.sort_unstable();
+            numbers.dedup();
+            let track_type = if (sectors.len() == 16 || sectors.len() == 13)
+                    && numbers.len() == sectors.len()
+                    && sectors.iter().all(|s| s.is_standard()) {
                 TrackType::Standard
             } else {
                 TrackType::Nonstandard
-            }
+            };
+            (track_type, sectors)
         };
 
         AnalyzedTrack {
-            nibbles: nibbles.to_vec(),
+            nibbles,
             track_type,
+            sectors,
+        }
+    }
+
+    /// Decode the address and data fields of the track. The track is circular: it's framed over
+    /// three revolutions, the nibbles are in sync by the time the second one starts, and the
+    /// sectors that start in it can end in the third one.
+    fn find_sectors(&self) -> Vec<AnalyzedSector> {
+        let revolution = self.bits.len();
+        if revolution < 8 {
+            return Vec::new();
+        }
+        let framed = Self::frame_nibbles(&self.bits.repeat(3));
+        let len = framed.len();
+        let value = |i: usize| framed.get(i).map_or(0, |(_, n)| n.value);
+        let values = |start: usize, count: usize| (start..start + count).map(value).collect::<Vec<u8>>();
+        let prologue = |i: usize, third: &[u8]| {
+            value(i) == 0xd5 && value(i + 1) == 0xaa && third.contains(&value(i + 2))
+        };
+
+        // The nibbles of each field, from its prologue to its epilogue, to measure the gaps
+        let mut in_field = vec![false; len];
+        let mut mark = |start: usize, count: usize| in_field[start..(start + count).min(len)].fill(true);
+        // The sectors, with the positions of their address and data prologues
+        let mut found: Vec<(AnalyzedSector, usize, Option<usize>)> = Vec::new();
+        for (i, (position, _)) in framed.iter().enumerate() {
+            if ! prologue(i, &[0x96, 0xb5]) {
+                continue;
+            }
+            let five_and_three = value(i + 2) == 0xb5;
+            let field = |k: usize| Dsk::decode_4_and_4(value(i + 3 + k), value(i + 4 + k));
+            let (volume, track, sector, checksum) = (field(0), field(2), field(4), field(6));
+            let address_end = i + 11;
+            mark(i, 14);
+
+            // The data field follows closely, unless another address field comes first
+            let data_start = (address_end..address_end + 64)
+                .take_while(|j| ! prologue(*j, &[0x96, 0xb5]))
+                .find(|j| prologue(*j, &[0xad]));
+            let (field_size, write_table) = if five_and_three {
+                (DATA_FIELD_SIZE_13, &WRITE_TABLE_53[..])
+            } else {
+                (DATA_FIELD_SIZE, &WRITE_TABLE[..])
+            };
+            if let Some(start) = data_start {
+                mark(start, field_size + 6);
+            }
+            // Only the sectors of the second revolution are reported
+            if ! (revolution..2 * revolution).contains(position) {
+                continue;
+            }
+            let data = data_start.map(|start| {
+                let content = values(start + 3, field_size);
+                AnalyzedData {
+                    checksum_ok: checksum_ok(&content, write_table),
+                    epilogue: values(start + 3 + field_size, 3).try_into().unwrap(),
+                    sync_bits: 0,
+                    bytes: if five_and_three {
+                        D13::decode_5_and_3(&content)
+                    } else {
+                        Dsk::decode_6_and_2(&content)
+                    },
+                }
+            });
+            found.push((AnalyzedSector {
+                volume, track, sector, five_and_three,
+                address_checksum_ok: volume ^ track ^ sector == checksum,
+                address_epilogue: values(address_end, 3).try_into().unwrap(),
+                address_sync_bits: 0,
+                data,
+            }, i, data_start));
         }
+
+        // The sync bits of the nibbles before a prologue, back to the previous field. The last
+        // nibble of that field counts too since its sync bits follow it.
+        let gap = |start: usize| {
+            let mut result = 0;
+            for j in (0..start).rev() {
+                result += framed[j].1.sync_bits;
+                if in_field[j] {
+                    break;
+                }
+            }
+            result
+        };
+        found.into_iter().map(|(mut sector, address_start, data_start)| {
+            sector.address_sync_bits = gap(address_start);
+            if let (Some(data), Some(start)) = (sector.data.as_mut(), data_start) {
+                data.sync_bits = gap(start);
+            }
+            sector
+        }).collect()
     }
 
     pub fn to_nibbles(&self) -> Vec<Nibble> {
+        Self::frame_nibbles(&self.bits).into_iter().map(|(_, nibble)| nibble).collect()
+    }
+
+    /// The nibbles of these bits, framed from the first one, with the position of their first bit
+    fn frame_nibbles(bits: &[u8]) -> Vec<(usize, Nibble)> {
         let mut i = 0;
-        let len = self.bits.len();
-        let mut result: Vec<Nibble> = Vec::new();
+        let len = bits.len();
+        let mut result: Vec<(usize, Nibble)> = Vec::new();
         while i < len {
-            // while i < len && self.bits[i] == 0 { i += 1; }
+            let start = i;
+            // while i < len && bits[i] == 0 { i += 1; }
             let mut value = 0;
             while i < len && (value & 0x80) == 0 {
-                value = (value << 1) | self.bits[i];
+                value = (value << 1) | bits[i];
                 i += 1;
             }
             let mut sync_bits: u16 = 0;
-            if i < len && self.bits[i] == 0 && self.bits[(i + 1) % self.len()] == 0 {
-                while i < len && self.bits[i] == 0 {
+            if i < len && bits[i] == 0 && bits[(i + 1) % len] == 0 {
+                while i < len && bits[i] == 0 {
                     sync_bits += 1;
                     i += 1;
                 }
             }
-            result.push(Nibble { value, sync_bits, area_type: Unknown });
+            result.push((start, Nibble { value, sync_bits, area_type: Unknown }));
         }
 
         result
@@ -181,7 +411,8 @@ impl BitStream {
         let mut current_area_start: usize = 0;
 
         while i < nibbles.len() {
-            if i < nibbles.len() - 3 && nibbles[i].is(0xd5) && nibbles[i+1].is(0xaa) && nibbles[i+2].is(0x96) {
+            if i < nibbles.len() - 3 && nibbles[i].is(0xd5) && nibbles[i+1].is(0xaa)
+                    && (nibbles[i+2].is(0x96) || nibbles[i+2].is(0xb5)) {
                 for n in &current_nibbles {
                     result.push(n.area(current_area_type));
                 }
@@ -240,7 +471,7 @@ impl BitStream {
     }
 
     pub fn copy(&self) -> Self {
-        Self { bits: self.bits.clone(), random: self.random }
+        self.clone()
     }
 
     pub fn len(&self) -> usize {
@@ -260,6 +491,11 @@ impl BitStream {
         }
     }
 
+    /// One bit per byte
+    pub(crate) fn bits(&self) -> &[u8] {
+        &self.bits
+    }
+
     pub(crate) fn set_bit(&mut self, bit_index: usize, value: u8) {
         self.bits[bit_index] = value;
     }
@@ -305,13 +541,16 @@ pub struct BitStreams {
     pub bit_streams: Vec<BitStream>,
     pub tmap: [u8; MAX_PHASE],
     pub disk_info: DiskInfo,
+    /// Duration of a bit cell in 125ns ticks, 32 (4µs) on standard 5.25 disks
+    pub optimal_bit_timing: u8,
     random: BitStream,
 }
 
 impl BitStreams {
     pub fn copy(&self) -> Self {
         Self { bit_streams: self.bit_streams.clone(), tmap: self.tmap,
-            disk_info: self.disk_info.clone(), random: BitStream::random() }
+            disk_info: self.disk_info.clone(), optimal_bit_timing: self.optimal_bit_timing,
+            random: BitStream::random() }
     }
 
     pub fn get_stream(&self, phase: usize) -> &BitStream {
@@ -327,18 +566,18 @@ impl BitStreams {
         }
     }
 
+    /// Write to the stream under the head. Like `get_stream`, each phase has its own stream,
+    /// nothing is written on the phases that have no track
     pub fn set_bit_and_advance(&mut self, phase_160: usize, bit_position: usize, bit: u8) {
-        self.bit_streams[self.tmap[phase_160] as usize].set_bit(bit_position, bit);
+        if self.tmap[phase_160] != 0xff {
+            self.get_stream_mut(phase_160).set_bit(bit_position, bit);
+        }
     }
 
+    /// The stream of that phase, indexed like `get_stream`
     pub fn get_stream_mut(&mut self, phase: usize) -> &mut BitStream {
         assert!((0..MAX_PHASE).contains(&(phase)));
-        let t = self.tmap[phase];
-        if t != 0xff {
-            &mut self.bit_streams[t as usize]
-        } else {
-            &mut self.random
-        }
+        &mut self.bit_streams[phase]
     }
 
     pub fn len(&self, phase: usize) -> usize {
@@ -360,6 +599,7 @@ impl Default for BitStreams {
             bit_streams: Vec::new(),
             tmap: [0; MAX_PHASE],
             disk_info: DiskInfo::default(),
+            optimal_bit_timing: DEFAULT_BIT_TIMING,
             random: BitStream::random(),
         }
     }
@@ -369,7 +609,8 @@ impl BitStreams {
     pub(crate) fn new(bit_streams: Vec<BitStream>, tmap: [u8; MAX_PHASE], disk_info: DiskInfo)
             -> BitStreams {
         Self {
-            bit_streams, tmap, disk_info, random: BitStream::random(),
+            bit_streams, tmap, disk_info, optimal_bit_timing: DEFAULT_BIT_TIMING,
+            random: BitStream::random(),
         }
     }
 }
\ No newline at end of file
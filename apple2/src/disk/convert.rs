@@ -0,0 +1,452 @@
+//! Conversion between the disk image formats, used by the `maple2-disk` binary.
+//!
+//! Sector images (.dsk, .do, .po, .d13, .2mg) are turned into tracks by encoding them, tracks
+//! (.nib, .woz, .a2r) are turned into sectors by decoding them. Decoding verifies the checksums
+//! of every sector, and refuses to go on if something would be lost: nonstandard tracks,
+//! quarter tracks, extra tracks, ... unless the conversion is forced.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use crate::disk::a2r::A2r;
+use crate::disk::archive;
+use crate::disk::bit_stream::{BitStream, BitStreams};
+use crate::disk::d13::{D13, SECTORS_13, TRACK_SIZE_BYTES_13};
+use crate::disk::disk::PDisk;
+use crate::disk::disk_controller::{DSK_SIZE_BYTES, MAX_PHASE, MAX_TRACK,
+    MAX_TRACK_DSK, SECTOR_SIZE_BYTES, TRACK_SIZE_BYTES};
+use crate::disk::dos33::Dos33;
+use crate::disk::dsk::{Dsk, SectorOrder};
+use crate::disk::hard_drive::{parse_2mg_header, BLOCK_SIZE, TWO_MG_DOS_ORDER, TWO_MG_HEADER_SIZE,
+    TWO_MG_MAGIC, TWO_MG_NIB, TWO_MG_PRODOS_ORDER};
+use crate::disk::nib::{Nib, NIB_SIZE_BYTES};
+use crate::disk::prodos::ProDos;
+use crate::disk::woz::{InfoChunk, Woz};
+
+/// The formats a disk image can be converted from and to, given by the suffix of its file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    /// .dsk and .do: 16 sector floppies, in DOS 3.3 order unless a .dsk turns out to be in
+    /// ProDOS order
+    Dsk,
+    /// .po and .hdv: ProDOS blocks, floppies or hard drives
+    Po,
+    D13,
+    Nib,
+    Woz,
+    /// Flux captures can only be converted from
+    A2r,
+    /// ProDOS blocks (or DOS sectors, or nibbles) after a 64 byte header
+    TwoMg,
+}
+
+impl ImageFormat {
+    pub fn of_path(path: &str) -> Result<ImageFormat, String> {
+        let extension = Path::new(path).extension().and_then(OsStr::to_str)
+            .unwrap_or_default().to_lowercase();
+        match extension.as_str() {
+            "dsk" | "do" => Ok(ImageFormat::Dsk),
+            "po" | "hdv" => Ok(ImageFormat::Po),
+            "d13" => Ok(ImageFormat::D13),
+            "nib" => Ok(ImageFormat::Nib),
+            "woz" => Ok(ImageFormat::Woz),
+            "a2r" => Ok(ImageFormat::A2r),
+            "2mg" => Ok(ImageFormat::TwoMg),
+            _ => Err(format!("Don't know the format of {path}")),
+        }
+    }
+}
+
+/// A disk image once read, in the most accurate form its format has. There is only one at a
+/// time, so the size of the tracks doesn't matter
+#[allow(clippy::large_enum_variant)]
+enum Image {
+    /// 35 tracks of 16 sectors, in DOS 3.3 order
+    Sectors16(Vec<u8>),
+    /// 35 tracks of 13 sectors
+    Sectors13(Vec<u8>),
+    /// The blocks of an image larger than a floppy
+    Blocks(Vec<u8>),
+    /// Bit and flux tracks, with the INFO and META of WOZ images
+    Tracks(BitStreams, InfoChunk, HashMap<String, String>),
+}
+
+/// Convert the image at `input` into `output`, their formats being given by their suffixes.
+/// If the conversion would lose something, it fails with the list of what would be lost,
+/// unless `force` is true: that list is then returned.
+pub fn convert(input: &str, output: &str, force: bool) -> Result<Vec<String>, String> {
+    let mut lost: Vec<String> = Vec::new();
+    let image = read(input, &mut lost)?;
+    let bytes = write(image, ImageFormat::of_path(output)?, &mut lost)?;
+    if ! lost.is_empty() && ! force {
+        return Err(format!("Converting {input} to {output} would lose data, use --force \
+            to convert anyway:\n{}", lost.join("\n")));
+    }
+    fs::write(output, bytes).map_err(|e| format!("Couldn't write {output}: {e}"))?;
+    Ok(lost)
+}
+
+/// The 16 sectors of the 35 tracks of the floppy at `path`, in DOS 3.3 order, whatever its
+/// format. The sectors that can't be read are zeroes.
+pub fn read_dos_sectors(path: &str) -> Result<Vec<u8>, String> {
+    let mut lost = Vec::new();
+    let image = read(path, &mut lost)?;
+    to_sectors_16(image, &mut lost)
+}
+
+/// Replace the sectors of the floppy at `path`, see [read_dos_sectors]. The tracks of .nib and
+/// .woz images are only encoded again if one of their sectors changed, the others are kept as is.
+pub fn write_dos_sectors(path: &str, sectors: &[u8]) -> Result<(), String> {
+    let mut lost = Vec::new();
+    let image = match read(path, &mut lost)? {
+        Image::Sectors16(_) => Image::Sectors16(sectors.to_vec()),
+        Image::Tracks(mut bit_streams, info, meta) => {
+            for track in 0..MAX_TRACK_DSK {
+                let new = &sectors[track * TRACK_SIZE_BYTES..(track + 1) * TRACK_SIZE_BYTES];
+                let phase = track * 4;
+                let (old, _) = read_sectors(bit_streams.get_stream(phase), track, 16);
+                let unchanged = old.iter().enumerate().all(|(physical, sector)| {
+                    let offset = SectorOrder::Dos.logical_sector(physical) * SECTOR_SIZE_BYTES;
+                    sector.is_some_and(|s| s[..] == new[offset..offset + SECTOR_SIZE_BYTES])
+                });
+                if unchanged {
+                    continue;
+                }
+                let encoded = BitStream::new(Dsk::encode_track(new, track as u8, SectorOrder::Dos));
+                // The quarter tracks that were copies of this track stay copies of it. They are
+                // found before anything is replaced, otherwise the quarter track after this one
+                // would be compared to the new content of the track
+                let copies: Vec<usize> = (phase.saturating_sub(1)..=phase + 1)
+                    .filter(|&p| same_track(bit_streams.get_stream(p), bit_streams.get_stream(phase)))
+                    .collect();
+                for p in copies {
+                    bit_streams.bit_streams[p] = encoded.clone();
+                    if bit_streams.tmap[p] == 0xff {
+                        bit_streams.tmap[p] = track as u8;
+                    }
+                }
+            }
+            Image::Tracks(bit_streams, info, meta)
+        }
+        _ => { return Err(format!("{path} is not a 16 sector floppy")); }
+    };
+    let bytes = write(image, ImageFormat::of_path(path)?, &mut lost)?;
+    fs::write(path, bytes).map_err(|e| format!("Couldn't write {path}: {e}"))
+}
+
+/// The ProDOS blocks of the floppy or hard drive at `path`, whatever its format
+pub fn read_blocks(path: &str) -> Result<Vec<u8>, String> {
+    let mut lost = Vec::new();
+    let image = read(path, &mut lost)?;
+    to_blocks(image, &mut lost)
+}
+
+/// Replace the blocks of the image at `path`, or create it. Existing floppies are updated
+/// like [write_dos_sectors] does
+pub fn write_blocks(path: &str, blocks: &[u8]) -> Result<(), String> {
+    if blocks.len() == DSK_SIZE_BYTES && Path::new(path).exists() {
+        return write_dos_sectors(path, &Dsk::convert_order(blocks, SectorOrder::Prodos, SectorOrder::Dos));
+    }
+    let bytes = write(blocks_to_image(blocks.to_vec()), ImageFormat::of_path(path)?, &mut Vec::new())?;
+    fs::write(path, bytes).map_err(|e| format!("Couldn't write {path}: {e}"))
+}
+
+/// What a new disk image holds
+#[derive(Clone, Debug, PartialEq)]
+pub enum NewDisk {
+    /// Tracks without any flux transition for .woz and .nib images, zeroes for sector images
+    Unformatted,
+    /// An empty DOS 3.3 disk with that volume number, see [Dos33::format]
+    Dos33(u8),
+    /// An empty ProDOS volume with that name
+    ProDos(String),
+}
+
+/// Create the image at `path` in the format given by its suffix: a floppy if `block_count`
+/// is 280, a hard drive otherwise, which only ProDOS block images can hold. An existing file
+/// is only replaced if `overwrite` is set, e.g. once a save dialog had the user confirm it
+pub fn create_disk(path: &str, content: &NewDisk, block_count: usize, overwrite: bool) -> Result<(), String> {
+    if ! overwrite && Path::new(path).exists() {
+        return Err(format!("{path} already exists"));
+    }
+    let is_floppy = block_count * BLOCK_SIZE == DSK_SIZE_BYTES;
+    let image = match content {
+        NewDisk::Unformatted if is_floppy => {
+            match ImageFormat::of_path(path)? {
+                ImageFormat::Woz | ImageFormat::Nib => {
+                    let tracks = vec![BitStream::new(vec![0; 51_200]); MAX_TRACK_DSK];
+                    Image::Tracks(Dsk::tracks_to_bit_streams(tracks), InfoChunk::default(), HashMap::new())
+                }
+                _ => Image::Sectors16(vec![0; DSK_SIZE_BYTES]),
+            }
+        }
+        NewDisk::Unformatted => Image::Blocks(vec![0; block_count * BLOCK_SIZE]),
+        NewDisk::Dos33(volume) if is_floppy => Image::Sectors16(Dos33::format(*volume).image().to_vec()),
+        NewDisk::Dos33(_) => { return Err("DOS 3.3 disks are 140K floppies".to_string()); }
+        NewDisk::ProDos(name) => blocks_to_image(ProDos::format(name, block_count)?.image().to_vec()),
+    };
+    let bytes = write(image, ImageFormat::of_path(path)?, &mut Vec::new())?;
+    fs::write(path, bytes).map_err(|e| format!("Couldn't write {path}: {e}"))
+}
+
+fn read(path: &str, lost: &mut Vec<String>) -> Result<Image, String> {
+    let format = ImageFormat::of_path(path)?;
+    let bytes = archive::read(path).map_err(|e| format!("Couldn't read {path}: {e}"))?;
+    let image = match format {
+        ImageFormat::Dsk => {
+            if bytes.len() < DSK_SIZE_BYTES || bytes.len() > MAX_TRACK * TRACK_SIZE_BYTES {
+                return Err(format!("{path} is not a 5.25\" floppy image ({} bytes)", bytes.len()));
+            }
+            if bytes.len() > DSK_SIZE_BYTES {
+                lost.push(format!("Tracks {} to {} are dropped", MAX_TRACK_DSK,
+                    bytes.len() / TRACK_SIZE_BYTES - 1));
+            }
+            let order = SectorOrder::of_file(path, &bytes);
+            Image::Sectors16(Dsk::convert_order(&bytes[..DSK_SIZE_BYTES], order, SectorOrder::Dos))
+        }
+        ImageFormat::Po => blocks_to_image(bytes),
+        ImageFormat::D13 => Image::Sectors13(bytes),
+        ImageFormat::Nib => {
+            if bytes.len() != NIB_SIZE_BYTES {
+                return Err(format!("{path} is not a .nib image ({} bytes instead of {NIB_SIZE_BYTES})",
+                    bytes.len()));
+            }
+            Image::Tracks(Nib::bytes_to_bit_streams(&bytes), InfoChunk::default(), HashMap::new())
+        }
+        ImageFormat::Woz => {
+            let woz = Woz::new_with_file(path, false)?;
+            Image::Tracks(woz.bit_streams.clone(), woz.info().clone(), woz.meta.clone())
+        }
+        ImageFormat::A2r => {
+            let a2r = A2r::new_with_file(path, false)?;
+            let mut info = InfoChunk::default();
+            info.write_protected = a2r.disk_info().is_write_protected();
+            Image::Tracks(a2r.bit_streams().clone(), info, a2r.meta.clone())
+        }
+        ImageFormat::TwoMg => {
+            let header = parse_2mg_header(&bytes)?;
+            let (format, data) = (header.format, &bytes[header.data_offset..][..header.data_length]);
+            match format {
+                TWO_MG_DOS_ORDER if data.len() == DSK_SIZE_BYTES => Image::Sectors16(data.to_vec()),
+                TWO_MG_PRODOS_ORDER => blocks_to_image(data.to_vec()),
+                TWO_MG_NIB if data.len() == NIB_SIZE_BYTES => Image::Tracks(Nib::bytes_to_bit_streams(data),
+                    InfoChunk::default(), HashMap::new()),
+                _ => { return Err(format!("Unsupported 2MG image in {path} (format {format}, {} bytes)",
+                    data.len())); }
+            }
+        }
+    };
+    Ok(image)
+}
+
+/// ProDOS blocks: a floppy if there are 280 of them, a hard drive otherwise
+fn blocks_to_image(bytes: Vec<u8>) -> Image {
+    if bytes.len() == DSK_SIZE_BYTES {
+        Image::Sectors16(Dsk::convert_order(&bytes, SectorOrder::Prodos, SectorOrder::Dos))
+    } else {
+        Image::Blocks(bytes)
+    }
+}
+
+fn write(image: Image, format: ImageFormat, lost: &mut Vec<String>) -> Result<Vec<u8>, String> {
+    match format {
+        ImageFormat::Dsk => to_sectors_16(image, lost),
+        ImageFormat::Po => to_blocks(image, lost),
+        ImageFormat::TwoMg => Ok(to_2mg(&to_blocks(image, lost)?)),
+        ImageFormat::D13 => to_sectors_13(image, lost),
+        ImageFormat::Nib => {
+            let (bit_streams, _, _) = to_tracks(image)?;
+            lost.extend(check_tracks(&bit_streams));
+            for track in 0..MAX_TRACK_DSK {
+                let stream = bit_streams.get_stream(track * 4);
+                if has_data(&bit_streams, track * 4) {
+                    let (sectors16, problems16) = read_sectors(stream, track, 16);
+                    let (sectors13, problems13) = read_sectors(stream, track, SECTORS_13);
+                    // Standard tracks keep all their content once turned into nibbles
+                    if sectors16.iter().chain(sectors13.iter()).all(|s| s.is_none()) {
+                        lost.push(format!("Track {track} is nonstandard"));
+                    } else if sectors16.iter().any(|s| s.is_some()) {
+                        lost.extend(problems16);
+                    } else {
+                        lost.extend(problems13);
+                    }
+                }
+            }
+            Ok(Nib::bit_streams_to_bytes(&bit_streams))
+        }
+        ImageFormat::Woz => {
+            let (bit_streams, info, meta) = to_tracks(image)?;
+            Woz::to_bytes(&info, &meta, &bit_streams)
+        }
+        ImageFormat::A2r => Err(".a2r files can only be converted to other formats".to_string()),
+    }
+}
+
+fn to_tracks(image: Image) -> Result<(BitStreams, InfoChunk, HashMap<String, String>), String> {
+    let mut info = InfoChunk::default();
+    let bit_streams = match image {
+        Image::Sectors16(bytes) => {
+            info.boot_sector_format = 1;
+            Dsk::tracks_to_bit_streams(bytes.chunks(TRACK_SIZE_BYTES).enumerate()
+                .map(|(track, sectors)| BitStream::new(Dsk::encode_track(sectors, track as u8,
+                    SectorOrder::Dos)))
+                .collect())
+        }
+        Image::Sectors13(bytes) => {
+            info.boot_sector_format = 2;
+            D13::bytes_to_bit_streams(&bytes)
+        }
+        Image::Blocks(_) => { return Err(hard_drive_error()); }
+        Image::Tracks(bit_streams, info, meta) => { return Ok((bit_streams, info, meta)); }
+    };
+    Ok((bit_streams, info, HashMap::new()))
+}
+
+fn to_sectors_16(image: Image, lost: &mut Vec<String>) -> Result<Vec<u8>, String> {
+    match image {
+        Image::Sectors16(bytes) => Ok(bytes),
+        Image::Sectors13(_) => Err("13 sector disks can only be converted to .d13, .nib and .woz"
+            .to_string()),
+        Image::Blocks(_) => Err(hard_drive_error()),
+        Image::Tracks(bit_streams, _, _) => {
+            lost.extend(check_tracks(&bit_streams));
+            let mut result = vec![0; DSK_SIZE_BYTES];
+            for track in 0..MAX_TRACK_DSK {
+                let (sectors, problems) = read_sectors(bit_streams.get_stream(track * 4), track, 16);
+                lost.extend(problems);
+                for (physical, sector) in sectors.iter().enumerate() {
+                    if let Some(sector) = sector {
+                        let offset = track * TRACK_SIZE_BYTES
+                            + SectorOrder::Dos.logical_sector(physical) * SECTOR_SIZE_BYTES;
+                        result[offset..offset + SECTOR_SIZE_BYTES].copy_from_slice(sector);
+                    }
+                }
+            }
+            Ok(result)
+        }
+    }
+}
+
+fn to_sectors_13(image: Image, lost: &mut Vec<String>) -> Result<Vec<u8>, String> {
+    let bit_streams = match image {
+        Image::Sectors13(bytes) => { return Ok(bytes); }
+        Image::Tracks(bit_streams, _, _) => bit_streams,
+        _ => { return Err("Only 13 sector disks can be converted to .d13".to_string()); }
+    };
+    lost.extend(check_tracks(&bit_streams));
+    let mut result = vec![0; TRACK_SIZE_BYTES_13 * MAX_TRACK_DSK];
+    for track in 0..MAX_TRACK_DSK {
+        let (sectors, problems) = read_sectors(bit_streams.get_stream(track * 4), track, SECTORS_13);
+        lost.extend(problems);
+        for (sector_number, sector) in sectors.iter().enumerate() {
+            if let Some(sector) = sector {
+                let offset = track * TRACK_SIZE_BYTES_13 + sector_number * SECTOR_SIZE_BYTES;
+                result[offset..offset + SECTOR_SIZE_BYTES].copy_from_slice(sector);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// The blocks of the image, in ProDOS order
+fn to_blocks(image: Image, lost: &mut Vec<String>) -> Result<Vec<u8>, String> {
+    match image {
+        Image::Blocks(bytes) => Ok(bytes),
+        image => {
+            let sectors = to_sectors_16(image, lost)?;
+            Ok(Dsk::convert_order(&sectors, SectorOrder::Dos, SectorOrder::Prodos))
+        }
+    }
+}
+
+fn hard_drive_error() -> String {
+    "Hard drive images can only be converted to .po, .hdv and .2mg".to_string()
+}
+
+/// True if the image has something on this phase
+fn has_data(bit_streams: &BitStreams, phase: usize) -> bool {
+    bit_streams.tmap[phase] != 0xff || bit_streams.get_stream(phase).is_flux()
+}
+
+fn same_track(a: &BitStream, b: &BitStream) -> bool {
+    a.bits() == b.bits() && a.flux_delays() == b.flux_delays()
+}
+
+/// What sector and nibble images can't hold: the tracks past the 35th and the quarter tracks
+/// that are not copies of their neighbour
+fn check_tracks(bit_streams: &BitStreams) -> Vec<String> {
+    let mut result = Vec::new();
+    for phase in 0..MAX_PHASE {
+        if ! has_data(bit_streams, phase) {
+            continue;
+        }
+        let track = format!("{}.{:02}", phase / 4, (phase % 4) * 25);
+        if phase >= MAX_TRACK_DSK * 4 {
+            result.push(format!("Track {track} is past the last track"));
+        } else if phase % 4 != 0 {
+            let stream = bit_streams.get_stream(phase);
+            let whole_tracks = [phase - phase % 4, phase - phase % 4 + 4];
+            let is_copy = whole_tracks.iter().any(|p| *p < MAX_PHASE
+                && has_data(bit_streams, *p) && same_track(stream, bit_streams.get_stream(*p)));
+            if ! is_copy {
+                result.push(format!("Track {track} has its own content"));
+            }
+        }
+    }
+    result
+}
+
+/// Decode the 16 (6 and 2) or 13 (5 and 3) sectors of this track, verifying the checksums of
+/// their address and data fields. Also return what went wrong.
+fn read_sectors(stream: &BitStream, track: usize, sector_count: usize)
+    -> (Vec<Option<[u8; SECTOR_SIZE_BYTES]>>, Vec<String>)
+{
+    let mut result: Vec<Option<[u8; SECTOR_SIZE_BYTES]>> = vec![None; sector_count];
+    let mut problems = Vec::new();
+
+    for found in stream.analyze_track().sectors {
+        let sector = found.sector as usize;
+        if found.five_and_three != (sector_count == SECTORS_13) {
+            continue;
+        }
+        if ! found.address_checksum_ok {
+            problems.push(format!("Track {track}: bad checksum in the address field of sector {sector}"));
+            continue;
+        }
+        if found.track as usize != track || sector >= sector_count || result[sector].is_some() {
+            continue;
+        }
+        let Some(data) = found.data else { continue };
+        if data.checksum_ok {
+            result[sector] = Some(data.bytes);
+        } else {
+            problems.push(format!("Track {track}: bad checksum in the data field of sector {sector}"));
+        }
+    }
+
+    let missing: Vec<String> = (0..sector_count).filter(|s| result[*s].is_none())
+        .map(|s| s.to_string()).collect();
+    if ! missing.is_empty() {
+        problems.push(format!("Track {track}: sectors {} can't be read", missing.join(", ")));
+    }
+    problems.dedup();
+    (result, problems)
+}
+
+/// A 2MG image of these ProDOS blocks
+fn to_2mg(blocks: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(TWO_MG_HEADER_SIZE + blocks.len());
+    result.extend_from_slice(TWO_MG_MAGIC);
+    result.extend_from_slice(b"MPL2");  // creator
+    result.extend_from_slice(&(TWO_MG_HEADER_SIZE as u16).to_le_bytes());
+    result.extend_from_slice(&1_u16.to_le_bytes());  // version
+    for value in [TWO_MG_PRODOS_ORDER, 0 /* flags */, (blocks.len() / BLOCK_SIZE) as u32,
+            TWO_MG_HEADER_SIZE as u32, blocks.len() as u32] {
+        result.extend_from_slice(&value.to_le_bytes());
+    }
+    // No comment and no creator data
+    result.resize(TWO_MG_HEADER_SIZE, 0);
+    result.extend_from_slice(blocks);
+    result
+}
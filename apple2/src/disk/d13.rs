@@ -0,0 +1,217 @@
+//! 13 sector disks, as formatted by DOS 3.1, 3.2 and 3.2.1: 5 and 3 encoding, address fields
+//! starting with D5 AA B5. They only boot with the 13 sector PROM, see
+//! [crate::card::CardType::Disk2Sector13].
+
+use std::fs;
+use std::ops::BitXor;
+use crate::disk::archive;
+use crate::disk::bit_stream::{AreaType, BitStream, BitStreams};
+use crate::disk::disk::PDisk;
+use crate::disk::disk_controller::{MAX_TRACK_DSK, SECTOR_SIZE_BYTES};
+use crate::disk::disk_info::{DiskInfo, WozVersion};
+use crate::disk::dsk::Dsk;
+use crate::misc::save;
+use crate::ui_log;
+
+pub const SECTORS_13: usize = 13;
+/// A track has 13 sectors of 256 bytes each
+pub const TRACK_SIZE_BYTES_13: usize = SECTORS_13 * SECTOR_SIZE_BYTES;
+pub const D13_SIZE_BYTES: usize = TRACK_SIZE_BYTES_13 * MAX_TRACK_DSK;
+/// Size of the data field (following D5 AA AD): 410 values and a checksum
+pub const DATA_FIELD_SIZE_13: usize = 411;
+/// The order in which DOS 3.2 writes the sectors on a track. The .d13 file is in sector order.
+pub const PHYSICAL_SECTORS_13: [u8; SECTORS_13] = [0, 10, 7, 4, 1, 11, 8, 5, 2, 12, 9, 6, 3];
+
+/// The nibbles without two consecutive zero bits, except the $AA and $D5 markers
+pub const WRITE_TABLE_53: [u8; 32] = [
+    0xab, 0xad, 0xae, 0xaf, 0xb5, 0xb6, 0xb7, 0xba,
+    0xbb, 0xbd, 0xbe, 0xbf, 0xd6, 0xd7, 0xda, 0xdb,
+    0xdd, 0xde, 0xdf, 0xea, 0xeb, 0xed, 0xee, 0xef,
+    0xf5, 0xf6, 0xf7, 0xfa, 0xfb, 0xfd, 0xfe, 0xff,
+];
+
+#[derive(Clone)]
+pub struct D13 {
+    disk_info: DiskInfo,
+    bit_streams: BitStreams,
+}
+
+impl PDisk for D13 {
+    fn bit_streams(&self) -> &BitStreams {
+        &self.bit_streams
+    }
+
+    fn bit_streams_mut(&mut self) -> &mut BitStreams {
+        &mut self.bit_streams
+    }
+
+    fn save(&mut self) {
+        let path = self.disk_info.path.clone();
+        ui_log(&format!("D13 saving {path}"));
+        // Sectors that can't be decoded keep their current content
+        let mut buffer = fs::read(&path).unwrap_or_default();
+        buffer.resize(D13_SIZE_BYTES, 0);
+        Self::decode_sectors(&self.bit_streams, &mut buffer);
+        match save(&path, &buffer) {
+            Ok(_) => { ui_log(&format!("Saved {path}")); }
+            Err(s) => { ui_log(&format!("Error saving {path}: {s}")); }
+        }
+    }
+
+    fn disk_info(&self) -> &DiskInfo {
+        &self.disk_info
+    }
+
+    fn disk_info_mut(&mut self) -> &mut DiskInfo {
+        &mut self.disk_info
+    }
+}
+
+impl D13 {
+    pub fn new_with_file(filename: &str, quick: bool) -> Result<D13, String> {
+        let bit_streams = if quick {
+            Default::default()
+        } else {
+            let bytes = archive::read(filename).map_err(|e| e.to_string())?;
+            if bytes.len() != D13_SIZE_BYTES {
+                return Err(format!("{filename} is not a .d13 image ({} bytes instead of {D13_SIZE_BYTES})",
+                    bytes.len()));
+            }
+            Self::bytes_to_bit_streams(&bytes)
+        };
+        let mut disk_info = DiskInfo::n(filename);
+        disk_info.woz_version = WozVersion::D13;
+        Ok(D13 { disk_info, bit_streams })
+    }
+
+    pub fn bytes_to_bit_streams(bytes: &[u8]) -> BitStreams {
+        Dsk::tracks_to_bit_streams(bytes.chunks(TRACK_SIZE_BYTES_13).enumerate()
+            .map(|(track, sectors)| BitStream::new(Self::encode_track(sectors, track as u8)))
+            .collect())
+    }
+
+    /// The 13 sector controller can't read two zero bits in a row, so the sync nibbles only
+    /// have one
+    fn write_sync(bytes: &mut Vec<u8>, count: u8) {
+        for _ in 0..count {
+            Dsk::write8(bytes, vec![0xff]);
+            bytes.push(0);
+        }
+    }
+
+    pub fn encode_track(bytes: &[u8], track: u8) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::new();
+        Self::write_sync(&mut result, 48);
+        for sector in PHYSICAL_SECTORS_13 {
+            Dsk::write8(&mut result, vec![0xd5, 0xaa, 0xb5]);
+            for value in [0xfe, track, sector, 0xfe.bitxor(track).bitxor(sector)] {
+                Dsk::write8(&mut result, vec![(value >> 1) | 0xaa, value | 0xaa]);
+            }
+            Dsk::write8(&mut result, vec![0xde, 0xaa, 0xeb]);
+            Self::write_sync(&mut result, 6);
+
+            Dsk::write8(&mut result, vec![0xd5, 0xaa, 0xad]);
+            let start = sector as usize * SECTOR_SIZE_BYTES;
+            Dsk::write8(&mut result, Self::encode_5_and_3(&bytes[start..start + SECTOR_SIZE_BYTES]));
+            Dsk::write8(&mut result, vec![0xde, 0xaa, 0xeb]);
+            Self::write_sync(&mut result, 27);
+        }
+        result
+    }
+
+    /// Encode 256 bytes into 411 nibbles. Each group of 5 bytes is split into five "top" values
+    /// (their high 5 bits) and three values made of their low 3 bits. Bytes 0-2 of the group
+    /// keep their 3 bits together, the bits of bytes 3 and 4 are spread over the three values.
+    /// The 154 low values are written backwards, then the 256 top values, each one exclusive
+    /// ORed with the one before it, and a checksum.
+    pub fn encode_5_and_3(values: &[u8]) -> Vec<u8> {
+        let mut top = [0_u8; 256];
+        let mut threes = [0_u8; 154];
+        for group in 0..51 {
+            let chunk = 50 - group;
+            let b = &values[group * 5..group * 5 + 5];
+            for (k, byte) in b.iter().enumerate() {
+                top[chunk + 51 * k] = byte >> 3;
+            }
+            threes[chunk] = (b[0] & 7) << 2 | (b[3] & 4) >> 1 | (b[4] & 4) >> 2;
+            threes[chunk + 51] = (b[1] & 7) << 2 | (b[3] & 2) | (b[4] & 2) >> 1;
+            threes[chunk + 102] = (b[2] & 7) << 2 | (b[3] & 1) << 1 | (b[4] & 1);
+        }
+        top[255] = values[255] >> 3;
+        threes[153] = values[255] & 7;
+
+        let mut result = Vec::with_capacity(DATA_FIELD_SIZE_13);
+        let mut last = 0;
+        for value in threes.iter().rev().chain(top.iter()) {
+            result.push(WRITE_TABLE_53[(value ^ last) as usize]);
+            last = *value;
+        }
+        result.push(WRITE_TABLE_53[last as usize]);
+        result
+    }
+
+    /// Decode an array of 411 nibbles into 256 bytes
+    pub fn decode_5_and_3(source: &[u8]) -> [u8; 256] {
+        assert_eq!(source.len(), DATA_FIELD_SIZE_13);
+        let mut read_table: [u8; 256] = [0; 256];
+        for (i, nibble) in WRITE_TABLE_53.iter().enumerate() {
+            read_table[*nibble as usize] = i as u8;
+        }
+        let mut top = [0_u8; 256];
+        let mut threes = [0_u8; 154];
+        let mut last = 0;
+        for (i, nibble) in source[0..DATA_FIELD_SIZE_13 - 1].iter().enumerate() {
+            last ^= read_table[*nibble as usize];
+            if i < threes.len() {
+                threes[threes.len() - 1 - i] = last;
+            } else {
+                top[i - threes.len()] = last;
+            }
+        }
+
+        let mut result = [0_u8; 256];
+        for group in 0..51 {
+            let chunk = 50 - group;
+            let (t0, t1, t2) = (threes[chunk], threes[chunk + 51], threes[chunk + 102]);
+            let low = [
+                t0 >> 2, t1 >> 2, t2 >> 2,
+                (t0 & 2) << 1 | (t1 & 2) | (t2 & 2) >> 1,
+                (t0 & 1) << 2 | (t1 & 1) << 1 | (t2 & 1),
+            ];
+            for k in 0..5 {
+                result[group * 5 + k] = top[chunk + 51 * k] << 3 | (low[k] & 7);
+            }
+        }
+        result[255] = top[255] << 3 | (threes[153] & 7);
+        result
+    }
+
+    /// Decode the sectors found in the bit streams of the 35 tracks into `buffer`
+    pub fn decode_sectors(bit_streams: &BitStreams, buffer: &mut [u8]) {
+        let mut track = 0;
+        let mut sector = 0;
+        for t in 0..MAX_TRACK_DSK {
+            let nibbles = bit_streams.get_stream(t * 4).analyze_track().nibbles;
+            let mut i = 0;
+            while i < nibbles.len() {
+                match nibbles[i].area_type {
+                    AreaType::AddressContent if i + 6 <= nibbles.len() => {
+                        track = Dsk::decode_4_and_4(nibbles[i + 2].value, nibbles[i + 3].value) as usize;
+                        sector = Dsk::decode_4_and_4(nibbles[i + 4].value, nibbles[i + 5].value) as usize;
+                        i += 10;
+                    }
+                    AreaType::DataContent if i + DATA_FIELD_SIZE_13 <= nibbles.len()
+                            && track < MAX_TRACK_DSK && sector < SECTORS_13 => {
+                        let values: Vec<u8> =
+                            nibbles[i..i + DATA_FIELD_SIZE_13].iter().map(|n| n.value).collect();
+                        let offset = track * TRACK_SIZE_BYTES_13 + sector * SECTOR_SIZE_BYTES;
+                        buffer[offset..offset + SECTOR_SIZE_BYTES].copy_from_slice(&Self::decode_5_and_3(&values));
+                        i += DATA_FIELD_SIZE_13;
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+        }
+    }
+}
@@ -1,21 +1,31 @@
 use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
 use crossbeam::channel::Sender;
 use dyn_clone::DynClone;
+use crate::disk::a2r::A2r;
+use crate::disk::archive;
 use crate::disk::bit_stream::{AnalyzedTrack, BitStream, BitStreams};
 use crate::disk::disk_info::DiskInfo;
 use crate::disk::dsk::Dsk;
+use crate::disk::d13::D13;
+use crate::disk::nib::Nib;
 use crate::disk::woz::Woz;
 use crate::messages::ToUi;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::ui_log;
 
-/// Each disk format (.dsk, .woz) supplies its owm implementation of this trait
+/// Each disk format (.dsk, .nib, .woz, .a2r) supplies its owm implementation of this trait
 pub trait PDisk: DynClone {
     fn bit_streams(&self) -> &BitStreams;
     fn bit_streams_mut(&mut self) -> &mut BitStreams;
     fn save(&mut self);
     fn disk_info(&self) -> &DiskInfo;
+    fn disk_info_mut(&mut self) -> &mut DiskInfo;
 }
 
-pub(crate) struct Disk {
+pub struct Disk {
     // Bit streams for each phase (0..MAX_PHASE)
     pdisk: Box<dyn PDisk>,
 
@@ -23,6 +33,9 @@ pub(crate) struct Disk {
     // 0 or 1
     // pub(crate) drive_number: usize,
     pub(crate) bit_position: usize,
+    /// True once something has been written to this disk. Snapshots then need to contain
+    /// the bit streams, the file might not match them
+    written: bool,
     sender: Option<Sender<ToUi>>,
 }
 
@@ -38,6 +51,7 @@ impl Clone for Disk {
         Self {
             pdisk: dyn_clone::clone_box(&*self.pdisk),
             bit_position: self.bit_position,
+            written: self.written,
             sender: self.sender.clone(),
         }
     }
@@ -49,12 +63,26 @@ impl Clone for Disk {
 // }
 
 impl Disk {
+    /// If the disk has an overlay from a previous session, it's loaded instead of the disk
+    /// itself, see [Disk::enable_overlay]. Disks inside archives always save to an overlay
     pub fn new(path: &str, quick: bool, sender: Option<Sender<ToUi>>) -> Result<Disk, String> {
-        match Self::new_pdisk(path, quick) {
+        let overlay = DiskInfo::overlay_path(path);
+        let has_overlay = Path::new(&overlay).exists();
+        let in_archive = archive::split_path(path).is_some();
+        let pdisk = Self::new_pdisk(if has_overlay { &overlay } else { path }, quick).map(|mut pdisk| {
+            if has_overlay || in_archive {
+                let disk_info = pdisk.disk_info_mut();
+                disk_info.path = path.to_string();
+                disk_info.overlay = Some(overlay);
+            }
+            pdisk
+        });
+        match pdisk {
             Ok(pdisk) => {
                 Ok(Self {
                     pdisk,
                     bit_position: 0,
+                    written: false,
                     sender,
                 })
             }
@@ -76,7 +104,80 @@ impl Disk {
         self.pdisk.disk_info().clone()
     }
 
+    pub fn is_write_protected(&self) -> bool {
+        self.pdisk.disk_info().is_write_protected
+    }
+
+    /// The write protect switch, as sensed by the controller
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.pdisk.disk_info_mut().is_write_protected = write_protected;
+    }
+
+    /// From now on, save to a copy of the image instead of the image itself. The copy is
+    /// created the first time something is saved.
+    pub fn enable_overlay(&mut self) {
+        let disk_info = self.pdisk.disk_info_mut();
+        if disk_info.overlay.is_none() {
+            disk_info.overlay = Some(DiskInfo::overlay_path(&disk_info.path));
+        }
+    }
+
+    /// Save the content of the disk to the image and delete the overlay
+    pub fn commit_overlay(&mut self) -> Result<(), String> {
+        let path = &self.pdisk.disk_info().path;
+        if archive::split_path(path).is_some() {
+            return Err(format!("{path} is inside an archive, its changes stay in its overlay"));
+        }
+        let overlay = self.pdisk.disk_info_mut().overlay.take();
+        self.pdisk.save();
+        if let Some(overlay) = overlay {
+            Self::remove_overlay(&overlay)?;
+        }
+        Ok(())
+    }
+
+    /// Delete the overlay and reload the image, losing everything written since the overlay
+    /// was enabled
+    pub fn discard_overlay(&mut self) -> Result<(), String> {
+        let disk_info = self.pdisk.disk_info().clone();
+        if let Some(overlay) = &disk_info.overlay {
+            Self::remove_overlay(overlay)?;
+        }
+        let mut pdisk = Self::new_pdisk(&disk_info.path, false)?;
+        pdisk.disk_info_mut().is_write_protected = disk_info.is_write_protected;
+        // Disks inside archives can't save anywhere else
+        if archive::split_path(&disk_info.path).is_some() {
+            pdisk.disk_info_mut().overlay = disk_info.overlay;
+        }
+        self.pdisk = pdisk;
+        self.written = false;
+        Ok(())
+    }
+
+    fn remove_overlay(overlay: &str) -> Result<(), String> {
+        match fs::remove_file(overlay) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(format!("Couldn't delete the overlay {overlay}: {e}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Nothing is written while the disk is write protected, the head only moves on
     pub fn set_bit_and_advance(&mut self, phase_160: usize, bit: u8) {
+        if self.is_write_protected() {
+            let len = self.pdisk.bit_streams().get_stream(phase_160).position_len();
+            self.bit_position = (self.bit_position + 1) % len;
+            return;
+        }
+        self.written = true;
+        let streams = self.pdisk.bit_streams_mut();
+        if streams.tmap[phase_160] != 0xff && streams.get_stream(phase_160).is_flux() {
+            // The head is now positioned in bits instead of ticks
+            let stream = &mut streams.bit_streams[phase_160];
+            self.bit_position = self.bit_position * stream.len() / stream.position_len();
+            stream.remove_flux();
+        }
         self.pdisk.bit_streams_mut().set_bit_and_advance(phase_160, self.bit_position, bit);
         let len = self.pdisk.bit_streams().get_stream(phase_160).len();
         // let stream = &mut streams.get_stream_mut(phase_160);
@@ -85,18 +186,25 @@ impl Disk {
     }
 
     fn new_pdisk(path: &str, quick: bool) -> Result<Box<dyn PDisk>, String> {
-        if path.to_lowercase().ends_with(".woz") {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".woz") {
             match Woz::new_with_file(path, quick) {
                 Ok(p) => { Ok(Box::new(p)) }
                 Err(s) => { Err(s) }
             }
-        } else if path.to_lowercase().ends_with(".dsk") {
+        } else if [".dsk", ".do", ".po"].iter().any(|suffix| lower.ends_with(suffix)) {
             match Dsk::new_with_file(path, quick) {
                 Ok(p) => { Ok(Box::new(p) ) }
                 Err(s) => { Err(s) }
             }
+        } else if lower.ends_with(".d13") {
+            Ok(Box::new(D13::new_with_file(path, quick)?))
+        } else if lower.ends_with(".nib") {
+            Ok(Box::new(Nib::new_with_file(path, quick)?))
+        } else if lower.ends_with(".a2r") {
+            Ok(Box::new(A2r::new_with_file(path, quick)?))
         } else {
-            panic!("Unknown disk format");
+            Err(format!("Unknown disk format: {path}"))
         }
     }
 
@@ -104,17 +212,86 @@ impl Disk {
         self.pdisk.bit_streams().get_stream(phase_160).clone()
     }
 
+    /// Length of the track in the unit of [Disk::bit_position]
     pub fn get_stream_len(&self, phase_160: usize) -> usize {
-        self.pdisk.bit_streams().get_stream(phase_160).len()
+        self.pdisk.bit_streams().get_stream(phase_160).position_len()
+    }
+
+    pub fn is_flux(&self, phase_160: usize) -> bool {
+        self.pdisk.bit_streams().get_stream(phase_160).is_flux()
+    }
+
+    pub fn optimal_bit_timing(&self) -> u8 {
+        self.pdisk.bit_streams().optimal_bit_timing
     }
 
     pub fn analyze_track(&self, phase_160: usize) -> AnalyzedTrack {
         self.pdisk.bit_streams().get_stream(phase_160).analyze_track()
     }
 
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        w.string(&self.pdisk.disk_info().path);
+        w.usize(self.bit_position);
+        w.bool(self.written);
+        if self.written {
+            let streams = self.pdisk.bit_streams();
+            w.bytes(&streams.tmap);
+            w.usize(streams.bit_streams.len());
+            for stream in &streams.bit_streams {
+                w.bool(stream.random);
+                w.bits(stream.bits());
+            }
+        }
+    }
+
+    /// Reload the disk saved by [Disk::save_state] from its file, then restore the
+    /// bit streams if they had been modified
+    pub(crate) fn new_with_state(r: &mut SnapshotReader, sender: Option<Sender<ToUi>>)
+            -> Result<Disk, String> {
+        let path = r.string()?;
+        let mut result = Disk::new(&path, false /* read bit_streams */, sender)
+            .map_err(|e| format!("Couldn't load the disk {path} of the snapshot: {e}"))?;
+        result.bit_position = r.usize()?;
+        result.written = r.bool()?;
+        if result.written {
+            let streams = result.pdisk.bit_streams_mut();
+            r.bytes_into(&mut streams.tmap)?;
+            let count = r.usize()?;
+            let mut bit_streams = Vec::with_capacity(count);
+            for i in 0..count {
+                let random = r.bool()?;
+                let bits = r.bits()?;
+                // Flux tracks that haven't been written to are kept from the file
+                let loaded = streams.bit_streams.get(i).filter(|s| s.is_flux() && s.bits() == bits);
+                bit_streams.push(if let Some(stream) = loaded {
+                    stream.clone()
+                } else if random {
+                    BitStream::random()
+                } else {
+                    BitStream::new(bits)
+                });
+            }
+            streams.bit_streams = bit_streams;
+        }
+        Ok(result)
+    }
+
     pub(crate) fn save(&mut self) {
         println!("Ready to write tracks");
-        self.pdisk.save();
+        let disk_info = self.pdisk.disk_info().clone();
+        if let Some(overlay) = &disk_info.overlay {
+            // Formats that only save the sectors they can decode need the rest of the image
+            if ! Path::new(overlay).exists() {
+                if let Err(e) = archive::read(&disk_info.path).and_then(|bytes| fs::write(overlay, bytes)) {
+                    ui_log(&format!("Couldn't create the overlay {overlay}: {e}"));
+                }
+            }
+            self.pdisk.disk_info_mut().path = overlay.clone();
+            self.pdisk.save();
+            self.pdisk.disk_info_mut().path = disk_info.path;
+        } else {
+            self.pdisk.save();
+        }
         // let path = self.disk_info.path.to_lowercase();
         // if path.ends_with(".woz") {
         //     Woz::save(&self.disk_info.path, &self.bit_streams);
@@ -152,6 +329,16 @@ impl Disk {
         (bits_seen, result)
     }
 
+    /// Move the head of a flux track by `ticks` (125ns each) and return 1 if it went over
+    /// a flux transition
+    pub(crate) fn next_flux_pulse(&mut self, phase160: usize, ticks: usize) -> u8 {
+        let stream = self.pdisk.bit_streams().get_stream(phase160);
+        let len = stream.position_len();
+        let result = stream.flux_pulse(self.bit_position % len, ticks);
+        self.bit_position = (self.bit_position % len + ticks) % len;
+        result
+    }
+
     pub(crate) fn next_bit(&mut self, phase160: usize) -> u8 {
         // if self.peek_nibbles(1).1 == [0xf0] {
         //     println!("BREAK");
@@ -1,16 +1,21 @@
-use std::{fs};
+use std::any::Any;
 use std::ops::{BitXor};
+use std::sync::Arc;
 use crossbeam::channel::Sender;
+use crate::card::{Bus, Card};
+use crate::disk::archive;
 use crate::disk::bit_stream::{Nibble};
+use crate::roms::{DISK2_13_SECTOR_ROM, DISK2_ROM};
 use crate::cycle_actions::{Actions, UpdatePhaseAction};
 use crate::cycle_actions::CycleAction::{UpdatePhase};
 use crate::disk::disk::{Disk};
-use crate::{send_message};
+use crate::{send_message, ui_log};
 use crate::disk::disk_info::DiskInfo;
 use crate::disk::drive::{Drive};
 use crate::messages::ToUi;
-use crate::ui::iced::shared::*;
+use crate::context::EmulatorContext;
 use crate::messages::ToUi::{DiskSelected, FirstRead};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
 
 /// Divide by 2 to get the phase, by 4 to get the track
 pub const MAX_PHASE: usize = 160;
@@ -36,6 +41,8 @@ pub const DATA_FIELD_SIZE: usize = 343;
 pub const LOGICAL_SECTORS: [u8; 16] = [0, 7, 14, 6, 13, 5, 12, 4, 11, 3, 10, 2, 9, 1, 8, 15];
 pub const LOGICAL_SECTORS_WRITE: [u8; 16]
     = [0, 0xd, 0xb, 9, 7, 5, 3, 1, 0xe, 0xc, 0xa, 8, 6, 4, 2, 0xf];
+/// Same as LOGICAL_SECTORS for ProDOS order: the sector of a .po file stored in each physical sector
+pub const PRODOS_LOGICAL_SECTORS: [u8; 16] = [0, 8, 1, 9, 2, 10, 3, 11, 4, 12, 5, 13, 6, 14, 7, 15];
 
 #[derive(Default)]
 pub(crate) struct DiskController {
@@ -87,46 +94,67 @@ pub(crate) struct DiskController {
     /// Keep track of the first time we read a phase. Indexed by the disk drive
     first_time_reading_phase: [u8; 2],
 
+    /// Where the disks, phases and sectors are published
+    context: Arc<EmulatorContext>,
+
+    /// Boot with the 13 sector PROM instead of the 16 sector one
+    sector_13_rom: bool,
+
+    /// The state read by [DiskController::prepare_state], restored by [DiskController::load_state]
+    pending_state: Option<Box<DiskController>>,
 }
 
 impl DiskController {
     pub(crate) fn new_with_filename(slot: u8, disk_infos: &[Option<DiskInfo>; 2],
-            sender: Option<Sender<ToUi>>) -> Self {
+            sender: Option<Sender<ToUi>>, context: Arc<EmulatorContext>) -> Self {
         let mut result = Self {
             slot,
             sender,
+            context,
             ..Default::default()
         };
-        if let Some(di) = Shared::get_drive(0) {
-            result.load_disk_from_file(0, di);
-        }
-        if let Some(di) = Shared::get_drive(1) {
-            result.load_disk_from_file(1, di);
+        for (drive_number, disk_info) in disk_infos.iter().enumerate() {
+            match disk_info {
+                Some(di) => { result.load_disk_from_file(drive_number, di.clone()); }
+                None => { result.eject(drive_number); }
+            }
         }
         result
 
     }
 
+    pub(crate) fn set_13_sector_rom(&mut self, sector_13_rom: bool) {
+        self.sector_13_rom = sector_13_rom;
+    }
+
     pub fn load_disk_new(path: &str, sender: Option<Sender<ToUi>>) -> Result<Disk, String> {
         Disk::new(path, true /* don't read bit_streams */, sender)
     }
 
     pub(crate) fn load_disk_from_file(&mut self, drive_number: usize, disk_info: DiskInfo)
     {
-        if DiskController::file_to_bytes(drive_number, &disk_info, &self.sender).is_some() {
+        if self.file_to_bytes(drive_number, &disk_info).is_some() {
             match Disk::new(&disk_info.path, false /* read bit_streams */, self.sender.clone()) {
                 Ok(disk) => {
+                    // The write protection and the overlay come from the disk
+                    let mut info = disk.disk_info();
+                    info.name = info.name.or(disk_info.name);
                     self.drives[drive_number].disk = Some(disk);
-                    Self::on_new_disk_info(self.sender.clone(), drive_number, Some(disk_info));
+                    self.on_new_disk_info(drive_number, Some(info));
                 }
                 Err(error) => {
                     println!("Couldn't load disk: {}", error);
-                    Self::on_new_disk_info(self.sender.clone(), drive_number, None);
+                    self.on_new_disk_info(drive_number, None);
                 }
             }
         }
     }
 
+    pub(crate) fn eject(&mut self, drive_number: usize) {
+        self.drives[drive_number].disk = None;
+        self.on_new_disk_info(drive_number, None);
+    }
+
     pub(crate) fn swap_disks(&mut self) {
         let disk_info_0 = self.drives[0].disk.clone().map(|d| d.disk_info());
         let disk_info_1 = self.drives[1].disk.clone().map(|d| d.disk_info());
@@ -134,36 +162,69 @@ impl DiskController {
         let tmp = self.drives[0].clone();
         self.drives[0] = self.drives[1].clone();
         self.drives[0].drive_number = 0;
-        Self::on_new_disk_info(self.sender.clone(), 0, disk_info_1);
+        self.on_new_disk_info(0, disk_info_1);
         self.drives[1] = tmp;
         self.drives[1].drive_number = 1;
-        Self::on_new_disk_info(self.sender.clone(), 1, disk_info_0);
+        self.on_new_disk_info(1, disk_info_0);
+    }
+
+    pub(crate) fn set_write_protected(&mut self, drive_number: usize, write_protected: bool) {
+        self.update_disk(drive_number, |disk| {
+            disk.set_write_protected(write_protected);
+            Ok(())
+        });
+    }
+
+    pub(crate) fn enable_overlay(&mut self, drive_number: usize) {
+        self.update_disk(drive_number, |disk| {
+            disk.enable_overlay();
+            Ok(())
+        });
+    }
+
+    /// Write the disk, including what hasn't been saved yet, to its image
+    pub(crate) fn commit_overlay(&mut self, drive_number: usize) {
+        self.write_dirty = false;
+        self.update_disk(drive_number, Disk::commit_overlay);
+    }
+
+    pub(crate) fn discard_overlay(&mut self, drive_number: usize) {
+        self.write_dirty = false;
+        self.update_disk(drive_number, Disk::discard_overlay);
+    }
+
+    /// Apply `f` to the disk in the drive, if any, and let the UI know about its new state
+    fn update_disk(&mut self, drive_number: usize, f: impl FnOnce(&mut Disk) -> Result<(), String>) {
+        if let Some(disk) = &mut self.drives[drive_number].disk {
+            if let Err(e) = f(disk) {
+                ui_log(&e);
+            }
+            let disk_info = disk.disk_info();
+            self.on_new_disk_info(drive_number, Some(disk_info));
+        }
     }
 
     pub fn disks(&self) -> [Option<DiskInfo>; 2] {
         self.drives.clone().map(|drive| drive.disk.map(|disk| disk.disk_info()))
     }
 
-    fn on_new_disk_info(sender: Option<Sender<ToUi>>, drive_number: usize,
-            disk_info: Option<DiskInfo>)
-    {
-        Shared::set_drive(drive_number, disk_info.clone());
-        send_message!(&sender, ToUi::DiskInserted(drive_number, disk_info));
+    fn on_new_disk_info(&self, drive_number: usize, disk_info: Option<DiskInfo>) {
+        self.context.set_drive(drive_number, disk_info.clone());
+        send_message!(&self.sender, ToUi::DiskInserted(drive_number, disk_info));
     }
 
-    pub fn file_to_bytes(drive_number: usize, disk_info: &DiskInfo, sender: &Option<Sender<ToUi>>)
-            -> Option<Vec<u8>> {
-        match fs::read(disk_info.path()) {
+    pub fn file_to_bytes(&self, drive_number: usize, disk_info: &DiskInfo) -> Option<Vec<u8>> {
+        match archive::read(&disk_info.path()) {
             Ok(f) => {
                 println!("Loading \"{}\", {}, in drive {}", disk_info.name(), disk_info.path(),
                     drive_number);
-                Self::on_new_disk_info(sender.clone(), drive_number, Some(disk_info.clone()));
+                self.on_new_disk_info(drive_number, Some(disk_info.clone()));
                 Some(f)
             }
             Err(_) => {
                 println!("Couldn't load \"{}\", {}, in drive {}", disk_info.name(),
                     disk_info.path(), drive_number);
-                Self::on_new_disk_info(sender.clone(), drive_number, None);
+                self.on_new_disk_info(drive_number, None);
                 None
             }
         }
@@ -205,7 +266,7 @@ impl DiskController {
                                 Some((current_bit_position * new_length / old_length) % new_length);
                             // println!("Old position: {}  new: {:#?}", current_bit_position, new_bit_position);
                         }
-                        Shared::set_phase_160(v.drive_index, v.phase_160 as u8);
+                        self.context.set_phase_160(v.drive_index, v.phase_160 as u8);
                     }
                     MotorOff(v) => {
                         if ! wrapper.has_run {
@@ -253,20 +314,10 @@ impl DiskController {
         }
     }
 
-    pub(crate) fn accept(&self, address: u16) -> bool {
-        if address >= 0xc080 {
-            let a = address - (self.slot * 16) as u16;
-            a >= 0xc080 && a <= 0xc08f
-        } else {
-            false
-        }
-    }
-
+    /// `address` is normalized to $C080-$C08F, regardless of the slot the controller is in
     pub(crate) fn get_or_set(&mut self, get: bool, address: u16, value: u8,
             sender: &Option<Sender<ToUi>>)
             -> u8 {
-        let address = address - (self.slot * 16) as u16;
-
         // Page 9-14 of "Understanding the Apple II" from Jim Sather :
         // "$C08C,X and $C08D,X are also the normal input and output port addresses used by RWTS
         // for transfer of disk data. In reality any even address could be used to load data from
@@ -320,9 +371,9 @@ impl DiskController {
             }
             0xc08c => {
                 let drive_index = self.drive_index;
-                let current_phase = Shared::get_phase_160(drive_index);
+                let current_phase = self.context.phase_160(drive_index);
                 if self.first_time_reading_phase[drive_index] != current_phase {
-                    send_message!(&self.sender, FirstRead(drive_index, Shared::get_phase_160(drive_index)));
+                    send_message!(&self.sender, FirstRead(drive_index, current_phase));
                     self.first_time_reading_phase[drive_index] = current_phase;
                 }
                 // Q6L
@@ -333,7 +384,7 @@ impl DiskController {
 
                     // Fill the latch
                     if (result & 0x80) > 0 {
-                        self.sector_read.read_byte(drive_index, result, &self.sender);
+                        self.sector_read.read_byte(drive_index, result, &self.context);
                     }
                     self.previous_write_clock = 0;
                     result
@@ -385,7 +436,8 @@ impl DiskController {
                         // }
                     }
                     self.previous_write_clock = self.clock;
-                    self.write_dirty = true;
+                    let protected = self.drives[drive_index].disk.as_ref().is_some_and(Disk::is_write_protected);
+                    self.write_dirty |= ! protected;
                     0
                 }
 
@@ -569,7 +621,7 @@ impl DiskController {
         // }
 
         // println!("Read {:02x} at {:?}", result, self.bit_buffers[self.track() as usize]);
-        self.sector_read.read_byte(self.drive_index, result, &self.sender);
+        self.sector_read.read_byte(self.drive_index, result, &self.context);
         result
     }
 
@@ -577,6 +629,117 @@ impl DiskController {
         &self.drives[0].disk
     }
 
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        for drive in &self.drives {
+            drive.save_state(w);
+        }
+        w.usize(self.drive_index);
+        w.u8(self.magnet_states);
+        w.usize(self.drive_phase_80);
+        w.u8(self.latch);
+        w.u128(self.clock);
+        w.u128(self.previous_write_clock);
+        w.u16(self.hold);
+        self.lss.save_state(w);
+        w.bool(self.q6);
+        w.bool(self.q7);
+        w.u8(self.next_qa);
+        w.u8(self.head_window);
+        self.actions.save_state(w);
+        w.bool(self.write_dirty);
+        w.u8(self.write_load);
+    }
+
+    /// Read the state written by [DiskController::save_state], including reloading the disks,
+    /// without changing the controller
+    pub(crate) fn prepare_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let mut state = DiskController::default();
+        for drive in state.drives.iter_mut() {
+            drive.load_state(r, &self.sender)?;
+        }
+        state.drive_index = r.usize()? & 1;
+        state.magnet_states = r.u8()?;
+        state.drive_phase_80 = r.usize()?;
+        state.latch = r.u8()?;
+        state.clock = r.u128()?;
+        state.previous_write_clock = r.u128()?;
+        state.hold = r.u16()?;
+        state.lss.load_state(r)?;
+        state.q6 = r.bool()?;
+        state.q7 = r.bool()?;
+        state.next_qa = r.u8()?;
+        state.head_window = r.u8()?;
+        state.actions.load_state(r)?;
+        state.write_dirty = r.bool()?;
+        state.write_load = r.u8()?;
+        self.pending_state = Some(Box::new(state));
+        Ok(())
+    }
+
+    /// Restore the state read by [DiskController::prepare_state]
+    pub(crate) fn load_state(&mut self) {
+        let Some(mut state) = self.pending_state.take() else { return };
+        for (drive, drive_state) in self.drives.iter_mut().zip(state.drives.iter_mut()) {
+            drive.restore_state(std::mem::take(drive_state));
+        }
+        self.drive_index = state.drive_index;
+        self.magnet_states = state.magnet_states;
+        self.drive_phase_80 = state.drive_phase_80;
+        self.latch = state.latch;
+        self.clock = state.clock;
+        self.previous_write_clock = state.previous_write_clock;
+        self.hold = state.hold;
+        self.lss = std::mem::take(&mut state.lss);
+        self.q6 = state.q6;
+        self.q7 = state.q7;
+        self.next_qa = state.next_qa;
+        self.head_window = state.head_window;
+        self.actions = std::mem::take(&mut state.actions);
+        self.write_dirty = state.write_dirty;
+        self.write_load = state.write_load;
+        self.sector_read = SectorRead::default();
+
+        for (i, drive) in self.drives.iter().enumerate() {
+            self.context.set_phase_160(i, drive.get_phase_160() as u8);
+            self.on_new_disk_info(i, drive.disk.as_ref().map(|d| d.disk_info()));
+        }
+        send_message!(&self.sender, DiskSelected(self.drive_index));
+    }
+
+}
+
+impl Card for DiskController {
+    fn name(&self) -> String {
+        if self.sector_13_rom { "Disk ][ (13 sectors)" } else { "Disk ][" }.to_string()
+    }
+
+    fn io(&mut self, offset: u8, value: u8, read: bool, _bus: &mut dyn Bus) -> u8 {
+        let sender = self.sender.clone();
+        self.get_or_set(read, 0xc080 + offset as u16, value, &sender)
+    }
+
+    fn rom(&self) -> Option<&[u8]> {
+        Some(if self.sector_13_rom { &DISK2_13_SECTOR_ROM } else { &DISK2_ROM })
+    }
+
+    fn step(&mut self) {
+        DiskController::step(self);
+    }
+
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        DiskController::save_state(self, w);
+    }
+
+    fn prepare_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        DiskController::prepare_state(self, r)
+    }
+
+    fn load_state(&mut self) {
+        DiskController::load_state(self);
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -605,7 +768,7 @@ impl Default for SectorRead {
 }
 
 impl SectorRead {
-    fn read_byte(&mut self, disk_index: usize, byte: u8, sender: &Option<Sender<ToUi>>) {
+    fn read_byte(&mut self, disk_index: usize, byte: u8, context: &EmulatorContext) {
         use State::*;
 
         fn pair(b0: u8, b1: u8) -> u8 {
@@ -641,7 +804,7 @@ impl SectorRead {
                     SECTOR1 => {
                         self.sector = pair(self.current_byte, byte);
                         self.state = START;
-                        Shared::set_sector(disk_index, self.sector);
+                        context.set_sector(disk_index, self.sector);
                         // println!();
                     }
                     _ => {
@@ -2,12 +2,15 @@ use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fmt::{Display, Formatter};
 use std::path::Path;
+use crate::disk::archive::sidecar_path;
+
+const OVERLAY: &str = "overlay";
 
 #[derive(Clone, Default, Debug, PartialEq)]
 pub enum WozVersion {
     #[default]
     Unknown,
-    Dsk, Woz1, Woz2,
+    Dsk, D13, Nib, Woz1, Woz2, A2r2, A2r3,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -17,6 +20,9 @@ pub struct DiskInfo {
     pub(crate) path: String,
     pub(crate) map: HashMap<String, String>,
     pub(crate) is_write_protected: bool,
+    /// When set, the writes go to this copy of the image and `path` is left untouched,
+    /// see [DiskInfo::overlay_path]
+    pub(crate) overlay: Option<String>,
 }
 
 impl Display for DiskInfo {
@@ -31,7 +37,7 @@ impl DiskInfo {
     pub fn new(name: &str, path: &str) -> Self {
         Self {
             name: Some(name.to_string()), path: path.to_string(), map: HashMap::new(),
-            woz_version: WozVersion::Unknown, is_write_protected: true,
+            woz_version: WozVersion::Unknown, is_write_protected: true, overlay: None,
         }
     }
 
@@ -40,13 +46,13 @@ impl DiskInfo {
             -> Self {
         Self {
             name, path: path.to_string(), map,
-            woz_version: disk_type, is_write_protected,
+            woz_version: disk_type, is_write_protected, overlay: None,
         }
     }
 
     pub fn n(path: &str) -> Self {
         Self { name: None, path: path.to_string(), map: HashMap::default(),
-            woz_version: WozVersion::Unknown, is_write_protected: true, }
+            woz_version: WozVersion::Unknown, is_write_protected: true, overlay: None, }
     }
 
     pub fn path(&self) -> String { self.path.to_string() }
@@ -62,6 +68,29 @@ impl DiskInfo {
         }
     }
 
+    pub fn is_write_protected(&self) -> bool { self.is_write_protected }
+
+    pub fn overlay(&self) -> Option<String> { self.overlay.clone() }
+
+    /// The overlay of "disks/Game.dsk" is "disks/Game.overlay.dsk": it keeps the extension,
+    /// so it's saved and loaded in the same format. Disks inside archives have theirs next to
+    /// the archive, see [sidecar_path]
+    pub fn overlay_path(path: &str) -> String {
+        let sidecar = sidecar_path(path);
+        let p = Path::new(sidecar.as_deref().unwrap_or(path));
+        let stem = p.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+        let name = match p.extension().and_then(OsStr::to_str) {
+            Some(extension) => format!("{stem}.{OVERLAY}.{extension}"),
+            None => format!("{stem}.{OVERLAY}"),
+        };
+        p.with_file_name(name).to_str().unwrap_or_default().to_string()
+    }
+
+    pub fn is_overlay_path(path: &str) -> bool {
+        Path::new(path).file_stem().and_then(OsStr::to_str)
+            .is_some_and(|stem| stem.ends_with(&format!(".{OVERLAY}")))
+    }
+
     pub fn side(&self) -> Option<String> {
         self.map.get("side").cloned()
     }
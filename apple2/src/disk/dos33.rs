@@ -0,0 +1,482 @@
+//! The DOS 3.3 file system: read the catalog and the files of a disk, add and delete files.
+//!
+//! The volume table of contents (VTOC) is in track 17 sector 0. It points to the catalog, a
+//! chain of sectors of 7 file entries each, and holds the bitmap of the free sectors. Each file
+//! has a chain of track/sector lists that give the sectors of its content in order.
+
+use std::fmt::{Display, Formatter};
+use crate::disk::disk_controller::{DSK_SIZE_BYTES, MAX_TRACK_DSK, SECTOR_SIZE_BYTES, TRACK_SIZE_BYTES};
+use crate::disk::prodos::parse_suffix;
+
+/// Where DOS 3.3 keeps its volume table of contents and its catalog
+pub const VTOC_TRACK: usize = 0x11;
+const SECTORS_PER_TRACK: usize = 16;
+/// Where the bitmap of the free sectors starts in the VTOC, 4 bytes per track
+const BITMAP: usize = 0x38;
+
+const FIRST_ENTRY: usize = 0x0b;
+const ENTRY_LENGTH: usize = 0x23;
+const ENTRIES_PER_SECTOR: usize = 7;
+const NAME_LENGTH: usize = 30;
+/// First byte of the entries of deleted files, their track is then in the last byte of the name
+const DELETED: u8 = 0xff;
+const LOCKED: u8 = 0x80;
+
+const FIRST_PAIR: usize = 0x0c;
+const PAIRS_PER_LIST: usize = 122;
+
+/// The type of a file, as shown by CATALOG
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileType {
+    Text,
+    Integer,
+    Applesoft,
+    Binary,
+    S,
+    Relocatable,
+    /// The new A and B types of the specification, rarely used
+    Other(u8),
+}
+
+impl FileType {
+    fn from_byte(byte: u8) -> FileType {
+        match byte & ! LOCKED {
+            0x00 => FileType::Text,
+            0x01 => FileType::Integer,
+            0x02 => FileType::Applesoft,
+            0x04 => FileType::Binary,
+            0x08 => FileType::S,
+            0x10 => FileType::Relocatable,
+            other => FileType::Other(other),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            FileType::Text => 0x00,
+            FileType::Integer => 0x01,
+            FileType::Applesoft => 0x02,
+            FileType::Binary => 0x04,
+            FileType::S => 0x08,
+            FileType::Relocatable => 0x10,
+            FileType::Other(byte) => byte,
+        }
+    }
+
+    /// The letter displayed by CATALOG
+    pub fn letter(&self) -> char {
+        match self {
+            FileType::Text => 'T',
+            FileType::Integer => 'I',
+            FileType::Applesoft => 'A',
+            FileType::Binary => 'B',
+            FileType::S => 'S',
+            FileType::Relocatable => 'R',
+            FileType::Other(0x20) => 'a',
+            FileType::Other(0x40) => 'b',
+            FileType::Other(_) => '?',
+        }
+    }
+
+    pub fn of_letter(letter: char) -> Option<FileType> {
+        [FileType::Text, FileType::Integer, FileType::Applesoft, FileType::Binary, FileType::S,
+            FileType::Relocatable].into_iter().find(|t| t.letter() == letter.to_ascii_uppercase())
+    }
+
+    /// The ProDOS type CiderPress gives to the files of this type
+    pub fn prodos_type(&self) -> u8 {
+        match self {
+            FileType::Text => 0x04,
+            FileType::Integer => 0xfa,
+            FileType::Applesoft => 0xfc,
+            FileType::Binary => 0x06,
+            FileType::S => 0xf2,
+            FileType::Relocatable => 0xfe,
+            FileType::Other(0x20) => 0xf3,
+            FileType::Other(_) => 0xf4,
+        }
+    }
+
+    /// The reverse of [FileType::prodos_type], binary for the other ProDOS types
+    pub fn of_prodos_type(prodos_type: u8) -> FileType {
+        [FileType::Text, FileType::Integer, FileType::Applesoft, FileType::S, FileType::Relocatable,
+            FileType::Other(0x20), FileType::Other(0x40)].into_iter()
+            .find(|t| t.prodos_type() == prodos_type)
+            .unwrap_or(FileType::Binary)
+    }
+}
+
+/// A file of the catalog
+#[derive(Clone, Debug, PartialEq)]
+pub struct CatalogEntry {
+    pub name: String,
+    pub file_type: FileType,
+    pub locked: bool,
+    /// The size given by the catalog, the track/sector lists included
+    pub sector_count: usize,
+    /// Track and sector of the first track/sector list
+    pub track_sector_list: (usize, usize),
+}
+
+impl Display for CatalogEntry {
+    /// Like CATALOG: "*B 034 HELLO"
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{} {:03} {}", if self.locked { '*' } else { ' ' }, self.file_type.letter(),
+            self.sector_count % 1000, self.name)
+    }
+}
+
+/// The content of a file without the header DOS puts in front of it
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileContent {
+    pub data: Vec<u8>,
+    /// Where B and R files load
+    pub address: Option<u16>,
+}
+
+/// Return true if DOS accepts this name: a letter followed by up to 29 characters, no comma
+pub fn is_valid_name(name: &str) -> bool {
+    name.len() <= NAME_LENGTH
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii() && ! c.is_ascii_control() && c != ',')
+}
+
+/// The name of a file exported to the host, with its type and address in the same `#ttaaaa`
+/// suffix as [crate::disk::prodos::suffixed_name]: "C600#06c600"
+pub fn host_name(entry: &CatalogEntry, content: &FileContent) -> String {
+    let name = entry.name.trim_end().replace(['/', '\\'], "_");
+    format!("{}#{:02x}{:04x}", name, entry.file_type.prodos_type(), content.address.unwrap_or(0))
+}
+
+/// The DOS name, type and address of a host file. Without a `#ttaaaa` suffix, the file is a
+/// binary file that loads at $2000
+pub fn parse_host_name(host_name: &str) -> (String, FileType, u16) {
+    let (name, file_type, address) = parse_suffix(host_name)
+        .map_or((host_name.to_string(), FileType::Binary, 0x2000),
+            |(name, file_type, address)| (name, FileType::of_prodos_type(file_type), address));
+    (name.to_uppercase().chars().take(NAME_LENGTH).collect(), file_type, address)
+}
+
+fn word(bytes: &[u8], offset: usize) -> usize {
+    bytes[offset] as usize | (bytes[offset + 1] as usize) << 8
+}
+
+fn set_word(bytes: &mut [u8], offset: usize, value: usize) {
+    bytes[offset] = value as u8;
+    bytes[offset + 1] = (value >> 8) as u8;
+}
+
+/// A DOS 3.3 disk, as the 35 tracks of its sectors in DOS 3.3 order (see
+/// [crate::disk::convert::read_dos_sectors])
+pub struct Dos33 {
+    image: Vec<u8>,
+}
+
+impl Dos33 {
+    pub fn new(image: Vec<u8>) -> Result<Dos33, String> {
+        if image.len() != DSK_SIZE_BYTES {
+            return Err(format!("Not a 5.25\" floppy ({} bytes)", image.len()));
+        }
+        let result = Dos33 { image };
+        let vtoc = result.sector(VTOC_TRACK, 0);
+        if vtoc[3] != 3 || vtoc[0x34] as usize != MAX_TRACK_DSK || vtoc[0x35] as usize != SECTORS_PER_TRACK
+                || vtoc[1] as usize >= MAX_TRACK_DSK {
+            return Err("Not a DOS 3.3 disk".to_string());
+        }
+        Ok(result)
+    }
+
+    /// An empty disk, like INIT leaves it but without DOS on tracks 0 to 2, so it doesn't boot.
+    /// The catalog goes down from track 17 sector 15 to sector 1
+    pub fn format(volume: u8) -> Dos33 {
+        let mut result = Dos33 { image: vec![0; DSK_SIZE_BYTES] };
+        let vtoc = result.sector_mut(VTOC_TRACK, 0);
+        vtoc[1] = VTOC_TRACK as u8;
+        vtoc[2] = (SECTORS_PER_TRACK - 1) as u8;
+        vtoc[3] = 3;
+        vtoc[6] = volume;
+        vtoc[0x27] = PAIRS_PER_LIST as u8;
+        // Last track allocated and direction: DOS starts next to the catalog, outwards
+        vtoc[0x30] = VTOC_TRACK as u8;
+        vtoc[0x31] = 1;
+        vtoc[0x34] = MAX_TRACK_DSK as u8;
+        vtoc[0x35] = SECTORS_PER_TRACK as u8;
+        set_word(vtoc, 0x36, SECTOR_SIZE_BYTES);
+        for track in (3..MAX_TRACK_DSK).filter(|&t| t != VTOC_TRACK) {
+            for sector in 0..SECTORS_PER_TRACK {
+                result.set_free(track, sector, true);
+            }
+        }
+        for sector in 1..SECTORS_PER_TRACK {
+            let catalog = result.sector_mut(VTOC_TRACK, sector);
+            if sector > 1 {
+                catalog[1] = VTOC_TRACK as u8;
+                catalog[2] = (sector - 1) as u8;
+            }
+        }
+        result
+    }
+
+    /// The sectors of the disk, in DOS 3.3 order
+    pub fn image(&self) -> &[u8] { &self.image }
+
+    pub fn volume(&self) -> u8 { self.sector(VTOC_TRACK, 0)[6] }
+
+    fn offset(track: usize, sector: usize) -> usize {
+        track * TRACK_SIZE_BYTES + sector * SECTOR_SIZE_BYTES
+    }
+
+    fn sector(&self, track: usize, sector: usize) -> &[u8] {
+        let offset = Self::offset(track, sector);
+        &self.image[offset..offset + SECTOR_SIZE_BYTES]
+    }
+
+    fn sector_mut(&mut self, track: usize, sector: usize) -> &mut [u8] {
+        let offset = Self::offset(track, sector);
+        &mut self.image[offset..offset + SECTOR_SIZE_BYTES]
+    }
+
+    /// Follow the links of a chain of sectors (catalog or track/sector lists): the track and
+    /// sector of the next one are in its bytes 1 and 2
+    fn chain(&self, first: (usize, usize)) -> Result<Vec<(usize, usize)>, String> {
+        let mut result = Vec::new();
+        let (mut track, mut sector) = first;
+        while track != 0 {
+            if track >= MAX_TRACK_DSK || sector >= SECTORS_PER_TRACK {
+                return Err(format!("Invalid link to track {track} sector {sector}"));
+            }
+            if result.len() > MAX_TRACK_DSK * SECTORS_PER_TRACK {
+                return Err("The sectors of the disk are linked in a loop".to_string());
+            }
+            result.push((track, sector));
+            let bytes = self.sector(track, sector);
+            (track, sector) = (bytes[1] as usize, bytes[2] as usize);
+        }
+        Ok(result)
+    }
+
+    /// Track and sector of every catalog sector, with the offset of each of its entries
+    fn entry_offsets(&self) -> Result<Vec<(usize, usize, usize)>, String> {
+        let vtoc = self.sector(VTOC_TRACK, 0);
+        let catalog = self.chain((vtoc[1] as usize, vtoc[2] as usize))?;
+        Ok(catalog.iter().flat_map(|(track, sector)| (0..ENTRIES_PER_SECTOR)
+            .map(move |i| (*track, *sector, FIRST_ENTRY + i * ENTRY_LENGTH)))
+            .collect())
+    }
+
+    fn entry_name(entry: &[u8]) -> String {
+        entry[3..3 + NAME_LENGTH].iter().map(|b| (b & 0x7f) as char).collect::<String>()
+            .trim_end().to_string()
+    }
+
+    /// The files of the disk, in the order of the catalog
+    pub fn catalog(&self) -> Result<Vec<CatalogEntry>, String> {
+        let mut result = Vec::new();
+        for (track, sector, offset) in self.entry_offsets()? {
+            let entry = &self.sector(track, sector)[offset..offset + ENTRY_LENGTH];
+            if entry[0] == 0 || entry[0] == DELETED {
+                continue;
+            }
+            result.push(CatalogEntry {
+                name: Self::entry_name(entry),
+                file_type: FileType::from_byte(entry[2]),
+                locked: entry[2] & LOCKED != 0,
+                sector_count: word(entry, 0x21),
+                track_sector_list: (entry[0] as usize, entry[1] as usize),
+            });
+        }
+        Ok(result)
+    }
+
+    pub fn find(&self, name: &str) -> Result<CatalogEntry, String> {
+        self.catalog()?.into_iter().find(|e| e.name == name)
+            .ok_or_else(|| format!("File not found: {name}"))
+    }
+
+    /// The sectors of the file in order, (0, 0) for the holes of random access text files
+    fn data_sectors(&self, entry: &CatalogEntry) -> Result<Vec<(usize, usize)>, String> {
+        let mut result = Vec::new();
+        for (track, sector) in self.chain(entry.track_sector_list)? {
+            let list = self.sector(track, sector);
+            for i in 0..PAIRS_PER_LIST {
+                let pair = (list[FIRST_PAIR + i * 2] as usize, list[FIRST_PAIR + i * 2 + 1] as usize);
+                if pair.0 >= MAX_TRACK_DSK || pair.1 >= SECTORS_PER_TRACK {
+                    return Err(format!("{}: invalid track {} sector {}", entry.name, pair.0, pair.1));
+                }
+                result.push(pair);
+            }
+        }
+        while result.last() == Some(&(0, 0)) {
+            result.pop();
+        }
+        Ok(result)
+    }
+
+    /// All the sectors of the file, headers included
+    pub fn read_file(&self, entry: &CatalogEntry) -> Result<Vec<u8>, String> {
+        let mut result = Vec::new();
+        for (track, sector) in self.data_sectors(entry)? {
+            if track == 0 {
+                result.extend([0; SECTOR_SIZE_BYTES]);
+            } else {
+                result.extend_from_slice(self.sector(track, sector));
+            }
+        }
+        Ok(result)
+    }
+
+    /// The content of the file: B and R files lose their address and length, A and I files
+    /// their length, and T files end at their first zero
+    pub fn extract(&self, entry: &CatalogEntry) -> Result<FileContent, String> {
+        let bytes = self.read_file(entry)?;
+        let header_error = || format!("{}: the file is too short for its header", entry.name);
+        let result = match entry.file_type {
+            FileType::Binary | FileType::Relocatable => {
+                if bytes.len() < 4 {
+                    return Err(header_error());
+                }
+                let length = word(&bytes, 2).min(bytes.len() - 4);
+                FileContent { data: bytes[4..4 + length].to_vec(), address: Some(word(&bytes, 0) as u16) }
+            }
+            FileType::Applesoft | FileType::Integer => {
+                if bytes.len() < 2 {
+                    return Err(header_error());
+                }
+                let length = word(&bytes, 0).min(bytes.len() - 2);
+                FileContent { data: bytes[2..2 + length].to_vec(), address: None }
+            }
+            FileType::Text => {
+                let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+                FileContent { data: bytes[..end].to_vec(), address: None }
+            }
+            FileType::S | FileType::Other(_) => FileContent { data: bytes, address: None },
+        };
+        Ok(result)
+    }
+
+    fn is_free(&self, track: usize, sector: usize) -> bool {
+        let vtoc = self.sector(VTOC_TRACK, 0);
+        let (byte, bit) = Self::bitmap_bit(track, sector);
+        vtoc[byte] & bit != 0
+    }
+
+    fn set_free(&mut self, track: usize, sector: usize, free: bool) {
+        let (byte, bit) = Self::bitmap_bit(track, sector);
+        let vtoc = self.sector_mut(VTOC_TRACK, 0);
+        if free { vtoc[byte] |= bit; } else { vtoc[byte] &= ! bit; }
+    }
+
+    /// The first byte of a track holds sectors 15 to 8, the second one sectors 7 to 0
+    fn bitmap_bit(track: usize, sector: usize) -> (usize, u8) {
+        let byte = BITMAP + track * 4 + if sector >= 8 { 0 } else { 1 };
+        (byte, 1 << (sector % 8))
+    }
+
+    /// The free sectors, in the order DOS uses them: away from the catalog track, outwards
+    /// first. Track 0 belongs to DOS.
+    fn free_sectors(&self) -> Vec<(usize, usize)> {
+        (VTOC_TRACK + 1..MAX_TRACK_DSK).chain((1..VTOC_TRACK).rev())
+            .flat_map(|track| (0..SECTORS_PER_TRACK).rev().map(move |sector| (track, sector)))
+            .filter(|(track, sector)| self.is_free(*track, *sector))
+            .collect()
+    }
+
+    pub fn free_sector_count(&self) -> usize {
+        self.free_sectors().len()
+    }
+
+    /// Add a file. `address` is only used by B and R files.
+    pub fn add_file(&mut self, name: &str, file_type: FileType, data: &[u8], address: u16)
+        -> Result<(), String>
+    {
+        if ! is_valid_name(name) {
+            return Err(format!("Invalid DOS 3.3 name: {name}"));
+        }
+        if self.catalog()?.iter().any(|e| e.name == name) {
+            return Err(format!("{name} already exists"));
+        }
+        let mut content = Vec::with_capacity(data.len() + 4);
+        match file_type {
+            FileType::Binary | FileType::Relocatable => {
+                content.extend(address.to_le_bytes());
+                content.extend((data.len() as u16).to_le_bytes());
+            }
+            FileType::Applesoft | FileType::Integer => {
+                content.extend((data.len() as u16).to_le_bytes());
+            }
+            _ => {}
+        }
+        content.extend_from_slice(data);
+        if content.len() > 0xffff + 4 {
+            return Err(format!("{name} is too large"));
+        }
+
+        let data_count = content.len().div_ceil(SECTOR_SIZE_BYTES).max(1);
+        let list_count = data_count.div_ceil(PAIRS_PER_LIST);
+        let free = self.free_sectors();
+        if free.len() < data_count + list_count {
+            return Err(format!("The disk is full, {name} needs {} sectors and there are {} left",
+                data_count + list_count, free.len()));
+        }
+        let (track, sector, offset) = self.entry_offsets()?.into_iter().find(|(track, sector, offset)| {
+            let first = self.sector(*track, *sector)[*offset];
+            first == 0 || first == DELETED
+        }).ok_or_else(|| format!("The catalog is full, can't add {name}"))?;
+
+        let (lists, data_sectors) = free[..list_count + data_count].split_at(list_count);
+        for (i, (t, s)) in data_sectors.iter().enumerate() {
+            let chunk = &content[(i * SECTOR_SIZE_BYTES).min(content.len())
+                ..((i + 1) * SECTOR_SIZE_BYTES).min(content.len())];
+            let bytes = self.sector_mut(*t, *s);
+            bytes.fill(0);
+            bytes[..chunk.len()].copy_from_slice(chunk);
+        }
+        for (i, (t, s)) in lists.iter().enumerate() {
+            let next = lists.get(i + 1).copied().unwrap_or((0, 0));
+            let pairs = &data_sectors[i * PAIRS_PER_LIST..((i + 1) * PAIRS_PER_LIST).min(data_count)];
+            let bytes = self.sector_mut(*t, *s);
+            bytes.fill(0);
+            (bytes[1], bytes[2]) = (next.0 as u8, next.1 as u8);
+            set_word(bytes, 5, i * PAIRS_PER_LIST);
+            for (j, (data_track, data_sector)) in pairs.iter().enumerate() {
+                (bytes[FIRST_PAIR + j * 2], bytes[FIRST_PAIR + j * 2 + 1]) = (*data_track as u8, *data_sector as u8);
+            }
+        }
+        for (t, s) in lists.iter().chain(data_sectors) {
+            self.set_free(*t, *s, false);
+        }
+        self.sector_mut(VTOC_TRACK, 0)[0x30] = lists[0].0 as u8;
+
+        let entry = &mut self.sector_mut(track, sector)[offset..offset + ENTRY_LENGTH];
+        (entry[0], entry[1], entry[2]) = (lists[0].0 as u8, lists[0].1 as u8, file_type.to_byte());
+        for (i, b) in entry[3..3 + NAME_LENGTH].iter_mut().enumerate() {
+            *b = name.as_bytes().get(i).copied().unwrap_or(b' ') | 0x80;
+        }
+        set_word(entry, 0x21, list_count + data_count);
+        Ok(())
+    }
+
+    /// Delete a file and free its sectors, like DELETE: the catalog entry remembers its track
+    pub fn delete_file(&mut self, name: &str) -> Result<(), String> {
+        let entry = self.find(name)?;
+        if entry.locked {
+            return Err(format!("{name} is locked"));
+        }
+        let lists = self.chain(entry.track_sector_list)?;
+        for (track, sector) in self.data_sectors(&entry)?.into_iter().chain(lists) {
+            if track != 0 {
+                self.set_free(track, sector, true);
+            }
+        }
+        for (track, sector, offset) in self.entry_offsets()? {
+            let bytes = self.sector_mut(track, sector);
+            if (bytes[offset] as usize, bytes[offset + 1] as usize) == entry.track_sector_list
+                    && Self::entry_name(&bytes[offset..offset + ENTRY_LENGTH]) == name {
+                bytes[offset + 3 + NAME_LENGTH - 1] = bytes[offset];
+                bytes[offset] = DELETED;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
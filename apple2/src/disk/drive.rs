@@ -4,6 +4,7 @@ use crate::disk::disk::{Disk};
 use crate::messages::ToUi;
 use crate::messages::ToUi::DriveMotorStatus;
 use crate::send_message;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
 
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum DriveStatus {
@@ -68,6 +69,46 @@ impl Drive {
         self.phase160
     }
 
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        w.u8(match self._status {
+            DriveStatus::On => 0,
+            DriveStatus::Off => 1,
+            DriveStatus::SpinningDown => 2,
+        });
+        w.usize(self.phase160);
+        w.bool(self.disk.is_some());
+        if let Some(disk) = &self.disk {
+            disk.save_state(w);
+        }
+    }
+
+    /// `sender` is passed to the disk, if any. Only invoked on a new drive, whose state is then
+    /// moved to the actual drive by [Drive::restore_state].
+    pub(crate) fn load_state(&mut self, r: &mut SnapshotReader, sender: &Option<Sender<ToUi>>)
+            -> Result<(), String> {
+        self._status = match r.u8()? {
+            0 => DriveStatus::On,
+            1 => DriveStatus::Off,
+            2 => DriveStatus::SpinningDown,
+            s => { return Err(format!("Unknown drive status: {s}")); }
+        };
+        self.phase160 = r.usize()?;
+        self.disk = if r.bool()? {
+            Some(Disk::new_with_state(r, sender.clone())?)
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    /// Take the state that [Drive::load_state] read into `state`
+    pub(crate) fn restore_state(&mut self, state: Drive) {
+        self._status = state._status;
+        self.phase160 = state.phase160;
+        self.disk = state.disk;
+        send_message!(&self.sender, DriveMotorStatus(self.drive_number, self._status));
+    }
+
     pub fn turn_on(&mut self) {
         self.set_status(DriveStatus::On);
         send_message!(&self.sender, DriveMotorStatus(self.drive_number, DriveStatus::On));
@@ -1,17 +1,101 @@
-use std::fs::File;
-use std::io::Read;
+use std::fs;
 use std::ops::BitXor;
-use crate::debug::hex_dump;
+use crate::disk::archive;
 use crate::disk::bit_stream::{AreaType, BitStream, BitStreams};
 use crate::disk::disk::PDisk;
 use crate::disk::disk_controller::*;
 use crate::disk::disk_info::{DiskInfo, WozVersion};
+use crate::disk::dos33::VTOC_TRACK;
+use crate::misc::save;
 use crate::ui_log;
 
+/// The order of the sectors of each track in a .dsk, .do or .po file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SectorOrder {
+    /// DOS 3.3 order: .do and most .dsk
+    Dos,
+    /// ProDOS order, two sectors per block: .po and some .dsk
+    Prodos,
+}
+
+impl SectorOrder {
+    /// The order given by the suffix of the file, or guessed from its content for .dsk
+    pub fn of_file(path: &str, bytes: &[u8]) -> SectorOrder {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".po") {
+            SectorOrder::Prodos
+        } else if lower.ends_with(".do") {
+            SectorOrder::Dos
+        } else {
+            Self::detect(bytes).unwrap_or(SectorOrder::Dos)
+        }
+    }
+
+    /// Look for a ProDOS volume directory first, then for a DOS 3.3 catalog
+    pub fn detect(bytes: &[u8]) -> Option<SectorOrder> {
+        use SectorOrder::*;
+        [Prodos, Dos].into_iter().find(|order| order.has_prodos_volume(bytes))
+            .or_else(|| [Dos, Prodos].into_iter().find(|order| order.has_dos_catalog(bytes)))
+    }
+
+    /// The sector of the file that is stored in this physical sector
+    pub fn logical_sector(&self, physical_sector: usize) -> usize {
+        match self {
+            SectorOrder::Dos => LOGICAL_SECTORS[physical_sector] as usize,
+            SectorOrder::Prodos => PRODOS_LOGICAL_SECTORS[physical_sector] as usize,
+        }
+    }
+
+    /// Where the physical sector is in the file
+    fn offset(&self, track: usize, physical_sector: usize) -> usize {
+        track * TRACK_SIZE_BYTES + self.logical_sector(physical_sector) * SECTOR_SIZE_BYTES
+    }
+
+    fn sector<'a>(&self, bytes: &'a [u8], track: usize, physical_sector: usize) -> Option<&'a [u8]> {
+        let offset = self.offset(track, physical_sector);
+        bytes.get(offset..offset + SECTOR_SIZE_BYTES)
+    }
+
+    /// True if block 2 looks like the key block of a volume directory when the file is read
+    /// in this order
+    fn has_prodos_volume(&self, bytes: &[u8]) -> bool {
+        // Block 2 starts with the 5th sector of a .po track
+        let physical = PRODOS_LOGICAL_SECTORS.iter().position(|s| *s == 4).unwrap();
+        self.sector(bytes, 0, physical).is_some_and(|block| {
+            block[0..4] == [0, 0, 3, 0] && block[4] >> 4 == 0xf && block[4] & 0xf != 0
+                && block[0x23] == 0x27 && block[0x24] == 0x0d
+        })
+    }
+
+    /// True if the sectors of the catalog link to each other when the file is read in this
+    /// order. Sector 15, usually the first one, is at the same place in both orders.
+    fn has_dos_catalog(&self, bytes: &[u8]) -> bool {
+        let Some(vtoc) = self.sector(bytes, VTOC_TRACK, 0) else { return false };
+        if vtoc[1] as usize != VTOC_TRACK || vtoc[3] != 3 || vtoc[0x35] != 16 {
+            return false;
+        }
+        let mut catalog_sector = vtoc[2] as usize;
+        let mut links = 0;
+        while (2..16).contains(&catalog_sector) {
+            let physical = LOGICAL_SECTORS_WRITE[catalog_sector] as usize;
+            match self.sector(bytes, VTOC_TRACK, physical) {
+                Some(catalog) if catalog[1] as usize == VTOC_TRACK
+                        && catalog[2] as usize == catalog_sector - 1 => {
+                    links += 1;
+                    catalog_sector -= 1;
+                }
+                _ => { break; }
+            }
+        }
+        links >= 2
+    }
+}
+
 #[derive(Clone)]
 pub struct Dsk {
     disk_info: DiskInfo,
     bit_streams: BitStreams,
+    order: SectorOrder,
 }
 
 impl PDisk for Dsk {
@@ -24,12 +108,26 @@ impl PDisk for Dsk {
     }
 
     fn save(&mut self) {
-        todo!()
+        let path = self.disk_info.path.clone();
+        ui_log(&format!("Dsk saving {path}"));
+        // Sectors that can't be decoded keep their current content, and so do the tracks past 35
+        // of 40 track images
+        let mut buffer = fs::read(&path).unwrap_or_default();
+        buffer.resize(buffer.len().max(DSK_SIZE_BYTES), 0);
+        Self::decode_sectors(&self.bit_streams, self.order, &mut buffer);
+        match save(&path, &buffer) {
+            Ok(_) => { ui_log(&format!("Saved {path}")); }
+            Err(s) => { ui_log(&format!("Error saving {path}: {s}")); }
+        }
     }
 
     fn disk_info(&self) -> &DiskInfo {
         &self.disk_info
     }
+
+    fn disk_info_mut(&mut self) -> &mut DiskInfo {
+        &mut self.disk_info
+    }
 }
 
 impl Dsk {
@@ -38,13 +136,30 @@ impl Dsk {
             Ok(Dsk {
                 disk_info: DiskInfo::n(filename),
                 bit_streams: Default::default(),
+                order: SectorOrder::Dos,
             })
         } else {
-            let mut file = File::open(filename).map_err(|e| e.to_string())?;
-            let mut buffer: Vec<u8> = Vec::new();
-            file.read_to_end(&mut buffer).unwrap();
-            Dsk::bytes_to_bit_streams(filename, &buffer)
+            let buffer = archive::read(filename).map_err(|e| e.to_string())?;
+            if buffer.len() < DSK_SIZE_BYTES || buffer.len() > MAX_TRACK * TRACK_SIZE_BYTES {
+                return Err(format!("{filename} is not a 5.25\" floppy image ({} bytes)", buffer.len()));
+            }
+            Dsk::bytes_to_bit_streams(filename, &buffer, SectorOrder::of_file(filename, &buffer))
+        }
+    }
+
+    pub fn order(&self) -> SectorOrder { self.order }
+
+    /// Return the same disk with its sectors stored in another order
+    pub fn convert_order(bytes: &[u8], from: SectorOrder, to: SectorOrder) -> Vec<u8> {
+        let mut result = bytes.to_vec();
+        for track in 0..bytes.len() / TRACK_SIZE_BYTES {
+            for physical in 0..16 {
+                let (source, destination) = (from.offset(track, physical), to.offset(track, physical));
+                result[destination..destination + SECTOR_SIZE_BYTES]
+                    .copy_from_slice(&bytes[source..source + SECTOR_SIZE_BYTES]);
+            }
         }
+        result
     }
 
     /// For the .dsk format, we use the same TMAP in default woz disks:
@@ -52,7 +167,7 @@ impl Dsk {
     /// result[0..3] = buffer for track 0
     /// result[4..7] = buffer for track 1
     /// ...
-    fn bytes_to_bit_streams(filename: &str, bytes: &[u8]) -> Result<Dsk, String> {
+    fn bytes_to_bit_streams(filename: &str, bytes: &[u8], order: SectorOrder) -> Result<Dsk, String> {
         let mut tracks: Vec<BitStream> = Vec::new();
         let mut track = 0;
 
@@ -62,11 +177,23 @@ impl Dsk {
             let end = /* min(bytes.len(), */ start + TRACK_SIZE_BYTES;
             if start < bytes.len() && end <= bytes.len() {
                 let slice = &bytes[start..end];
-                tracks.push(BitStream::new(Dsk::encode_track(slice, track as u8)));
+                tracks.push(BitStream::new(Dsk::encode_track(slice, track as u8, order)));
             }
             track += 1;
         };
 
+        let mut dsk = Dsk {
+            disk_info: DiskInfo::n(filename),
+            bit_streams: Self::tracks_to_bit_streams(tracks),
+            order,
+        };
+        dsk.disk_info.woz_version = WozVersion::Dsk;
+        Ok(dsk)
+    }
+
+    /// Spread the 35 tracks on the phases with the same TMAP as default woz disks, the phases
+    /// past the last track are random
+    pub(crate) fn tracks_to_bit_streams(tracks: Vec<BitStream>) -> BitStreams {
         // Fill the tmap
         let mut tmap: [u8; MAX_PHASE] = [0xff; MAX_PHASE];
         tmap[0] = 0;   // 0.0
@@ -92,13 +219,7 @@ impl Dsk {
             };
             bit_streams.push(bs);
         }
-
-        let mut dsk = Dsk {
-            disk_info: DiskInfo::n(filename),
-            bit_streams: BitStreams::new(bit_streams, tmap, DiskInfo::default()),
-        };
-        dsk.disk_info.woz_version = WozVersion::Dsk;
-        Ok(dsk)
+        BitStreams::new(bit_streams, tmap, DiskInfo::default())
     }
 
     pub fn decode_4_and_4(a: u8, b: u8) -> u8 {
@@ -172,7 +293,7 @@ impl Dsk {
         }
     }
 
-    pub fn encode_track(bytes: &[u8], track: u8) -> Vec<u8> {
+    pub fn encode_track(bytes: &[u8], track: u8, order: SectorOrder) -> Vec<u8> {
         let mut result: Vec<u8> = Vec::new();
         Dsk::write_sync(&mut result, 16);
         for sector in 0..16 {
@@ -185,7 +306,7 @@ impl Dsk {
             Dsk::write_sync(&mut result, 7);
 
             Dsk::write8(&mut result, vec![0xd5, 0xaa, 0xad]);
-            let start = LOGICAL_SECTORS[sector as usize] as usize * SECTOR_SIZE_BYTES;
+            let start = order.logical_sector(sector as usize) * SECTOR_SIZE_BYTES;
             let end = start + SECTOR_SIZE_BYTES; // std::cmp::max(bytes.len(), start + 256_usize);
             // println!("Encoding track {} sector {} (logical {}) at {}-{}", track, sector,
             //          logical_sector, start, end);
@@ -253,9 +374,8 @@ impl Dsk {
         result
     }
 
-    pub fn save(path: &str, bit_streams: &BitStreams) {
-        ui_log("Dsk saving {path}");
-        let mut buffer: [u8; DSK_SIZE_BYTES] = [0; DSK_SIZE_BYTES]; // 40 tracks, 16 sectors per track
+    /// Decode the sectors found in the bit streams of the 35 tracks into `buffer`
+    pub fn decode_sectors(bit_streams: &BitStreams, order: SectorOrder, buffer: &mut [u8]) {
         let mut track = 0;
         let mut sector = 0;
         for t in 0..MAX_TRACK_DSK {
@@ -265,21 +385,18 @@ impl Dsk {
             while i < nibbles.len() {
                 let n = nibbles[i];
                 match n.area_type {
-                    AreaType::AddressPrologue => {}
-                    AreaType::AddressContent => {
+                    AreaType::AddressContent if i + 6 <= nibbles.len() => {
                         track = Self::decode_4_and_4(nibbles[i + 2].value, nibbles[i + 3].value);
                         sector = Self::decode_4_and_4(nibbles[i + 4].value, nibbles[i + 5].value);
                         i += 10;
                     }
-                    AreaType::DataContent => {
+                    AreaType::DataContent if i + DATA_FIELD_SIZE <= nibbles.len()
+                            && (track as usize) < MAX_TRACK_DSK && sector < 16 => {
                         let values: Vec<u8> =
                             nibbles[i..i + DATA_FIELD_SIZE].iter().map(|n| n.value).collect();
                         let bytes = Dsk::decode_6_and_2(&values);
-                        let start = LOGICAL_SECTORS[sector as usize] as usize;
-                        let offset = (track as usize * 16 + start) * SECTOR_SIZE_BYTES;
+                        let offset = order.offset(track as usize, sector as usize);
                         buffer[offset..offset + SECTOR_SIZE_BYTES].clone_from_slice(&bytes);
-                        hex_dump(&bytes);
-                        println!("Storing {track}/{sector} at {offset:02X}");
                         i += DATA_FIELD_SIZE;
                     }
                     _ => {}
@@ -287,13 +404,6 @@ impl Dsk {
                 i += 1;
             }
         }
-
-        let path = "d:\\Apple Disks\\saved.dsk";
-
-        match crate::misc::save(path, &buffer) {
-            Ok(_) => { ui_log(&format!("Saved {path}")); }
-            Err(s) => { ui_log(&format!("Error saving {path}: {s}")); }
-        }
     }
 }
 
@@ -1,171 +1,21 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Read, Write};
 use std::path::Path;
-use crate::debug::{hex_dump, hex_dump_at};
-use crate::disk::dsk::{Dsk, WRITE_TABLE};
-use crate::disk::woz::Woz;
+use crate::disk::convert::convert;
 
+/// Convert a .woz file into a .dsk file next to it, return the path of that file.
+/// See [convert] for the other formats.
 pub fn woz_to_dsk(path: &str) -> Result<String, String> {
-    let woz = Woz::new_with_file(path, false)?;
-    println!("Bit stream count: {}", woz.bit_streams.bit_streams.len());
-
-    for phase in (0..160).step_by(4) {
-        let mut buffer: Vec<u8> = Vec::new();
-        let bs = &woz.bit_streams.bit_streams[phase];
-        let mut index = 0;
-        while index < bs.len() {
-            let (increment, nibble) = bs.next_nibble(index);
-            buffer.push(nibble);
-            index += increment;
-        }
-        // println!("Read track {}", phase / 4);
-        // for i in 0..30 {
-        //     print!("{:02X} ", buffer[i]);
-        // }
-        decode_track(&buffer);
-        // println!();
-    }
-    // Save the file
-    let stem = Path::new(path).file_stem().unwrap().to_str().unwrap();
-    let file_out = format!("{}.woz", stem);
-    // let mut file = File::create(&file_out).map_err(|e| e.to_string())?;
-    // file.write(&buffer).map(|_| file_out).map_err(|e| e.to_string())
-
-    Ok(file_out)
-}
-
-struct Sector {
-    sector_number: u8,
-    bytes: [u8; 256],
-}
-
-/// track contains a nibble track (FF FF D5 AA 96...)
-/// Return 16 tracks of 256 bytes, in the correct sector order
-fn decode_track(track: &[u8]) {
-    let mut result: Vec<u8> = Vec::new();
-    let mut i = 0;
-
-    let mut tracks: Vec<Sector> = Vec::new();
-
-    while i < track.len() {
-        let mut sector_number = 0xff;
-        let mut track_number = 0xff;
-        if track.len() > 10 {
-            if track[i] == 0xd5 && track[i + 1] == 0xaa && track[i + 2] == 0x96 {
-                // hex_dump_at(track, i as u16, 48);
-                let volume = Dsk::decode_4_and_4(track[i + 3], track[i + 4]);
-                i += 5; // skip volume
-                track_number = Dsk::decode_4_and_4(track[i], track[i + 1]);
-                sector_number = Dsk::decode_4_and_4(track[i + 2], track[i + 3]);
-                let checksum = Dsk::decode_4_and_4(track[i + 4], track[i + 5]);
-                if volume ^ track_number ^ sector_number != checksum {
-                    panic!("Checksums do not match!");
-                }
-                // println!();
-                i += 10;
-            }
-
-            if track[i] == 0xd5 && track[i + 1] == 0xaa && track[i + 2] == 0xad {
-                let bytes = Dsk::decode_6_and_2(&track[i + 3..i + 346]);
-                tracks.push(Sector { sector_number, bytes });
-                assert_eq!(track[i + 346], 0xde);
-                assert_eq!(track[i + 347], 0xaa);
-            }
-        }
-        i += 1;
-    }
+    convert_next_to(path, "dsk")
 }
 
+/// Convert a .dsk file into a .woz file next to it, return the path of that file
 pub fn dsk_to_woz(path: &str) -> Result<String, String> {
-    let mut buffer: Vec<u8> = Vec::new();
-
-    // Woz::woz_file_start(&mut buffer);
-
-
-    // TRKS
-    let mut dsk_buffer: Vec<u8> = Vec::new();
-    let mut dsk = File::open(path).map_err(|e| e.to_string())?;
-    dsk.read_to_end(&mut dsk_buffer).map_err(|e| e.to_string())?;
-
-    Woz::push_string(&mut buffer, "TRKS");
-    // Memorize the index where we will store the length once we know it
-    let chunk_size_index = &buffer.len();
-    Woz::push_32(&mut buffer, 0);   // chunk size, we'll only know wiat it is later
-    let mut block: u16 = 3;
-    let mut encoded_track_in_bits: Vec<Vec<u8>> = Vec::new();
-    for track in 0..35 {
-        Woz::push_16(&mut buffer, block);
-        Woz::push_16(&mut buffer, 13);
-        block += 13;
-        let this_track = &buffer[track * 256..(track + 1) * 256 * 16];
-        let encoded = Dsk::encode_track(this_track, track as u8);
-        Woz::push_32(&mut buffer, encoded.len() as u32);  // bit count
-        encoded_track_in_bits.push(encoded);
-    }
-    for _ in 35..160 {
-        Woz::push_16(&mut buffer, 0);
-        Woz::push_16(&mut buffer, 0);
-        Woz::push_32(&mut buffer, 0);  // bit count
-    }
-
-    for track in encoded_track_in_bits {
-        let mut i = 0;
-        // Need to fill exactly 13 blocks
-        let mut total = 13 * 512;
-        // println!("track len: {}", track.len());
-        while i < track.len() {
-            let mut n = 0;
-            let mut a = 0;
-            while i < track.len() && n < 8 {
-                let bit = track[i];
-                a = (a << 1) | bit;
-                i += 1;
-                n += 1;
-            }
-            if n != 8 {
-                panic!("Ending on a non byte boundary");
-            }
-            buffer.push(a);
-            total -= 1;
-        }
-        // Pad the rest of the content to the block boundary
-        while total > 0 {
-            buffer.push(0);
-            total -= 1;
-        }
-
-    }
-    // Write the size now that we know it
-    let chunk_size = buffer.len() - *chunk_size_index - 4;
-    set_32(&mut buffer, *chunk_size_index, chunk_size as u32);
-
-    // Done with the file, calculate the checksum and save it
-    let checksum = crc32(0, &buffer[12..]);
-    set_32(&mut buffer, 8, checksum);
-
-    // Save the file
-    let stem = Path::new(path).file_stem().unwrap().to_str().unwrap();
-    let file_out = "bad.woz".to_string(); // format!("{}.woz", stem);
-    let mut file = File::create(&file_out).map_err(|e| e.to_string())?;
-    file.write(&buffer).map(|_| file_out).map_err(|e| e.to_string())
-}
-
-fn set_32(buffer: &mut [u8], index: usize, value: u32) {
-    buffer[index] = (value & 0xff) as u8;
-    buffer[index + 1] = ((value & 0xff00) >> 8) as u8;
-    buffer[index + 2] = ((value & 0xffff00) >> 16) as u8;
-    buffer[index + 3] = ((value & 0xff000000) >>24) as u8;
-}
-
-fn push_string(buffer: &mut Vec<u8>, s: &str) {
-    s.chars().for_each(|c| buffer.push(c as u8));
+    convert_next_to(path, "woz")
 }
 
-fn push_bytes(buffer: &mut Vec<u8>, a: &[u8]) {
-    for b in a.iter() {
-        buffer.push(*b);
-    }
+fn convert_next_to(path: &str, extension: &str) -> Result<String, String> {
+    let file_out = Path::new(path).with_extension(extension).to_str().unwrap().to_string();
+    convert(path, &file_out, false)?;
+    Ok(file_out)
 }
 
 const CRC32_TAB: [u32;256] = [
@@ -221,6 +71,3 @@ pub fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
     }
     crc ^ 0xffffffff
 }
-
-
-
@@ -0,0 +1,217 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::disk::archive;
+use crate::disk::disk_info::DiskInfo;
+use crate::disk::virtual_volume::VirtualVolume;
+use crate::ui_log;
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// The files that can be used as hard drives: raw ProDOS blocks (.hdv, .po) and 2MG images.
+/// .po files that are not larger than a floppy are floppies.
+pub const HARD_DRIVE_SUFFIXES: [&str; 3] = ["hdv", "po", "2mg"];
+
+/// Size of a 5.25" floppy, 280 blocks
+const FLOPPY_SIZE: u64 = 143_360;
+
+pub(crate) const TWO_MG_MAGIC: &[u8] = b"2IMG";
+pub(crate) const TWO_MG_HEADER_SIZE: usize = 64;
+/// Image formats of a 2MG file. Hard drives can only use ProDOS order, the only one that
+/// contains ProDOS blocks.
+pub(crate) const TWO_MG_DOS_ORDER: u32 = 0;
+pub(crate) const TWO_MG_PRODOS_ORDER: u32 = 1;
+pub(crate) const TWO_MG_NIB: u32 = 2;
+/// Bit 31 of the flags
+const TWO_MG_LOCKED: u32 = 0x8000_0000;
+
+/// The header of a 2MG image, see [parse_2mg_header]
+pub(crate) struct TwoMgHeader {
+    /// [TWO_MG_DOS_ORDER], [TWO_MG_PRODOS_ORDER] or [TWO_MG_NIB]
+    pub(crate) format: u32,
+    pub(crate) locked: bool,
+    /// Where the data starts in the file
+    pub(crate) data_offset: usize,
+    pub(crate) data_length: usize,
+}
+
+/// Parse the header of the 2MG image in `content`, and check that its data is in `content`
+pub(crate) fn parse_2mg_header(content: &[u8]) -> Result<TwoMgHeader, String> {
+    if ! content.starts_with(TWO_MG_MAGIC) {
+        return Err("Not a 2MG image".to_string());
+    }
+    if content.len() < TWO_MG_HEADER_SIZE {
+        return Err("2MG header is truncated".to_string());
+    }
+    let u32_at = |i: usize| u32::from_le_bytes(content[i..i + 4].try_into().unwrap());
+    let blocks = u32_at(20) as usize;
+    let data_offset = u32_at(24) as usize;
+    let data_length = match u32_at(28) as usize {
+        0 => blocks * BLOCK_SIZE,
+        length => length,
+    };
+    if data_offset.saturating_add(data_length) > content.len() {
+        return Err(format!("2MG data ({data_length} bytes at {data_offset}) is past the end \
+            of the file ({} bytes)", content.len()));
+    }
+    Ok(TwoMgHeader { format: u32_at(12), locked: u32_at(16) & TWO_MG_LOCKED != 0, data_offset, data_length })
+}
+
+/// Return true if the file should go in a hard drive rather than a floppy drive.
+/// Directories are mounted as virtual volumes, see [VirtualVolume].
+pub fn is_hard_drive_image(path: &str) -> bool {
+    let lower = path.to_lowercase();
+    if Path::new(path).is_dir() {
+        true
+    } else if lower.ends_with(".po") {
+        archive::file_size(path).is_some_and(|size| size > FLOPPY_SIZE)
+    } else {
+        HARD_DRIVE_SUFFIXES.iter().any(|suffix| lower.ends_with(&format!(".{suffix}")))
+    }
+}
+
+/// A hard drive image loaded in memory. Writes go to both the memory and the file. Virtual
+/// volumes are only written to the host directory by [HardDriveImage::flush], once the Apple ][
+/// is done writing.
+pub struct HardDriveImage {
+    pub(crate) path: String,
+    /// Where the images inside archives are written, created by the first write
+    sidecar: Option<String>,
+    content: Vec<u8>,
+    /// Where the blocks start in `content`: the size of the header for 2MG, 0 otherwise
+    data_offset: usize,
+    block_count: usize,
+    write_protected: bool,
+    virtual_volume: Option<VirtualVolume>,
+    /// The virtual volume was written to since the last flush
+    dirty: bool,
+}
+
+impl HardDriveImage {
+    pub fn new(path: &str) -> Result<HardDriveImage, String> {
+        if Path::new(path).is_dir() {
+            let (volume, content) = VirtualVolume::mount(path)?;
+            let mut result = Self::new_with_content(path, content, false)?;
+            result.virtual_volume = Some(volume);
+            return Ok(result);
+        }
+        if archive::split_path(path).is_some() {
+            // Keep going with the changes of a previous session
+            let sidecar = DiskInfo::overlay_path(path);
+            let content = if Path::new(&sidecar).exists() { fs::read(&sidecar) } else { archive::read(path) }
+                .map_err(|e| format!("Couldn't read {path}: {e}"))?;
+            let mut result = Self::new_with_content(path, content, false)?;
+            result.sidecar = Some(sidecar);
+            return Ok(result);
+        }
+        let content = fs::read(path).map_err(|e| format!("Couldn't read {path}: {e}"))?;
+        let read_only = fs::metadata(path).map_or(true, |m| m.permissions().readonly());
+        Self::new_with_content(path, content, read_only)
+    }
+
+    pub fn new_with_content(path: &str, content: Vec<u8>, read_only: bool)
+        -> Result<HardDriveImage, String>
+    {
+        let (data_offset, data_length, locked) = if content.starts_with(TWO_MG_MAGIC) {
+            let header = parse_2mg_header(&content)?;
+            if header.format != TWO_MG_PRODOS_ORDER {
+                return Err(format!("Unsupported 2MG format {}, only ProDOS order can be used", header.format));
+            }
+            (header.data_offset, header.data_length, header.locked)
+        } else {
+            (0, content.len(), false)
+        };
+        Ok(HardDriveImage {
+            path: path.to_string(),
+            content,
+            data_offset,
+            block_count: data_length / BLOCK_SIZE,
+            write_protected: locked || read_only,
+            virtual_volume: None,
+            sidecar: None,
+            dirty: false,
+        })
+    }
+
+
+    pub fn block_count(&self) -> usize { self.block_count }
+
+    pub fn is_write_protected(&self) -> bool { self.write_protected }
+
+    pub fn name(&self) -> String {
+        Path::new(&self.path).file_stem().map_or(String::new(), |s| s.to_string_lossy().to_string())
+    }
+
+    /// None if the block is past the end of the image
+    pub fn read_block(&self, block_number: usize) -> Option<&[u8]> {
+        let offset = self.block_offset(block_number)?;
+        Some(&self.content[offset..offset + BLOCK_SIZE])
+    }
+
+    pub fn write_block(&mut self, block_number: usize, bytes: &[u8]) -> Result<(), String> {
+        let offset = self.block_offset(block_number)
+            .ok_or_else(|| format!("Block {block_number} is past the end of {}", self.path))?;
+        self.write(offset, &bytes[..BLOCK_SIZE])
+    }
+
+    /// Zero all the blocks. Creating the volume is up to the caller.
+    pub fn format(&mut self) -> Result<(), String> {
+        if self.virtual_volume.is_some() {
+            return Err(format!("{} is a host directory, it can't be formatted", self.path));
+        }
+        let zeroes = vec![0; self.block_count * BLOCK_SIZE];
+        self.write(self.data_offset, &zeroes)
+    }
+
+    fn block_offset(&self, block_number: usize) -> Option<usize> {
+        if block_number < self.block_count {
+            Some(self.data_offset + block_number * BLOCK_SIZE)
+        } else {
+            None
+        }
+    }
+
+    /// Bring the host directory of a virtual volume up to date with the writes since the last
+    /// flush. Nothing to do for the other images, which are written right away.
+    pub fn flush(&mut self) {
+        if let (Some(volume), true) = (&mut self.virtual_volume, self.dirty) {
+            self.dirty = false;
+            if let Err(e) = volume.sync(&self.content) {
+                ui_log(&format!("Couldn't update {}: {e}", self.path));
+            }
+        }
+    }
+
+    /// Write `bytes` at `offset`, in the file first
+    fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), String> {
+        if self.write_protected {
+            return Err(format!("{} is write protected", self.path));
+        }
+        if self.virtual_volume.is_some() {
+            // The volume is often inconsistent in the middle of a ProDOS call, the host directory
+            // is only updated when the writes are over
+            self.content[offset..offset + bytes.len()].copy_from_slice(bytes);
+            self.dirty = true;
+            return Ok(());
+        }
+        let path = self.sidecar.as_ref().unwrap_or(&self.path);
+        if ! Path::new(path).exists() {
+            fs::write(path, &self.content).map_err(|e| format!("Couldn't create {path}: {e}"))?;
+        }
+        OpenOptions::new().write(true).open(path)
+            .and_then(|mut file| {
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.write_all(bytes)
+            })
+            .map_err(|e| format!("Couldn't write to {path}: {e}"))?;
+        self.content[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl Drop for HardDriveImage {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
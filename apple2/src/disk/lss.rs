@@ -1,6 +1,7 @@
 use rand::random;
 use crate::disk::disk::Disk;
 use crate::disk::drive::Drive;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
 
 // It's 1one bit every 4 cpu cycles/8 lss cycles.  One nibble is between 32 and 40 cpu cycles
 // (64-80 lss cycles) usually, depending on the number of 0 sync bits.  As for the clearing
@@ -8,6 +9,9 @@ use crate::disk::drive::Drive;
 // On 10 the latch is cleared 12 lss cycles after the first 1 (50% margin).
 // On 11 3 lss cycles after the second 1.
 
+/// Duration of one LSS cycle in 125ns ticks, the unit of the WOZ timings
+const LSS_TICKS: usize = 4;
+
 /// Beneath Apple ProDOS - pages D-6 and D-7
 const P6: [u8; 256] = [
     //                Q7 L (Read)                                         Q7 H (Write)
@@ -34,6 +38,8 @@ const P6: [u8; 256] = [
 
 #[derive(Default)]
 pub struct Lss {
+    /// Position in the current bit cell, in 125ns ticks. The LSS runs at 2MHz, each of its
+    /// cycles is 4 ticks
     clock: u128,
     state: u8,
     zeros: u16,
@@ -45,6 +51,21 @@ impl Lss {
         self.latch = self.latch & 0x7F;
         self.state = 0;
     }
+
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        w.u128(self.clock);
+        w.u8(self.state);
+        w.u16(self.zeros);
+        w.u8(self.latch);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.clock = r.u128()?;
+        self.state = r.u8()?;
+        self.zeros = r.u16()?;
+        self.latch = r.u8()?;
+        Ok(())
+    }
 }
 
 impl Lss {
@@ -59,8 +80,16 @@ impl Lss {
 
     fn step(&mut self, q6: bool, q7: bool, motor_on: bool, phase_160: usize, disk: &mut Disk) {
         let mut pulse = 0;
+        // Bit cells last 32 ticks on most disks, the bit is read in the middle of the cell
+        let bit_timing = disk.optimal_bit_timing().max(LSS_TICKS as u8) as u128;
+        let half = bit_timing / 2;
         // Adding q6 and q7 tests break Algernonn
-        if self.clock == 4 && ! q7 && ! q6 {
+        if disk.is_flux(phase_160) {
+            // Flux tracks carry their own timing, the head moves by one LSS cycle
+            if ! q7 && ! q6 {
+                pulse = disk.next_flux_pulse(phase_160, LSS_TICKS);
+            }
+        } else if self.clock <= half && half < self.clock + LSS_TICKS as u128 && ! q7 && ! q6 {
             pulse = disk.next_bit(phase_160);
             if pulse == 0 {
                 // Just need to know that there were more than 2 zeros in a row, no point in saturating
@@ -124,6 +153,9 @@ impl Lss {
         //     }
         // }
 
-        self.clock = (self.clock + 1) % 8;
+        self.clock += LSS_TICKS as u128;
+        if self.clock >= bit_timing {
+            self.clock -= bit_timing;
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+use crate::disk::archive;
+use crate::disk::bit_stream::{BitStream, BitStreams};
+use crate::disk::disk::PDisk;
+use crate::disk::disk_controller::MAX_TRACK_DSK;
+use crate::disk::disk_info::{DiskInfo, WozVersion};
+use crate::disk::dsk::Dsk;
+use crate::misc::save;
+use crate::ui_log;
+
+/// Number of nibbles of each track in a .nib file
+pub const NIB_TRACK_SIZE: usize = 6_656;
+pub const NIB_SIZE_BYTES: usize = NIB_TRACK_SIZE * MAX_TRACK_DSK;
+
+/// A .nib image: the raw nibbles of 35 tracks, without the sync bits
+#[derive(Clone)]
+pub struct Nib {
+    disk_info: DiskInfo,
+    bit_streams: BitStreams,
+}
+
+impl PDisk for Nib {
+    fn bit_streams(&self) -> &BitStreams {
+        &self.bit_streams
+    }
+
+    fn bit_streams_mut(&mut self) -> &mut BitStreams {
+        &mut self.bit_streams
+    }
+
+    fn save(&mut self) {
+        let path = self.disk_info.path.clone();
+        ui_log(&format!("Nib saving {path}"));
+        match save(&path, &Self::bit_streams_to_bytes(&self.bit_streams)) {
+            Ok(_) => { ui_log(&format!("Saved {path}")); }
+            Err(s) => { ui_log(&format!("Error saving {path}: {s}")); }
+        }
+    }
+
+    fn disk_info(&self) -> &DiskInfo {
+        &self.disk_info
+    }
+
+    fn disk_info_mut(&mut self) -> &mut DiskInfo {
+        &mut self.disk_info
+    }
+}
+
+impl Nib {
+    pub fn new_with_file(filename: &str, quick: bool) -> Result<Nib, String> {
+        let bit_streams = if quick {
+            Default::default()
+        } else {
+            let bytes = archive::read(filename).map_err(|e| e.to_string())?;
+            if bytes.len() != NIB_SIZE_BYTES {
+                return Err(format!("{filename} is not a .nib image ({} bytes instead of {NIB_SIZE_BYTES})",
+                    bytes.len()));
+            }
+            Self::bytes_to_bit_streams(&bytes)
+        };
+        let mut disk_info = DiskInfo::n(filename);
+        disk_info.woz_version = WozVersion::Nib;
+        Ok(Nib { disk_info, bit_streams })
+    }
+
+    pub fn bytes_to_bit_streams(bytes: &[u8]) -> BitStreams {
+        Dsk::tracks_to_bit_streams(bytes.chunks(NIB_TRACK_SIZE)
+            .map(|track| BitStream::new(Self::nibbles_to_bits(track)))
+            .collect())
+    }
+
+    /// The .nib format lost the sync bits: put two zero bits after each $FF that is part of a
+    /// run of $FF, which turns the gaps back into 10 bit sync nibbles
+    pub fn nibbles_to_bits(nibbles: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(nibbles.len() * 9);
+        let len = nibbles.len();
+        for (i, &nibble) in nibbles.iter().enumerate() {
+            Dsk::write8(&mut result, vec![nibble]);
+            let is_sync = nibble == 0xff
+                && (nibbles[(i + 1) % len] == 0xff || nibbles[(i + len - 1) % len] == 0xff);
+            if is_sync {
+                result.extend([0, 0]);
+            }
+        }
+        result
+    }
+
+    pub fn bit_streams_to_bytes(bit_streams: &BitStreams) -> Vec<u8> {
+        (0..MAX_TRACK_DSK)
+            .flat_map(|track| Self::bits_to_nibbles(bit_streams.get_stream(track * 4)))
+            .collect()
+    }
+
+    /// Read the nibbles of the track and make them fit in NIB_TRACK_SIZE bytes by growing or
+    /// shrinking its longest gap
+    pub fn bits_to_nibbles(stream: &BitStream) -> Vec<u8> {
+        let mut nibbles: Vec<u8> = stream.to_nibbles().iter().map(|n| n.value).collect();
+        if nibbles.is_empty() {
+            return vec![0xff; NIB_TRACK_SIZE];
+        }
+
+        // Start and length of the longest run of $FF
+        let (mut gap_start, mut gap_len) = (0, 0);
+        let mut i = 0;
+        while i < nibbles.len() {
+            let start = i;
+            while i < nibbles.len() && nibbles[i] == 0xff {
+                i += 1;
+            }
+            if i - start > gap_len {
+                (gap_start, gap_len) = (start, i - start);
+            }
+            i += 1;
+        }
+
+        if nibbles.len() > NIB_TRACK_SIZE {
+            let extra = (nibbles.len() - NIB_TRACK_SIZE).min(gap_len);
+            nibbles.drain(gap_start..gap_start + extra);
+        } else {
+            let missing = NIB_TRACK_SIZE - nibbles.len();
+            nibbles.splice(gap_start..gap_start, vec![0xff; missing]);
+        }
+        nibbles.resize(NIB_TRACK_SIZE, 0xff);
+        nibbles
+    }
+}
@@ -0,0 +1,763 @@
+//! The ProDOS file system: read the directories and files of a volume, build new volumes and
+//! edit existing ones.
+//!
+//! A volume is a sequence of 512 byte blocks. Blocks 0-1 hold the boot loader, blocks 2-5 the
+//! volume directory, and the bitmap of the free blocks follows.
+
+use std::fmt::{Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::disk::hard_drive::BLOCK_SIZE;
+
+pub const MAX_BLOCKS: usize = 65_535;
+pub const VOLUME_DIRECTORY_BLOCK: usize = 2;
+const VOLUME_DIRECTORY_BLOCKS: usize = 4;
+const BITMAP_BLOCK: usize = 6;
+const BITS_PER_BITMAP_BLOCK: usize = BLOCK_SIZE * 8;
+
+const ENTRY_LENGTH: usize = 0x27;
+const ENTRIES_PER_BLOCK: usize = 0x0d;
+/// Offset of the first entry in a directory block, after the previous and next pointers
+const FIRST_ENTRY: usize = 4;
+const MAX_NAME_LENGTH: usize = 15;
+/// Index blocks hold 256 pointers, low bytes first then high bytes
+const POINTERS_PER_BLOCK: usize = 256;
+
+pub const SEEDLING: u8 = 1;
+pub const SAPLING: u8 = 2;
+pub const TREE: u8 = 3;
+pub const SUBDIRECTORY: u8 = 0xd;
+const SUBDIRECTORY_HEADER: u8 = 0xe;
+const VOLUME_HEADER: u8 = 0xf;
+
+pub const DIRECTORY_TYPE: u8 = 0x0f;
+pub const BINARY_TYPE: u8 = 0x06;
+/// Destroy, rename, backup, write and read enabled
+pub const DEFAULT_ACCESS: u8 = 0xe3;
+const ACCESS_DESTROY: u8 = 0x80;
+/// Written in the subdirectory headers
+const SUBDIRECTORY_MAGIC: u8 = 0x75;
+
+/// A file or a subdirectory found in a directory
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileEntry {
+    pub name: String,
+    pub storage_type: u8,
+    pub file_type: u8,
+    pub aux_type: u16,
+    pub access: u8,
+    pub key_block: usize,
+    pub blocks_used: usize,
+    pub eof: usize,
+    pub modified: [u8; 4],
+}
+
+impl FileEntry {
+    pub fn is_directory(&self) -> bool { self.storage_type == SUBDIRECTORY }
+
+    pub fn is_locked(&self) -> bool { self.access & ACCESS_DESTROY == 0 }
+}
+
+impl Display for FileEntry {
+    /// Like CATALOG: "*HELLO           BAS      1      100  A=$0801"
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{:<15}  {:<4} {:>6} {:>8}  A=${:04X}", if self.is_locked() { '*' } else { ' ' },
+            self.name, file_type_name(self.file_type), self.blocks_used, self.eof, self.aux_type)
+    }
+}
+
+/// The three letters ProDOS shows for the common file types, "$xx" for the others
+pub fn file_type_name(file_type: u8) -> String {
+    match file_type {
+        0x04 => "TXT".to_string(),
+        BINARY_TYPE => "BIN".to_string(),
+        DIRECTORY_TYPE => "DIR".to_string(),
+        0x19 => "ADB".to_string(),
+        0x1a => "AWP".to_string(),
+        0x1b => "ASP".to_string(),
+        0xfa => "INT".to_string(),
+        0xfc => "BAS".to_string(),
+        0xfd => "VAR".to_string(),
+        0xfe => "REL".to_string(),
+        0xff => "SYS".to_string(),
+        other => format!("${other:02X}"),
+    }
+}
+
+/// What describes a file besides its name and its content
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileAttributes {
+    pub file_type: u8,
+    pub aux_type: u16,
+    pub access: u8,
+    pub modified: [u8; 4],
+}
+
+/// Return true if ProDOS accepts this name: a letter followed by letters, digits and periods
+pub fn is_valid_name(name: &str) -> bool {
+    name.len() <= MAX_NAME_LENGTH
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.')
+}
+
+/// Turn any name into a valid ProDOS name
+pub fn to_prodos_name(name: &str) -> String {
+    let mut result: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '.' })
+        .collect();
+    if ! result.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        result.insert(0, 'A');
+    }
+    result.truncate(MAX_NAME_LENGTH);
+    result
+}
+
+/// The host name of a file that keeps its type and aux type: "HELLO#fc0801"
+pub fn suffixed_name(entry: &FileEntry) -> String {
+    format!("{}#{:02x}{:04x}", entry.name, entry.file_type, entry.aux_type)
+}
+
+/// "HELLO#fc0801" -> ("HELLO", 0xfc, 0x0801)
+pub fn parse_suffix(host_name: &str) -> Option<(String, u8, u16)> {
+    let (name, suffix) = host_name.rsplit_once('#')?;
+    if suffix.len() != 6 || name.is_empty() {
+        return None;
+    }
+    let file_type = u8::from_str_radix(&suffix[0..2], 16).ok()?;
+    let aux_type = u16::from_str_radix(&suffix[2..6], 16).ok()?;
+    Some((name.to_string(), file_type, aux_type))
+}
+
+/// The ProDOS date and time of `time`: day, month and year in the first word, then
+/// minute and hour. Years are stored modulo 100.
+pub fn prodos_date(time: SystemTime) -> [u8; 4] {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let date = ((year % 100) as u16) << 9 | (month as u16) << 5 | day as u16;
+    let minutes = seconds / 60 % 60;
+    let hours = seconds / 3600 % 24;
+    [date as u8, (date >> 8) as u8, minutes as u8, hours as u8]
+}
+
+/// Convert days since 1970-01-01 into (year, month, day)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn word(bytes: &[u8], offset: usize) -> usize {
+    bytes[offset] as usize | (bytes[offset + 1] as usize) << 8
+}
+
+fn set_word(bytes: &mut [u8], offset: usize, value: usize) {
+    bytes[offset] = value as u8;
+    bytes[offset + 1] = (value >> 8) as u8;
+}
+
+fn block(image: &[u8], block_number: usize) -> Result<&[u8], String> {
+    let start = block_number * BLOCK_SIZE;
+    image.get(start..start + BLOCK_SIZE)
+        .ok_or_else(|| format!("Block {block_number} is past the end of the volume"))
+}
+
+/// Return the name and the number of blocks of the volume
+pub fn volume_header(image: &[u8]) -> Result<(String, usize), String> {
+    let key = block(image, VOLUME_DIRECTORY_BLOCK)?;
+    let header = &key[FIRST_ENTRY..FIRST_ENTRY + ENTRY_LENGTH];
+    if header[0] >> 4 != VOLUME_HEADER {
+        return Err("Not a ProDOS volume".to_string());
+    }
+    Ok((entry_name(header), word(header, 0x25)))
+}
+
+fn entry_name(entry: &[u8]) -> String {
+    let length = (entry[0] & 0xf) as usize;
+    String::from_utf8_lossy(&entry[1..1 + length]).to_string()
+}
+
+/// The active entries of the directory starting at `key_block`, without its header
+pub fn read_directory(image: &[u8], key_block: usize) -> Result<Vec<FileEntry>, String> {
+    Ok(directory_entries(image, key_block)?.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// The entries of a directory with their offset in the image
+fn directory_entries(image: &[u8], key_block: usize) -> Result<Vec<(usize, FileEntry)>, String> {
+    let mut result = Vec::new();
+    for block_number in directory_blocks(image, key_block)? {
+        let bytes = block(image, block_number)?;
+        for slot in 0..ENTRIES_PER_BLOCK {
+            if block_number == key_block && slot == 0 {
+                continue;
+            }
+            let offset = FIRST_ENTRY + slot * ENTRY_LENGTH;
+            let entry = &bytes[offset..offset + ENTRY_LENGTH];
+            let storage_type = entry[0] >> 4;
+            if storage_type == 0 {
+                continue;
+            }
+            result.push((block_number * BLOCK_SIZE + offset, FileEntry {
+                name: entry_name(entry),
+                storage_type,
+                file_type: entry[0x10],
+                aux_type: word(entry, 0x1f) as u16,
+                access: entry[0x1e],
+                key_block: word(entry, 0x11),
+                blocks_used: word(entry, 0x13),
+                eof: word(entry, 0x15) | (entry[0x17] as usize) << 16,
+                modified: entry[0x21..0x25].try_into().unwrap(),
+            }));
+        }
+    }
+    Ok(result)
+}
+
+/// The blocks of the directory starting at `key_block`, following the next pointers
+fn directory_blocks(image: &[u8], key_block: usize) -> Result<Vec<usize>, String> {
+    let mut result = Vec::new();
+    let mut block_number = key_block;
+    while block_number != 0 {
+        if result.len() >= image.len() / BLOCK_SIZE {
+            return Err(format!("The directory at block {key_block} loops"));
+        }
+        result.push(block_number);
+        block_number = word(block(image, block_number)?, 2);
+    }
+    Ok(result)
+}
+
+/// The content of a seedling, sapling or tree file. Sparse blocks read as zeroes.
+pub fn read_file(image: &[u8], entry: &FileEntry) -> Result<Vec<u8>, String> {
+    let block_count = entry.eof.div_ceil(BLOCK_SIZE);
+    let (data_blocks, _) = file_blocks(image, entry)?;
+    let mut result = Vec::with_capacity(block_count * BLOCK_SIZE);
+    for &data_block in data_blocks.iter().take(block_count.max(1)) {
+        if data_block == 0 {
+            result.extend([0; BLOCK_SIZE]);
+        } else {
+            result.extend_from_slice(block(image, data_block)?);
+        }
+    }
+    result.resize(entry.eof, 0);
+    Ok(result)
+}
+
+/// The data blocks of a file (0 for the sparse ones) and its index blocks
+fn file_blocks(image: &[u8], entry: &FileEntry) -> Result<(Vec<usize>, Vec<usize>), String> {
+    let block_count = entry.eof.div_ceil(BLOCK_SIZE);
+    match entry.storage_type {
+        SEEDLING => Ok((vec![entry.key_block], vec![])),
+        SAPLING => Ok((index_pointers(image, entry.key_block)?, vec![entry.key_block])),
+        TREE => {
+            let mut data_blocks = Vec::new();
+            let mut index_blocks = vec![entry.key_block];
+            for index in index_pointers(image, entry.key_block)? {
+                if data_blocks.len() >= block_count {
+                    break;
+                }
+                if index != 0 {
+                    index_blocks.push(index);
+                }
+                data_blocks.extend(index_pointers(image, index)?);
+            }
+            Ok((data_blocks, index_blocks))
+        }
+        storage_type => Err(format!("{}: unsupported storage type {storage_type:X}", entry.name)),
+    }
+}
+
+fn index_pointers(image: &[u8], index_block: usize) -> Result<Vec<usize>, String> {
+    if index_block == 0 {
+        return Ok(vec![0; POINTERS_PER_BLOCK]);
+    }
+    let bytes = block(image, index_block)?;
+    Ok((0..POINTERS_PER_BLOCK).map(|i| bytes[i] as usize | (bytes[i + 256] as usize) << 8).collect())
+}
+
+/// Write `content` to blocks given by `allocate`, as a seedling, a sapling or a tree depending
+/// on its size, and return the storage type, the key block and the number of blocks used
+fn store_file(image: &mut [u8], name: &str, content: &[u8],
+    allocate: &mut dyn FnMut(&mut [u8]) -> Result<usize, String>) -> Result<(u8, usize, usize), String>
+{
+    let data_block_count = content.len().div_ceil(BLOCK_SIZE).max(1);
+    if data_block_count > POINTERS_PER_BLOCK * 128 {
+        return Err(format!("{name} is too large for ProDOS"));
+    }
+    let data_blocks: Vec<usize> = (0..data_block_count)
+        .map(|_| allocate(image))
+        .collect::<Result<_, _>>()?;
+    for (i, n) in data_blocks.iter().enumerate() {
+        let chunk = &content[(i * BLOCK_SIZE).min(content.len())..((i + 1) * BLOCK_SIZE).min(content.len())];
+        image[n * BLOCK_SIZE..n * BLOCK_SIZE + chunk.len()].copy_from_slice(chunk);
+    }
+
+    if data_blocks.len() == 1 {
+        Ok((SEEDLING, data_blocks[0], 1))
+    } else if data_blocks.len() <= POINTERS_PER_BLOCK {
+        let index = allocate(image)?;
+        write_pointers(image, index, &data_blocks);
+        Ok((SAPLING, index, data_blocks.len() + 1))
+    } else {
+        let indexes: Vec<usize> = data_blocks.chunks(POINTERS_PER_BLOCK).map(|chunk| {
+            let index = allocate(image)?;
+            write_pointers(image, index, chunk);
+            Ok(index)
+        }).collect::<Result<_, String>>()?;
+        let master = allocate(image)?;
+        write_pointers(image, master, &indexes);
+        Ok((TREE, master, data_blocks.len() + indexes.len() + 1))
+    }
+}
+
+/// The number of blocks [store_file] needs for `length` bytes
+fn blocks_needed(length: usize) -> usize {
+    let data_blocks = length.div_ceil(BLOCK_SIZE).max(1);
+    match data_blocks {
+        1 => 1,
+        n if n <= POINTERS_PER_BLOCK => n + 1,
+        n => n + n.div_ceil(POINTERS_PER_BLOCK) + 1,
+    }
+}
+
+fn write_pointers(image: &mut [u8], index_block: usize, pointers: &[usize]) {
+    let offset = index_block * BLOCK_SIZE;
+    for (i, p) in pointers.iter().enumerate() {
+        image[offset + i] = *p as u8;
+        image[offset + 256 + i] = (*p >> 8) as u8;
+    }
+}
+
+fn write_name(image: &mut [u8], offset: usize, storage_type: u8, name: &str) {
+    image[offset] = storage_type << 4 | name.len() as u8;
+    image[offset + 1..offset + 1 + name.len()].copy_from_slice(name.as_bytes());
+}
+
+/// The fields shared by the volume and subdirectory headers
+fn write_header_fields(image: &mut [u8], header: usize, date: [u8; 4]) {
+    image[header + 0x18..header + 0x1c].copy_from_slice(&date);
+    image[header + 0x1e] = DEFAULT_ACCESS;
+    image[header + 0x1f] = ENTRY_LENGTH as u8;
+    image[header + 0x20] = ENTRIES_PER_BLOCK as u8;
+}
+
+/// The header of the subdirectory at `key_block`, whose entry is at `parent_entry` in the image
+fn write_subdirectory_header(image: &mut [u8], key_block: usize, name: &str, date: [u8; 4],
+    parent_entry: usize)
+{
+    let header = key_block * BLOCK_SIZE + FIRST_ENTRY;
+    write_name(image, header, SUBDIRECTORY_HEADER, name);
+    image[header + 0x10] = SUBDIRECTORY_MAGIC;
+    write_header_fields(image, header, date);
+    set_word(image, header + 0x23, parent_entry / BLOCK_SIZE);
+    image[header + 0x25] = ((parent_entry % BLOCK_SIZE - FIRST_ENTRY) / ENTRY_LENGTH + 1) as u8;
+    image[header + 0x26] = ENTRY_LENGTH as u8;
+}
+
+/// Everything in a file entry but its name and storage type
+fn write_entry_fields(image: &mut [u8], entry: usize, key_block: usize, blocks_used: usize, eof: usize,
+    attributes: &FileAttributes, created: [u8; 4])
+{
+    image[entry + 0x10] = attributes.file_type;
+    set_word(image, entry + 0x11, key_block);
+    set_word(image, entry + 0x13, blocks_used);
+    set_eof(image, entry, eof);
+    image[entry + 0x18..entry + 0x1c].copy_from_slice(&created);
+    image[entry + 0x1e] = attributes.access;
+    set_word(image, entry + 0x1f, attributes.aux_type as usize);
+    image[entry + 0x21..entry + 0x25].copy_from_slice(&attributes.modified);
+}
+
+fn set_eof(image: &mut [u8], entry: usize, eof: usize) {
+    set_word(image, entry + 0x15, eof & 0xffff);
+    image[entry + 0x17] = (eof >> 16) as u8;
+}
+
+/// A directory being filled by a [VolumeBuilder]
+struct Directory {
+    blocks: Vec<usize>,
+    /// Number of entries, the header included
+    entry_count: usize,
+    /// Where the entry of this subdirectory is in its parent: block and offset
+    parent_entry: Option<(usize, usize)>,
+}
+
+/// Build a volume in memory, for example from the files of a host directory.
+/// The volume has no boot loader.
+pub struct VolumeBuilder {
+    image: Vec<u8>,
+    next_free_block: usize,
+    date: [u8; 4],
+    directories: Vec<Directory>,
+}
+
+/// Identifies a directory of a [VolumeBuilder], the volume directory is `ROOT`
+pub type DirectoryId = usize;
+pub const ROOT: DirectoryId = 0;
+
+impl VolumeBuilder {
+    pub fn new(name: &str, block_count: usize, date: [u8; 4]) -> Result<VolumeBuilder, String> {
+        let bitmap_blocks = block_count.div_ceil(BITS_PER_BITMAP_BLOCK);
+        let first_free = BITMAP_BLOCK + bitmap_blocks;
+        if ! is_valid_name(name) {
+            return Err(format!("Invalid volume name {name}"));
+        }
+        if block_count > MAX_BLOCKS || block_count <= first_free {
+            return Err(format!("Invalid volume size: {block_count} blocks"));
+        }
+        let mut result = VolumeBuilder {
+            image: vec![0; block_count * BLOCK_SIZE],
+            next_free_block: first_free,
+            date,
+            directories: vec![Directory {
+                blocks: (VOLUME_DIRECTORY_BLOCK..VOLUME_DIRECTORY_BLOCK + VOLUME_DIRECTORY_BLOCKS).collect(),
+                entry_count: 1,
+                parent_entry: None,
+            }],
+        };
+
+        // The boot blocks just go to the next slot, like the firmware does for volumes that
+        // don't boot: JMP $C600
+        result.image[0..4].copy_from_slice(&[0x01, 0x4c, 0x00, 0xc6]);
+
+        // All the blocks are free except the ones used so far
+        for n in first_free..block_count {
+            result.image[BITMAP_BLOCK * BLOCK_SIZE + n / 8] |= 0x80 >> (n % 8);
+        }
+
+        // Link the blocks of the volume directory
+        for (i, n) in result.directories[ROOT].blocks.clone().iter().enumerate() {
+            let offset = n * BLOCK_SIZE;
+            set_word(&mut result.image, offset, if i == 0 { 0 } else { n - 1 });
+            set_word(&mut result.image, offset + 2, if i == VOLUME_DIRECTORY_BLOCKS - 1 { 0 } else { n + 1 });
+        }
+
+        let header = result.header_offset(ROOT);
+        write_name(&mut result.image, header, VOLUME_HEADER, name);
+        write_header_fields(&mut result.image, header, date);
+        set_word(&mut result.image, header + 0x23, BITMAP_BLOCK);
+        set_word(&mut result.image, header + 0x25, block_count);
+        Ok(result)
+    }
+
+    /// The volume, ready to be written to a file
+    pub fn finish(self) -> Vec<u8> {
+        self.image
+    }
+
+    /// Add a subdirectory in `parent` and return its id
+    pub fn add_directory(&mut self, parent: DirectoryId, name: &str, modified: [u8; 4])
+        -> Result<DirectoryId, String>
+    {
+        let key_block = self.allocate()?;
+        let entry = self.add_entry(parent, name, SUBDIRECTORY)?;
+        let attributes = FileAttributes { file_type: DIRECTORY_TYPE, aux_type: 0, access: DEFAULT_ACCESS, modified };
+        write_entry_fields(&mut self.image, entry, key_block, 1, BLOCK_SIZE, &attributes, self.date);
+
+        let id = self.directories.len();
+        self.directories.push(Directory {
+            blocks: vec![key_block],
+            entry_count: 1,
+            parent_entry: Some((entry / BLOCK_SIZE, entry % BLOCK_SIZE)),
+        });
+        write_subdirectory_header(&mut self.image, key_block, name, self.date, entry);
+        Ok(id)
+    }
+
+    /// Add a file in `directory`, as a seedling, a sapling or a tree depending on its size
+    pub fn add_file(&mut self, directory: DirectoryId, name: &str, attributes: &FileAttributes,
+        content: &[u8]) -> Result<(), String>
+    {
+        let next_free_block = &mut self.next_free_block;
+        let (storage_type, key_block, blocks_used) = store_file(&mut self.image, name, content,
+            &mut |image| Self::allocate_next(image, next_free_block))?;
+        let entry = self.add_entry(directory, name, storage_type)?;
+        write_entry_fields(&mut self.image, entry, key_block, blocks_used, content.len(), attributes, self.date);
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> Result<usize, String> {
+        Self::allocate_next(&mut self.image, &mut self.next_free_block)
+    }
+
+    /// The blocks are allocated in order, nothing is ever freed
+    fn allocate_next(image: &mut [u8], next_free_block: &mut usize) -> Result<usize, String> {
+        let n = *next_free_block;
+        if n * BLOCK_SIZE >= image.len() {
+            return Err("The volume is full".to_string());
+        }
+        image[BITMAP_BLOCK * BLOCK_SIZE + n / 8] &= ! (0x80 >> (n % 8));
+        *next_free_block += 1;
+        Ok(n)
+    }
+
+    /// Reserve the next entry of `directory`, growing it if it's full (subdirectories only),
+    /// and return its offset in the image
+    fn add_entry(&mut self, directory: DirectoryId, name: &str, storage_type: u8) -> Result<usize, String> {
+        if ! is_valid_name(name) {
+            return Err(format!("Invalid ProDOS name {name}"));
+        }
+        let index = self.directories[directory].entry_count;
+        if index / ENTRIES_PER_BLOCK == self.directories[directory].blocks.len() {
+            if directory == ROOT {
+                return Err(format!("The volume directory is full, can't add {name}"));
+            }
+            let new_block = self.allocate()?;
+            let previous = *self.directories[directory].blocks.last().unwrap();
+            set_word(&mut self.image, previous * BLOCK_SIZE + 2, new_block);
+            set_word(&mut self.image, new_block * BLOCK_SIZE, previous);
+            let d = &mut self.directories[directory];
+            d.blocks.push(new_block);
+            let block_count = d.blocks.len();
+            let (parent_block, parent_offset) = d.parent_entry.unwrap();
+            let parent_entry = parent_block * BLOCK_SIZE + parent_offset;
+            set_word(&mut self.image, parent_entry + 0x13, block_count);
+            set_eof(&mut self.image, parent_entry, block_count * BLOCK_SIZE);
+        }
+        let d = &mut self.directories[directory];
+        let offset = d.blocks[index / ENTRIES_PER_BLOCK] * BLOCK_SIZE
+            + FIRST_ENTRY + (index % ENTRIES_PER_BLOCK) * ENTRY_LENGTH;
+        d.entry_count += 1;
+        let file_count = d.entry_count - 1;
+        let header = self.header_offset(directory);
+        set_word(&mut self.image, header + 0x21, file_count);
+        write_name(&mut self.image, offset, storage_type, name);
+        set_word(&mut self.image, offset + 0x25, self.directories[directory].blocks[0]);
+        Ok(offset)
+    }
+
+    fn header_offset(&self, directory: DirectoryId) -> usize {
+        self.directories[directory].blocks[0] * BLOCK_SIZE + FIRST_ENTRY
+    }
+}
+
+/// A ProDOS volume in memory, whose files and subdirectories can be added and deleted.
+/// Paths are made of names separated by '/', the volume directory is "".
+pub struct ProDos {
+    image: Vec<u8>,
+}
+
+impl ProDos {
+    pub fn new(image: Vec<u8>) -> Result<ProDos, String> {
+        let (_, block_count) = volume_header(&image)?;
+        if block_count * BLOCK_SIZE > image.len() {
+            return Err(format!("The volume has {block_count} blocks but the image is smaller"));
+        }
+        let result = ProDos { image };
+        if result.bitmap_offset() + block_count.div_ceil(8) > result.image.len() {
+            return Err("The bitmap of the volume is past the end of the image".to_string());
+        }
+        Ok(result)
+    }
+
+    /// A new volume without any file
+    pub fn format(name: &str, block_count: usize) -> Result<ProDos, String> {
+        let name = name.to_uppercase();
+        let image = VolumeBuilder::new(&name, block_count, prodos_date(SystemTime::now()))?.finish();
+        ProDos::new(image)
+    }
+
+    /// The blocks of the volume
+    pub fn image(&self) -> &[u8] { &self.image }
+
+    pub fn volume_name(&self) -> String { self.header().0 }
+
+    pub fn block_count(&self) -> usize { self.header().1 }
+
+    fn header(&self) -> (String, usize) {
+        volume_header(&self.image).unwrap()
+    }
+
+    fn bitmap_offset(&self) -> usize {
+        word(&self.image, VOLUME_DIRECTORY_BLOCK * BLOCK_SIZE + FIRST_ENTRY + 0x23) * BLOCK_SIZE
+    }
+
+    pub(crate) fn is_free(&self, block_number: usize) -> bool {
+        self.image[self.bitmap_offset() + block_number / 8] & (0x80 >> (block_number % 8)) != 0
+    }
+
+    fn set_free(&mut self, block_number: usize, free: bool) {
+        let offset = self.bitmap_offset() + block_number / 8;
+        if free {
+            self.image[offset] |= 0x80 >> (block_number % 8);
+        } else {
+            self.image[offset] &= ! (0x80 >> (block_number % 8));
+        }
+    }
+
+    pub fn free_block_count(&self) -> usize {
+        (0..self.block_count()).filter(|n| self.is_free(*n)).count()
+    }
+
+    /// The files and subdirectories of the directory at `path`
+    pub fn list(&self, path: &str) -> Result<Vec<FileEntry>, String> {
+        read_directory(&self.image, self.directory_key_block(path)?)
+    }
+
+    /// The entry of the file or subdirectory at `path`
+    pub fn find(&self, path: &str) -> Result<FileEntry, String> {
+        self.locate(path).map(|(_, _, entry)| entry)
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, String> {
+        let entry = self.find(path)?;
+        if entry.is_directory() {
+            return Err(format!("{path} is a directory"));
+        }
+        read_file(&self.image, &entry)
+    }
+
+    /// Add a file to the directory at `directory`, its name is turned to upper case
+    pub fn add_file(&mut self, directory: &str, name: &str, attributes: &FileAttributes, content: &[u8])
+        -> Result<(), String>
+    {
+        let name = &name.to_uppercase();
+        let key_block = self.directory_key_block(directory)?;
+        self.check_new_name(key_block, name, blocks_needed(content.len()))?;
+        let (storage_type, file_key_block, blocks_used) = store_file(&mut self.image, name, content,
+            &mut Self::allocate)?;
+        let entry = self.add_entry(key_block, name, storage_type)?;
+        write_entry_fields(&mut self.image, entry, file_key_block, blocks_used, content.len(), attributes,
+            prodos_date(SystemTime::now()));
+        Ok(())
+    }
+
+    /// Add an empty subdirectory to the directory at `parent`
+    pub fn add_directory(&mut self, parent: &str, name: &str) -> Result<(), String> {
+        let name = &name.to_uppercase();
+        let parent_key_block = self.directory_key_block(parent)?;
+        self.check_new_name(parent_key_block, name, 1)?;
+        let date = prodos_date(SystemTime::now());
+        let key_block = Self::allocate(&mut self.image)?;
+        let entry = self.add_entry(parent_key_block, name, SUBDIRECTORY)?;
+        let attributes = FileAttributes { file_type: DIRECTORY_TYPE, aux_type: 0, access: DEFAULT_ACCESS, modified: date };
+        write_entry_fields(&mut self.image, entry, key_block, 1, BLOCK_SIZE, &attributes, date);
+        write_subdirectory_header(&mut self.image, key_block, name, date, entry);
+        Ok(())
+    }
+
+    /// Delete a file, or a subdirectory if it's empty, and free its blocks
+    pub fn delete(&mut self, path: &str) -> Result<(), String> {
+        let (directory_key_block, offset, entry) = self.locate(path)?;
+        if entry.is_locked() {
+            return Err(format!("{path} is locked"));
+        }
+        let blocks = if entry.is_directory() {
+            if ! read_directory(&self.image, entry.key_block)?.is_empty() {
+                return Err(format!("{path} is not empty"));
+            }
+            directory_blocks(&self.image, entry.key_block)?
+        } else {
+            let (data_blocks, index_blocks) = file_blocks(&self.image, &entry)?;
+            data_blocks.into_iter().chain(index_blocks).filter(|n| *n != 0).collect()
+        };
+        for n in blocks {
+            self.set_free(n, true);
+        }
+        self.image[offset] = 0;
+        let header = directory_key_block * BLOCK_SIZE + FIRST_ENTRY;
+        let file_count = word(&self.image, header + 0x21);
+        set_word(&mut self.image, header + 0x21, file_count.saturating_sub(1));
+        Ok(())
+    }
+
+    /// The key block of the directory at `path`
+    fn directory_key_block(&self, path: &str) -> Result<usize, String> {
+        if path.trim_matches('/').is_empty() {
+            return Ok(VOLUME_DIRECTORY_BLOCK);
+        }
+        let entry = self.find(path)?;
+        if ! entry.is_directory() {
+            return Err(format!("{path} is not a directory"));
+        }
+        Ok(entry.key_block)
+    }
+
+    /// The key block of the directory holding the entry at `path`, the offset of the entry in
+    /// the image, and the entry
+    fn locate(&self, path: &str) -> Result<(usize, usize, FileEntry), String> {
+        let names: Vec<&str> = path.split('/').filter(|n| ! n.is_empty()).collect();
+        let mut key_block = VOLUME_DIRECTORY_BLOCK;
+        for (i, name) in names.iter().enumerate() {
+            let (offset, entry) = directory_entries(&self.image, key_block)?.into_iter()
+                .find(|(_, e)| e.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("{path} not found"))?;
+            if i == names.len() - 1 {
+                return Ok((key_block, offset, entry));
+            }
+            if ! entry.is_directory() {
+                return Err(format!("{} is not a directory", entry.name));
+            }
+            key_block = entry.key_block;
+        }
+        Err("The volume directory has no entry".to_string())
+    }
+
+    /// Fail if `name` can't be added to the directory, or if the volume doesn't have
+    /// `block_count` free blocks plus the one the directory might need to grow
+    fn check_new_name(&self, key_block: usize, name: &str, block_count: usize) -> Result<(), String> {
+        if ! is_valid_name(name) {
+            return Err(format!("Invalid ProDOS name {name}"));
+        }
+        if read_directory(&self.image, key_block)?.iter().any(|e| e.name.eq_ignore_ascii_case(name)) {
+            return Err(format!("There is already a {name}"));
+        }
+        if self.free_block_count() < block_count + 1 {
+            return Err("The volume is full".to_string());
+        }
+        Ok(())
+    }
+
+    /// The first free block, now used and cleared
+    fn allocate(image: &mut [u8]) -> Result<usize, String> {
+        let (_, block_count) = volume_header(image)?;
+        let bitmap = word(image, VOLUME_DIRECTORY_BLOCK * BLOCK_SIZE + FIRST_ENTRY + 0x23) * BLOCK_SIZE;
+        let n = (0..block_count).find(|n| image[bitmap + n / 8] & (0x80 >> (n % 8)) != 0)
+            .ok_or_else(|| "The volume is full".to_string())?;
+        image[bitmap + n / 8] &= ! (0x80 >> (n % 8));
+        image[n * BLOCK_SIZE..(n + 1) * BLOCK_SIZE].fill(0);
+        Ok(n)
+    }
+
+    /// Use the first free entry of the directory, growing it if it's full (subdirectories
+    /// only), and return its offset in the image
+    fn add_entry(&mut self, key_block: usize, name: &str, storage_type: u8) -> Result<usize, String> {
+        let blocks = directory_blocks(&self.image, key_block)?;
+        let free_entry = blocks.iter().flat_map(|&b| (0..ENTRIES_PER_BLOCK).map(move |slot| (b, slot)))
+            .filter(|&(b, slot)| b != key_block || slot != 0)
+            .map(|(b, slot)| b * BLOCK_SIZE + FIRST_ENTRY + slot * ENTRY_LENGTH)
+            .find(|offset| self.image[*offset] >> 4 == 0);
+        let offset = match free_entry {
+            Some(offset) => offset,
+            None if key_block == VOLUME_DIRECTORY_BLOCK => {
+                return Err(format!("The volume directory is full, can't add {name}"));
+            }
+            None => {
+                let new_block = Self::allocate(&mut self.image)?;
+                let previous = *blocks.last().unwrap();
+                set_word(&mut self.image, previous * BLOCK_SIZE + 2, new_block);
+                set_word(&mut self.image, new_block * BLOCK_SIZE, previous);
+                // The entry of the subdirectory in its parent
+                let header = key_block * BLOCK_SIZE + FIRST_ENTRY;
+                let parent_block = word(&self.image, header + 0x23);
+                let parent_entry = parent_block * BLOCK_SIZE + FIRST_ENTRY
+                    + (self.image[header + 0x25] as usize - 1) * ENTRY_LENGTH;
+                set_word(&mut self.image, parent_entry + 0x13, blocks.len() + 1);
+                set_eof(&mut self.image, parent_entry, (blocks.len() + 1) * BLOCK_SIZE);
+                new_block * BLOCK_SIZE + FIRST_ENTRY
+            }
+        };
+        self.image[offset..offset + ENTRY_LENGTH].fill(0);
+        write_name(&mut self.image, offset, storage_type, name);
+        set_word(&mut self.image, offset + 0x25, key_block);
+        let header = key_block * BLOCK_SIZE + FIRST_ENTRY;
+        let file_count = word(&self.image, header + 0x21);
+        set_word(&mut self.image, header + 0x21, file_count + 1);
+        Ok(offset)
+    }
+}
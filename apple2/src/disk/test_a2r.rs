@@ -0,0 +1,152 @@
+use std::fs;
+use crate::disk::a2r::*;
+use crate::disk::bit_stream::TrackType;
+use crate::disk::disk::PDisk;
+use crate::disk::disk_controller::DSK_SIZE_BYTES;
+use crate::disk::dsk::{Dsk, SectorOrder};
+use crate::disk::test_woz::bits_to_delays;
+use crate::disk::woz::Woz;
+use crate::test_util::temp_path;
+
+/// The flux transitions of the 35 tracks of master.dsk
+fn master_delays() -> Vec<Vec<u32>> {
+    let dsk = Dsk::new_with_file("files/master.dsk", false).unwrap();
+    (0..35).map(|track| bits_to_delays(dsk.bit_streams().get_stream(track * 4), 32)).collect()
+}
+
+/// Two revolutions and a half, the later ones with some jitter
+fn extended(delays: &[u32]) -> Vec<u32> {
+    let mut result = delays.to_vec();
+    for (i, d) in delays.iter().chain(delays[0..delays.len() / 2].iter()).enumerate() {
+        result.push(if i % 3 == 0 { d + 1 } else { *d });
+    }
+    result
+}
+
+fn header(version: u8, chunks: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut result = vec![b'A', b'2', b'R', version, 0xff, 0xa, 0xd, 0xa];
+    for (name, content) in chunks {
+        result.extend(name.as_bytes());
+        result.extend((content.len() as u32).to_le_bytes());
+        result.extend(content);
+    }
+    result
+}
+
+fn info_chunk() -> Vec<u8> {
+    let mut result = vec![1];
+    result.extend(format!("{: <32}", "Test").as_bytes());
+    result.extend([1, 1, 1]);  // 5.25, write protected, synchronized
+    result
+}
+
+#[test]
+pub fn load_a2r_v2() {
+    let tracks = master_delays();
+    let mut strm = Vec::new();
+    for (track, delays) in tracks.iter().enumerate() {
+        // Track 2 never repeats itself
+        let data = if track == 2 {
+            let mut seed = 1_u32;
+            (0..60_000).map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                20 + (seed >> 16) % 60
+            }).collect()
+        } else {
+            extended(delays)
+        };
+        let data = Woz::delays_to_bytes(&data);
+        strm.push((track * 4) as u8);
+        strm.push(3);  // xtiming
+        strm.extend((data.len() as u32).to_le_bytes());
+        strm.extend(0_u32.to_le_bytes());  // no estimated loop point
+        strm.extend(data);
+    }
+    strm.push(0xff);
+    let path = temp_path("master.a2r");
+    fs::write(&path, header(b'2', &[("INFO", info_chunk()), ("STRM", strm),
+        ("META", b"title\tDOS 3.3 Master\n".to_vec())])).unwrap();
+
+    let a2r = A2r::new_with_file(&path, false).unwrap();
+    assert_eq!(a2r.disk_info().name(), "DOS 3.3 Master");
+    assert_eq!(a2r.unresolved, [8]);
+    let streams = a2r.bit_streams();
+    assert_eq!(streams.get_stream(4).flux_delays().unwrap(), tracks[1]);
+    // The quarter-tracks around a track read it
+    assert_eq!(streams.get_stream(5).flux_delays().unwrap(), tracks[1]);
+    assert!(! streams.get_stream(8).is_flux());
+    assert_eq!(streams.get_stream(12).analyze_track().track_type, TrackType::Standard);
+    assert_eq!(A2r::track_names(&[8, 13, 70]), "2, 3.25, 17.50");
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+pub fn convert_a2r_v3() {
+    // 62.5ns ticks, the delays are twice as long
+    let tracks = master_delays();
+    let mut rwcp = vec![1];
+    rwcp.extend(62_500_u32.to_le_bytes());
+    rwcp.extend([0; 11]);
+    for (track, delays) in tracks.iter().enumerate() {
+        let doubled: Vec<u32> = extended(delays).iter().map(|d| d * 2).collect();
+        let data = Woz::delays_to_bytes(&doubled);
+        let revolution: u32 = delays.iter().sum::<u32>() * 2;
+        rwcp.push(b'C');
+        rwcp.push(3);
+        rwcp.extend((track as u16 * 4).to_le_bytes());
+        // Two index signals, one revolution apart
+        rwcp.push(2);
+        rwcp.extend(100_u32.to_le_bytes());
+        rwcp.extend((100 + revolution).to_le_bytes());
+        rwcp.extend((data.len() as u32).to_le_bytes());
+        rwcp.extend(data);
+    }
+    rwcp.push(b'X');
+    let path = temp_path("master3.a2r");
+    let mut info = info_chunk();
+    info.push(0);  // soft sectored
+    fs::write(&path, header(b'3', &[("INFO", info), ("RWCP", rwcp)])).unwrap();
+
+    let woz_path = a2r_to_woz(&path).unwrap();
+    assert!(woz_path.ends_with("master3.woz"));
+    let woz = Woz::new_with_file(&woz_path, false).unwrap();
+    assert_eq!(woz.bit_streams().get_stream(8).flux_delays().unwrap(), tracks[2]);
+    let mut dsk = vec![0; DSK_SIZE_BYTES];
+    Dsk::decode_sectors(woz.bit_streams(), SectorOrder::Dos, &mut dsk);
+    assert!(dsk == fs::read("files/master.dsk").unwrap());
+
+    assert!(A2r::new_with_file("files/master.dsk", false).is_err());
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(woz_path);
+}
+
+#[test]
+pub fn convert_quarter_tracks() {
+    // 141 different captures, more flux and bit tracks than TRKS can hold
+    let tracks = master_delays();
+    let captures: Vec<Vec<u32>> = (0..=140).map(|location| {
+        let mut delays = tracks[(location / 4).min(34)].clone();
+        delays.rotate_left(location);
+        delays
+    }).collect();
+    let mut strm = Vec::new();
+    for (location, delays) in captures.iter().enumerate() {
+        let data = Woz::delays_to_bytes(&extended(delays));
+        strm.push(location as u8);
+        strm.push(3);
+        strm.extend((data.len() as u32).to_le_bytes());
+        strm.extend(0_u32.to_le_bytes());
+        strm.extend(data);
+    }
+    strm.push(0xff);
+    let path = temp_path("quarter.a2r");
+    fs::write(&path, header(b'2', &[("INFO", info_chunk()), ("STRM", strm)])).unwrap();
+
+    let woz_path = a2r_to_woz(&path).unwrap();
+    let woz = Woz::new_with_file(&woz_path, false).unwrap();
+    for phase in [0, 5, 70, 139, 140] {
+        assert_eq!(woz.bit_streams().get_stream(phase).flux_delays().unwrap(), captures[phase]);
+    }
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(woz_path);
+}
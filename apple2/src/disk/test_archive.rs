@@ -0,0 +1,206 @@
+use std::fs;
+use std::path::Path;
+use crate::disk::archive::*;
+use crate::disk::convert::read_blocks;
+use crate::disk::disk::Disk;
+use crate::disk::disk_info::DiskInfo;
+use crate::disk::dsk::{Dsk, SectorOrder};
+use crate::disk::hard_drive::HardDriveImage;
+use crate::disk::prodos::ProDos;
+use crate::test_util::temp_dir;
+
+fn master() -> Vec<u8> {
+    fs::read("files/master.dsk").unwrap()
+}
+
+#[test]
+pub fn zip_and_gzip() {
+    assert_eq!(members("files/disks.zip").unwrap(), [
+        Member { name: "master.dsk".to_string(), size: 143_360 },
+        Member { name: "Extras/README.txt".to_string(), size: 24 },
+    ]);
+    assert_eq!(read("files/disks.zip/master.dsk").unwrap(), master());
+    assert_eq!(read("files/disks.zip/Extras/README.txt").unwrap(), b"The DOS 3.3 master disk\n");
+    assert!(read("files/disks.zip/nope.dsk").is_err());
+
+    assert_eq!(members("files/master.dsk.gz").unwrap(), [Member { name: "master.dsk".to_string(), size: 143_360 }]);
+    assert_eq!(read("files/master.dsk.gz/master.dsk").unwrap(), master());
+    assert_eq!(file_size("files/master.dsk.gz/master.dsk"), Some(143_360));
+}
+
+/// Inflating stops past the size declared by the archive
+#[test]
+pub fn larger_than_declared() {
+    let dir = temp_dir("declared");
+    let mut gz = fs::read("files/master.dsk.gz").unwrap();
+    let end = gz.len();
+    gz[end - 4..].copy_from_slice(&1000_u32.to_le_bytes());
+    fs::write(format!("{dir}/master.dsk.gz"), gz).unwrap();
+    assert!(read(&format!("{dir}/master.dsk.gz/master.dsk")).unwrap_err().to_string().contains("larger"));
+
+    // The size of master.dsk in the central directory
+    let mut zip = fs::read("files/disks.zip").unwrap();
+    let entry = (0..zip.len() - 56).find(|i| zip[*i..*i + 4] == [0x50, 0x4b, 1, 2]
+        && zip[*i + 46..].starts_with(b"master.dsk")).unwrap();
+    zip[entry + 24..entry + 28].copy_from_slice(&1000_u32.to_le_bytes());
+    fs::write(format!("{dir}/disks.zip"), zip).unwrap();
+    assert!(read(&format!("{dir}/disks.zip/master.dsk")).unwrap_err().to_string().contains("larger"));
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+pub fn archive_paths() {
+    assert_eq!(split_path("files/disks.zip/Extras/README.txt"),
+        Some(("files/disks.zip".to_string(), "Extras/README.txt".to_string())));
+    assert_eq!(split_path("files/master.dsk"), None);
+    assert_eq!(split_path("files/nope.zip/master.dsk"), None);
+    assert_eq!(DiskInfo::overlay_path("files/disks.zip/Extras/Game.woz"), "files/disks.zip.Extras.Game.overlay.woz");
+}
+
+/// Writes go to the overlay next to the archive, which is never written to
+#[test]
+pub fn save_next_to_archive() {
+    let dir = temp_dir("archive");
+    let zip = format!("{dir}/disks.zip");
+    fs::copy("files/disks.zip", &zip).unwrap();
+    let path = format!("{zip}/master.dsk");
+    let overlay = format!("{dir}/disks.zip.master.overlay.dsk");
+
+    let mut disk = Disk::new(&path, false, None).unwrap();
+    assert_eq!(disk.disk_info().overlay(), Some(overlay.clone()));
+    disk.set_write_protected(false);
+    disk.set_bit_and_advance(8, 0);
+    disk.save();
+    assert_eq!(fs::read(&overlay).unwrap().len(), 143_360);
+    assert_eq!(fs::read(&zip).unwrap(), fs::read("files/disks.zip").unwrap());
+    assert!(disk.commit_overlay().is_err());
+
+    // The overlay is picked up the next time, and a new one is started once discarded
+    let mut disk = Disk::new(&path, false, None).unwrap();
+    assert_eq!(disk.disk_info().overlay(), Some(overlay.clone()));
+    disk.discard_overlay().unwrap();
+    assert!(! Path::new(&overlay).exists());
+    assert_eq!(disk.disk_info().overlay(), Some(overlay));
+    let _ = fs::remove_dir_all(dir);
+}
+
+/// A ShrinkIt disk image record, its 4K chunks stored with LZW/2 framing: the first one as
+/// LZW codes that are all literals, the others run length encoded when it makes them smaller
+fn shrinkit_disk(name: &str, blocks: &[u8]) -> Vec<u8> {
+    const DELIMITER: u8 = 0xdb;
+    let mut data = vec![0, DELIMITER];
+    for (i, chunk) in blocks.chunks(4096).enumerate() {
+        if i == 0 {
+            let mut bits: Vec<u8> = Vec::new();
+            for (j, byte) in chunk.iter().enumerate() {
+                // The first code doesn't add an entry to the table
+                let next_code = 0x101 + j.saturating_sub(1);
+                let width = match next_code + 1 { 0..=0x1ff => 9, 0x200..=0x3ff => 10, 0x400..=0x7ff => 11, _ => 12 };
+                bits.extend((0..width).map(|b| ((*byte as usize) >> b) as u8 & 1));
+            }
+            let codes: Vec<u8> = bits.chunks(8).map(|c| c.iter().rev().fold(0, |a, b| a << 1 | b)).collect();
+            data.extend(&(0x8000u16 | 4096).to_le_bytes());
+            data.extend(&(codes.len() as u16 + 4).to_le_bytes());
+            data.extend(codes);
+            continue;
+        }
+        let mut rle = Vec::new();
+        for run in chunk.chunk_by(|a, b| a == b) {
+            for part in run.chunks(256) {
+                if part.len() > 3 || part[0] == DELIMITER {
+                    rle.extend([DELIMITER, part[0], (part.len() - 1) as u8]);
+                } else {
+                    rle.extend(part);
+                }
+            }
+        }
+        let stored = if rle.len() < 4096 { rle } else { chunk.to_vec() };
+        data.extend(&(stored.len() as u16).to_le_bytes());
+        data.extend(stored);
+    }
+
+    let mut result = vec![0x4e, 0xf5, 0x46, 0xe9, 0x6c, 0xe5, 0, 0];
+    result.extend(1u32.to_le_bytes());
+    result.resize(48, 0);
+    // The record header, without its file name, then a file name thread and the disk thread
+    result.extend([0x4e, 0xf5, 0x46, 0xd8, 0, 0, 58, 0, 3, 0, 2, 0, 0, 0, 1, 0, b'/', 0]);
+    result.extend([0; 8]);
+    result.extend(((blocks.len() / 512) as u32).to_le_bytes());
+    result.extend(512u16.to_le_bytes());
+    result.extend([0; 24]);
+    result.extend([0, 0]);
+    for (class, format, kind, length, compressed_length) in [(3, 0, 0, name.len(), 32), (2, 3, 1, 0, data.len())] {
+        for word in [class, format, kind, 0u16] {
+            result.extend(word.to_le_bytes());
+        }
+        result.extend((length as u32).to_le_bytes());
+        result.extend((compressed_length as u32).to_le_bytes());
+    }
+    let mut file_name = name.as_bytes().to_vec();
+    file_name.resize(32, 0);
+    result.extend(file_name);
+    result.extend(data);
+    result
+}
+
+#[test]
+pub fn shrinkit_disk_image() {
+    let dir = temp_dir("shrinkit");
+    let blocks = Dsk::convert_order(&master(), SectorOrder::Dos, SectorOrder::Prodos);
+    let sdk = format!("{dir}/master.sdk");
+    fs::write(&sdk, shrinkit_disk("MASTER", &blocks)).unwrap();
+    assert_eq!(members(&sdk).unwrap(), [Member { name: "MASTER.po".to_string(), size: 143_360 }]);
+    assert_eq!(read(&format!("{sdk}/MASTER.po")).unwrap(), blocks);
+
+    // The size of disk images comes from the header, it can't be larger than a ProDOS volume
+    let mut huge = shrinkit_disk("HUGE", &blocks);
+    huge[48 + 26..48 + 30].copy_from_slice(&0xffff_u32.to_le_bytes());
+    huge[48 + 30..48 + 32].copy_from_slice(&0xffff_u16.to_le_bytes());
+    let huge_path = format!("{dir}/huge.sdk");
+    fs::write(&huge_path, huge).unwrap();
+    assert!(read(&format!("{huge_path}/HUGE.po")).unwrap_err().to_string().contains("too large"));
+
+    // A ProDOS hard drive inside a ShrinkIt archive, written to next to it
+    let volume = ProDos::format("ARCHIVED", 1600).unwrap();
+    let shk = format!("{dir}/hard.shk");
+    fs::write(&shk, shrinkit_disk("HARD", volume.image())).unwrap();
+    let path = format!("{shk}/HARD.po");
+    assert_eq!(ProDos::new(read_blocks(&path).unwrap()).unwrap().volume_name(), "ARCHIVED");
+    let mut image = HardDriveImage::new(&path).unwrap();
+    assert_eq!(image.block_count(), 1600);
+    image.write_block(1000, &[0x42; 512]).unwrap();
+    let sidecar = fs::read(format!("{dir}/hard.shk.HARD.overlay.po")).unwrap();
+    assert_eq!(sidecar[1000 * 512], 0x42);
+    assert_eq!(HardDriveImage::new(&path).unwrap().read_block(1000).unwrap(), [0x42; 512]);
+    let _ = fs::remove_dir_all(dir);
+}
+
+/// The content of both files of files/lzw.shk
+fn lzw_content() -> Vec<u8> {
+    // "abab..." makes the codes that are being defined (KwKwK) show up
+    let mut result = b"ab".repeat(1500);
+    for i in 0..3000 {
+        result.extend(format!("{i} squared is {}\n", i * i).bytes());
+    }
+    // Less repetitive: the codes grow to 12 bits, and the LZW/2 table fills up and is cleared
+    let mut seed = 1_u32;
+    for _ in 0..40_000 {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        result.push(b'A' + (seed >> 16) as u8 % 32);
+    }
+    result
+}
+
+/// files/lzw.shk has the same file compressed with LZW/1 and with LZW/2. It doesn't come from
+/// this decoder's tests: its encoder follows the NuFX documentation, with 12 bit codes, codes
+/// used as they are being defined, chunks continuing the LZW/2 table and clear codes.
+#[test]
+pub fn shrinkit_lzw() {
+    assert_eq!(members("files/lzw.shk").unwrap(), [
+        Member { name: "LZW1.TXT".to_string(), size: 112_427 },
+        Member { name: "LZW2.TXT".to_string(), size: 112_427 },
+    ]);
+    let content = lzw_content();
+    assert!(read("files/lzw.shk/LZW1.TXT").unwrap() == content);
+    assert!(read("files/lzw.shk/LZW2.TXT").unwrap() == content);
+}
@@ -0,0 +1,81 @@
+use crate::disk::bit_stream::{BitStream, TrackType};
+use crate::disk::d13::D13;
+use crate::disk::disk_controller::{SECTOR_SIZE_BYTES, TRACK_SIZE_BYTES};
+use crate::disk::dsk::{Dsk, SectorOrder};
+use crate::test_util::different_sectors;
+
+/// Overwrite the nibble that starts at `bit`, keeping the framing since its first bit is still 1
+fn set_nibble(bits: &mut [u8], bit: usize, value: u8) {
+    for i in 0..8 {
+        bits[bit + i] = (value >> (7 - i)) & 1;
+    }
+}
+
+#[test]
+pub fn decode_sectors() {
+    let content = different_sectors(TRACK_SIZE_BYTES);
+    let track = BitStream::new(Dsk::encode_track(&content, 5, SectorOrder::Dos)).analyze_track();
+    assert_eq!(track.track_type, TrackType::Standard);
+    assert_eq!(track.sectors.len(), 16);
+    for (physical, sector) in track.sectors.iter().enumerate() {
+        assert_eq!((sector.volume, sector.track, sector.sector), (0xfe, 5, physical as u8));
+        assert!(sector.address_checksum_ok && ! sector.five_and_three);
+        assert_eq!(sector.address_epilogue, [0xde, 0xaa, 0xeb]);
+        // 16 sync nibbles between the sectors, 32 before the first one since the track wraps
+        assert_eq!(sector.address_sync_bits, if physical == 0 { 64 } else { 32 });
+        let data = sector.data.as_ref().unwrap();
+        assert!(data.checksum_ok);
+        assert_eq!(data.epilogue, [0xde, 0xaa, 0xeb]);
+        assert_eq!(data.sync_bits, 14);
+        let logical = SectorOrder::Dos.logical_sector(physical) * SECTOR_SIZE_BYTES;
+        assert_eq!(data.bytes, content[logical..logical + SECTOR_SIZE_BYTES]);
+    }
+
+    let content = different_sectors(13 * SECTOR_SIZE_BYTES);
+    let track = BitStream::new(D13::encode_track(&content, 3)).analyze_track();
+    assert_eq!(track.track_type, TrackType::Standard);
+    assert_eq!(track.sectors.len(), 13);
+    assert!(track.sectors.iter().all(|s| s.five_and_three && s.is_standard()));
+    let sector = track.sectors.iter().find(|s| s.sector == 12).unwrap();
+    assert_eq!(sector.data.as_ref().unwrap().bytes, content[12 * SECTOR_SIZE_BYTES..]);
+}
+
+#[test]
+pub fn nonstandard_sectors() {
+    let content = different_sectors(TRACK_SIZE_BYTES);
+    let mut bits = Dsk::encode_track(&content, 0, SectorOrder::Dos);
+    // Sector 0: 16 sync nibbles, the address prologue and field, then its epilogue
+    let address_epilogue = 16 * 10 + 3 * 8 + 8 * 8;
+    set_nibble(&mut bits, address_epilogue + 8, 0xff);
+    // Then 7 sync nibbles and the data prologue before the data field
+    let data = address_epilogue + 3 * 8 + 7 * 10 + 3 * 8;
+    set_nibble(&mut bits, data, 0xff);
+
+    let track = BitStream::new(bits).analyze_track();
+    assert_eq!(track.track_type, TrackType::Nonstandard);
+    let sector = &track.sectors[0];
+    assert_eq!(sector.address_epilogue, [0xde, 0xff, 0xeb]);
+    assert!(sector.address_checksum_ok);
+    assert!(! sector.data.as_ref().unwrap().checksum_ok);
+    assert!(! sector.is_standard());
+    assert!(track.sectors[1..].iter().all(|s| s.is_standard()));
+
+    assert_eq!(BitStream::random().analyze_track().track_type, TrackType::Empty);
+    assert!(BitStream::random().analyze_track().sectors.is_empty());
+}
+
+#[test]
+pub fn rotated_track() {
+    let content = different_sectors(TRACK_SIZE_BYTES);
+    let bits = Dsk::encode_track(&content, 7, SectorOrder::Dos);
+    for rotation in [1000, 20_003, bits.len() - 5] {
+        let mut rotated = bits.clone();
+        rotated.rotate_left(rotation);
+        let track = BitStream::new(rotated).analyze_track();
+        assert_eq!(track.track_type, TrackType::Standard, "rotated by {rotation}");
+        for sector in &track.sectors {
+            let logical = SectorOrder::Dos.logical_sector(sector.sector as usize) * SECTOR_SIZE_BYTES;
+            assert_eq!(sector.data.as_ref().unwrap().bytes, content[logical..logical + SECTOR_SIZE_BYTES]);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::fs;
+use crate::disk::bit_stream::BitStream;
+use crate::disk::convert::{convert, create_disk, read_blocks, read_dos_sectors, NewDisk};
+use crate::disk::d13::D13_SIZE_BYTES;
+use crate::disk::disk::PDisk;
+use crate::disk::disk_controller::SECTOR_SIZE_BYTES;
+use crate::disk::dos33::Dos33;
+use crate::disk::dsk::{Dsk, WRITE_TABLE};
+use crate::disk::prodos::ProDos;
+use crate::disk::woz::{InfoChunk, Woz};
+use crate::test_util::temp_path;
+
+/// Replace the 10th nibble of the first data field of the stream with another valid nibble
+fn corrupt_data_field(stream: &BitStream) -> BitStream {
+    let mut bits = stream.bits().to_vec();
+    let mut prologue = Vec::new();
+    Dsk::write8(&mut prologue, vec![0xd5, 0xaa, 0xad]);
+    let start = bits.windows(prologue.len()).position(|w| w == prologue).unwrap() + prologue.len() + 9 * 8;
+    let value = bits[start..start + 8].iter().fold(0, |a, b| a << 1 | b);
+    let index = WRITE_TABLE.iter().position(|n| *n == value).unwrap();
+    let mut other = Vec::new();
+    Dsk::write8(&mut other, vec![WRITE_TABLE[(index + 1) % WRITE_TABLE.len()]]);
+    bits.splice(start..start + 8, other);
+    BitStream::new(bits)
+}
+
+#[test]
+pub fn dsk_woz_nib_round_trip() {
+    let (woz, nib, po, two_mg, dsk) = (temp_path("master.woz"), temp_path("master.nib"),
+        temp_path("master.po"), temp_path("master.2mg"), temp_path("master.dsk"));
+    assert!(convert("files/master.dsk", &woz, false).unwrap().is_empty());
+    assert!(convert(&woz, &nib, false).unwrap().is_empty());
+    assert!(convert(&nib, &po, false).unwrap().is_empty());
+    assert!(convert(&po, &two_mg, false).unwrap().is_empty());
+    assert!(convert(&two_mg, &dsk, false).unwrap().is_empty());
+    assert!(fs::read(&dsk).unwrap() == fs::read("files/master.dsk").unwrap());
+    assert_eq!(&fs::read(&two_mg).unwrap()[0..4], b"2IMG");
+
+    assert!(convert(&woz, &temp_path("master.a2r"), true).is_err());
+    assert!(convert(&dsk, &temp_path("master.d13"), true).is_err());
+    for path in [woz, nib, po, two_mg, dsk] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Sectors that cross the start of the track decode as well as the others
+#[test]
+pub fn rotated_tracks() {
+    let woz = temp_path("rotated.woz");
+    let mut bit_streams = Dsk::new_with_file("files/master.dsk", false).unwrap().bit_streams().clone();
+    for (phase, stream) in bit_streams.bit_streams.iter_mut().enumerate() {
+        if stream.random {
+            continue;
+        }
+        let mut bits = stream.bits().to_vec();
+        bits.rotate_left(if phase % 8 < 4 { 1000 } else { 20_003 });
+        *stream = BitStream::new(bits);
+    }
+    fs::write(&woz, Woz::to_bytes(&InfoChunk::default(), &HashMap::new(), &bit_streams).unwrap()).unwrap();
+    assert!(read_dos_sectors(&woz).unwrap() == fs::read("files/master.dsk").unwrap());
+    let _ = fs::remove_file(woz);
+}
+
+#[test]
+pub fn preserve_meta() {
+    let (source, destination) = (temp_path("meta.woz"), temp_path("meta-copy.woz"));
+    let bit_streams = Dsk::new_with_file("files/master.dsk", false).unwrap().bit_streams().clone();
+    let mut info = InfoChunk::default();
+    info.compatible_hardware = 0x1ff;
+    let meta: HashMap<String, String> = [("title", "DOS 3.3 Master"), ("publisher", "Apple Computer, Inc."),
+        ("notes", "Émulé")].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    fs::write(&source, Woz::to_bytes(&info, &meta, &bit_streams).unwrap()).unwrap();
+
+    convert(&source, &destination, false).unwrap();
+    let woz = Woz::new_with_file(&destination, false).unwrap();
+    assert_eq!(woz.meta, meta);
+    assert_eq!(woz.info().compatible_hardware, 0x1ff);
+    assert_eq!(woz.title(), Some("DOS 3.3 Master".to_string()));
+    let _ = fs::remove_file(source);
+    let _ = fs::remove_file(destination);
+}
+
+#[test]
+pub fn refuse_lossy_conversions() {
+    let (woz, dsk) = (temp_path("bad.woz"), temp_path("bad.dsk"));
+    let mut bit_streams = Dsk::new_with_file("files/master.dsk", false).unwrap().bit_streams().clone();
+    // Track 3 has a bad data checksum
+    let corrupted = corrupt_data_field(&bit_streams.bit_streams[12]);
+    for phase in 11..=13 {
+        bit_streams.bit_streams[phase] = corrupted.clone();
+    }
+    fs::write(&woz, Woz::to_bytes(&InfoChunk::default(), &HashMap::new(), &bit_streams).unwrap()).unwrap();
+    let error = convert(&woz, &dsk, false).unwrap_err();
+    assert!(error.contains("Track 3: bad checksum in the data field"), "{error}");
+    assert!(fs::metadata(&dsk).is_err());
+    let lost = convert(&woz, &dsk, true).unwrap();
+    assert!(lost.iter().any(|l| l.starts_with("Track 3: sectors")), "{lost:?}");
+    assert_eq!(fs::read(&dsk).unwrap().len(), fs::read("files/master.dsk").unwrap().len());
+
+    // Quarter track with its own content
+    let mut bit_streams = Dsk::new_with_file("files/master.dsk", false).unwrap().bit_streams().clone();
+    bit_streams.bit_streams[2] = bit_streams.bit_streams[8].clone();
+    bit_streams.tmap[2] = 2;
+    fs::write(&woz, Woz::to_bytes(&InfoChunk::default(), &HashMap::new(), &bit_streams).unwrap()).unwrap();
+    let error = convert(&woz, &temp_path("bad.nib"), false).unwrap_err();
+    assert!(error.contains("Track 0.50 has its own content"), "{error}");
+    let _ = fs::remove_file(woz);
+    let _ = fs::remove_file(dsk);
+}
+
+#[test]
+pub fn d13_round_trip() {
+    let (d13, woz, copy) = (temp_path("disk.d13"), temp_path("disk13.woz"), temp_path("copy.d13"));
+    let content: Vec<u8> = (0..D13_SIZE_BYTES).map(|i| (i / SECTOR_SIZE_BYTES * 7 + i) as u8).collect();
+    fs::write(&d13, &content).unwrap();
+    assert!(convert(&d13, &woz, false).unwrap().is_empty());
+    assert_eq!(Woz::new_with_file(&woz, false).unwrap().info().boot_sector_format, 2);
+    assert!(convert(&woz, &copy, false).unwrap().is_empty());
+    assert!(fs::read(&copy).unwrap() == content);
+    assert!(convert(&woz, &temp_path("disk13.dsk"), true).is_ok_and(|lost| ! lost.is_empty()));
+    for path in [d13, woz, copy, temp_path("disk13.dsk")] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+pub fn create_disks() {
+    let (dsk, woz, hdv) = (temp_path("new.dsk"), temp_path("new.woz"), temp_path("new.hdv"));
+    create_disk(&dsk, &NewDisk::Dos33(254), 280, false).unwrap();
+    assert_eq!(Dos33::new(read_dos_sectors(&dsk).unwrap()).unwrap().volume(), 254);
+    assert!(create_disk(&dsk, &NewDisk::Unformatted, 280, false).is_err());
+    // Once confirmed, the existing disk is replaced
+    create_disk(&dsk, &NewDisk::ProDos("AGAIN".to_string()), 280, true).unwrap();
+    assert_eq!(ProDos::new(read_blocks(&dsk).unwrap()).unwrap().volume_name(), "AGAIN");
+
+    create_disk(&woz, &NewDisk::ProDos("blank".to_string()), 280, false).unwrap();
+    assert_eq!(ProDos::new(read_blocks(&woz).unwrap()).unwrap().volume_name(), "BLANK");
+    let _ = fs::remove_file(&woz);
+    // Nothing can be read from the tracks, but they can be written to
+    create_disk(&woz, &NewDisk::Unformatted, 280, false).unwrap();
+    let woz_disk = Woz::new_with_file(&woz, true).unwrap();
+    assert!((0..35).all(|track| woz_disk.bit_streams().tmap[track * 4] != 0xff));
+    assert!(read_dos_sectors(&woz).unwrap().iter().all(|b| *b == 0));
+
+    create_disk(&hdv, &NewDisk::ProDos("HARD".to_string()), 65_535, false).unwrap();
+    assert_eq!(fs::metadata(&hdv).unwrap().len(), 65_535 * 512);
+    assert_eq!(ProDos::new(read_blocks(&hdv).unwrap()).unwrap().block_count(), 65_535);
+    assert!(create_disk(&temp_path("dos.hdv"), &NewDisk::Dos33(1), 1600, false).is_err());
+    assert!(create_disk(&temp_path("hard.woz"), &NewDisk::ProDos("HARD".to_string()), 1600, false).is_err());
+    for path in [dsk, woz, hdv] {
+        let _ = fs::remove_file(path);
+    }
+}
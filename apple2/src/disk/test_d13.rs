@@ -0,0 +1,66 @@
+use std::fs;
+use std::sync::Arc;
+use crate::Apple2;
+use crate::card::CardType;
+use crate::config_file::ConfigFile;
+use crate::context::EmulatorContext;
+use crate::disk::bit_stream::{AreaType, TrackType};
+use crate::disk::d13::*;
+use crate::disk::disk::PDisk;
+use crate::disk::disk_controller::SECTOR_SIZE_BYTES;
+use crate::test_util::{different_sectors, temp_path};
+
+#[test]
+pub fn encode_5_and_3() {
+    for sector in different_sectors(D13_SIZE_BYTES).chunks(SECTOR_SIZE_BYTES).take(20) {
+        let nibbles = D13::encode_5_and_3(sector);
+        assert_eq!(nibbles.len(), DATA_FIELD_SIZE_13);
+        assert!(nibbles.iter().all(|n| WRITE_TABLE_53.contains(n)));
+        assert_eq!(D13::decode_5_and_3(&nibbles), sector);
+    }
+}
+
+#[test]
+pub fn load_and_save_d13() {
+    let path = temp_path("disk.d13");
+    let content = different_sectors(D13_SIZE_BYTES);
+    fs::write(&path, &content).unwrap();
+    let mut d13 = D13::new_with_file(&path, false).unwrap();
+
+    let track = d13.bit_streams().get_stream(0).analyze_track();
+    assert_eq!(track.track_type, TrackType::Standard);
+    let prologue = track.nibbles.iter().position(|n| n.area_type == AreaType::AddressPrologue).unwrap();
+    let values: Vec<u8> = track.nibbles[prologue..prologue + 3].iter().map(|n| n.value).collect();
+    assert_eq!(values, [0xd5, 0xaa, 0xb5]);
+
+    // Saving decodes the bit streams back into the same file
+    fs::write(&path, vec![0; D13_SIZE_BYTES]).unwrap();
+    d13.save();
+    assert!(fs::read(&path).unwrap() == content);
+
+    fs::write(&path, &content[0..1000]).unwrap();
+    assert!(D13::new_with_file(&path, false).is_err());
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+pub fn boot_13_sector_rom() {
+    // Sector 0 writes the slot found in X to the screen and loops
+    let mut content = different_sectors(D13_SIZE_BYTES);
+    let boot = [0x01, 0x8e, 0x00, 0x04, 0x4c, 0x04, 0x03]; // STX $0400, JMP $0304
+    content[0..boot.len()].copy_from_slice(&boot);
+    let path = temp_path("boot.d13");
+    fs::write(&path, &content).unwrap();
+
+    // The slots are only set in the config file, like a user would
+    let mut config = serde_json::to_value(ConfigFile::default()).unwrap();
+    config["slots"][6] = serde_json::to_value(CardType::Disk2Sector13).unwrap();
+    let config: ConfigFile = serde_json::from_value(config).unwrap();
+    let mut apple2 = Apple2::new(config, [None, None], Arc::new(EmulatorContext::default()));
+    apple2.insert_disk(0, &path).unwrap();
+    // Moving the head to track 0 takes about 1.5 second
+    apple2.step_frames(150);
+    assert_eq!(apple2.peek(0x400), 0x60);
+    assert!(apple2.main_memory()[0x300..0x400] == content[0..SECTOR_SIZE_BYTES]);
+    let _ = fs::remove_file(path);
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use crate::disk::convert::{convert, read_dos_sectors, write_dos_sectors};
+use crate::disk::dos33::*;
+use crate::test_util::temp_path;
+
+fn master() -> Dos33 {
+    Dos33::new(read_dos_sectors("files/master.dsk").unwrap()).unwrap()
+}
+
+#[test]
+pub fn read_catalog() {
+    let dos = master();
+    let catalog = dos.catalog().unwrap();
+    let names: Vec<String> = catalog.iter().map(|e| e.to_string()).collect();
+    assert_eq!(names, [" A 002 HELLO", " B 066 AWIN.ROM", " B 018 C000", " B 003 C600", " B 005 A800"]);
+    assert_eq!(dos.volume(), 254);
+
+    let c600 = dos.extract(&dos.find("C600").unwrap()).unwrap();
+    assert_eq!(c600.address, Some(0xc600));
+    assert_eq!(c600.data.len(), 256);
+    // The Disk II boot PROM
+    assert_eq!(&c600.data[0..4], &[0xa2, 0x20, 0xa0, 0x00]);
+    assert!(dos.find("NOPE").is_err());
+}
+
+#[test]
+pub fn add_and_delete_files() {
+    let mut dos = master();
+    let free = dos.free_sector_count();
+
+    let binary: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    dos.add_file("PROGRAM", FileType::Binary, &binary, 0x6000).unwrap();
+    // 1000 bytes and 4 bytes of header: 4 sectors and a track/sector list
+    assert_eq!(dos.free_sector_count(), free - 5);
+    let entry = dos.find("PROGRAM").unwrap();
+    assert_eq!(entry.sector_count, 5);
+    assert_eq!(dos.extract(&entry).unwrap(), FileContent { data: binary, address: Some(0x6000) });
+
+    // More than 122 sectors need a second track/sector list
+    let text: Vec<u8> = (0..130 * 256).map(|i| 0xc1 + (i % 26) as u8).collect();
+    dos.add_file("LONG TEXT", FileType::Text, &text, 0).unwrap();
+    let entry = dos.find("LONG TEXT").unwrap();
+    assert_eq!(entry.sector_count, 132);
+    assert_eq!(dos.extract(&entry).unwrap().data, text);
+
+    assert!(dos.add_file("PROGRAM", FileType::Binary, &[], 0).is_err());
+    assert!(dos.add_file("1BAD,NAME", FileType::Text, &[], 0).is_err());
+    assert!(dos.add_file("HUGE", FileType::S, &vec![0; 500 * 256], 0).is_err());
+
+    dos.delete_file("LONG TEXT").unwrap();
+    dos.delete_file("PROGRAM").unwrap();
+    assert_eq!(dos.free_sector_count(), free);
+    assert_eq!(dos.catalog().unwrap().len(), 5);
+    assert!(dos.delete_file("PROGRAM").is_err());
+
+    // The entries of the deleted files are reused
+    dos.add_file("HI", FileType::Applesoft, &[1, 2, 3], 0).unwrap();
+    assert_eq!(dos.catalog().unwrap()[5].name, "HI");
+    assert_eq!(dos.extract(&dos.find("HI").unwrap()).unwrap().data, [1, 2, 3]);
+}
+
+#[test]
+pub fn write_back_to_woz() {
+    let woz = temp_path("dos33.woz");
+    convert("files/master.dsk", &woz, false).unwrap();
+    let mut dos = Dos33::new(read_dos_sectors(&woz).unwrap()).unwrap();
+    dos.add_file("NEW", FileType::Binary, &[0x60], 0x300).unwrap();
+    write_dos_sectors(&woz, dos.image()).unwrap();
+
+    let dos = Dos33::new(read_dos_sectors(&woz).unwrap()).unwrap();
+    assert_eq!(dos.extract(&dos.find("NEW").unwrap()).unwrap().data, [0x60]);
+    assert_eq!(dos.catalog().unwrap().len(), 6);
+    // The quarter tracks on both sides of each track were updated with it: otherwise, the
+    // .dsk conversion would lose them
+    let dsk = temp_path("dos33.dsk");
+    assert!(convert(&woz, &dsk, false).unwrap().is_empty());
+    let _ = fs::remove_file(woz);
+    let _ = fs::remove_file(dsk);
+}
+
+#[test]
+pub fn host_names() {
+    let dos = master();
+    let c600 = dos.find("C600").unwrap();
+    assert_eq!(host_name(&c600, &dos.extract(&c600).unwrap()), "C600#06c600");
+    let hello = dos.find("HELLO").unwrap();
+    assert_eq!(host_name(&hello, &dos.extract(&hello).unwrap()), "HELLO#fc0000");
+
+    assert_eq!(parse_host_name("C600#06c600"), ("C600".to_string(), FileType::Binary, 0xc600));
+    assert_eq!(parse_host_name("hello#fc0801"), ("HELLO".to_string(), FileType::Applesoft, 0x801));
+    assert_eq!(parse_host_name("notes#040000"), ("NOTES".to_string(), FileType::Text, 0));
+    assert_eq!(parse_host_name("game.bin"), ("GAME.BIN".to_string(), FileType::Binary, 0x2000));
+}
+
+#[test]
+pub fn format_disk() {
+    let mut dos = Dos33::new(Dos33::format(42).image().to_vec()).unwrap();
+    assert_eq!(dos.volume(), 42);
+    assert!(dos.catalog().unwrap().is_empty());
+    // Tracks 3 to 34 but the catalog track
+    assert_eq!(dos.free_sector_count(), 31 * 16);
+    // 15 catalog sectors of 7 entries
+    for i in 0..105 {
+        dos.add_file(&format!("F{i}"), FileType::Text, &[], 0).unwrap();
+    }
+    assert_eq!(dos.catalog().unwrap().len(), 105);
+    assert!(dos.add_file("ONE.MORE", FileType::Text, &[], 0).is_err());
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use crate::disk::disk::{Disk, PDisk};
+use crate::disk::disk_controller::{DSK_SIZE_BYTES, TRACK_SIZE_BYTES};
+use crate::disk::dsk::{Dsk, SectorOrder};
+use crate::disk::prodos::VolumeBuilder;
+use crate::test_util::{different_sectors, temp_path};
+
+/// A blank ProDOS floppy, in ProDOS order
+fn prodos_floppy() -> Vec<u8> {
+    VolumeBuilder::new("BLANK", 280, [0; 4]).unwrap().finish()
+}
+
+#[test]
+pub fn detect_order() {
+    use SectorOrder::*;
+    let po = prodos_floppy();
+    assert_eq!(po.len(), DSK_SIZE_BYTES);
+    assert_eq!(SectorOrder::detect(&po), Some(Prodos));
+    assert_eq!(SectorOrder::detect(&Dsk::convert_order(&po, Prodos, Dos)), Some(Dos));
+
+    let dos = fs::read("files/master.dsk").unwrap();
+    assert_eq!(SectorOrder::detect(&dos), Some(Dos));
+    assert_eq!(SectorOrder::detect(&Dsk::convert_order(&dos, Dos, Prodos)), Some(Prodos));
+    assert_eq!(Dsk::convert_order(&Dsk::convert_order(&dos, Dos, Prodos), Prodos, Dos), dos);
+
+    assert_eq!(SectorOrder::detect(&vec![0; DSK_SIZE_BYTES]), None);
+    assert_eq!(SectorOrder::of_file("blank.dsk", &vec![0; DSK_SIZE_BYTES]), Dos);
+    assert_eq!(SectorOrder::of_file("game.PO", &dos), Prodos);
+}
+
+#[test]
+pub fn load_and_save_po() {
+    let content = different_sectors(DSK_SIZE_BYTES);
+    for (name, order) in [("disk.po", SectorOrder::Prodos), ("disk.do", SectorOrder::Dos)] {
+        let path = temp_path(name);
+        fs::write(&path, &content).unwrap();
+        let mut dsk = Dsk::new_with_file(&path, false).unwrap();
+        assert_eq!(dsk.order(), order);
+
+        // Saving decodes the bit streams back into the same file
+        fs::write(&path, vec![0; DSK_SIZE_BYTES]).unwrap();
+        dsk.save();
+        assert!(fs::read(&path).unwrap() == content, "{name} wasn't saved in the right order");
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// The tracks past 35 of 40 track images are kept when saving
+#[test]
+pub fn save_40_tracks() {
+    let path = temp_path("40.dsk");
+    let mut content = fs::read("files/master.dsk").unwrap();
+    content.extend((0..5 * TRACK_SIZE_BYTES).map(|i| i as u8));
+    fs::write(&path, &content).unwrap();
+    let mut dsk = Dsk::new_with_file(&path, false).unwrap();
+    dsk.save();
+    assert!(fs::read(&path).unwrap() == content);
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+pub fn unknown_formats() {
+    assert!(Disk::new("files/smartport.rom", false, None).is_err());
+    let path = temp_path("short.dsk");
+    fs::write(&path, [0; 1000]).unwrap();
+    assert!(Disk::new(&path, false, None).is_err());
+    let _ = fs::remove_file(path);
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use crate::disk::bit_stream::BitStream;
+use crate::disk::disk::{Disk, PDisk};
+use crate::disk::disk_controller::{DSK_SIZE_BYTES, MAX_TRACK_DSK, TRACK_SIZE_BYTES};
+use crate::disk::dsk::{Dsk, SectorOrder};
+use crate::disk::nib::*;
+use crate::test_util::temp_path;
+
+/// master.dsk as a .nib
+pub(crate) fn master_nib() -> Vec<u8> {
+    let dsk = fs::read("files/master.dsk").unwrap();
+    (0..MAX_TRACK_DSK).flat_map(|track| {
+        let bits = Dsk::encode_track(&dsk[track * TRACK_SIZE_BYTES..(track + 1) * TRACK_SIZE_BYTES],
+            track as u8, SectorOrder::Dos);
+        Nib::bits_to_nibbles(&BitStream::new(bits))
+    }).collect()
+}
+
+#[test]
+pub fn decode_nib() {
+    let nib = master_nib();
+    assert_eq!(nib.len(), NIB_SIZE_BYTES);
+    let bit_streams = Nib::bytes_to_bit_streams(&nib);
+    let mut dsk = vec![0; DSK_SIZE_BYTES];
+    Dsk::decode_sectors(&bit_streams, SectorOrder::Dos, &mut dsk);
+    assert!(dsk == fs::read("files/master.dsk").unwrap());
+
+    // The gaps are made of 10 bit sync nibbles
+    let nibbles = bit_streams.get_stream(0).to_nibbles();
+    assert!(nibbles[0..5].iter().all(|n| n.value == 0xff && n.sync_bits == 2));
+
+    // Reads and writes go to the stream of the phase itself
+    let mut bit_streams = bit_streams;
+    bit_streams.set_bit_and_advance(9, 0, 1 - bit_streams.get_stream(9).next_bit(0));
+    let written = bit_streams.get_stream(9).next_bit(0);
+    assert_eq!(bit_streams.get_stream_mut(9).next_bit(0), written);
+    assert_ne!(bit_streams.get_stream(8).next_bit(0), written);
+}
+
+#[test]
+pub fn save_nib() {
+    let path = &temp_path("master.nib");
+    let nib = master_nib();
+    fs::write(path, &nib).unwrap();
+
+    let mut disk = Disk::new(path, false, None).unwrap();
+    assert_eq!(disk.get_stream(8).next_bit(0), 1);
+    disk.set_write_protected(false);
+    disk.set_bit_and_advance(8, 0);
+    assert_eq!(disk.get_stream(8).next_bit(0), 0);
+    assert_eq!(disk.get_stream(4).next_bit(0), 1);
+
+    let mut nib_disk = Nib::new_with_file(path, false).unwrap();
+    fs::write(path, []).unwrap();
+    nib_disk.save();
+    assert!(fs::read(path).unwrap() == nib);
+
+    assert!(Nib::new_with_file("files/master.dsk", false).is_err());
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+pub fn resize_tracks() {
+    // Too many nibbles: the longest gap shrinks
+    let mut nibbles = vec![0xd5, 0xaa, 0x96];
+    nibbles.extend(vec![0xff; 100]);
+    nibbles.extend(vec![0x96; NIB_TRACK_SIZE]);
+    let resized = Nib::bits_to_nibbles(&BitStream::new(Nib::nibbles_to_bits(&nibbles)));
+    assert_eq!(resized.len(), NIB_TRACK_SIZE);
+    assert_eq!(&resized[0..4], &[0xd5, 0xaa, 0x96, 0x96]);
+
+    // Not enough: the longest gap grows
+    let resized = Nib::bits_to_nibbles(&BitStream::new(Nib::nibbles_to_bits(&[0xd5, 0xff, 0xff, 0xaa])));
+    assert_eq!(resized.len(), NIB_TRACK_SIZE);
+    assert_eq!((resized[0], resized[NIB_TRACK_SIZE - 1]), (0xd5, 0xaa));
+}
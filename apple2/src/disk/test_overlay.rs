@@ -0,0 +1,104 @@
+use std::fs;
+use std::path::Path;
+use crate::disk::disk::Disk;
+use crate::disk::disk_info::DiskInfo;
+use crate::disk::test_nib::master_nib;
+use crate::test_util::temp_path;
+
+/// A fresh copy of master.dsk as a .nib, and the path of its overlay
+fn temp_nib(name: &str) -> (String, String) {
+    let path = temp_path(&format!("{name}.nib"));
+    fs::write(&path, master_nib()).unwrap();
+    let overlay = DiskInfo::overlay_path(&path);
+    let _ = fs::remove_file(&overlay);
+    (path, overlay)
+}
+
+/// Flip the first bit of track 2 and save. Disks that aren't .woz are write protected until
+/// they're unlocked.
+fn write_and_save(disk: &mut Disk) {
+    disk.set_write_protected(false);
+    assert_eq!(disk.get_stream(8).next_bit(0), 1);
+    disk.set_bit_and_advance(8, 0);
+    disk.save();
+}
+
+#[test]
+pub fn overlay_path() {
+    assert_eq!(DiskInfo::overlay_path("dir/Game.dsk"), "dir/Game.overlay.dsk");
+    assert_eq!(DiskInfo::overlay_path("Game"), "Game.overlay");
+    assert!(DiskInfo::is_overlay_path("dir/Game.overlay.dsk"));
+    assert!(! DiskInfo::is_overlay_path("dir/Game.dsk"));
+}
+
+#[test]
+pub fn write_protect() {
+    let mut disk = Disk::new("files/master.dsk", false, None).unwrap();
+    disk.set_write_protected(false);
+    assert!(! disk.is_write_protected());
+    disk.set_write_protected(true);
+    assert!(disk.is_write_protected());
+    assert!(disk.disk_info().is_write_protected());
+}
+
+/// Writes are dropped while the disk is write protected
+#[test]
+pub fn write_protected_writes() {
+    let (path, overlay) = temp_nib("protected");
+    let original = fs::read(&path).unwrap();
+    let mut disk = Disk::new(&path, false, None).unwrap();
+    disk.set_write_protected(true);
+    disk.set_bit_and_advance(8, 0);
+    assert_eq!(disk.get_stream(8).next_bit(0), 1);
+    disk.save();
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    write_and_save(&mut disk);
+    assert_ne!(fs::read(&path).unwrap(), original);
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(overlay);
+}
+
+#[test]
+pub fn commit_overlay() {
+    let (path, overlay) = temp_nib("commit");
+    let original = fs::read(&path).unwrap();
+
+    // Writes go to the overlay, the image is left alone
+    let mut disk = Disk::new(&path, false, None).unwrap();
+    disk.enable_overlay();
+    write_and_save(&mut disk);
+    assert!(fs::read(&path).unwrap() == original);
+    let written = fs::read(&overlay).unwrap();
+    assert!(written != original);
+
+    // The overlay is picked up the next time the image is loaded
+    let mut disk = Disk::new(&path, false, None).unwrap();
+    assert_eq!(disk.disk_info().path, path);
+    assert_eq!(disk.disk_info().overlay(), Some(overlay.clone()));
+
+    disk.commit_overlay().unwrap();
+    assert!(fs::read(&path).unwrap() == written);
+    assert!(! Path::new(&overlay).exists());
+    assert_eq!(disk.disk_info().overlay(), None);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+pub fn discard_overlay() {
+    let (path, overlay) = temp_nib("discard");
+    let original = fs::read(&path).unwrap();
+
+    let mut disk = Disk::new(&path, false, None).unwrap();
+    disk.enable_overlay();
+    write_and_save(&mut disk);
+    assert!(Path::new(&overlay).exists());
+    disk.set_write_protected(true);
+
+    disk.discard_overlay().unwrap();
+    assert!(! Path::new(&overlay).exists());
+    assert!(fs::read(&path).unwrap() == original);
+    assert_eq!(disk.get_stream(8).next_bit(0), 1);
+    assert!(disk.is_write_protected());
+    let _ = fs::remove_file(&path);
+}
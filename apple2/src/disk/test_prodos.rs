@@ -0,0 +1,107 @@
+use std::fs;
+use crate::disk::convert::{convert, read_blocks, write_blocks};
+use crate::disk::hard_drive::BLOCK_SIZE;
+use crate::disk::prodos::*;
+use crate::test_util::temp_path;
+
+fn attributes(file_type: u8, aux_type: u16) -> FileAttributes {
+    FileAttributes { file_type, aux_type, access: DEFAULT_ACCESS, modified: [0; 4] }
+}
+
+#[test]
+pub fn format_volume() {
+    let volume = ProDos::format("blank", 1600).unwrap();
+    assert_eq!(volume.volume_name(), "BLANK");
+    assert_eq!(volume.block_count(), 1600);
+    // Boot blocks, volume directory and bitmap
+    assert_eq!(volume.free_block_count(), 1600 - 7);
+    assert!(volume.list("").unwrap().is_empty());
+    assert!(ProDos::format("1BAD", 280).is_err());
+    assert!(ProDos::format("BIG", MAX_BLOCKS + 1).is_err());
+    assert!(ProDos::new(vec![0; 280 * BLOCK_SIZE]).is_err());
+    // The bitmap pointer of the volume header is in block 2, entry 1, offset $23
+    let mut image = volume.image().to_vec();
+    image[2 * BLOCK_SIZE + 4 + 0x23..][..2].copy_from_slice(&1600u16.to_le_bytes());
+    assert!(ProDos::new(image).err().unwrap().contains("bitmap"));
+}
+
+#[test]
+pub fn add_read_and_delete() {
+    let mut volume = ProDos::format("TEST", 1600).unwrap();
+    let free = volume.free_block_count();
+    let seedling = vec![0x42; 100];
+    let sapling: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+    let tree: Vec<u8> = (0..200_000).map(|i| (i / 512) as u8).collect();
+    volume.add_file("", "hello", &attributes(0xfc, 0x801), &seedling).unwrap();
+    volume.add_file("", "BIG", &attributes(BINARY_TYPE, 0x2000), &sapling).unwrap();
+    volume.add_directory("", "SUB").unwrap();
+    volume.add_file("SUB", "TREE", &attributes(BINARY_TYPE, 0), &tree).unwrap();
+
+    let hello = volume.find("HELLO").unwrap();
+    assert_eq!(hello.to_string(), " HELLO            BAS       1      100  A=$0801");
+    assert_eq!((hello.storage_type, hello.file_type, hello.aux_type), (SEEDLING, 0xfc, 0x801));
+    assert_eq!(volume.find("big").unwrap().storage_type, SAPLING);
+    assert_eq!(volume.find("/SUB/TREE").unwrap().storage_type, TREE);
+    assert_eq!(volume.read_file("HELLO").unwrap(), seedling);
+    assert_eq!(volume.read_file("BIG").unwrap(), sapling);
+    assert_eq!(volume.read_file("SUB/TREE").unwrap(), tree);
+    // 1 + 40 + 1 + 1 + 391 + 2 + 1 blocks
+    assert_eq!(volume.free_block_count(), free - 437);
+
+    assert!(volume.add_file("", "HELLO", &attributes(0, 0), &[]).is_err());
+    assert!(volume.add_file("HELLO", "NOPE", &attributes(0, 0), &[]).is_err());
+    assert!(volume.add_file("", "HUGE", &attributes(0, 0), &vec![0; 1600 * BLOCK_SIZE]).is_err());
+    assert!(volume.delete("SUB").is_err());
+    assert!(volume.read_file("SUB/NOPE").is_err());
+
+    volume.delete("SUB/TREE").unwrap();
+    volume.delete("SUB").unwrap();
+    volume.delete("BIG").unwrap();
+    volume.delete("HELLO").unwrap();
+    assert_eq!(volume.free_block_count(), free);
+    assert!(volume.list("").unwrap().is_empty());
+}
+
+#[test]
+pub fn grow_subdirectory() {
+    let mut volume = ProDos::format("TEST", 280).unwrap();
+    volume.add_directory("", "SUB").unwrap();
+    for i in 0..30 {
+        volume.add_file("SUB", &format!("FILE{i}"), &attributes(BINARY_TYPE, 0), &[i as u8]).unwrap();
+    }
+    let sub = volume.find("SUB").unwrap();
+    // 13 entries per block, the header included
+    assert_eq!((sub.blocks_used, sub.eof), (3, 3 * BLOCK_SIZE));
+    let names: Vec<String> = volume.list("SUB").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names.len(), 30);
+    assert_eq!(volume.read_file("SUB/FILE29").unwrap(), [29]);
+
+    // Deleted entries are reused
+    volume.delete("SUB/FILE3").unwrap();
+    volume.add_file("SUB", "NEW", &attributes(BINARY_TYPE, 0), &[]).unwrap();
+    assert_eq!(volume.list("SUB").unwrap()[3].name, "NEW");
+    assert_eq!(volume.find("SUB").unwrap().blocks_used, 3);
+}
+
+#[test]
+pub fn floppy_images() {
+    let (dsk, woz, po) = (temp_path("prodos.dsk"), temp_path("prodos.woz"), temp_path("prodos.po"));
+    let mut volume = ProDos::format("FLOPPY", 280).unwrap();
+    volume.add_file("", "DATA", &attributes(BINARY_TYPE, 0x300), &[1, 2, 3]).unwrap();
+    write_blocks(&dsk, volume.image()).unwrap();
+    convert(&dsk, &woz, false).unwrap();
+
+    // Edit the decoded WOZ and write it back
+    let mut volume = ProDos::new(read_blocks(&woz).unwrap()).unwrap();
+    assert_eq!(volume.read_file("DATA").unwrap(), [1, 2, 3]);
+    volume.add_file("", "MORE", &attributes(BINARY_TYPE, 0), &[4]).unwrap();
+    write_blocks(&woz, volume.image()).unwrap();
+
+    convert(&woz, &po, false).unwrap();
+    let volume = ProDos::new(fs::read(&po).unwrap()).unwrap();
+    assert_eq!(volume.volume_name(), "FLOPPY");
+    assert_eq!(volume.read_file("MORE").unwrap(), [4]);
+    for path in [dsk, woz, po] {
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::PathBuf;
+use crate::disk::hard_drive::{HardDriveImage, BLOCK_SIZE};
+use crate::disk::prodos::*;
+use crate::disk::virtual_volume::FILE_INFORMATION;
+use crate::test_util::temp_dir;
+
+/// A host directory with a seedling, a sapling, a tree and a subdirectory
+fn create_directory(name: &str) -> PathBuf {
+    let root = PathBuf::from(temp_dir(name));
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("HELLO#fc0801"), [0x42; 100]).unwrap();
+    fs::write(root.join("big.bin"), (0..20_000).map(|i| i as u8).collect::<Vec<u8>>()).unwrap();
+    fs::write(root.join("huge"), (0..200_000).map(|i| (i / 512) as u8).collect::<Vec<u8>>()).unwrap();
+    fs::write(root.join(".hidden"), "").unwrap();
+    fs::write(root.join("sub").join("notes.txt"), "NOTES\r").unwrap();
+    fs::write(root.join("sub").join(FILE_INFORMATION),
+        "NOTES.TXT=Type(04),AuxType(0000),VersionCreate(00),Access(C3)\n").unwrap();
+    root
+}
+
+fn volume(image: &HardDriveImage) -> Vec<u8> {
+    (0..image.block_count()).flat_map(|b| image.read_block(b).unwrap().to_vec()).collect()
+}
+
+/// Offset of the entry called `name` in a directory block
+fn entry_offset(block: &[u8], name: &str) -> usize {
+    (0..13).map(|slot| 4 + slot * 0x27)
+        .find(|&o| block[o] & 0xf == name.len() as u8 && &block[o + 1..o + 1 + name.len()] == name.as_bytes())
+        .unwrap()
+}
+
+/// Mark the block as free in the volume bitmap
+fn free_block(image: &mut HardDriveImage, block_number: usize) {
+    let header = image.read_block(VOLUME_DIRECTORY_BLOCK).unwrap();
+    let bitmap = u16::from_le_bytes([header[0x27], header[0x28]]) as usize + block_number / 8 / BLOCK_SIZE;
+    let mut block = image.read_block(bitmap).unwrap().to_vec();
+    block[block_number / 8 % BLOCK_SIZE] |= 0x80 >> (block_number % 8);
+    image.write_block(bitmap, &block).unwrap();
+}
+
+#[test]
+pub fn mount_directory() {
+    let root = create_directory("mount");
+    let image = HardDriveImage::new(root.to_str().unwrap()).unwrap();
+    let bytes = volume(&image);
+    assert_eq!(volume_header(&bytes).unwrap(), (format!("MAPLE2.{}.MOUNT", std::process::id())
+        .chars().take(15).collect(), MAX_BLOCKS));
+
+    let entries = read_directory(&bytes, VOLUME_DIRECTORY_BLOCK).unwrap();
+    let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, ["HELLO", "BIG.BIN", "HUGE", "SUB"]);
+    let types: Vec<(u8, u8, u16)> = entries.iter().map(|e| (e.storage_type, e.file_type, e.aux_type)).collect();
+    assert_eq!(types, [(SEEDLING, 0xfc, 0x0801), (SAPLING, BINARY_TYPE, 0), (TREE, BINARY_TYPE, 0),
+        (SUBDIRECTORY, DIRECTORY_TYPE, 0)]);
+    for entry in &entries[0..3] {
+        let host_name = if entry.name == "HELLO" { "HELLO#fc0801".to_string() } else { entry.name.to_lowercase() };
+        assert_eq!(read_file(&bytes, entry).unwrap(), fs::read(root.join(host_name)).unwrap());
+    }
+    assert_eq!(entries[2].blocks_used, 391 + 2 + 1);
+
+    let notes = read_directory(&bytes, entries[3].key_block).unwrap();
+    assert_eq!(notes.len(), 1);
+    assert_eq!((notes[0].name.as_str(), notes[0].file_type, notes[0].access), ("NOTES.TXT", 4, 0xc3));
+    assert_eq!(read_file(&bytes, &notes[0]).unwrap(), b"NOTES\r");
+
+    let _ = fs::remove_dir_all(root);
+}
+
+#[test]
+pub fn write_back_to_host() {
+    let root = create_directory("write");
+    let mut image = HardDriveImage::new(root.to_str().unwrap()).unwrap();
+    let entries = read_directory(&volume(&image), VOLUME_DIRECTORY_BLOCK).unwrap();
+
+    // Modify a file, the host only sees it once the writes are flushed
+    image.write_block(entries[0].key_block, &[0x55; BLOCK_SIZE]).unwrap();
+    assert_eq!(fs::read(root.join("HELLO#fc0801")).unwrap(), [0x42; 100]);
+    image.flush();
+    assert_eq!(fs::read(root.join("HELLO#fc0801")).unwrap(), [0x55; 100]);
+
+    // Change the type of a file described in the sidecar
+    let sub = entries[3].key_block;
+    let mut block = image.read_block(sub).unwrap().to_vec();
+    let offset = entry_offset(&block, "NOTES.TXT");
+    block[offset + 0x10] = BINARY_TYPE;
+    block[offset + 0x1f] = 0x00;
+    block[offset + 0x20] = 0x20;
+    image.write_block(sub, &block).unwrap();
+    image.flush();
+    assert_eq!(fs::read_to_string(root.join("sub").join(FILE_INFORMATION)).unwrap(),
+        "NOTES.TXT=Type(06),AuxType(2000),VersionCreate(00),Access(C3)\n");
+
+    // Delete a file and create another one
+    image.write_block(MAX_BLOCKS - 1, &[b'A'; BLOCK_SIZE]).unwrap();
+    let mut block = image.read_block(VOLUME_DIRECTORY_BLOCK).unwrap().to_vec();
+    let deleted = entry_offset(&block, "BIG.BIN");
+    block[deleted] &= 0x0f;
+    let new = 4 + 5 * 0x27;
+    block[new] = SEEDLING << 4 | 3;
+    block[new + 1..new + 4].copy_from_slice(b"NEW");
+    block[new + 0x10] = 0x04;
+    block[new + 0x11..new + 0x13].copy_from_slice(&((MAX_BLOCKS - 1) as u16).to_le_bytes());
+    block[new + 0x15] = 5;
+    image.write_block(VOLUME_DIRECTORY_BLOCK, &block).unwrap();
+    image.flush();
+    assert_eq!(fs::read(root.join("NEW#040000")).unwrap(), b"AAAAA");
+    // The blocks of the deleted file are still used, it might be a transient state
+    assert!(root.join("big.bin").exists());
+    free_block(&mut image, entries[1].key_block);
+    image.flush();
+    assert!(! root.join("big.bin").exists());
+
+    // Rename a file and a directory
+    let mut block = image.read_block(VOLUME_DIRECTORY_BLOCK).unwrap().to_vec();
+    for (old, new) in [("HUGE", "LARGE"), ("SUB", "NOTES")] {
+        let offset = entry_offset(&block, old);
+        block[offset] = block[offset] & 0xf0 | new.len() as u8;
+        block[offset + 1..offset + 1 + new.len()].copy_from_slice(new.as_bytes());
+    }
+    image.write_block(VOLUME_DIRECTORY_BLOCK, &block).unwrap();
+    image.flush();
+    assert!(! root.join("huge").exists());
+    assert_eq!(fs::read(root.join("LARGE#060000")).unwrap().len(), 200_000);
+    assert_eq!(fs::read(root.join("NOTES").join("notes.txt")).unwrap(), b"NOTES\r");
+
+    assert!(image.format().is_err());
+    let _ = fs::remove_dir_all(root);
+}
+
+#[test]
+pub fn build_volume() {
+    assert_eq!(to_prodos_name("1st file.s"), "A1ST.FILE.S");
+    assert!(is_valid_name("HELLO.WORLD"));
+    assert!(! is_valid_name("1HELLO"));
+    assert_eq!(prodos_date(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000)),
+        // 2023-11-14 22:13
+        [0x6e | 0x20, 0x2f, 13, 22]);
+
+    // Subdirectories grow past their first block
+    let mut builder = VolumeBuilder::new("TEST", 280, [0; 4]).unwrap();
+    let sub = builder.add_directory(ROOT, "SUB", [0; 4]).unwrap();
+    let text = FileAttributes { file_type: 4, aux_type: 0, access: DEFAULT_ACCESS, modified: [0; 4] };
+    for i in 0..20 {
+        builder.add_file(sub, &format!("F{i}"), &text, &[i]).unwrap();
+    }
+    assert!(builder.add_file(ROOT, "BIG", &text, &[0; 300 * BLOCK_SIZE]).is_err());
+    let image = builder.finish();
+    assert_eq!(image.len(), 280 * BLOCK_SIZE);
+    let root = read_directory(&image, VOLUME_DIRECTORY_BLOCK).unwrap();
+    assert_eq!((root[0].blocks_used, root[0].eof), (2, 2 * BLOCK_SIZE));
+    let files = read_directory(&image, root[0].key_block).unwrap();
+    assert_eq!(files.len(), 20);
+    assert_eq!(read_file(&image, &files[19]).unwrap(), [19]);
+}
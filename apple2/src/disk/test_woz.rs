@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use crate::Apple2;
+use crate::config_file::ConfigFile;
+use crate::context::EmulatorContext;
+use crate::disk::bit_stream::{BitStream, BitStreams};
+use crate::disk::disk::{Disk, PDisk};
+use crate::disk::drive::Drive;
+use crate::disk::dsk::Dsk;
+use crate::disk::lss::Lss;
+use crate::disk::woz::{InfoChunk, Woz};
+use crate::test_util::temp_path;
+
+fn master_bit_streams() -> BitStreams {
+    Dsk::new_with_file("files/master.dsk", false).unwrap().bit_streams().clone()
+}
+
+/// The delays between the 1 bits of a track, with bit cells of `bit_timing` ticks
+pub(crate) fn bits_to_delays(stream: &BitStream, bit_timing: u32) -> Vec<u32> {
+    let mut result = Vec::new();
+    let mut cells = 0;
+    for bit in stream.bits() {
+        cells += 1;
+        if *bit == 1 {
+            result.push(cells * bit_timing);
+            cells = 0;
+        }
+    }
+    // The zeros at the end of the track come before the first transition
+    result[0] += cells * bit_timing;
+    result
+}
+
+/// master.dsk where every track has a flux track, and a bit track if `keep_bits` is true
+fn master_flux_woz(path: &str, keep_bits: bool) -> Vec<Vec<u32>> {
+    let mut bit_streams = master_bit_streams();
+    let mut delays = Vec::new();
+    for phase in 0..bit_streams.bit_streams.len() {
+        if bit_streams.tmap[phase] != 0xff {
+            let d = bits_to_delays(&bit_streams.bit_streams[phase], 32);
+            let bits = if keep_bits { Some(bit_streams.bit_streams[phase].bits().to_vec()) } else { None };
+            bit_streams.bit_streams[phase] = BitStream::new_flux(&d, bits, 32);
+            if ! keep_bits { bit_streams.tmap[phase] = 0xff; }
+            delays.push(d);
+        }
+    }
+    fs::write(path, Woz::to_bytes(&InfoChunk::default(), &HashMap::new(), &bit_streams).unwrap()).unwrap();
+    delays
+}
+
+#[test]
+pub fn flux_delays() {
+    let delays = [40, 255, 300, 32, 600];
+    let bytes = Woz::delays_to_bytes(&delays);
+    assert_eq!(bytes, [40, 255, 0, 255, 45, 32, 255, 255, 90]);
+    assert_eq!(Woz::bytes_to_delays(&bytes), delays);
+
+    // Transitions every 32 ticks read as 1 bits, 64 ticks as 01
+    let stream = BitStream::new_flux(&[32, 64, 32], None, 32);
+    assert_eq!(stream.bits(), [1, 0, 1, 1]);
+    assert_eq!(stream.position_len(), 128);
+    assert_eq!(stream.flux_delays().unwrap(), [32, 64, 32]);
+    let pulses: Vec<u8> = (0..32).map(|i| stream.flux_pulse(i * 4, 4)).collect();
+    assert_eq!(pulses.iter().filter(|p| **p == 1).count(), 3);
+    assert_eq!((pulses[0], pulses[8], pulses[24]), (1, 1, 1));
+}
+
+#[test]
+pub fn load_and_save_flux() {
+    let path = temp_path("flux.woz");
+    let delays = master_flux_woz(&path, true);
+    let bytes = fs::read(&path).unwrap();
+    let mut woz = Woz::new_with_file(&path, false).unwrap();
+    assert_eq!(woz.version(), 3);
+    // Phases 0 and 1 share the same track, the 35 bit tracks come first
+    assert_eq!((woz.tmap[0], woz.tmap[1], woz.tmap[4]), (0, 0, 1));
+    assert_eq!((woz.flux_map[0], woz.flux_map[1], woz.flux_map[4]), (35, 35, 36));
+    let stream = woz.bit_streams().get_stream(4).clone();
+    assert!(stream.is_flux());
+    assert_eq!(stream.flux_delays().unwrap(), delays[2]);
+
+    // Saving writes the same FLUX chunk back
+    fs::write(&path, []).unwrap();
+    woz.save();
+    assert!(fs::read(&path).unwrap() == bytes);
+
+    // Writing turns the track into a bit track
+    let mut disk = Disk::new(&path, false, None).unwrap();
+    disk.bit_position = stream.position_len() / 2;
+    disk.set_bit_and_advance(4, 1);
+    assert!(! disk.is_flux(4));
+    assert_eq!(disk.bit_position, stream.len() / 2 + 1);
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+pub fn boot_flux_woz() {
+    let path = temp_path("boot-flux.woz");
+    master_flux_woz(&path, false);
+    let mut apple2 = Apple2::new(ConfigFile::default(), [None, None],
+        Arc::new(EmulatorContext::default()));
+    apple2.insert_disk(0, &path).unwrap();
+    while ! apple2.text_screen()[23].starts_with(']') {
+        assert!(apple2.cycles() < 20_000_000, "Didn't boot:\n{}", apple2.text_screen().join("\n"));
+        apple2.step_frame();
+    }
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+pub fn optimal_bit_timing() {
+    let path = temp_path("timing.woz");
+    let mut info = InfoChunk::default();
+    (info.optimal_bit_timing, info.boot_sector_format, info.compatible_hardware, info.required_ram) =
+        (16, 1, 0x1ff, 48);
+    fs::write(&path, Woz::to_bytes(&info, &HashMap::new(), &master_bit_streams()).unwrap()).unwrap();
+    let woz = Woz::new_with_file(&path, false).unwrap();
+    let read = woz.info();
+    assert_eq!((read.optimal_bit_timing, read.boot_sector_format, read.compatible_hardware, read.required_ram),
+        (16, 1, 0x1ff, 48));
+
+    // The LSS reads a bit every 4 of its cycles instead of 8
+    let disk = Disk::new(&path, false, None).unwrap();
+    let mut drive = Drive::new(0, Some(disk), None);
+    let mut lss = Lss::default();
+    for _ in 0..64 {
+        lss.on_pulse(false, false, true, &mut drive);
+    }
+    assert_eq!(drive.disk.unwrap().bit_position, 16);
+    let _ = fs::remove_file(path);
+}
+
+/// TRKS has 160 entries: when they're all used, the bit track of a flux phase is left out
+#[test]
+pub fn too_many_tracks() {
+    let path = temp_path("full.woz");
+    let mut bit_streams = master_bit_streams();
+    for phase in 0..bit_streams.bit_streams.len() {
+        let mut bits = bit_streams.bit_streams[4].bits().to_vec();
+        bits.rotate_left(phase);
+        bit_streams.bit_streams[phase] = BitStream::new(bits);
+        bit_streams.tmap[phase] = phase as u8;
+    }
+    let delays = bits_to_delays(&bit_streams.bit_streams[0], 32);
+    bit_streams.bit_streams[0] = BitStream::new_flux(&delays, None, 32);
+    fs::write(&path, Woz::to_bytes(&InfoChunk::default(), &HashMap::new(), &bit_streams).unwrap()).unwrap();
+    let woz = Woz::new_with_file(&path, false).unwrap();
+    assert_eq!(woz.bit_streams().tmap[0], 0xff);
+    assert_eq!(woz.bit_streams().get_stream(0).flux_delays().unwrap(), delays);
+    assert!(woz.bit_streams().get_stream(159).bits() == bit_streams.bit_streams[159].bits());
+    let _ = fs::remove_file(path);
+}
@@ -0,0 +1,305 @@
+//! A ProDOS volume synthesized from a host directory, so that files built on the host can be
+//! used right away from a SmartPort unit.
+//!
+//! Subdirectories become ProDOS subdirectories. The file type and aux type of a file come
+//! from a `#ttaaaa` suffix in its name (e.g. `HELLO#fc0801`), or from the `_FileInformation.txt`
+//! file of its directory (one `NAME=Type(FC),AuxType(0801),Access(E3)` line per file), or
+//! default to BIN. When the Apple ][ is done writing to the volume, the changes go back to the
+//! host: modified files are rewritten, new files are created with a `#ttaaaa` suffix, renamed
+//! files are renamed and deleted files are removed.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::disk::prodos::*;
+use crate::ui_log;
+
+pub const FILE_INFORMATION: &str = "_FileInformation.txt";
+
+/// Where the ProDOS type of a host file comes from
+#[derive(Clone, Debug, PartialEq)]
+enum Naming {
+    Suffix,
+    Sidecar,
+    /// Neither, the file is BIN
+    Plain,
+}
+
+#[derive(Clone, Debug)]
+struct HostFile {
+    path: PathBuf,
+    is_directory: bool,
+    file_type: u8,
+    aux_type: u16,
+    access: u8,
+    naming: Naming,
+    content: Vec<u8>,
+    /// Stays the same when ProDOS renames the file
+    key_block: usize,
+}
+
+pub struct VirtualVolume {
+    root: PathBuf,
+    /// The host files by ProDOS path, e.g. "GAMES/SNAKE"
+    files: BTreeMap<String, HostFile>,
+}
+
+impl VirtualVolume {
+    /// Build the volume from the content of `root`, return the volume and its blocks
+    pub fn mount(root: &str) -> Result<(VirtualVolume, Vec<u8>), String> {
+        let root = PathBuf::from(root);
+        let name = root.file_name().map_or("HOST".to_string(), |n| to_prodos_name(&n.to_string_lossy()));
+        let mut builder = VolumeBuilder::new(&name, MAX_BLOCKS, prodos_date(std::time::SystemTime::now()))?;
+        let mut result = VirtualVolume { root: root.clone(), files: BTreeMap::new() };
+        result.add_directory(&mut builder, &root, ROOT, "")?;
+        let image = builder.finish();
+        let mut tree = BTreeMap::new();
+        read_tree(&image, VOLUME_DIRECTORY_BLOCK, "", &mut tree)?;
+        for (path, (entry, _)) in tree {
+            if let Some(file) = result.files.get_mut(&path) {
+                file.key_block = entry.key_block;
+            }
+        }
+        Ok((result, image))
+    }
+
+    fn add_directory(&mut self, builder: &mut VolumeBuilder, directory: &Path, id: DirectoryId,
+        prodos_path: &str) -> Result<(), String>
+    {
+        let sidecar = read_sidecar(directory);
+        let mut entries: Vec<PathBuf> = fs::read_dir(directory)
+            .map_err(|e| format!("Couldn't read {directory:?}: {e}"))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.file_name().is_some_and(|n| {
+                let n = n.to_string_lossy();
+                ! n.starts_with('.') && n != FILE_INFORMATION
+            }))
+            .collect();
+        entries.sort();
+
+        let mut names = HashSet::new();
+        for path in entries {
+            let host_name = path.file_name().unwrap().to_string_lossy().to_string();
+            let (name, file_type, aux_type, access, naming) = if let Some((n, t, a)) = parse_suffix(&host_name) {
+                (n, t, a, DEFAULT_ACCESS, Naming::Suffix)
+            } else if let Some((t, a, access)) = sidecar.get(&host_name.to_uppercase()) {
+                (host_name.clone(), *t, *a, *access, Naming::Sidecar)
+            } else {
+                (host_name.clone(), BINARY_TYPE, 0, DEFAULT_ACCESS, Naming::Plain)
+            };
+            let name = to_prodos_name(&name);
+            if ! names.insert(name.clone()) {
+                ui_log(&format!("Skipping {path:?}: there is already a {name} in {directory:?}"));
+                continue;
+            }
+            let modified = prodos_date(fs::metadata(&path).and_then(|m| m.modified())
+                .unwrap_or(std::time::UNIX_EPOCH));
+            let child_path = if prodos_path.is_empty() { name.clone() } else { format!("{prodos_path}/{name}") };
+            if path.is_dir() {
+                let child = builder.add_directory(id, &name, modified)?;
+                self.files.insert(child_path.clone(), HostFile { path: path.clone(), is_directory: true,
+                    file_type: DIRECTORY_TYPE, aux_type: 0, access: DEFAULT_ACCESS, naming: Naming::Plain,
+                    content: Vec::new(), key_block: 0 });
+                self.add_directory(builder, &path, child, &child_path)?;
+            } else {
+                let content = fs::read(&path).map_err(|e| format!("Couldn't read {path:?}: {e}"))?;
+                builder.add_file(id, &name, &FileAttributes { file_type, aux_type, access, modified }, &content)?;
+                self.files.insert(child_path, HostFile { path, is_directory: false, file_type, aux_type,
+                    access, naming, content, key_block: 0 });
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring the host directory up to date with the volume after the Apple ][ wrote to it.
+    /// Nothing changes on the host if the volume can't be read. A file missing from the volume
+    /// is only removed from the host once ProDOS freed its key block, it's kept as long as the
+    /// volume doesn't look like it was deleted.
+    pub fn sync(&mut self, image: &[u8]) -> Result<(), String> {
+        let mut current = BTreeMap::new();
+        read_tree(image, VOLUME_DIRECTORY_BLOCK, "", &mut current)?;
+        let volume = ProDos::new(image.to_vec())?;
+
+        // Renamed files keep their key block
+        let missing: Vec<String> = self.files.keys()
+            .filter(|path| ! current.contains_key(*path)).cloned().collect();
+        for path in missing {
+            let Some(file) = self.files.get(&path) else { continue };
+            let renamed = current.iter().find(|(new_path, (entry, _))| {
+                ! self.files.contains_key(*new_path) && entry.key_block == file.key_block
+                    && entry.is_directory() == file.is_directory
+                    && new_path.rsplit_once('/').map(|(parent, _)| parent)
+                        == path.rsplit_once('/').map(|(parent, _)| parent)
+            }).map(|(new_path, (entry, _))| (new_path.clone(), entry.clone()));
+            if let Some((new_path, entry)) = renamed {
+                self.rename_file(&path, &new_path, &entry)?;
+            }
+        }
+
+        // Deepest first so that directories are empty when they get removed
+        let deleted: Vec<String> = self.files.keys().rev()
+            .filter(|path| ! current.contains_key(*path)).cloned().collect();
+        for path in deleted {
+            let key_block = self.files[&path].key_block;
+            if key_block >= volume.block_count() || ! volume.is_free(key_block) {
+                continue;
+            }
+            let file = self.files.remove(&path).unwrap();
+            let result = if file.is_directory { fs::remove_dir(&file.path) } else { fs::remove_file(&file.path) };
+            if let Err(e) = result {
+                ui_log(&format!("Couldn't remove {:?}: {e}", file.path));
+            }
+        }
+
+        for (path, (entry, content)) in current {
+            match self.files.get(&path).cloned() {
+                Some(file) if file.is_directory == entry.is_directory() => {
+                    if ! file.is_directory {
+                        self.update_file(&path, file, &entry, content)?;
+                    }
+                }
+                _ => {
+                    self.create_file(&path, &entry, content)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rename the host file, and move the files of a directory along with it
+    fn rename_file(&mut self, path: &str, new_path: &str, entry: &FileEntry) -> Result<(), String> {
+        let mut file = self.files.remove(path).unwrap();
+        let host_name = if file.is_directory { entry.name.clone() } else { suffixed_name(entry) };
+        let host_path = file.path.with_file_name(host_name);
+        fs::rename(&file.path, &host_path).map_err(|e| format!("Couldn't rename {:?}: {e}", file.path))?;
+        if file.is_directory {
+            let prefix = format!("{path}/");
+            let children: Vec<String> = self.files.keys().filter(|p| p.starts_with(&prefix)).cloned().collect();
+            for child in children {
+                let mut child_file = self.files.remove(&child).unwrap();
+                child_file.path = host_path.join(child_file.path.strip_prefix(&file.path).unwrap());
+                self.files.insert(format!("{new_path}/{}", &child[prefix.len()..]), child_file);
+            }
+        } else {
+            file.naming = Naming::Suffix;
+            (file.file_type, file.aux_type, file.access) = (entry.file_type, entry.aux_type, entry.access);
+        }
+        file.path = host_path;
+        self.files.insert(new_path.to_string(), file);
+        Ok(())
+    }
+
+    fn update_file(&mut self, path: &str, mut file: HostFile, entry: &FileEntry, content: Vec<u8>)
+        -> Result<(), String>
+    {
+        if (file.file_type, file.aux_type, file.access) != (entry.file_type, entry.aux_type, entry.access) {
+            match file.naming {
+                Naming::Sidecar => {
+                    write_sidecar(&file.path, entry)?;
+                }
+                Naming::Suffix | Naming::Plain => {
+                    let new_path = file.path.with_file_name(suffixed_name(entry));
+                    fs::rename(&file.path, &new_path)
+                        .map_err(|e| format!("Couldn't rename {:?}: {e}", file.path))?;
+                    file.path = new_path;
+                    file.naming = Naming::Suffix;
+                }
+            }
+            (file.file_type, file.aux_type, file.access) = (entry.file_type, entry.aux_type, entry.access);
+        }
+        if file.content != content {
+            fs::write(&file.path, &content).map_err(|e| format!("Couldn't write {:?}: {e}", file.path))?;
+            file.content = content;
+        }
+        file.key_block = entry.key_block;
+        self.files.insert(path.to_string(), file);
+        Ok(())
+    }
+
+    fn create_file(&mut self, path: &str, entry: &FileEntry, content: Vec<u8>) -> Result<(), String> {
+        let parent = match path.rsplit_once('/') {
+            Some((parent, _)) => self.files.get(parent).map(|f| f.path.clone())
+                .ok_or_else(|| format!("No host directory for {parent}"))?,
+            None => self.root.clone(),
+        };
+        let file = if entry.is_directory() {
+            let host_path = parent.join(&entry.name);
+            fs::create_dir(&host_path).map_err(|e| format!("Couldn't create {host_path:?}: {e}"))?;
+            HostFile { path: host_path, is_directory: true, file_type: DIRECTORY_TYPE, aux_type: 0,
+                access: entry.access, naming: Naming::Plain, content: Vec::new(), key_block: entry.key_block }
+        } else {
+            let host_path = parent.join(suffixed_name(entry));
+            fs::write(&host_path, &content).map_err(|e| format!("Couldn't write {host_path:?}: {e}"))?;
+            HostFile { path: host_path, is_directory: false, file_type: entry.file_type,
+                aux_type: entry.aux_type, access: entry.access, naming: Naming::Suffix, content,
+                key_block: entry.key_block }
+        };
+        self.files.insert(path.to_string(), file);
+        Ok(())
+    }
+}
+
+/// Read all the entries of the directory and its subdirectories, with the content of the files
+fn read_tree(image: &[u8], key_block: usize, prodos_path: &str,
+    result: &mut BTreeMap<String, (FileEntry, Vec<u8>)>) -> Result<(), String>
+{
+    for entry in read_directory(image, key_block)? {
+        let path = if prodos_path.is_empty() { entry.name.clone() } else { format!("{prodos_path}/{}", entry.name) };
+        if entry.is_directory() {
+            read_tree(image, entry.key_block, &path, result)?;
+            result.insert(path, (entry, Vec::new()));
+        } else {
+            let content = read_file(image, &entry)?;
+            result.insert(path, (entry, content));
+        }
+    }
+    Ok(())
+}
+
+/// The value of `field` in a `_FileInformation.txt` line, e.g. `Type(FC)`
+fn sidecar_field(line: &str, field: &str) -> Option<u32> {
+    let start = line.find(&format!("{field}("))? + field.len() + 1;
+    let end = start + line[start..].find(')')?;
+    u32::from_str_radix(&line[start..end], 16).ok()
+}
+
+/// The (type, aux type, access) of the files of `directory`, by upper case host name
+fn read_sidecar(directory: &Path) -> BTreeMap<String, (u8, u16, u8)> {
+    let content = fs::read_to_string(directory.join(FILE_INFORMATION)).unwrap_or_default();
+    content.lines().filter_map(|line| {
+        let (name, fields) = line.split_once('=')?;
+        let file_type = sidecar_field(fields, "Type")? as u8;
+        let aux_type = sidecar_field(fields, "AuxType").unwrap_or(0) as u16;
+        let access = sidecar_field(fields, "Access").map_or(DEFAULT_ACCESS, |a| a as u8);
+        Some((name.trim().to_uppercase(), (file_type, aux_type, access)))
+    }).collect()
+}
+
+/// Update the line of `path` in the `_FileInformation.txt` next to it, keeping the other fields
+fn write_sidecar(path: &Path, entry: &FileEntry) -> Result<(), String> {
+    let sidecar = path.with_file_name(FILE_INFORMATION);
+    let host_name = path.file_name().unwrap().to_string_lossy().to_uppercase();
+    let content = fs::read_to_string(&sidecar).unwrap_or_default();
+    let values = [("Type", format!("{:02X}", entry.file_type)), ("AuxType", format!("{:04X}", entry.aux_type)),
+        ("Access", format!("{:02X}", entry.access))];
+    let lines: Vec<String> = content.lines().map(|line| {
+        match line.split_once('=') {
+            Some((name, fields)) if name.trim().to_uppercase() == host_name => {
+                let mut fields = fields.to_string();
+                for (field, value) in &values {
+                    fields = match sidecar_field(&fields, field) {
+                        Some(_) => {
+                            let start = fields.find(&format!("{field}(")).unwrap() + field.len() + 1;
+                            let end = start + fields[start..].find(')').unwrap();
+                            format!("{}{value}{}", &fields[..start], &fields[end..])
+                        }
+                        None => format!("{fields},{field}({value})"),
+                    };
+                }
+                format!("{name}={fields}")
+            }
+            _ => line.to_string(),
+        }
+    }).collect();
+    fs::write(&sidecar, lines.join("\n") + "\n").map_err(|e| format!("Couldn't write {sidecar:?}: {e}"))
+}
@@ -1,7 +1,6 @@
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{Read};
-use crate::disk::bit_stream::{BitStream, BitStreams};
+use crate::disk::archive;
+use crate::disk::bit_stream::{BitStream, BitStreams, DEFAULT_BIT_TIMING};
 use crate::disk::disk::PDisk;
 use crate::disk::disk_controller::{MAX_PHASE};
 use crate::disk::disk_info::DiskInfo;
@@ -12,12 +11,26 @@ use crate::misc::{bit, save};
 use crate::ui_log;
 
 const TMAP_SIZE: usize = MAX_PHASE;
+/// Offset of the content of the INFO chunk in the file
+const INFO_OFFSET: usize = 20;
+/// Offset of the first track, after INFO, TMAP and the TRKS entries
+const TRACKS_OFFSET: usize = 0x600;
+
+/// A track of TRKS: its bit count (byte count for the flux tracks) and its content
+type Track = (u32, Vec<u8>);
+
+/// The keys of META defined by the specification
+const META_KEYS: [&str; 14] = ["title", "subtitle", "publisher", "developer", "copyright", "version",
+    "language", "requires_ram", "requires_machine", "notes", "side", "side_name", "contributor",
+    "image_date"];
 
 #[derive(Clone)]
 pub struct Woz {
     disk_info: DiskInfo,
     i: usize,
     pub tmap: [u8; TMAP_SIZE],
+    /// The FLUX chunk (WOZ 2.1): which TRKS entry holds the flux track of each phase
+    pub flux_map: [u8; TMAP_SIZE],
     tracks: (Option<Vec<TracksV1>>, Option<[Tracks; TMAP_SIZE]>),
     pub bit_streams: BitStreams,
     pub meta: HashMap<String, String>,
@@ -33,11 +46,33 @@ enum DiskType {
     ThreePointFive,  // 3.5
 }
 
-#[derive(Clone, Default)]
-struct InfoChunk {
+#[derive(Clone)]
+pub struct InfoChunk {
     version: u8,
     disk_type: DiskType,
-    write_protected: bool,
+    pub write_protected: bool,
+    /// 0: unknown, 1: 16 sectors, 2: 13 sectors, 3: both
+    pub boot_sector_format: u8,
+    /// Duration of a bit cell in 125ns ticks
+    pub optimal_bit_timing: u8,
+    /// One bit per model (II, II+, IIe, ...), 0 if unknown
+    pub compatible_hardware: u16,
+    /// In KB, 0 if unknown
+    pub required_ram: u16,
+}
+
+impl Default for InfoChunk {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            disk_type: DiskType::default(),
+            write_protected: false,
+            boot_sector_format: 0,
+            optimal_bit_timing: DEFAULT_BIT_TIMING,
+            compatible_hardware: 0,
+            required_ram: 0,
+        }
+    }
 }
 
 impl Default for Woz {
@@ -46,6 +81,7 @@ impl Default for Woz {
             disk_info: Default::default(),
             i: 0,
             tmap: [0; TMAP_SIZE],
+            flux_map: [0xff; TMAP_SIZE],
             tracks: (None, None),
             bit_streams: Default::default(),
             meta: Default::default(),
@@ -61,6 +97,7 @@ impl Woz {
     pub fn title(&self) -> Option<String> { self.meta.get("title").cloned() }
     pub fn version(&self) -> u8 { self.info_chunk.version }
     pub fn is_write_protected(&self) -> bool { self.info_chunk.write_protected }
+    pub fn info(&self) -> &InfoChunk { &self.info_chunk }
 }
 
 #[derive(Default, Clone, Copy)]
@@ -93,12 +130,9 @@ impl PDisk for Woz {
     }
 
     fn save(&mut self) {
-        ui_log("Woz saving {path}");
-        let mut buffer: Vec<u8> = Vec::new();
-        Self::woz_file_1(&mut buffer);
-        Self::woz_file_2(&mut buffer, &self.bit_streams);
         let path = &self.disk_info.path;
-        match save(path, &buffer) {
+        ui_log(&format!("Woz saving {path}"));
+        match Self::to_bytes(&self.info_chunk, &self.meta, &self.bit_streams).and_then(|bytes| save(path, &bytes)) {
             Ok(_) => { ui_log(&format!("Saved {path}")); }
             Err(s) => { ui_log(&format!("Error saving {path}: {s}")); }
         }
@@ -107,15 +141,17 @@ impl PDisk for Woz {
     fn disk_info(&self) -> &DiskInfo {
         &self.disk_info
     }
+
+    fn disk_info_mut(&mut self) -> &mut DiskInfo {
+        &mut self.disk_info
+    }
 }
 
 impl Woz {
     /// If `read_version` is true, then we only read the version of the file and do not
     /// parse anything else. If it's false, decode the whole file, including the bit streams
     pub fn new_with_file(filename: &str, quick: bool) -> Result<Woz, String> {
-        let mut file = File::open(filename).map_err(|e| e.to_string())?;
-        let mut buffer: Vec<u8> = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
+        let buffer = archive::read(filename).map_err(|e| e.to_string())?;
         Woz::new(&buffer, filename, quick)
     }
 
@@ -155,6 +191,8 @@ impl Woz {
                     panic!("Should never happen");
                 }
                 woz.i += size - 8 * 160;  // 160 phases, each takes 8 bytes to describe
+            } else if name == "FLUX" {
+                woz.read_flux_chunk(bytes);
             } else if name == "META" {
                 woz.meta = woz.read_meta(bytes, size);
             } else {
@@ -187,6 +225,19 @@ impl Woz {
         }
     }
 
+    /// The content of a WOZ 2 file, with its checksum
+    pub fn to_bytes(info: &InfoChunk, meta: &HashMap<String, String>, bit_streams: &BitStreams)
+        -> Result<Vec<u8>, String>
+    {
+        let mut buffer: Vec<u8> = Vec::new();
+        Self::woz_file_1(&mut buffer, info);
+        Self::woz_file_2(&mut buffer, bit_streams)?;
+        Self::woz_file_meta(&mut buffer, meta);
+        let checksum = crc32(0, &buffer[12..]);
+        Woz::set_32(&mut buffer, 8, checksum);
+        Ok(buffer)
+    }
+
     fn push_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
         for b in bytes { buffer.push(*b); }
     }
@@ -197,60 +248,176 @@ impl Woz {
         }
     }
 
-    /// Encode TMAP and TRKS
-    pub fn woz_file_2(buffer: &mut Vec<u8>, bit_streams: &BitStreams) {
+    /// Encode TMAP, TRKS and FLUX. Phases with identical tracks share the same TRKS entry.
+    /// The sizes of the largest tracks and the location of FLUX are then set in INFO. Fails if
+    /// there are more different tracks than the 160 entries of TRKS. The bit tracks of the flux
+    /// phases are only there for the emulators that don't read FLUX, they're left out when
+    /// there's no room for them (e.g. quarter-track captures).
+    pub fn woz_file_2(buffer: &mut Vec<u8>, bit_streams: &BitStreams) -> Result<(), String> {
+        // The bit tracks come first in TRKS, the flux indexes are moved after them at the end
+        let mut bit_tracks: Vec<Track> = Vec::new();
+        let mut flux_tracks: Vec<Track> = Vec::new();
+        let mut tmap = [0xff_u8; TMAP_SIZE];
+        let mut flux_map = [0xff_u8; TMAP_SIZE];
+        let streams = || bit_streams.bit_streams.iter().enumerate().take(TMAP_SIZE);
+        let bit_track = |bs: &BitStream| (bs.len() as u32, Self::bits_to_bytes(bs));
+        for (phase, bs) in streams() {
+            if bit_streams.tmap[phase] != 0xff && ! bs.is_flux() {
+                tmap[phase] = Self::add_track(&mut bit_tracks, bit_track(bs), phase, TMAP_SIZE)?;
+            }
+        }
+        for (phase, bs) in streams() {
+            if let Some(delays) = bs.flux_delays() {
+                let bytes = Self::delays_to_bytes(&delays);
+                flux_map[phase] = Self::add_track(&mut flux_tracks, (bytes.len() as u32, bytes), phase,
+                    TMAP_SIZE - bit_tracks.len())?;
+            }
+        }
+        let room = TMAP_SIZE - flux_tracks.len();
+        for (phase, bs) in streams() {
+            if bit_streams.tmap[phase] != 0xff && bs.is_flux() {
+                if let Ok(i) = Self::add_track(&mut bit_tracks, bit_track(bs), phase, room) {
+                    tmap[phase] = i;
+                }
+            }
+        }
+        for t in flux_map.iter_mut().filter(|t| **t != 0xff) {
+            *t += bit_tracks.len() as u8;
+        }
+        let bit_track_count = bit_tracks.len();
+        let mut tracks = bit_tracks;
+        tracks.extend(flux_tracks);
+        let has_flux = flux_map.iter().any(|t| *t != 0xff);
+
         Woz::push_string(buffer, "TMAP");
         Woz::push_32(buffer, MAX_PHASE as u32);
-        for t in &bit_streams.tmap {
-            buffer.push(*t);
-        }
+        Woz::push_multiple(buffer, &tmap);
 
         Woz::push_string(buffer, "TRKS");
         let chunk_size_index = buffer.len();
         Woz::push_32(buffer, 0);  // length, will be set later
-        let mut block = 3;
-        for bs in &bit_streams.bit_streams {
+        let mut block = (TRACKS_OFFSET / 512) as u16;
+        let (mut largest_track, mut largest_flux_track) = (0, 0);
+        for (i, (count, bytes)) in tracks.iter().enumerate() {
+            let block_count = bytes.len().div_ceil(512) as u16;
             Woz::push_16(buffer, block);
-            let block_count = (bs.len() / 512 / 8) + 1;
-            Woz::push_16(buffer, block_count as u16);
-            Woz::push_32(buffer, bs.len() as u32);
-            block += block_count as u16;
+            Woz::push_16(buffer, block_count);
+            Woz::push_32(buffer, *count);
+            block += block_count;
+            if i < bit_track_count {
+                largest_track = largest_track.max(block_count);
+            } else {
+                largest_flux_track = largest_flux_track.max(block_count);
+            }
         }
 
-        while buffer.len() != 0x600 {
+        while buffer.len() != TRACKS_OFFSET {
             buffer.push(0);
         }
 
-        for bs in &bit_streams.bit_streams {
-            let mut bit_index = 0;
-            let mut a = 0;
-            while bit_index < bs.len() {
-                let mut a_index = 0;
-                while bit_index < bs.len() && a_index < 8 {
-                    a = (a << 1) | bs.next_bit(bit_index);
-                    bit_index += 1;
-                    a_index += 1;
-                }
-                // If we reached the end of the bit stream, pad a with 0's before
-                // adding it to the buffer
-                while a_index < 8 {
-                    a <<= 1;
-                    a_index += 1;
-                }
-                buffer.push(a);
-            }
+        for (_, bytes) in &tracks {
+            Woz::push_multiple(buffer, bytes);
             // Pad to the next 512 boundary
             while (buffer.len() % 512) != 0 {
                 buffer.push(0);
             }
         }
         let chunk_size = (buffer.len() - chunk_size_index - 4) as u32;
-        println!("Chunk size: {:08X}", chunk_size);
         Woz::set_32(buffer, chunk_size_index, chunk_size);
+
+        // FLUX starts on a block boundary, INFO records that block
+        let flux_block = if has_flux { (buffer.len() / 512) as u16 } else { 0 };
+        if has_flux {
+            Woz::push_string(buffer, "FLUX");
+            Woz::push_32(buffer, MAX_PHASE as u32);
+            Woz::push_multiple(buffer, &flux_map);
+            buffer[INFO_OFFSET] = buffer[INFO_OFFSET].max(3);
+        }
+        Woz::set_16(buffer, INFO_OFFSET + 44, largest_track);
+        Woz::set_16(buffer, INFO_OFFSET + 46, flux_block);
+        Woz::set_16(buffer, INFO_OFFSET + 48, largest_flux_track);
+        Ok(())
     }
 
-    /// Encode INFO
-    pub fn woz_file_1(buffer: &mut Vec<u8>) {
+    /// Index of `track` in `tracks`, an error if there is no room left for it in the `room`
+    /// entries `tracks` can use
+    fn add_track(tracks: &mut Vec<Track>, track: Track, phase: usize, room: usize)
+        -> Result<u8, String>
+    {
+        if let Some(i) = tracks.iter().position(|t| *t == track) {
+            Ok(i as u8)
+        } else if tracks.len() < room {
+            tracks.push(track);
+            Ok((tracks.len() - 1) as u8)
+        } else {
+            Err(format!("More than {TMAP_SIZE} different tracks, the track of phase {phase} can't be written"))
+        }
+    }
+
+    fn bits_to_bytes(bs: &BitStream) -> Vec<u8> {
+        let mut result = Vec::with_capacity(bs.len().div_ceil(8));
+        let mut bit_index = 0;
+        while bit_index < bs.len() {
+            let mut a = 0;
+            for _ in 0..8 {
+                // If we reached the end of the bit stream, pad with 0's
+                let bit = if bit_index < bs.len() { bs.next_bit(bit_index) } else { 0 };
+                a = (a << 1) | bit;
+                bit_index += 1;
+            }
+            result.push(a);
+        }
+        result
+    }
+
+    /// Flux tracks have one byte per delay between two transitions, in ticks of 125ns.
+    /// Longer delays are made of several bytes, $FF meaning that the delay continues
+    /// in the next byte
+    pub(crate) fn delays_to_bytes(delays: &[u32]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(delays.len());
+        for delay in delays {
+            let mut d = *delay;
+            while d >= 255 {
+                result.push(255);
+                d -= 255;
+            }
+            result.push(d as u8);
+        }
+        result
+    }
+
+    pub(crate) fn bytes_to_delays(bytes: &[u8]) -> Vec<u32> {
+        let mut result = Vec::with_capacity(bytes.len());
+        let mut delay = 0;
+        for b in bytes {
+            delay += *b as u32;
+            if *b != 255 {
+                result.push(delay);
+                delay = 0;
+            }
+        }
+        if delay > 0 {
+            result.push(delay);
+        }
+        result
+    }
+
+    /// Encode META, if there is any: one "key\tvalue" line per entry, the keys of the
+    /// specification first and in its order, then the others sorted
+    fn woz_file_meta(buffer: &mut Vec<u8>, meta: &HashMap<String, String>) {
+        if meta.is_empty() {
+            return;
+        }
+        let mut keys: Vec<&String> = meta.keys().collect();
+        keys.sort_by_key(|k| (META_KEYS.iter().position(|m| m == k).unwrap_or(META_KEYS.len()), *k));
+        let lines = keys.iter().map(|k| format!("{k}\t{}", meta[*k])).collect::<Vec<_>>().join("\n");
+        Woz::push_string(buffer, "META");
+        Woz::push_32(buffer, lines.len() as u32);
+        Woz::push_multiple(buffer, lines.as_bytes());
+    }
+
+    /// Encode INFO. The sizes of the tracks are set by [Woz::woz_file_2]
+    pub fn woz_file_1(buffer: &mut Vec<u8>, info: &InfoChunk) {
         Woz::push_string(buffer, "WOZ2");
         Woz::push_bytes(buffer, &[0xff, 0xa, 0xd, 0xa]);
         // Checksum, will set later
@@ -259,20 +426,20 @@ impl Woz {
         // INFO
         Woz::push_string(buffer, "INFO");
         Woz::push_32(buffer, 60);
-        buffer.push(2); // version
+        buffer.push(2); // version, 3 if there are flux tracks
         buffer.push(1); // floppy
-        buffer.push(1); // write protected
+        buffer.push(info.write_protected as u8);
         buffer.push(0); // not synchronized
         buffer.push(1); // cleaned
         Woz::push_string(buffer, &format!("{: <32}", "Maple-2, by Cédric Beust"));
         buffer.push(1); // # of sides
-        buffer.push(1); // boot sector format
-        buffer.push(32); // ideal bit rate
-        Woz::push_16(buffer, 0); // compatible with EVERYTHING
-        Woz::push_16(buffer, 0); // required RAM
-        Woz::push_16(buffer, 0xd); // largest track in blocks
-        Woz::push_16(buffer, 0); // flux
-        Woz::push_16(buffer, 0); // number of blocks for flux
+        buffer.push(info.boot_sector_format);
+        buffer.push(info.optimal_bit_timing);
+        Woz::push_16(buffer, info.compatible_hardware);
+        Woz::push_16(buffer, info.required_ram);
+        Woz::push_16(buffer, 0); // largest track in blocks
+        Woz::push_16(buffer, 0); // flux block
+        Woz::push_16(buffer, 0); // largest flux track in blocks
         Woz::push_n(buffer, 10, 0);
     }
 
@@ -281,6 +448,11 @@ impl Woz {
         buffer.push(((v & 0xff00) >> 8) as u8);
     }
 
+    pub(crate) fn set_16(buffer: &mut [u8], index: usize, v: u16) {
+        buffer[index] = (v & 0xff) as u8;
+        buffer[index + 1] = ((v & 0xff00) >> 8) as u8;
+    }
+
     pub(crate) fn set_32(buffer: &mut Vec<u8>, index: usize, v: u32) {
         buffer[index] = (v & 0xff) as u8;
         buffer[index + 1] = ((v & 0xff00) >> 8) as u8;
@@ -402,14 +574,35 @@ impl Woz {
                     }
                 }
             }
+
+            // Flux tracks take precedence over the bit track of the same phase
+            let flux = self.flux_map[phase];
+            if let (Some(trackv2), true) = (&self.tracks.1, flux != 0xff) {
+                let track = trackv2.get(flux as usize)
+                    .ok_or(format!("Invalid flux track {flux} for phase {phase}"))?;
+                let start = track.starting_block as usize * 512;
+                let end = start + track.bit_count as usize;  // a byte count for flux tracks
+                if end > bytes.len() {
+                    return Err(format!("Flux track {flux} is past the end of the file"));
+                }
+                let delays = Self::bytes_to_delays(&bytes[start..end]);
+                let bits = if tmap != 0xff { Some(bit_streams[phase].bits().to_vec()) } else { None };
+                bit_streams[phase] = BitStream::new_flux(&delays, bits,
+                    self.info_chunk.optimal_bit_timing);
+            }
         }
 
-        Ok(BitStreams::new(bit_streams, self.tmap, disk_info))
+        let mut result = BitStreams::new(bit_streams, self.tmap, disk_info);
+        result.optimal_bit_timing = self.info_chunk.optimal_bit_timing;
+        Ok(result)
     }
 
     fn read_meta(&mut self, bytes: &[u8], size: usize) -> HashMap<String, String> {
         let mut result = HashMap::new();
-        let m = self.read_string(bytes, size);
+        // UTF-8, unlike the names of the chunks
+        let end = (self.i + size).min(bytes.len());
+        let m = String::from_utf8_lossy(&bytes[self.i..end]).to_string();
+        self.i = end;
         let strings = m.split('\n').map(|e| e.to_string()).collect::<Vec<String>>();
         for s in strings {
             let mut sp = s.split('\t');
@@ -491,17 +684,30 @@ impl Woz {
         let _cleaned = self.read8(bytes);
         let _creator = self.read_many(bytes, 32);
         let _disk_sides = self.read8(bytes);
-        let _boot_sector_format = self.read8(bytes);
-        let _optimal_bit_timing = self.read8(bytes);
-        let _compatible_hardware = self.read16(bytes);
-        let _required_ram = self.read16(bytes);
+        let boot_sector_format = self.read8(bytes);
+        let optimal_bit_timing = self.read8(bytes);
+        let compatible_hardware = self.read16(bytes);
+        let required_ram = self.read16(bytes);
         let _largest_track = self.read16(bytes);
         let _flux_block = self.read16(bytes);
         let _largest_flux_track = self.read16(bytes);
 
         self.skip(10);
 
-        InfoChunk { version, write_protected, disk_type }
+        // These fields were only added in version 2
+        if version >= 2 {
+            InfoChunk { version, write_protected, disk_type, boot_sector_format,
+                optimal_bit_timing: if optimal_bit_timing == 0 { DEFAULT_BIT_TIMING } else { optimal_bit_timing },
+                compatible_hardware, required_ram }
+        } else {
+            InfoChunk { version, write_protected, disk_type, ..Default::default() }
+        }
+    }
+
+    fn read_flux_chunk(&mut self, bytes: &[u8]) {
+        for i in 0..TMAP_SIZE {
+            self.flux_map[i] = self.read8(bytes);
+        }
     }
 
     fn skip(&mut self, n: usize) {
@@ -0,0 +1,247 @@
+//! Run the emulator without any window, sound or joystick, e.g. to verify in CI that a disk
+//! still boots:
+//!
+//! `maple2-headless --disk files/master.dsk --until "]" --png master.png`
+//!
+//! The machine runs until the pattern appears on the text screen or until the cycle budget
+//! is exhausted, then the screen is saved and/or hashed.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use crate::Apple2;
+use crate::config_file::ConfigFile;
+use crate::constants::*;
+use crate::context::EmulatorContext;
+use crate::disk::disk::Disk;
+use crate::disk::disk_info::DiskInfo;
+use crate::disk::dsk_to_woz::crc32;
+use crate::memory_constants::*;
+use crate::messages::DrawCommand::Rectangle;
+use crate::soft_switch;
+use crate::ui::hires_screen::{text_coordinates_to_address, HiresScreen};
+
+/// Exit codes of [run_headless]
+pub const EXIT_OK: i32 = 0;
+/// The pattern didn't appear or the screen hash didn't match
+pub const EXIT_FAILED: i32 = 1;
+/// The machine couldn't be created (e.g. missing disk)
+pub const EXIT_ERROR: i32 = 2;
+
+/// How often the text screen is searched for the pattern
+const CHECK_INTERVAL_CYCLES: u64 = 10_000;
+
+/// Magnification of the captured screen, 2 so that 80 columns and double hires are not lossy
+const SCREEN_MAGNIFICATION: u16 = 2;
+
+#[derive(Clone, Debug, Default)]
+pub struct HeadlessOptions {
+    /// Disks inserted in drive 1 and 2
+    pub disks: Vec<String>,
+    /// If present, the machine boots from this hard drive through the SmartPort card
+    pub hard_drive: Option<String>,
+    pub max_cycles: u64,
+    /// Stop as soon as this text appears on the text screen
+    pub until: Option<String>,
+    pub png: Option<String>,
+    /// Print the hash of the screen
+    pub hash: bool,
+    /// Fail if the hash of the screen is different
+    pub expected_hash: Option<String>,
+}
+
+pub struct HeadlessResult {
+    pub cycles: u64,
+    /// true if `until` was found (always false if there was no pattern)
+    pub found: bool,
+    pub text: Vec<String>,
+    pub screen: Screen,
+}
+
+/// Run the machine described by `options`, print the text screen and return the exit code
+pub fn run_headless(options: &HeadlessOptions) -> i32 {
+    let result = match run_machine(options) {
+        Ok(r) => { r }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return EXIT_ERROR;
+        }
+    };
+
+    for line in &result.text {
+        println!("{}", line.trim_end());
+    }
+    println!("Cycles: {}", result.cycles);
+
+    let mut exit_code = EXIT_OK;
+    if let Some(until) = &options.until {
+        if result.found {
+            println!("Found \"{until}\"");
+        } else {
+            println!("Didn't find \"{until}\" after {} cycles", result.cycles);
+            exit_code = EXIT_FAILED;
+        }
+    }
+    if let Some(png) = &options.png {
+        if let Err(e) = fs::write(png, result.screen.to_png()) {
+            eprintln!("Couldn't write {png}: {e}");
+            return EXIT_ERROR;
+        }
+        println!("Wrote {png}");
+    }
+    let hash = result.screen.hash();
+    if options.hash {
+        println!("Hash: {hash}");
+    }
+    if let Some(expected) = &options.expected_hash {
+        if ! expected.eq_ignore_ascii_case(&hash) {
+            println!("Hash mismatch: expected {expected}, got {hash}");
+            exit_code = EXIT_FAILED;
+        }
+    }
+    exit_code
+}
+
+/// Create the machine with the default settings (the user's config file is ignored so that the
+/// runs are reproducible), insert the disks and run it
+pub fn run_machine(options: &HeadlessOptions) -> Result<HeadlessResult, String> {
+    if options.disks.len() > 2 {
+        return Err(format!("At most two disks can be inserted, got {}", options.disks.len()));
+    }
+
+    let mut disk_infos: [Option<DiskInfo>; 2] = [None, None];
+    for (i, path) in options.disks.iter().enumerate() {
+        let disk = Disk::new(path, false, None).map_err(|e| format!("Couldn't load {path}: {e}"))?;
+        disk_infos[i] = Some(disk.disk_info().clone());
+    }
+    if let Some(path) = &options.hard_drive {
+        if ! Path::new(path).exists() {
+            return Err(format!("Couldn't find {path}"));
+        }
+    }
+    let context = Arc::new(EmulatorContext::default());
+    context.set_boot_from_hard_drive(options.hard_drive.is_some());
+    let mut apple2 = Apple2::new(ConfigFile::headless(options.hard_drive.clone()), disk_infos,
+        context);
+
+    let mut found = false;
+    while apple2.cycles() < options.max_cycles && ! found {
+        apple2.step_cycles(CHECK_INTERVAL_CYCLES.min(options.max_cycles - apple2.cycles()));
+        if let Some(until) = &options.until {
+            found = apple2.text_screen().iter().any(|line| line.contains(until.as_str()));
+        }
+    }
+
+    Ok(HeadlessResult {
+        cycles: apple2.cycles(),
+        found,
+        text: apple2.text_screen(),
+        screen: apple2.screen(),
+    })
+}
+
+/// The content of the text screen (the current page, 40 or 80 columns) as ASCII. Inverse and
+/// flashing characters are converted to their normal equivalent.
+pub fn screen_text(memory: &[u8], aux_memory: &[u8]) -> Vec<String> {
+    let page2 = soft_switch(memory, PAGE_2_STATUS);
+    let is_80 = soft_switch(memory, EIGHTY_COLUMNS_STATUS);
+    (0..TEXT_HEIGHT).map(|y| {
+        let mut line = String::new();
+        for x in 0..TEXT_WIDTH {
+            let address = text_coordinates_to_address(x, y, page2) as usize;
+            if is_80 {
+                line.push(to_ascii(aux_memory[address]));
+            }
+            line.push(to_ascii(memory[address]));
+        }
+        line
+    }).collect()
+}
+
+fn to_ascii(c: u8) -> char {
+    // $80-$FF: normal, $00-$7F: inverse and flashing, which only have upper case and symbols
+    let c = if c >= 0x80 { c & 0x7f } else { c & 0x3f };
+    let c = if c < 0x20 { c + 0x40 } else { c };
+    c as char
+}
+
+/// The screen as RGB pixels, rendered from the same draw commands as the UI
+pub struct Screen {
+    pub width: usize,
+    pub height: usize,
+    /// Three bytes per pixel
+    pub pixels: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(memory: &Vec<u8>, aux_memory: &Vec<u8>) -> Self {
+        let mag = SCREEN_MAGNIFICATION;
+        let width = (HIRES_WIDTH * mag) as usize;
+        let height = (HIRES_HEIGHT * mag) as usize;
+        let mut pixels = vec![0; width * height * 3];
+        for command in HiresScreen::new().get_draw_commands(memory, aux_memory, mag) {
+            let Rectangle(x0, y0, x1, y1, color) = command;
+            let (r, g, b) = color.to_rgb();
+            let clip = |v: f32, max: usize| (v.max(0.0) as usize).min(max);
+            for y in clip(y0, height)..clip(y1, height) {
+                for x in clip(x0, width)..clip(x1, width) {
+                    let i = (y * width + x) * 3;
+                    pixels[i..i + 3].copy_from_slice(&[r, g, b]);
+                }
+            }
+        }
+        Self { width, height, pixels }
+    }
+
+    /// CRC32 of the pixels, as 8 hex digits
+    pub fn hash(&self) -> String {
+        format!("{:08x}", crc32(0, &self.pixels))
+    }
+
+    /// Encode the screen as a PNG. The image data is stored without compression, which keeps
+    /// the encoder trivial and is good enough for screenshots of that size.
+    pub fn to_png(&self) -> Vec<u8> {
+        // Each row starts with its filter type (0: none)
+        let mut raw = Vec::with_capacity((self.width * 3 + 1) * self.height);
+        for row in self.pixels.chunks(self.width * 3) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+
+        // zlib stream made of "stored" deflate blocks
+        let mut zlib = vec![0x78, 0x01];
+        let chunks: Vec<&[u8]> = raw.chunks(0xffff).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            zlib.push(if i == chunks.len() - 1 { 1 } else { 0 });
+            let len = chunk.len() as u16;
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(! len).to_le_bytes());
+            zlib.extend_from_slice(chunk);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGB, default compression, filter and no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut result = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+        for (chunk_type, data) in [(b"IHDR", header), (b"IDAT", zlib), (b"IEND", Vec::new())] {
+            result.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            result.extend_from_slice(chunk_type);
+            result.extend_from_slice(&data);
+            result.extend_from_slice(&crc32(crc32(0, chunk_type), &data).to_be_bytes());
+        }
+        result
+    }
+}
+
+pub fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for byte in bytes {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
@@ -1,12 +1,3 @@
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
-
-use gilrs::{Axis, Button, Gilrs};
-use gilrs::ev::Code;
-use gilrs::EventType::{AxisChanged, ButtonChanged, ButtonPressed, ButtonReleased};
-use crate::send_message;
-use crate::ui::iced::shared::Shared;
-
 pub struct Joystick {
     // _gilrs: Arc<RwLock<Gilrs>>,
     reset: bool,
@@ -34,81 +25,6 @@ impl Joystick {
         self.reset_cycles.fill(cycle);
     }
 
-    pub fn run(&mut self) {
-        loop {
-            self.main_loop();
-        }
-    }
-
-    /// Run a forever loop that constantly updates the raw values from the controller
-    pub fn main_loop(&mut self) {
-        // Map the -1..1 range to 0..255
-        fn scale(x: f32, invert: bool) -> u8 {
-            let result = ((128.0 * x) + 128.0) as u8;
-            if invert { 255 - result } else { result }
-        }
-
-        let mut gilrs = Gilrs::new().unwrap();
-        while let Some(gilrs::Event { id, event, time }) = gilrs.next_event_blocking(
-            Some(Duration::from_millis(100)))
-        {
-            let mut values = Shared::get_controller_raw_values();
-            match event {
-                AxisChanged(axis, value, _code) => {
-                    match axis {
-                        Axis::LeftStickX => {
-                            values[0] = scale(value, false);
-                        }
-                        Axis::LeftStickY => {
-                            values[1] = scale(value, true);
-                        }
-                        _ => {
-                            // println!("Unknown event: {event:#?}");
-                        }
-                    }
-                }
-                ButtonPressed(button, _code) | ButtonReleased(button, _code) => {
-                    let pressed = matches!(event, ButtonPressed(_, _));
-                    match button {
-                        Button::South => { Shared::set_controller_button_value(0, pressed); }
-                        Button::West => { Shared::set_controller_button_value(1, pressed); }
-                        _ => {}
-                    };
-                }
-                _ => {}
-            }
-            Shared::update_controller_raw_values(values);
-        }
-
-        //
-        // if let Some(gamepad) = active_gamepad.map(|id| gilrs.gamepad(id)) {
-        //     let mut result: Vec<u8> = Vec::new();
-        //     let state = gamepad.state();
-        //     for (index, axis) in [Axis::LeftStickX, Axis::LeftStickY, Axis::RightStickX, Axis::RightStickY].iter().enumerate() {
-        //         let live_axis = gamepad.axis_data(*axis);
-        //         let cached_axis = state.axis_data(gamepad.axis_code(*axis).unwrap());
-        //         let float_value = match (live_axis, cached_axis) {
-        //             (Some(live), Some(cached)) => {
-        //                 println!("Live: {} cached: {}", live.value(), cached.value());
-        //                 live.value()
-        //             }
-        //             (Some(live), None) => {
-        //                 println!("Live:: {}", live.value());
-        //                 live.value()
-        //             }
-        //             (None, Some(cached)) => {
-        //                 println!("Cached: {}", cached.value());
-        //                 cached.value()
-        //             }
-        //             (None, None) => { 0.0 }
-        //         };
-        //         result.push(scale(float_value, false));
-        //     }
-        //     println!("Updating to values {} {}", result[0], result[1]);
-        //     Shared::update_controller_raw_values([result[0], result[1], result[2], result[3]]);
-        // };
-
-    }
 //
     //     pub fn reset_timers(&mut self, cycle: u64) {
 //         self.reset_cycles.fill(cycle);
@@ -118,8 +34,10 @@ impl Joystick {
 //     }
 //
 
-    pub fn get_value_for_paddle(&mut self, paddle_index: usize, current_cycle: u64) -> u8 {
-        let values = Shared::get_controller_raw_values();
+    /// `values`: the raw values of the paddles (0-255)
+    pub fn get_value_for_paddle(&mut self, paddle_index: usize, current_cycle: u64, values: &[u8; 4])
+        -> u8
+    {
         let result = if self.reset_cycles[paddle_index] > 0 {
             if current_cycle > self.reset_cycles[paddle_index] + 256 * MULT {
                 self.reset_cycles[paddle_index] = 0;
@@ -141,4 +59,95 @@ impl Joystick {
         result
     }
 
-}
\ No newline at end of file
+}
+
+/// The thread that reads the gamepad and publishes its axes and buttons in the context, only
+/// compiled with the `gamepad` feature
+#[cfg(feature = "gamepad")]
+mod gamepad {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use gilrs::{Axis, Button, Gilrs};
+    use gilrs::EventType::{AxisChanged, ButtonPressed, ButtonReleased};
+    use crate::context::EmulatorContext;
+    use crate::joystick::Joystick;
+
+    impl Joystick {
+        pub fn run(&mut self, context: Arc<EmulatorContext>) {
+            loop {
+                self.main_loop(&context);
+            }
+        }
+
+        /// Run a forever loop that constantly updates the raw values from the controller
+        pub fn main_loop(&mut self, context: &EmulatorContext) {
+            // Map the -1..1 range to 0..255
+            fn scale(x: f32, invert: bool) -> u8 {
+                let result = ((128.0 * x) + 128.0) as u8;
+                if invert { 255 - result } else { result }
+            }
+
+            let mut gilrs = Gilrs::new().unwrap();
+            while let Some(gilrs::Event { event, .. }) = gilrs.next_event_blocking(
+                Some(Duration::from_millis(100)))
+            {
+                let mut values = context.controller().values;
+                match event {
+                    AxisChanged(axis, value, _code) => {
+                        match axis {
+                            Axis::LeftStickX => {
+                                values[0] = scale(value, false);
+                            }
+                            Axis::LeftStickY => {
+                                values[1] = scale(value, true);
+                            }
+                            _ => {
+                                // println!("Unknown event: {event:#?}");
+                            }
+                        }
+                    }
+                    ButtonPressed(button, _code) | ButtonReleased(button, _code) => {
+                        let pressed = matches!(event, ButtonPressed(_, _));
+                        match button {
+                            Button::South => { context.set_controller_button(0, pressed); }
+                            Button::West => { context.set_controller_button(1, pressed); }
+                            _ => {}
+                        };
+                    }
+                    _ => {}
+                }
+                context.set_controller_values(values);
+            }
+
+            //
+            // if let Some(gamepad) = active_gamepad.map(|id| gilrs.gamepad(id)) {
+            //     let mut result: Vec<u8> = Vec::new();
+            //     let state = gamepad.state();
+            //     for (index, axis) in [Axis::LeftStickX, Axis::LeftStickY, Axis::RightStickX, Axis::RightStickY].iter().enumerate() {
+            //         let live_axis = gamepad.axis_data(*axis);
+            //         let cached_axis = state.axis_data(gamepad.axis_code(*axis).unwrap());
+            //         let float_value = match (live_axis, cached_axis) {
+            //             (Some(live), Some(cached)) => {
+            //                 println!("Live: {} cached: {}", live.value(), cached.value());
+            //                 live.value()
+            //             }
+            //             (Some(live), None) => {
+            //                 println!("Live:: {}", live.value());
+            //                 live.value()
+            //             }
+            //             (None, Some(cached)) => {
+            //                 println!("Cached: {}", cached.value());
+            //                 cached.value()
+            //             }
+            //             (None, None) => { 0.0 }
+            //         };
+            //         result.push(scale(float_value, false));
+            //     }
+            //     println!("Updating to values {} {}", result[0], result[1]);
+            //     Shared::update_controller_raw_values([result[0], result[1], result[2], result[3]]);
+            // };
+
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Maple // as a library: the emulated machine without any window, sound output or joystick.
+//!
+//! [Apple2] is the entry point. It is created from a [ConfigFile] and driven by the caller, who
+//! decides when to run it, what to type and when to look at the screen:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use maple_2::Apple2;
+//! use maple_2::config_file::ConfigFile;
+//! use maple_2::context::EmulatorContext;
+//!
+//! let context = Arc::new(EmulatorContext::default());
+//! let mut apple2 = Apple2::new(ConfigFile::default(), [None, None], context);
+//! apple2.insert_disk(0, "files/master.dsk").unwrap();
+//! apple2.step_frames(200);
+//! apple2.type_text("CATALOG\r");
+//! apple2.step_frames(60);
+//! println!("{}", apple2.text_screen().join("\n"));
+//! let pixels = apple2.screen().pixels;
+//! let samples = apple2.audio_samples();
+//! ```
+//!
+//! Each machine owns its [EmulatorContext], so several of them can run side by side. Subscribe
+//! an [context::EmulatorObserver] to the context to be notified of the drive activity.
+//!
+//! The iced frontend (`ui::iced`) is only compiled with the `gui` feature, which is on by
+//! default. Embedders can turn it off with `default-features = false`. The frontend also turns
+//! on `audio`, the sound output (rodio), and `gamepad`, the thread that reads the gamepad (gilrs).
+
+use std::sync::Arc;
+use std::time::Instant;
+use crossbeam::channel::{Receiver, Sender};
+use cpu::config::Config;
+use cpu::cpu::Cpu;
+use cpu::memory::Memory;
+use cpu::messages::ToLogging;
+use crate::apple2_cpu::{AppleCpu, EmulatorConfigMsg};
+use crate::config_file::ConfigFile;
+use crate::constants::{CYCLES_PER_FRAME, START};
+use crate::context::EmulatorContext;
+use crate::disk::disk::Disk;
+use crate::disk::disk_info::DiskInfo;
+use crate::headless::{screen_text, Screen};
+use crate::memory::{Apple2Memory, AUX, MAIN};
+use crate::messages::{ToCpu, ToUi};
+
+mod debug;
+pub mod constants;
+pub mod apple2_cpu;
+pub mod messages;
+mod rolling_times;
+mod test;
+mod test_disk_controller;
+mod cycle_actions;
+mod misc;
+mod alog;
+mod test_memory;
+#[cfg(test)]
+mod test_speaker;
+pub mod memory;
+pub mod roms;
+pub mod memory_constants;
+mod macros;
+pub mod mini_fb;
+pub mod config_file;
+mod smartport;
+pub mod speaker;
+pub mod joystick;
+pub mod card;
+pub mod context;
+pub mod snapshot;
+mod rewind;
+#[cfg(test)]
+mod test_snapshot;
+#[cfg(test)]
+mod test_rewind;
+pub mod headless;
+#[cfg(test)]
+mod test_headless;
+#[cfg(test)]
+mod test_context;
+#[cfg(test)]
+mod test_lib;
+#[cfg(test)]
+mod test_smartport;
+#[cfg(test)]
+mod test_util;
+
+pub mod disk {
+    pub mod disk_controller;
+    pub mod disk;
+    pub mod drive;
+    pub mod dsk;
+    pub mod d13;
+    pub mod nib;
+    pub mod woz;
+    mod woz_test;
+    pub mod a2r;
+    pub mod archive;
+    pub mod bit_stream;
+    pub mod lss;
+    pub mod dsk_to_woz;
+    pub mod convert;
+    pub mod dos33;
+    pub mod disk_info;
+    pub mod hard_drive;
+    pub mod prodos;
+    pub mod virtual_volume;
+    #[cfg(test)]
+    mod test_virtual_volume;
+    #[cfg(test)]
+    mod test_prodos;
+    #[cfg(test)]
+    mod test_dsk;
+    #[cfg(test)]
+    mod test_nib;
+    #[cfg(test)]
+    mod test_overlay;
+    #[cfg(test)]
+    mod test_d13;
+    #[cfg(test)]
+    mod test_woz;
+    #[cfg(test)]
+    mod test_a2r;
+    #[cfg(test)]
+    mod test_convert;
+    #[cfg(test)]
+    mod test_dos33;
+    #[cfg(test)]
+    mod test_archive;
+    #[cfg(test)]
+    mod test_bit_stream;
+}
+
+pub mod mockingboard {
+    pub mod mockingboard;
+    pub mod via;
+    pub mod psg;
+    pub mod ssi263;
+    #[cfg(test)]
+    mod test_mockingboard;
+}
+
+pub mod ui {
+    pub mod soft_switches;
+    pub mod text_screen;
+    pub mod hires_screen;
+    mod test_graphics;
+    #[cfg(test)]
+    mod test_lores;
+
+    #[cfg(feature = "gui")]
+    pub mod iced {
+        pub mod ui_iced;
+        pub mod message;
+        mod disks_tab;
+        mod catalog_tab;
+        mod nibbles_tab;
+        mod memory_view;
+        mod style;
+        mod tab;
+        mod keyboard;
+        mod debugger_window;
+        mod main_window;
+        pub mod shared;
+        mod disk_tab;
+        mod drives_view;
+        mod debug_tab;
+    }
+}
+
+/// An Apple //e: the 65C02, its memory and the cards in its slots.
+pub struct Apple2 {
+    pub(crate) cpu: AppleCpu,
+}
+
+impl Apple2 {
+    /// Create a machine driven by the caller: it doesn't talk to any UI and only runs when one
+    /// of the `step` functions is called. The hard drives come from `config_file`, the floppies
+    /// from `disks`.
+    pub fn new(config_file: ConfigFile, disks: [Option<DiskInfo>; 2],
+        context: Arc<EmulatorContext>) -> Apple2
+    {
+        START.get_or_init(Instant::now);
+        let config = Config {
+            emulator_speed_hz: config_file.emulator_speed_hz(),
+            ..Default::default()
+        };
+        create_apple2(None, None, None, disks,
+            Box::new(EmulatorConfigMsg::new(config, config_file)), context)
+    }
+
+    pub fn context(&self) -> &Arc<EmulatorContext> {
+        &self.cpu.cpu.memory.context
+    }
+
+    /// The 65C02, to read its registers
+    pub fn cpu(&self) -> &Cpu<Apple2Memory> {
+        &self.cpu.cpu
+    }
+
+    /// Number of cycles run since the machine was created
+    pub fn cycles(&self) -> u64 {
+        self.cpu.cpu.memory.cycles
+    }
+
+    pub fn step_cycles(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.cpu.step();
+        }
+    }
+
+    /// Run for the duration of one video frame (1/60th of a second)
+    pub fn step_frame(&mut self) {
+        self.step_cycles(CYCLES_PER_FRAME);
+    }
+
+    pub fn step_frames(&mut self, frames: u64) {
+        self.step_cycles(frames * CYCLES_PER_FRAME);
+    }
+
+    /// Press a key, `key` is its ASCII code (e.g. `b'\r'` for Return). Like on the real machine,
+    /// the key stays in the keyboard latch until the program reads it, so the caller should
+    /// let the machine run between two keys.
+    pub fn press_key(&mut self, key: u8) {
+        for address in 0xc000..=0xc00f {
+            self.cpu.cpu.memory.set_force(address, key | 0x80);
+        }
+    }
+
+    /// Type `text`, letting the machine run for a few frames after each key
+    pub fn type_text(&mut self, text: &str) {
+        for c in text.bytes() {
+            self.press_key(c);
+            self.step_frames(3);
+        }
+    }
+
+    /// Keep compressed snapshots of the machine to go back in time, like the rewind slider of
+    /// the frontend. Off by default since it costs a snapshot every 0.1s and up to 64M of memory.
+    pub fn enable_rewind(&mut self) {
+        self.cpu.enable_rewind();
+    }
+
+    /// Insert the floppy at `path` in drive 0 or 1
+    pub fn insert_disk(&mut self, drive_number: usize, path: &str) -> Result<(), String> {
+        let disk = Disk::new(path, false, None).map_err(|e| format!("Couldn't load {path}: {e}"))?;
+        self.cpu.cpu.memory.load_disk_from_file(false, drive_number, disk.disk_info().clone())
+    }
+
+    pub fn eject_disk(&mut self, drive_number: usize) {
+        self.cpu.cpu.memory.eject_disk(false, drive_number);
+    }
+
+    /// The floppies currently in the drives
+    pub fn disks(&self) -> [Option<DiskInfo>; 2] {
+        self.cpu.cpu.memory.disk_controller().map_or([None, None], |dc| dc.disks())
+    }
+
+    /// The screen as displayed by the frontends (text, lores or hires, in color)
+    pub fn screen(&self) -> Screen {
+        let memory = &self.cpu.cpu.memory;
+        Screen::new(&memory.memories[MAIN].to_vec(), &memory.memories[AUX].to_vec())
+    }
+
+    /// The text screen as ASCII, one string per line
+    pub fn text_screen(&self) -> Vec<String> {
+        let memory = &self.cpu.cpu.memory;
+        screen_text(&memory.memories[MAIN], &memory.memories[AUX])
+    }
+
+    /// The sound produced since the last call, at [constants::SAMPLE_RATE] samples per second,
+    /// with the Mockingboard mixed in if there is one. If they are not read, only the last samples
+    /// are kept.
+    pub fn audio_samples(&self) -> Vec<f32> {
+        let context = self.context();
+        let mut result = Vec::with_capacity(context.sound_sample_count());
+        while let Some(sample) = context.next_sound_sample() {
+            result.push(sample + context.next_mockingboard_sample().unwrap_or(0.0));
+        }
+        result
+    }
+
+    /// Read main memory without triggering any soft switch
+    pub fn peek(&self, address: u16) -> u8 {
+        self.cpu.cpu.memory.memories[MAIN][address as usize]
+    }
+
+    /// The 64K of main memory, without the language card and ROM banking
+    pub fn main_memory(&self) -> &[u8] {
+        &self.cpu.cpu.memory.memories[MAIN]
+    }
+
+    /// The 64K of auxiliary memory
+    pub fn aux_memory(&self) -> &[u8] {
+        &self.cpu.cpu.memory.memories[AUX]
+    }
+
+    /// Write to main memory without triggering any soft switch
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.cpu.cpu.memory.set_force(address, value);
+    }
+
+    /// Run the machine as fast as the speed in the config allows, driven by the messages of
+    /// the frontend, until it's rebooted or exited
+    pub fn run(&mut self) -> messages::CpuStateMsg {
+        self.cpu.run()
+    }
+}
+
+pub fn create_apple2(
+    sender: Option<Sender<ToUi>>,
+    logging_sender: Option<Sender<ToLogging>>,
+    receiver: Option<Receiver<ToCpu>>,
+    disk_infos: [Option<DiskInfo>; 2],
+    config: Box<EmulatorConfigMsg>,
+    context: Arc<EmulatorContext>)
+-> Apple2
+{
+    let hard_drives = config.config_file.hard_drives().map(|hd| hd.map(|s| DiskInfo::n(&s)));
+
+    let mut m = Apple2Memory::new(disk_infos, hard_drives, &config.config_file.slots(),
+        sender.clone(), context);
+
+    m.load_roms(config.config_file.rom_type());
+
+    let mut cpu = AppleCpu::new(Cpu::new(m, logging_sender, config.config.clone()),
+        config.clone(), sender.clone(), receiver);
+    cpu.cpu.pc = cpu.cpu.memory.word(0xfffc);
+    send_message!(sender, ToUi::Config(config.clone()));
+    Apple2 { cpu }
+}
+
+/// Will need to log this into the UI somewhere
+pub fn ui_log(s: &str) {
+    println!("{}", s);
+}
+
+pub fn soft_switch(memory: &[u8], address: u16) -> bool {
+    (memory[address as usize] & 0x80) != 0
+}
@@ -1,62 +1,5 @@
-mod memory;
-mod debug;
-mod constants;
-mod apple2_cpu;
-mod messages;
-mod rolling_times;
-mod test;
-mod test_disk_controller;
-mod cycle_actions;
-mod misc;
-mod alog;
-mod test_memory;
-pub mod roms;
-mod memory_constants;
-mod macros;
-mod mini_fb;
-mod config_file;
-mod smartport;
-mod speaker;
-mod joystick;
-
-mod disk {
-    pub mod disk_controller;
-    pub mod disk;
-    pub mod drive;
-    pub mod dsk;
-    pub mod woz;
-    mod woz_test;
-    pub mod bit_stream;
-    pub mod lss;
-    pub mod dsk_to_woz;
-    pub mod disk_info;
-}
-
-
-mod ui {
-    pub mod soft_switches;
-    pub mod text_screen;
-    pub mod hires_screen;
-    mod test_graphics;
-
-    pub mod iced {
-        pub mod ui_iced;
-        pub mod message;
-        mod disks_tab;
-        mod nibbles_tab;
-        mod memory_view;
-        mod style;
-        mod tab;
-        mod keyboard;
-        mod debugger_window;
-        mod main_window;
-        pub mod shared;
-        mod disk_tab;
-        mod drives_view;
-        mod debug_tab;
-    }
-}
-
+//! The Maple // application: the iced frontend on top of the `maple_2` library, which runs the
+//! emulator in its own thread and plays its sound and joystick in two others.
 
 use cpu::messages::ToLogging;
 use crossbeam::channel::{Receiver, Sender, unbounded};
@@ -66,11 +9,8 @@ use std::fs::File;
 use std::io::{stdout};
 use std::path::{Path};
 use std::process::exit;
-use std::sync::{Mutex, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
-use cpu::cpu::Cpu;
-use cpu::memory::Memory;
-use messages::ToUi;
 use clap::Parser;
 use gilrs::{Axis, Event, Gilrs};
 use notify::{RecursiveMode};
@@ -82,19 +22,19 @@ use tracing_subscriber::fmt::format::{Compact, DefaultFields, Format};
 use tracing_subscriber::layer::SubscriberExt;
 use cpu::config::{Config};
 use cpu::logging_thread::Logging;
-use crate::apple2_cpu::{AppleCpu, EmulatorConfigMsg};
-use crate::config_file::ConfigFile;
-use crate::constants::*;
-use crate::disk::disk::Disk;
-use crate::disk::disk_info::DiskInfo;
-use crate::joystick::Joystick;
-use crate::memory::{Apple2Memory};
-use crate::messages::*;
-use crate::messages::ToCpu::FileModified;
-use crate::speaker::{play_file_rodio, Speaker, Speaker2};
-use crate::ui::iced::message::InternalUiMessage;
-use crate::ui::iced::shared::Shared;
-use crate::ui::iced::ui_iced::{main_iced};
+use maple_2::{create_apple2, ui_log};
+use maple_2::apple2_cpu::EmulatorConfigMsg;
+use maple_2::config_file::ConfigFile;
+use maple_2::constants::*;
+use maple_2::context::EmulatorContext;
+use maple_2::disk::disk::Disk;
+use maple_2::joystick::Joystick;
+use maple_2::messages::*;
+use maple_2::messages::ToCpu::FileModified;
+use maple_2::speaker::Speaker;
+use maple_2::ui::iced::message::InternalUiMessage;
+use maple_2::ui::iced::shared::{Shared, SharedObserver};
+use maple_2::ui::iced::ui_iced::{main_iced};
 
 fn configure_tracing(to_file: bool) {
     // A layer that logs events to a file
@@ -128,24 +68,6 @@ struct Args {
     dir: Option<String>,
 }
 
-fn t() {
-    match disk::dsk_to_woz::dsk_to_woz("d:\\Apple disks\\Apple DOS 3.3.dsk") {
-        Ok(f) => {
-            println!("Wrote {f}");
-        }
-        Err(err) => {
-            println!("ERROR: {}", err);
-        }
-    }
-    println!("Correct size");
-    disk::dsk_to_woz::woz_to_dsk("d:\\Apple disks\\Apple DOS 3.3.woz");
-
-    println!("My size");
-    if let Ok(f) = disk::dsk_to_woz::woz_to_dsk("c:\\Users\\Ced\\rust\\sixty.rs\\bad.woz") {
-        println!("Wrote {f}");
-    }
-}
-
 fn controller() {
     let mut active_gamepad = None;
 
@@ -177,10 +99,13 @@ fn controller() {
 
 fn start() {
     // controller();
-
-
     let config_file = ConfigFile::new();
-    Shared::set_show_drives(! config_file.show_hard_drive);
+    Shared::set_show_drives(! config_file.show_hard_drive());
+
+    // The state of the machine, mirrored in Shared for the UI
+    let context = Arc::new(EmulatorContext::default());
+    context.set_boot_from_hard_drive(config_file.show_hard_drive());
+    context.subscribe(Arc::new(SharedObserver));
 
     let mut config = Config {
         emulator_speed_hz: config_file.emulator_speed_hz(),
@@ -189,7 +114,6 @@ fn start() {
     ui_log(&format!("Set speed of emulator to {} Mhz",
         (config.emulator_speed_hz as f32 / 1_000_000.0)));
 
-    // t();
     // exit(0);
     for wf in WATCHED_FILES.iter() {
         config.watched_files.push(wf.clone());
@@ -206,15 +130,6 @@ fn start() {
     info!("[Info] Logging");
     debug!("[Debug] Logging");
 
-    // if true {
-    if false {
-        let path = "c:\\t\\speaker-events.txt".to_string();
-        let path = "c:\\t\\pop.cycles.txt".to_string();
-        let path = "c:\\t\\archon.cycles.txt".to_string();
-        play_file_rodio(&path);
-        exit(0);
-    }
-
     //
     // Main emulator
     //
@@ -247,14 +162,11 @@ fn start() {
     let emulator_config = EmulatorConfigMsg::new(config.copy(), config_file.clone());
     let (logging_sender, logging_receiver): (Sender<ToLogging>, Receiver<ToLogging>) = unbounded();
 
-    Shared::set_drive(0, disks[0].clone());
-    Shared::set_drive(1, disks[1].clone());
-
     if benchmark {
         let mut apple2 = create_apple2(
             Some(sender), Some(logging_sender),
-            Some(receiver2), disks, Box::new(emulator_config.clone()));
-        apple2.cpu.run();
+            Some(receiver2), disks, Box::new(emulator_config.clone()), context);
+        apple2.run();
     } else {
         let sender4 = sender2.clone();
         let config_file_minifb = config_file.clone();
@@ -262,15 +174,17 @@ fn start() {
         //
         // Spawn the speaker thread
         //
+        let context2 = context.clone();
         let _ = thread::Builder::new().name("Maple // - Speaker".to_string()).spawn(move || {
-            Speaker::new().run();
+            Speaker::new(context2).run();
         });
 
         //
         // Spawn the controller thread
         //
+        let context2 = context.clone();
         let _ = thread::Builder::new().name("Maple // - Controller".to_string()).spawn(move || {
-            Joystick::default().run();
+            Joystick::default().run(context2);
         });
 
         //
@@ -286,6 +200,7 @@ fn start() {
         //
         // Spawn the emulator
         //
+        let context2 = context.clone();
         let _ = thread::Builder::new().name("Maple // - Emulator".to_string()).spawn(move || {
             let mut state = CpuStateMsg::Running;
             while state != CpuStateMsg::Exit { // running != CpuStateMsg::Paused {
@@ -295,7 +210,9 @@ fn start() {
                 };
                 let mut apple2 = create_apple2(Some(sender.clone()),
                     Some(logging_sender.clone()),
-                    Some(receiver2.clone()), disks.clone(), Box::new(ecm));
+                    Some(receiver2.clone()), disks.clone(), Box::new(ecm), context2.clone());
+                // For the rewind slider and F5/F6
+                apple2.enable_rewind();
                 // if audit {
                 //     apple2.cpu.cpu.memory.load_file("/Users/Ced/rust/a2audit/audit/audit.o", 0x6000, 0, 0, true);
                 //     apple2.cpu.cpu.pc = 0x6000;
@@ -304,7 +221,7 @@ fn start() {
                     sender4.send(FileModified(wf.clone())).unwrap();
                 }
                 while state != CpuStateMsg::Rebooting && state != CpuStateMsg::Exit {
-                    state = apple2.cpu.run();
+                    state = apple2.run();
                 }
                 println!("Exiting loop, status: {:#?}", state);
                 disks = apple2.disks();
@@ -322,7 +239,7 @@ fn start() {
         let (sender_minifb, receiver_minifb): (Sender<ToMiniFb>, Receiver<ToMiniFb>) = unbounded();
         #[cfg(feature = "minifb")]
         let _ = thread::Builder::new().name("Maple // - minifb".to_string()).spawn(move || {
-            mini_fb::main_minifb(receiver_minifb, &config_file);
+            maple_2::mini_fb::main_minifb(receiver_minifb, &config_file);
         });
 
         //
@@ -376,47 +293,6 @@ fn start() {
         // if true {
         println!("Running iced");
         _ = main_iced(Some(sender2), receiver, Some(sender_minifb),
-            config_file_minifb.clone());
+            config_file_minifb.clone(), context);
     }
 }
-
-struct Apple2 {
-    cpu: AppleCpu,
-}
-
-impl Apple2 {
-    fn disks(&self) -> [Option<DiskInfo>; 2] {
-        self.cpu.cpu.memory.disk_controller.disks()
-    }
-}
-
-pub(crate) fn create_apple2(
-    sender: Option<Sender<ToUi>>,
-    logging_sender: Option<Sender<ToLogging>>,
-    receiver: Option<Receiver<ToCpu>>,
-    disk_infos: [Option<DiskInfo>; 2],
-    config: Box<EmulatorConfigMsg>)
--> Apple2
-{
-    let di0 = config.config_file.hard_drive_1().map(|s| DiskInfo::n(&s));
-    let di1 = config.config_file.hard_drive_2().map(|s| DiskInfo::n(&s));
-
-    let mut m = Apple2Memory::new(disk_infos, [di0, di1], sender.clone());
-
-    m.load_roms(config.config_file.rom_type());
-
-    let mut cpu = AppleCpu::new(Cpu::new(m, logging_sender, config.config.clone()),
-        config.clone(), sender.clone(), receiver);
-    cpu.cpu.pc = cpu.cpu.memory.word(0xfffc);
-    send_message!(sender, ToUi::Config(config.clone()));
-    Apple2 { cpu }
-}
-
-/// Will need to log this into the UI somewhere
-pub fn ui_log(s: &str) {
-    println!("{}", s);
-}
-
-pub fn soft_switch(memory: &[u8], address: u16) -> bool {
-    (memory[address as usize] & 0x80) != 0
-}
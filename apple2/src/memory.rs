@@ -1,25 +1,29 @@
 use std::fs;
-use std::sync::RwLock;
+use std::sync::Arc;
 use std::time::Instant;
 use crossbeam::channel::Sender;
 use tracing::{event, info, Level};
 pub use cpu::memory::{Memory, DefaultMemory};
 use crate::alog::alog;
-use crate::constants::{CYCLES, PC, START};
+use crate::card::{Bus, CardType, Slots, SLOT_COUNT};
+use crate::constants::{HARD_DRIVE_COUNT, PC};
+use crate::context::EmulatorContext;
 use crate::disk::disk_controller::{DiskController};
 use crate::disk::disk_info::DiskInfo;
 use crate::debug::hex_dump_at;
 use crate::memory_constants::*;
 use crate::messages::ToUi;
 use crate::messages::ToUi::RgbModeUpdate;
-use crate::roms::{DISK2_ROM, Roms, RomType, SMARTPORT_ROM};
+use crate::roms::{Roms, RomType};
 use crate::{send_message};
 use crate::joystick::Joystick;
+use crate::mockingboard::mockingboard::Mockingboard;
 use crate::smartport::SmartPort;
-use crate::ui::iced::shared::{Shared, SpeakerEvent};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::speaker::SpeakerSynth;
 
-const MAIN: usize = 0;
-const AUX: usize = 1;
+pub(crate) const MAIN: usize = 0;
+pub(crate) const AUX: usize = 1;
 
 #[macro_export]
 macro_rules! is_set {
@@ -104,15 +108,100 @@ pub struct Apple2Memory {
     /// There is no way to read this status, so we maintain it here
     slot_c8_status: bool,
 
-    pub(crate) disk_controller: DiskController,
-    smartport: SmartPort,
+    /// The peripheral cards in slots 1-7
+    pub(crate) slots: Slots,
+
+    /// Turns the $C030 accesses into sound samples
+    pub(crate) speaker: SpeakerSynth,
     vbl: u8,
+
+    /// Number of cycles since the machine was created
+    pub(crate) cycles: u64,
+    /// Timing of the paddles, their values are in the context
+    joystick: Joystick,
+    /// Shared with the frontend, see [EmulatorContext]
+    pub(crate) context: Arc<EmulatorContext>,
 }
 
 impl Apple2Memory {
     pub(crate) fn on_reboot(&mut self) {
         self.dhg_rgb_mode = 0;
         self.dhg_rgb_flags = 0;
+        self.slots.reset();
+    }
+
+    pub(crate) fn disk_controller(&self) -> Option<&DiskController> {
+        self.slots.find::<DiskController>()
+    }
+
+    pub(crate) fn disk_controller_mut(&mut self) -> Option<&mut DiskController> {
+        self.slots.find_mut::<DiskController>()
+    }
+
+    /// See [crate::snapshot]
+    pub(crate) fn save_state(&self, w: &mut SnapshotWriter) {
+        self.slots.save_state(w);
+        for memory in &self.memories {
+            w.bytes(memory);
+        }
+        w.bytes(&self.extra_text_memory);
+        for high_ram in &self.high_ram {
+            w.bytes(&high_ram.banks[0]);
+            w.bytes(&high_ram.banks[1]);
+            w.bytes(&high_ram.high_ram);
+        }
+        w.u8(self.prewrite);
+        w.bool(self.bank1);
+        w.bool(self.read_enabled);
+        w.bool(self.write_enabled);
+        w.u16(self.dhg_previous_address);
+        w.bool(self.dhg_iou_disabled);
+        w.u8(self.dhg_rgb_mode);
+        w.u8(self.dhg_rgb_flags);
+        w.bool(self.slot_c8_status);
+        w.u8(self.vbl);
+    }
+
+    /// `cycle` is the cycle count of the snapshot, the speaker resumes from there
+    pub(crate) fn load_state(&mut self, r: &mut SnapshotReader, cycle: u64) -> Result<(), String> {
+        // Everything is read before anything changes, so that a snapshot taken with different
+        // cards, or whose disks can't be reloaded, leaves the machine untouched
+        let expansion_slot = self.slots.prepare_state(r)?;
+        let mut memories = vec![[0; DefaultMemory::MEMORY_SIZE as usize]; 2];
+        for memory in memories.iter_mut() {
+            r.bytes_into(memory)?;
+        }
+        let mut extra_text_memory = [0; 0x400];
+        r.bytes_into(&mut extra_text_memory)?;
+        let mut high_rams: [HighRam; 2] = Default::default();
+        for high_ram in high_rams.iter_mut() {
+            r.bytes_into(&mut high_ram.banks[0])?;
+            r.bytes_into(&mut high_ram.banks[1])?;
+            r.bytes_into(&mut high_ram.high_ram)?;
+        }
+        let (prewrite, bank1, read_enabled, write_enabled) = (r.u8()?, r.bool()?, r.bool()?, r.bool()?);
+        let (dhg_previous_address, dhg_iou_disabled) = (r.u16()?, r.bool()?);
+        let (dhg_rgb_mode, dhg_rgb_flags) = (r.u8()?, r.u8()?);
+        let (slot_c8_status, vbl) = (r.bool()?, r.u8()?);
+
+        self.slots.load_state(expansion_slot);
+        self.memories.copy_from_slice(&memories);
+        self.extra_text_memory = extra_text_memory;
+        self.high_ram = high_rams;
+        self.prewrite = prewrite;
+        self.bank1 = bank1;
+        self.read_enabled = read_enabled;
+        self.write_enabled = write_enabled;
+        self.dhg_previous_address = dhg_previous_address;
+        self.dhg_iou_disabled = dhg_iou_disabled;
+        self.dhg_rgb_mode = dhg_rgb_mode;
+        self.dhg_rgb_flags = dhg_rgb_flags;
+        self.slot_c8_status = slot_c8_status;
+        self.vbl = vbl;
+        self.cycles = cycle;
+        self.speaker.reset(cycle);
+        send_message!(&self.sender, RgbModeUpdate(self.dhg_rgb_mode));
+        Ok(())
     }
 }
 
@@ -131,11 +220,41 @@ impl HardDrive {
 impl Apple2Memory {
     pub(crate) fn new(
         disk_infos: [Option<DiskInfo>; 2],
-        hard_drives: [Option<DiskInfo>; 2],
-        sender: Option<Sender<ToUi>>) -> Self
+        hard_drives: [Option<DiskInfo>; HARD_DRIVE_COUNT],
+        slot_cards: &[Option<CardType>; SLOT_COUNT],
+        sender: Option<Sender<ToUi>>,
+        context: Arc<EmulatorContext>) -> Self
     {
-        Shared::set_hard_drive(0, hard_drives[0].clone());
-        Shared::set_hard_drive(1, hard_drives[1].clone());
+        for (i, hard_drive) in hard_drives.into_iter().enumerate() {
+            context.set_hard_drive(i, hard_drive);
+        }
+
+        let mut slots = Slots::default();
+        for (slot, card_type) in slot_cards.iter().enumerate() {
+            match card_type {
+                Some(card_type @ (CardType::Disk2 | CardType::Disk2Sector13)) => {
+                    let mut controller = DiskController::new_with_filename(slot as u8,
+                        &disk_infos, sender.clone(), context.clone());
+                    controller.set_13_sector_rom(*card_type == CardType::Disk2Sector13);
+                    slots.insert(slot, Box::new(controller));
+                }
+                Some(CardType::SmartPort) => {
+                    // The SmartPort is only visible if we're booting from the hard drive, and
+                    // there is an image in one of its units
+                    let has_image = (0..HARD_DRIVE_COUNT).any(|unit| context.hard_drive(unit).is_some());
+                    if context.boot_from_hard_drive() && has_image {
+                        slots.insert(slot, Box::new(SmartPort::new(slot, context.clone())));
+                    }
+                }
+                Some(CardType::Mockingboard) => {
+                    slots.insert(slot, Box::new(Mockingboard::new(false, Some(context.clone()))));
+                }
+                Some(CardType::MockingboardC) => {
+                    slots.insert(slot, Box::new(Mockingboard::new(true, Some(context.clone()))));
+                }
+                None => {}
+            }
+        }
 
         Self {
             // memory2: Memory2::new(),
@@ -150,14 +269,16 @@ impl Apple2Memory {
             extra_text_memory: [0; 0x400],
             high_ram: [HighRam::default(), HighRam::default()],
             slot_c8_status: false,
-            // disk_controller: DiskController::new(6, "apple2/files/master.dsk"),
-            disk_controller: DiskController::new_with_filename(6, &disk_infos, sender.clone()),
+            slots,
+            speaker: SpeakerSynth::default(),
             dhg_previous_address: 0,
             dhg_iou_disabled: false,
             dhg_rgb_mode: 0,
             dhg_rgb_flags: 0,
-            smartport: SmartPort::default(),
             vbl: 0,
+            cycles: 0,
+            joystick: Joystick::default(),
+            context,
         }
     }
 
@@ -193,27 +314,30 @@ impl Apple2Memory {
         let rom_info = Roms::default().get_rom(rom_type);
         self.load_bytes(&rom_info.bytes, rom_info.offset, 0, 0, true /* main mem */);
 
-        // Disk2 at $C600 in slot (aux mem)
-        self.load_bytes(&DISK2_ROM, 0xc600, 0 /* skip */, 0x100, false /* aux mem */);
+        // The slot ROMs ($C100-$C7FF) are supplied by the cards themselves, see `Slots`
+    }
 
-        // Smartport in $C700
-        // let bytes2 = include_bytes!("c:\\Users\\Ced\\Downloads\\hddrvr-v2.bin");
-        // let bytes = include_bytes!("c:\\Users\\Ced\\Downloads\\HDDRVR.BIN");
-        // self.load_bytes(bytes, 0xc700, 0 /* skip */, 0x100, false /* aux mem */);
-        if ! Shared::get_show_drives() && Shared::get_hard_drive(0).is_some() {
-            self.load_bytes(&SMARTPORT_ROM, 0xc700, 0 /* skip */, 0x100, false /* aux mem */);
+    pub(crate) fn eject_disk(&mut self, is_hard_drive: bool, drive_number: usize) {
+        if is_hard_drive {
+            self.context.set_hard_drive(drive_number, None);
+        } else if let Some(dc) = self.disk_controller_mut() {
+            dc.eject(drive_number);
         }
     }
 
+    /// Fails if the machine doesn't have the controller for this drive
     pub(crate) fn load_disk_from_file(&mut self, is_hard_drive: bool, drive_number: usize,
-        disk_info: DiskInfo)
+        disk_info: DiskInfo) -> Result<(), String>
     {
         if is_hard_drive {
-            Shared::set_hard_drive(drive_number, Some(disk_info.clone()));
+            self.context.set_hard_drive(drive_number, Some(disk_info.clone()));
             send_message!(&self.sender, ToUi::HardDriveInserted(drive_number, Some(disk_info)));
+        } else if let Some(dc) = self.disk_controller_mut() {
+            dc.load_disk_from_file(drive_number, disk_info);
         } else {
-            self.disk_controller.load_disk_from_file(drive_number, disk_info);
+            return Err(format!("No Disk ][ controller in any slot, can't load {}", disk_info.path()));
         }
+        Ok(())
     }
 
     fn log_mem(&self, address: u16, s: &str) {
@@ -247,6 +371,7 @@ impl Apple2Memory {
         if address == 0xcfff {
             // println!("CFFF ACCESSED, c8_status is now false");
             self.slot_c8_status = false;
+            self.slots.reset_expansion_rom();
         } else if (address & 0xc300) == 0xc300 && ! is_set!(self, SLOT_C3_STATUS) {
             // println!("c8_status is now true (accessed {:04X}", address);
             self.slot_c8_status = true;
@@ -314,16 +439,7 @@ impl Apple2Memory {
 
             0xc030..=0xc03f => {
                 // alog(&format!("Speaker"));
-                let result = *CYCLES.read().unwrap();
-                let ts = START.get().unwrap().elapsed().as_micros() as u64;
-                // if *PC.read().unwrap() < 0xf000 {
-                //     event!(Level::DEBUG, "{result}");
-                //     // alog(&format!("SOUND c:{result}"));
-                // }
-                Shared::add_speaker_event(SpeakerEvent {
-                    cycle:  result,
-                    timestamp: ts
-                });
+                self.speaker.toggle(self.cycles);
             }
             0xc080..=0xc08f => {
                 // Language card
@@ -382,11 +498,12 @@ impl Apple2Memory {
             ALT_CHAR_ON if write => { set_soft_switch!(self, ALT_CHAR_STATUS); }
             // 0xc010 => if set { self.memories[MAIN][0xc000] &= 0x7f; }
 
-            0xc0f8 => {
-                let block_number = self.word(0x46);
-                if let Ok(byte) = self.smartport.next_byte(block_number) {
-                    result = Some(byte);
-                }
+            0xc090..=0xc0ff => {
+                // Slots 1-7 I/O. The cards access the memory through the soft switches, like
+                // the CPU. The slots are put back before the next access to them.
+                let mut slots = std::mem::take(&mut self.slots);
+                result = slots.io(address, value, read, self);
+                self.slots = slots;
             }
             0xc100..=0xcffe => {
                 // Sather, Understanding the Apple ][, 5-28
@@ -427,8 +544,18 @@ impl Apple2Memory {
                     (true, true) => { MAIN }
                 };
 
-                let address = address as usize;
-                let value = self.memories[index][address];
+                // Slot space is served by the cards, falling back on aux memory when the slot
+                // is empty or the card doesn't have a ROM there
+                let value = if index == AUX {
+                    let card_value = if address < 0xc800 {
+                        self.slots.page(address, value, read)
+                    } else {
+                        self.slots.read_expansion_rom(address)
+                    };
+                    card_value.unwrap_or(self.memories[AUX][address as usize])
+                } else {
+                    self.memories[MAIN][address as usize]
+                };
                 if read {
                     result = Some(value);
                 } else {
@@ -483,11 +610,9 @@ impl Apple2Memory {
             0xc010 => {
                 // Clear the keyboard location, $C000
                 let value = self.memories[MAIN][0xc000] & 0x7f;
-                if let Some(sender) = &self.sender {
-                    sender.send(ToUi::KeyboardStrobe).unwrap();
-                    self.memories[MAIN][0xc000] = value;
-                    result = Some(value)
-                }
+                self.memories[MAIN][0xc000] = value;
+                result = Some(value);
+                send_message!(&self.sender, ToUi::KeyboardStrobe);
             }
             0xc050 => { clear_soft_switch!(self, TEXT_STATUS); }
             0xc051 => { set_soft_switch!(self, TEXT_STATUS); }
@@ -532,29 +657,23 @@ impl Apple2Memory {
                 }
             }
             0xc061 => {
-                result = Some(if Shared::get_controller_button_value(0) { 0x80 } else { 0 });
+                result = Some(if self.context.controller().buttons[0] { 0x80 } else { 0 });
             }
             0xc062 => {
-                result = Some(if Shared::get_controller_button_value(1) { 0x80 } else { 0 });
+                result = Some(if self.context.controller().buttons[1] { 0x80 } else { 0 });
             }
             0xc064 => {
-                // println!("Querying C064 at cycle {}", *CYCLES.read().unwrap());
-                result = Some(Shared::get_controller_value(0, *CYCLES.read().unwrap()))
+                result = Some(self.joystick.get_value_for_paddle(0, self.cycles,
+                    &self.context.controller().values))
             }
             0xc065 => {
-                // println!("Querying C065 at cycle {}", *CYCLES.read().unwrap());
-                result = Some(Shared::get_controller_value(1, *CYCLES.read().unwrap()))
+                result = Some(self.joystick.get_value_for_paddle(1, self.cycles,
+                    &self.context.controller().values))
             }
             0xc070 => {
-                Shared::reset_joystick(*CYCLES.read().unwrap());
-            }
-            _ => {
-                if self.disk_controller.accept(address) {
-                    let value = self.disk_controller.get_or_set(read, address, value, &self.sender);
-                    // println!("Returning byte {:04X}: {:02X}", address, result);
-                    result = Some(value);
-                }
+                self.joystick.reset_cycles(self.cycles);
             }
+            _ => {}
         }
 
         if result.is_none() {
@@ -564,7 +683,7 @@ impl Apple2Memory {
                 } else { MAIN };
                 result = Some(self.memories[index][address as usize]);
                 if address == 0xc064 || address == 0xc06c {
-                    alog(&format!("{address:04X} returning {:02X}", result.unwrap()));
+                    alog(self.cycles, &format!("{address:04X} returning {:02X}", result.unwrap()));
                     println!("");
                 }
             } else if (0x200..0xc000).contains(&address) {
@@ -634,6 +753,16 @@ impl Apple2Memory {
 }
 
 
+impl Bus for Apple2Memory {
+    fn read(&mut self, address: u16) -> u8 {
+        self.get(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.set(address, value);
+    }
+}
+
 impl Memory for Apple2Memory {
     fn get(&mut self, address: u16) -> u8 {
         if let Some(v) = self.get_or_set(address, 0, true /* get */) {
@@ -657,7 +786,7 @@ impl Memory for Apple2Memory {
             result.push(self.memories[MAIN][i]);
         }
         for i in 0xc600..=0xc6ff {
-            result.push(self.memories[AUX][i])
+            result.push(self.slots.peek_rom(i as u16).unwrap_or(self.memories[AUX][i]))
         }
         for i in 0xc700..=0xffff {
             // result.push(self.memories[MAIN][i])
@@ -107,11 +107,27 @@ pub enum ToCpu {
     SaveGraphics,
     /// Bool: is_hard_drive, Drive number (0 or 1), path
     LoadDisk(bool, usize, DiskInfo),
+    /// Bool: is_hard_drive, Drive number (0 or 1)
+    EjectDisk(bool, usize),
     /// Make disk write protected
     LockDisk(usize),
     /// Make disk writable
     UnlockDisk(usize),
+    /// Drive number: save the writes to an overlay instead of the image
+    EnableDiskOverlay(usize),
+    /// Drive number: save the disk to its image and delete the overlay
+    CommitDiskOverlay(usize),
+    /// Drive number: delete the overlay and reload the image
+    DiscardDiskOverlay(usize),
     Debug,
+    /// Save the state of the machine to this file, see [crate::snapshot]
+    SaveState(String),
+    /// Restore the state of the machine from this file
+    LoadState(String),
+    /// Pause and restore the rewind snapshot at this index (0 is the oldest)
+    Rewind(usize),
+    /// Resume from the rewind snapshot being displayed, the more recent ones are discarded
+    ResumeRewind,
     /// true: run, false: pause
     CpuState(CpuStateMsg),
     TraceStatus(TraceStatusMsg),
@@ -0,0 +1,242 @@
+use std::any::Any;
+use std::sync::Arc;
+use crate::card::{Bus, Card};
+use crate::constants::{CPU_FREQUENCY_HZ, MOCKINGBOARD_VOLUME, SAMPLE_RATE};
+use crate::context::EmulatorContext;
+use crate::mockingboard::psg::{BusFunction, Psg};
+use crate::mockingboard::ssi263::Ssi263;
+use crate::mockingboard::via::{PortWrite, Via, IRQ_CA1};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+/// Offset of the SSI-263 registers in the slot page ($Cn40-$Cn44)
+const SSI263_OFFSET: u8 = 0x40;
+
+/// Number of samples accumulated before they are handed to the sound thread
+const SAMPLE_BATCH_SIZE: usize = 256;
+
+/// Sweet Micro Systems Mockingboard. All the registers live in the slot page:
+/// - $Cn00-$Cn7F: first 6522, driving the first AY-3-8910 (left channel)
+/// - $Cn80-$CnFF: second 6522, driving the second AY-3-8910 (right channel)
+/// - $Cn40-$Cn44: SSI-263 (Mockingboard C only)
+///
+/// The card doesn't use the $C0n0 I/O locations and doesn't have a ROM.
+pub struct Mockingboard {
+    pub vias: [Via; 2],
+    pub psgs: [Psg; 2],
+    pub ssi263: Option<Ssi263>,
+
+    /// [Card::step] is invoked at 2 Mhz, the chips run at 1 Mhz
+    odd_step: bool,
+
+    /// Sound samples at SAMPLE_RATE, mixed down to mono
+    samples: Vec<f32>,
+    /// Accumulates the output of the PSGs between two samples
+    sample_sum: f32,
+    sample_cycles: u32,
+    /// Fractional part of the cycles per sample, scaled by SAMPLE_RATE
+    sample_error: u32,
+    /// Number of cycles the emulator runs per second, the samples are played in real time
+    cpu_frequency: u32,
+    /// If present, send the samples to the sound thread through the context. Tests don't and
+    /// read the samples with [Mockingboard::take_samples] instead.
+    context: Option<Arc<EmulatorContext>>,
+    /// The state read by [Card::prepare_state], restored by [Card::load_state]
+    pending_state: Option<Box<Mockingboard>>,
+}
+
+impl Mockingboard {
+    pub fn new(with_speech: bool, context: Option<Arc<EmulatorContext>>) -> Self {
+        let mut result = Self {
+            vias: [Via::default(), Via::default()],
+            psgs: [Psg::new(), Psg::new()],
+            ssi263: if with_speech { Some(Ssi263::default()) } else { None },
+            odd_step: false,
+            samples: Vec::new(),
+            sample_sum: 0.0,
+            sample_cycles: 0,
+            sample_error: 0,
+            cpu_frequency: CPU_FREQUENCY_HZ,
+            context,
+            pending_state: None,
+        };
+        result.reset();
+        result
+    }
+
+    /// Same as [crate::speaker::SpeakerSynth::set_cpu_frequency]
+    pub fn set_cpu_frequency(&mut self, cpu_frequency: u64) {
+        self.cpu_frequency = u32::try_from(cpu_frequency).unwrap_or(u32::MAX);
+    }
+
+    /// Read a register. `offset` is the offset in the slot page.
+    pub fn read(&mut self, offset: u8) -> u8 {
+        if let (Some(ssi263), true) = (&self.ssi263, is_ssi263(offset)) {
+            ssi263.read()
+        } else {
+            self.vias[via_index(offset)].read(offset & 0xf)
+        }
+    }
+
+    /// Write a register. `offset` is the offset in the slot page.
+    pub fn write(&mut self, offset: u8, value: u8) {
+        if let (Some(ssi263), true) = (&mut self.ssi263, is_ssi263(offset)) {
+            ssi263.write((offset - SSI263_OFFSET) as usize, value);
+            return;
+        }
+
+        let index = via_index(offset);
+        match self.vias[index].write(offset & 0xf, value) {
+            PortWrite::B(b) => {
+                // Port B selects the bus function of the PSG, port A is its data bus
+                let data = self.vias[index].ora;
+                if let Some(v) = self.psgs[index].bus(BusFunction::from_port_b(b), data) {
+                    self.vias[index].ira = v;
+                }
+            }
+            PortWrite::A(_) | PortWrite::None => {}
+        }
+    }
+
+    /// Advance all the chips by one cycle and produce a sound sample when it's time
+    fn cycle(&mut self) {
+        for via in &mut self.vias {
+            via.step();
+        }
+        for psg in &mut self.psgs {
+            psg.step();
+        }
+        if let Some(ssi263) = &mut self.ssi263 {
+            if ssi263.step() {
+                // A/R is connected to CA1 of the first 6522
+                self.vias[0].set_interrupt_flag(IRQ_CA1);
+            }
+        }
+
+        self.sample_sum += (self.psgs[0].output() + self.psgs[1].output()) / 2.0;
+        self.sample_cycles += 1;
+
+        // Spread the remainder of cpu_frequency / SAMPLE_RATE over the samples
+        let cycles_per_sample = self.cpu_frequency / SAMPLE_RATE;
+        let extra = if self.sample_error >= SAMPLE_RATE { 1 } else { 0 };
+        if self.sample_cycles >= cycles_per_sample + extra {
+            self.sample_error = self.sample_error + self.cpu_frequency % SAMPLE_RATE - extra * SAMPLE_RATE;
+            self.samples.push(MOCKINGBOARD_VOLUME * self.sample_sum / self.sample_cycles as f32);
+            self.sample_sum = 0.0;
+            self.sample_cycles = 0;
+            if let Some(context) = &self.context {
+                if self.samples.len() >= SAMPLE_BATCH_SIZE {
+                    // Silent batches too: skipping them would play the next sounds early
+                    context.add_mockingboard_samples(&self.samples);
+                    self.samples.clear();
+                }
+            }
+        }
+    }
+
+    /// The samples generated so far
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+fn via_index(offset: u8) -> usize {
+    if offset & 0x80 == 0 { 0 } else { 1 }
+}
+
+fn is_ssi263(offset: u8) -> bool {
+    (SSI263_OFFSET..=SSI263_OFFSET + 4).contains(&offset)
+}
+
+impl Card for Mockingboard {
+    fn name(&self) -> String {
+        if self.ssi263.is_some() { "Mockingboard C" } else { "Mockingboard" }.to_string()
+    }
+
+    fn io(&mut self, _offset: u8, _value: u8, _read: bool, _bus: &mut dyn Bus) -> u8 {
+        0
+    }
+
+    fn page(&mut self, offset: u8, value: u8, read: bool) -> Option<u8> {
+        if read {
+            Some(self.read(offset))
+        } else {
+            self.write(offset, value);
+            None
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.vias[0].irq() || self.vias[1].irq()
+    }
+
+    fn step(&mut self) {
+        self.odd_step = ! self.odd_step;
+        if self.odd_step {
+            self.cycle();
+        }
+    }
+
+    fn reset(&mut self) {
+        for via in &mut self.vias {
+            via.reset();
+        }
+        for psg in &mut self.psgs {
+            psg.reset();
+        }
+        if let Some(ssi263) = &mut self.ssi263 {
+            ssi263.reset();
+        }
+    }
+
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        for via in &self.vias {
+            via.save_state(w);
+        }
+        for psg in &self.psgs {
+            psg.save_state(w);
+        }
+        // The presence of the SSI-263 is part of the card name, see [crate::card::Slots::save_state]
+        if let Some(ssi263) = &self.ssi263 {
+            ssi263.save_state(w);
+        }
+        w.bool(self.odd_step);
+        w.f32(self.sample_sum);
+        w.u32(self.sample_cycles);
+        w.u32(self.sample_error);
+    }
+
+    fn prepare_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let mut state = Mockingboard::new(self.ssi263.is_some(), None);
+        for via in &mut state.vias {
+            via.load_state(r)?;
+        }
+        for psg in &mut state.psgs {
+            psg.load_state(r)?;
+        }
+        if let Some(ssi263) = &mut state.ssi263 {
+            ssi263.load_state(r)?;
+        }
+        state.odd_step = r.bool()?;
+        state.sample_sum = r.f32()?;
+        state.sample_cycles = r.u32()?;
+        state.sample_error = r.u32()?;
+        self.pending_state = Some(Box::new(state));
+        Ok(())
+    }
+
+    fn load_state(&mut self) {
+        if let Some(state) = self.pending_state.take() {
+            self.vias = state.vias;
+            self.psgs = state.psgs;
+            self.ssi263 = state.ssi263;
+            self.odd_step = state.odd_step;
+            self.sample_sum = state.sample_sum;
+            self.sample_cycles = state.sample_cycles;
+            self.sample_error = state.sample_error;
+            self.samples.clear();
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
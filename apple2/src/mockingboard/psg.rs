@@ -0,0 +1,282 @@
+//! General Instrument AY-3-8910 Programmable Sound Generator: three square wave channels,
+//! one noise generator and one envelope generator. On the Mockingboard, the PSG is clocked
+//! by the Apple ][ 1 Mhz clock and driven by the ports of a 6522 (see [crate::mockingboard::via]).
+//!
+//! Reference: General Instrument AY-3-8910/8912 data manual.
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// Registers
+pub const TONE_A_FINE: usize = 0;
+pub const TONE_A_COARSE: usize = 1;
+pub const TONE_B_FINE: usize = 2;
+pub const TONE_B_COARSE: usize = 3;
+pub const TONE_C_FINE: usize = 4;
+pub const TONE_C_COARSE: usize = 5;
+pub const NOISE_PERIOD: usize = 6;
+pub const MIXER: usize = 7;
+pub const AMPLITUDE_A: usize = 8;
+pub const AMPLITUDE_B: usize = 9;
+pub const AMPLITUDE_C: usize = 10;
+pub const ENVELOPE_FINE: usize = 11;
+pub const ENVELOPE_COARSE: usize = 12;
+pub const ENVELOPE_SHAPE: usize = 13;
+pub const IO_PORT_A: usize = 14;
+pub const IO_PORT_B: usize = 15;
+
+/// Some registers don't use all their bits
+const REGISTER_MASKS: [u8; 16] = [
+    0xff, 0x0f, 0xff, 0x0f, 0xff, 0x0f, 0x1f, 0xff,
+    0x1f, 0x1f, 0x1f, 0xff, 0xff, 0x0f, 0xff, 0xff,
+];
+
+/// Amplitude bit 4: the volume is controlled by the envelope generator
+const AMPLITUDE_ENVELOPE: u8 = 0x10;
+
+// Envelope shape bits
+const ENVELOPE_HOLD: u8 = 1;
+const ENVELOPE_ALTERNATE: u8 = 2;
+const ENVELOPE_ATTACK: u8 = 4;
+const ENVELOPE_CONTINUE: u8 = 8;
+
+/// The DAC of the AY-3-8910 is logarithmic, about 3dB per step
+const VOLUMES: [f32; 16] = [
+    0.0, 0.0106, 0.0150, 0.0222, 0.0320, 0.0466, 0.0665, 0.1039,
+    0.1237, 0.1986, 0.2803, 0.3548, 0.4702, 0.6030, 0.7530, 1.0,
+];
+
+/// The bus functions selected by BDIR, BC2 and BC1. On the Mockingboard, BC2 is tied high,
+/// BC1 is PB0, BDIR is PB1 and /RESET is PB2.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BusFunction {
+    Reset,
+    Inactive,
+    Read,
+    Write,
+    LatchAddress,
+}
+
+impl BusFunction {
+    /// Decode the value written to the port B of the 6522 the PSG is connected to
+    pub fn from_port_b(value: u8) -> Self {
+        if value & 4 == 0 {
+            BusFunction::Reset
+        } else {
+            match value & 3 {
+                1 => BusFunction::Read,
+                2 => BusFunction::Write,
+                3 => BusFunction::LatchAddress,
+                _ => BusFunction::Inactive,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Psg {
+    pub registers: [u8; 16],
+    /// The register selected by the last LatchAddress
+    pub address: u8,
+
+    /// The 1 Mhz clock is divided by 8 before it reaches the generators
+    prescaler: u8,
+    /// Noise and envelope run at half the rate of the tone generators
+    half: bool,
+
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+
+    noise_counter: u8,
+    /// 17 bit linear feedback shift register
+    noise_shift: u32,
+
+    envelope_counter: u16,
+    /// Counts down from 15 to 0 at each envelope step
+    envelope_step: u8,
+    /// 0 for a decaying envelope, 15 for an attack (xor'ed with envelope_step)
+    envelope_attack: u8,
+    envelope_holding: bool,
+}
+
+impl Psg {
+    pub fn new() -> Self {
+        let mut result = Psg::default();
+        result.reset();
+        result
+    }
+
+    pub fn reset(&mut self) {
+        *self = Psg::default();
+        self.noise_shift = 1;
+        // All channels disabled
+        self.registers[MIXER] = 0xff;
+    }
+
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.bytes(&self.registers);
+        w.u8(self.address);
+        w.u8(self.prescaler);
+        w.bool(self.half);
+        for i in 0..3 {
+            w.u16(self.tone_counters[i]);
+            w.bool(self.tone_outputs[i]);
+        }
+        w.u8(self.noise_counter);
+        w.u32(self.noise_shift);
+        w.u16(self.envelope_counter);
+        w.u8(self.envelope_step);
+        w.u8(self.envelope_attack);
+        w.bool(self.envelope_holding);
+    }
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        r.bytes_into(&mut self.registers)?;
+        self.address = r.u8()?;
+        self.prescaler = r.u8()?;
+        self.half = r.bool()?;
+        for i in 0..3 {
+            self.tone_counters[i] = r.u16()?;
+            self.tone_outputs[i] = r.bool()?;
+        }
+        self.noise_counter = r.u8()?;
+        self.noise_shift = r.u32()?;
+        self.envelope_counter = r.u16()?;
+        self.envelope_step = r.u8()?;
+        self.envelope_attack = r.u8()?;
+        self.envelope_holding = r.bool()?;
+        Ok(())
+    }
+
+    /// Perform the bus function selected on port B, `data` being the value on port A.
+    /// Return the value the PSG puts on the data bus for a read.
+    pub fn bus(&mut self, function: BusFunction, data: u8) -> Option<u8> {
+        match function {
+            BusFunction::Reset => { self.reset(); None },
+            BusFunction::Inactive => None,
+            BusFunction::Read => {
+                if self.address < 16 { Some(self.registers[self.address as usize]) } else { Some(0xff) }
+            }
+            BusFunction::Write => {
+                if self.address < 16 {
+                    self.write_register(self.address as usize, data);
+                }
+                None
+            }
+            BusFunction::LatchAddress => { self.address = data; None }
+        }
+    }
+
+    pub fn write_register(&mut self, register: usize, value: u8) {
+        self.registers[register] = value & REGISTER_MASKS[register];
+        if register == ENVELOPE_SHAPE {
+            // Writing the shape restarts the envelope
+            self.envelope_counter = 0;
+            self.envelope_step = 15;
+            self.envelope_holding = false;
+            self.envelope_attack = if value & ENVELOPE_ATTACK != 0 { 15 } else { 0 };
+        }
+    }
+
+    pub fn tone_period(&self, channel: usize) -> u16 {
+        self.registers[channel * 2] as u16 | ((self.registers[channel * 2 + 1] as u16) << 8)
+    }
+
+    fn envelope_period(&self) -> u16 {
+        self.registers[ENVELOPE_FINE] as u16 | ((self.registers[ENVELOPE_COARSE] as u16) << 8)
+    }
+
+    /// Current volume of the envelope generator, 0..=15
+    pub fn envelope_volume(&self) -> u8 {
+        self.envelope_step ^ self.envelope_attack
+    }
+
+    /// Advance the PSG by one cycle (1 Mhz)
+    pub fn step(&mut self) {
+        self.prescaler += 1;
+        if self.prescaler < 8 {
+            return;
+        }
+        self.prescaler = 0;
+
+        // Tone: the output toggles every `period` ticks, so the frequency is clock / (16 * period)
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.tone_period(channel).max(1) {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = ! self.tone_outputs[channel];
+            }
+        }
+
+        self.half = ! self.half;
+        if self.half {
+            return;
+        }
+
+        // Noise
+        self.noise_counter += 1;
+        if self.noise_counter >= self.registers[NOISE_PERIOD].max(1) {
+            self.noise_counter = 0;
+            let bit = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+            self.noise_shift = (self.noise_shift >> 1) | (bit << 16);
+        }
+
+        // Envelope: 16 steps per period, so the frequency is clock / (256 * period)
+        self.envelope_counter += 1;
+        if self.envelope_counter >= self.envelope_period().max(1) {
+            self.envelope_counter = 0;
+            self.step_envelope();
+        }
+    }
+
+    fn step_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        if self.envelope_step > 0 {
+            self.envelope_step -= 1;
+            return;
+        }
+
+        // End of a cycle
+        let shape = self.registers[ENVELOPE_SHAPE];
+        if shape & ENVELOPE_CONTINUE == 0 {
+            // Shapes 0-7 drop to 0 and stay there
+            self.envelope_attack = 0;
+            self.envelope_holding = true;
+        } else {
+            if shape & ENVELOPE_ALTERNATE != 0 {
+                self.envelope_attack ^= 15;
+            }
+            if shape & ENVELOPE_HOLD != 0 {
+                self.envelope_holding = true;
+            } else {
+                self.envelope_step = 15;
+            }
+        }
+    }
+
+    /// Output of one channel, 0.0..=1.0
+    pub fn channel_output(&self, channel: usize) -> f32 {
+        let mixer = self.registers[MIXER];
+        let tone_disabled = mixer & (1 << channel) != 0;
+        let noise_disabled = mixer & (8 << channel) != 0;
+        let noise = self.noise_shift & 1 != 0;
+        let on = (self.tone_outputs[channel] || tone_disabled) && (noise || noise_disabled);
+        if on {
+            let amplitude = self.registers[AMPLITUDE_A + channel];
+            let volume = if amplitude & AMPLITUDE_ENVELOPE != 0 {
+                self.envelope_volume()
+            } else {
+                amplitude & 0xf
+            };
+            VOLUMES[volume as usize]
+        } else {
+            0.0
+        }
+    }
+
+    /// Mix of the three channels, 0.0..=1.0
+    pub fn output(&self) -> f32 {
+        (self.channel_output(0) + self.channel_output(1) + self.channel_output(2)) / 3.0
+    }
+}
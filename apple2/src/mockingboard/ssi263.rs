@@ -0,0 +1,91 @@
+//! Silicon Systems SSI-263 phoneme speech synthesizer, present on the Mockingboard C.
+//! Only the register interface is emulated: phonemes are accepted and timed so that programs
+//! waiting for the chip to request the next one don't hang, but no sound is produced yet.
+//!
+//! Reference: SSI 263A data sheet.
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// Registers
+pub const DURATION_PHONEME: usize = 0;
+pub const INFLECTION: usize = 1;
+pub const RATE_INFLECTION: usize = 2;
+pub const CONTROL_ARTICULATION_AMPLITUDE: usize = 3;
+pub const FILTER_FREQUENCY: usize = 4;
+
+/// Control bit: when set, the chip is in power down / standby mode
+const CONTROL: u8 = 0x80;
+
+/// Bit 7 of the value read: A/R, set when the chip is requesting the next phoneme
+pub const REQUEST: u8 = 0x80;
+
+#[derive(Clone, Debug, Default)]
+pub struct Ssi263 {
+    pub registers: [u8; 5],
+    /// Cycles left before the current phoneme is done
+    cycles_left: u32,
+    /// True when the current phoneme is done and the chip is waiting for the next one
+    request: bool,
+}
+
+impl Ssi263 {
+    pub fn reset(&mut self) {
+        *self = Ssi263::default();
+        self.registers[CONTROL_ARTICULATION_AMPLITUDE] = CONTROL;
+    }
+
+    pub fn read(&self) -> u8 {
+        if self.request { REQUEST } else { 0 }
+    }
+
+    pub fn write(&mut self, register: usize, value: u8) {
+        if register >= self.registers.len() {
+            return;
+        }
+        self.registers[register] = value;
+        if register == DURATION_PHONEME {
+            self.request = false;
+            if ! self.is_powered_down() {
+                self.cycles_left = self.phoneme_cycles();
+            }
+        }
+    }
+
+    fn is_powered_down(&self) -> bool {
+        self.registers[CONTROL_ARTICULATION_AMPLITUDE] & CONTROL != 0
+    }
+
+    /// Approximate length of the current phoneme. The duration (bits 6-7 of register 0) and
+    /// the rate (bits 4-7 of register 2) scale a base length of about 50ms.
+    fn phoneme_cycles(&self) -> u32 {
+        let duration = 4 - (self.registers[DURATION_PHONEME] >> 6) as u32;
+        let rate = 16 - (self.registers[RATE_INFLECTION] >> 4) as u32;
+        duration * rate * 800
+    }
+
+    /// Advance one cycle (1 Mhz). Return true when the current phoneme just ended, which
+    /// is when the chip raises its A/R line (connected to CA1 of the 6522).
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.bytes(&self.registers);
+        w.u32(self.cycles_left);
+        w.bool(self.request);
+    }
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        r.bytes_into(&mut self.registers)?;
+        self.cycles_left = r.u32()?;
+        self.request = r.bool()?;
+        Ok(())
+    }
+
+    pub fn step(&mut self) -> bool {
+        if self.cycles_left > 0 {
+            self.cycles_left -= 1;
+            if self.cycles_left == 0 {
+                self.request = true;
+                return true;
+            }
+        }
+        false
+    }
+}
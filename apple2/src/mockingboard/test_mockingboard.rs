@@ -0,0 +1,233 @@
+use std::sync::Arc;
+use crate::card::Card;
+use crate::context::EmulatorContext;
+use crate::mockingboard::mockingboard::Mockingboard;
+use crate::mockingboard::psg::*;
+use crate::mockingboard::ssi263::REQUEST;
+use crate::mockingboard::via::*;
+
+/// Run `cycles` 1 Mhz cycles (two card steps each)
+fn run(mb: &mut Mockingboard, cycles: usize) {
+    for _ in 0..cycles * 2 {
+        mb.step();
+    }
+}
+
+/// Write a PSG register the way programs do it: through the ports of the 6522
+fn write_psg(mb: &mut Mockingboard, via_base: u8, register: u8, value: u8) {
+    mb.write(via_base + ORA, register);
+    mb.write(via_base + ORB, 7);  // Latch address
+    mb.write(via_base + ORB, 4);  // Inactive
+    mb.write(via_base + ORA, value);
+    mb.write(via_base + ORB, 6);  // Write
+    mb.write(via_base + ORB, 4);
+}
+
+fn new_mockingboard(with_speech: bool) -> Mockingboard {
+    let mut result = Mockingboard::new(with_speech, None);
+    for base in [0, 0x80] {
+        result.write(base + DDRA, 0xff);
+        result.write(base + DDRB, 0x07);
+        result.write(base + ORB, 4);
+    }
+    result
+}
+
+#[test]
+fn via_timer1_one_shot() {
+    let mut via = Via::default();
+    via.write(IER, 0x80 | IRQ_T1);
+    via.write(T1C_L, 10);
+    via.write(T1C_H, 0);
+    assert_eq!(via.read(T1C_L), 10);
+    for _ in 0..10 { via.step(); }
+    assert!(! via.irq());
+    via.step();
+    assert!(via.irq());
+    assert_eq!(via.read(IFR), IRQ_ANY | IRQ_T1);
+
+    // Reading T1C-L acknowledges the interrupt
+    via.read(T1C_L);
+    assert!(! via.irq());
+
+    // One shot: no more interrupts until T1C-H is written again
+    for _ in 0..0x20000 { via.step(); }
+    assert!(! via.irq());
+}
+
+#[test]
+fn via_timer1_continuous() {
+    let mut via = Via::default();
+    via.write(ACR, 0x40);
+    via.write(IER, 0x80 | IRQ_T1);
+    via.write(T1L_L, 99);
+    via.write(T1C_H, 0);
+    let mut interrupts = 0;
+    for _ in 0..1000 {
+        via.step();
+        if via.irq() {
+            interrupts += 1;
+            // Acknowledge by writing to the IFR
+            via.write(IFR, IRQ_T1);
+        }
+    }
+    // One interrupt every 100 cycles (latch + 1)
+    assert_eq!(interrupts, 10);
+}
+
+#[test]
+fn via_ier() {
+    let mut via = Via::default();
+    via.write(T2C_L, 1);
+    via.write(T2C_H, 0);
+    via.step();
+    via.step();
+    // The flag is set but the interrupt isn't enabled
+    assert_eq!(via.read(IFR), IRQ_T2);
+    assert!(! via.irq());
+    via.write(IER, 0x80 | IRQ_T2 | IRQ_T1);
+    assert_eq!(via.read(IER), 0x80 | IRQ_T2 | IRQ_T1);
+    assert!(via.irq());
+    via.write(IER, IRQ_T2);
+    assert_eq!(via.read(IER), 0x80 | IRQ_T1);
+    assert!(! via.irq());
+}
+
+#[test]
+fn psg_through_via() {
+    let mut mb = new_mockingboard(false);
+    write_psg(&mut mb, 0, TONE_A_FINE as u8, 0x34);
+    write_psg(&mut mb, 0, TONE_A_COARSE as u8, 0xff);
+    write_psg(&mut mb, 0x80, AMPLITUDE_C as u8, 0x0a);
+    assert_eq!(mb.psgs[0].tone_period(0), 0xf34);
+    assert_eq!(mb.psgs[1].registers[AMPLITUDE_C], 0x0a);
+    assert_eq!(mb.psgs[0].registers[AMPLITUDE_C], 0);
+
+    // Read the register back through port A
+    mb.write(DDRA, 0);
+    mb.write(ORA, TONE_A_FINE as u8);
+    mb.write(ORB, 7);
+    mb.write(ORB, 5);
+    assert_eq!(mb.read(ORA), 0x34);
+
+    // /RESET low resets the PSG
+    mb.write(ORB, 0);
+    assert_eq!(mb.psgs[0].tone_period(0), 0);
+}
+
+#[test]
+fn psg_tone_frequency() {
+    let mut psg = Psg::new();
+    // Period 50: 1 Mhz / (16 * 50) = 1250 Hz, so 2500 transitions per second
+    psg.write_register(TONE_A_FINE, 50);
+    psg.write_register(MIXER, 0b111110);
+    psg.write_register(AMPLITUDE_A, 15);
+    let mut transitions = 0;
+    let mut previous = psg.channel_output(0);
+    for _ in 0..1_000_000 {
+        psg.step();
+        let output = psg.channel_output(0);
+        if output != previous {
+            transitions += 1;
+            previous = output;
+        }
+    }
+    assert_eq!(transitions, 2500);
+}
+
+#[test]
+fn psg_envelope() {
+    let mut psg = Psg::new();
+    psg.write_register(ENVELOPE_FINE, 1);
+    // Attack then hold at the maximum
+    psg.write_register(ENVELOPE_SHAPE, 0b1101);
+    assert_eq!(psg.envelope_volume(), 0);
+    // One envelope step every 16 cycles with a period of 1
+    for _ in 0..16 { psg.step(); }
+    assert_eq!(psg.envelope_volume(), 1);
+    for _ in 0..16 * 20 { psg.step(); }
+    assert_eq!(psg.envelope_volume(), 15);
+
+    // Decay then stay at 0
+    psg.write_register(ENVELOPE_SHAPE, 0b0000);
+    assert_eq!(psg.envelope_volume(), 15);
+    for _ in 0..16 * 20 { psg.step(); }
+    assert_eq!(psg.envelope_volume(), 0);
+
+    // Triangle
+    psg.write_register(ENVELOPE_SHAPE, 0b1110);
+    for _ in 0..16 * 16 { psg.step(); }
+    assert_eq!(psg.envelope_volume(), 15);
+    for _ in 0..16 * 8 { psg.step(); }
+    assert_eq!(psg.envelope_volume(), 7);
+}
+
+#[test]
+fn mockingboard_irq_and_reset() {
+    let mut mb = new_mockingboard(false);
+    mb.write(0x80 + IER, 0x80 | IRQ_T1);
+    mb.write(0x80 + T1C_L, 0x10);
+    mb.write(0x80 + T1C_H, 0);
+    run(&mut mb, 0x10);
+    assert!(! mb.irq());
+    run(&mut mb, 1);
+    assert!(mb.irq());
+    mb.reset();
+    assert!(! mb.irq());
+    assert_eq!(mb.read(0x80 + IER), 0x80);
+}
+
+#[test]
+fn mockingboard_samples() {
+    let mut mb = new_mockingboard(false);
+    run(&mut mb, 10_000);
+    let samples = mb.take_samples();
+    // 10ms at 48 kHz
+    assert_eq!(samples.len(), 480);
+    assert!(samples.iter().all(|s| *s == 0.0));
+
+    write_psg(&mut mb, 0, TONE_A_FINE as u8, 100);
+    write_psg(&mut mb, 0, MIXER as u8, 0b111110);
+    write_psg(&mut mb, 0, AMPLITUDE_A as u8, 15);
+    run(&mut mb, 10_000);
+    let samples = mb.take_samples();
+    assert!(samples.iter().any(|s| *s > 0.0));
+    assert!(samples.contains(&0.0));
+
+    // At 2 Mhz, twice as many cycles make a sample
+    mb.set_cpu_frequency(2_000_000);
+    run(&mut mb, 10_000);
+    assert_eq!(mb.take_samples().len(), 240);
+}
+
+#[test]
+fn silent_samples_are_sent() {
+    let context = Arc::new(EmulatorContext::default());
+    let mut mb = Mockingboard::new(false, Some(context.clone()));
+    // A bit more than 256 samples
+    run(&mut mb, 6_000);
+    let mut count = 0;
+    while let Some(sample) = context.next_mockingboard_sample() {
+        assert_eq!(sample, 0.0);
+        count += 1;
+    }
+    assert_eq!(count, 256);
+}
+
+#[test]
+fn ssi263() {
+    // Without the speech chip, $Cn40 is the first 6522
+    let mut mb = new_mockingboard(false);
+    mb.write(0x40 + DDRB, 0x12);
+    assert_eq!(mb.vias[0].ddrb, 0x12);
+
+    let mut mb = new_mockingboard(true);
+    mb.write(IER, 0x80 | IRQ_CA1);
+    // Power up, then send a phoneme
+    mb.write(0x43, 0x00);
+    mb.write(0x40, 0xc1);
+    assert_eq!(mb.read(0x40) & REQUEST, 0);
+    run(&mut mb, 100_000);
+    assert_eq!(mb.read(0x40) & REQUEST, REQUEST);
+    assert!(mb.irq());
+}
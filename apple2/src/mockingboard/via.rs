@@ -0,0 +1,232 @@
+//! Rockwell/Synertek 6522 Versatile Interface Adapter. The Mockingboard uses two of them:
+//! port B drives the control lines of a PSG, port A is its data bus, and timer 1
+//! is used by most programs as a periodic interrupt source.
+//!
+//! Timer reference: Rockwell R6522 data sheet, "Timer 1 Operation" and "Timer 2 Operation".
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// Registers
+pub const ORB: u8 = 0x0;
+pub const ORA: u8 = 0x1;
+pub const DDRB: u8 = 0x2;
+pub const DDRA: u8 = 0x3;
+pub const T1C_L: u8 = 0x4;
+pub const T1C_H: u8 = 0x5;
+pub const T1L_L: u8 = 0x6;
+pub const T1L_H: u8 = 0x7;
+pub const T2C_L: u8 = 0x8;
+pub const T2C_H: u8 = 0x9;
+pub const SR: u8 = 0xa;
+pub const ACR: u8 = 0xb;
+pub const PCR: u8 = 0xc;
+pub const IFR: u8 = 0xd;
+pub const IER: u8 = 0xe;
+pub const ORA_NO_HANDSHAKE: u8 = 0xf;
+
+// Interrupt flags (IFR and IER)
+pub const IRQ_CA2: u8 = 0x01;
+pub const IRQ_CA1: u8 = 0x02;
+pub const IRQ_SR: u8 = 0x04;
+pub const IRQ_CB2: u8 = 0x08;
+pub const IRQ_CB1: u8 = 0x10;
+pub const IRQ_T2: u8 = 0x20;
+pub const IRQ_T1: u8 = 0x40;
+pub const IRQ_ANY: u8 = 0x80;
+
+/// ACR bit 6: timer 1 reloads itself from its latch and keeps interrupting
+const ACR_T1_CONTINUOUS: u8 = 0x40;
+
+/// What happened on the ports after a register write, so the owner of the VIA
+/// can forward it to the devices connected to them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortWrite {
+    None,
+    A(u8),
+    B(u8),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Via {
+    pub ora: u8,
+    pub orb: u8,
+    pub ddra: u8,
+    pub ddrb: u8,
+    /// Values driven on the port pins by the outside world (used for the pins configured as inputs)
+    pub ira: u8,
+    pub irb: u8,
+
+    pub t1_counter: u16,
+    pub t1_latch: u16,
+    /// One shot mode: the timer only interrupts once after being started
+    t1_armed: bool,
+
+    pub t2_counter: u16,
+    t2_latch_low: u8,
+    t2_armed: bool,
+
+    pub sr: u8,
+    pub acr: u8,
+    pub pcr: u8,
+    pub ifr: u8,
+    pub ier: u8,
+}
+
+impl Via {
+    pub fn reset(&mut self) {
+        // The reset line clears all the registers except the timers and the shift register
+        let (t1_counter, t1_latch, t2_counter, sr) = (self.t1_counter, self.t1_latch,
+            self.t2_counter, self.sr);
+        *self = Via::default();
+        self.t1_counter = t1_counter;
+        self.t1_latch = t1_latch;
+        self.t2_counter = t2_counter;
+        self.sr = sr;
+    }
+
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.bytes(&[self.ora, self.orb, self.ddra, self.ddrb, self.ira, self.irb]);
+        w.u16(self.t1_counter);
+        w.u16(self.t1_latch);
+        w.bool(self.t1_armed);
+        w.u16(self.t2_counter);
+        w.u8(self.t2_latch_low);
+        w.bool(self.t2_armed);
+        w.bytes(&[self.sr, self.acr, self.pcr, self.ifr, self.ier]);
+    }
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let mut ports = [0; 6];
+        r.bytes_into(&mut ports)?;
+        [self.ora, self.orb, self.ddra, self.ddrb, self.ira, self.irb] = ports;
+        self.t1_counter = r.u16()?;
+        self.t1_latch = r.u16()?;
+        self.t1_armed = r.bool()?;
+        self.t2_counter = r.u16()?;
+        self.t2_latch_low = r.u8()?;
+        self.t2_armed = r.bool()?;
+        let mut registers = [0; 5];
+        r.bytes_into(&mut registers)?;
+        [self.sr, self.acr, self.pcr, self.ifr, self.ier] = registers;
+        Ok(())
+    }
+
+    pub fn read(&mut self, register: u8) -> u8 {
+        match register & 0xf {
+            ORB => (self.orb & self.ddrb) | (self.irb & ! self.ddrb),
+            ORA | ORA_NO_HANDSHAKE => (self.ora & self.ddra) | (self.ira & ! self.ddra),
+            DDRB => self.ddrb,
+            DDRA => self.ddra,
+            T1C_L => {
+                self.ifr &= ! IRQ_T1;
+                self.t1_counter as u8
+            }
+            T1C_H => (self.t1_counter >> 8) as u8,
+            T1L_L => self.t1_latch as u8,
+            T1L_H => (self.t1_latch >> 8) as u8,
+            T2C_L => {
+                self.ifr &= ! IRQ_T2;
+                self.t2_counter as u8
+            }
+            T2C_H => (self.t2_counter >> 8) as u8,
+            SR => self.sr,
+            ACR => self.acr,
+            PCR => self.pcr,
+            IFR => self.ifr_value(),
+            IER => self.ier | 0x80,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) -> PortWrite {
+        match register & 0xf {
+            ORB => {
+                self.orb = value;
+                return PortWrite::B(value);
+            }
+            ORA | ORA_NO_HANDSHAKE => {
+                self.ora = value;
+                return PortWrite::A(value);
+            }
+            DDRB => self.ddrb = value,
+            DDRA => self.ddra = value,
+            T1C_L | T1L_L => {
+                self.t1_latch = (self.t1_latch & 0xff00) | value as u16;
+            }
+            T1C_H => {
+                // Writing the high byte of the counter transfers the latch into the counter
+                // and starts the timer
+                self.t1_latch = (self.t1_latch & 0xff) | ((value as u16) << 8);
+                self.t1_counter = self.t1_latch;
+                self.ifr &= ! IRQ_T1;
+                self.t1_armed = true;
+            }
+            T1L_H => {
+                self.t1_latch = (self.t1_latch & 0xff) | ((value as u16) << 8);
+                self.ifr &= ! IRQ_T1;
+            }
+            T2C_L => self.t2_latch_low = value,
+            T2C_H => {
+                self.t2_counter = ((value as u16) << 8) | self.t2_latch_low as u16;
+                self.ifr &= ! IRQ_T2;
+                self.t2_armed = true;
+            }
+            SR => self.sr = value,
+            ACR => self.acr = value,
+            PCR => self.pcr = value,
+            IFR => {
+                // Writing a 1 clears the corresponding flag
+                self.ifr &= ! (value & 0x7f);
+            }
+            IER => {
+                // Bit 7 decides whether the bits set in the value are set or cleared
+                if value & 0x80 != 0 {
+                    self.ier |= value & 0x7f;
+                } else {
+                    self.ier &= ! (value & 0x7f);
+                }
+            }
+            _ => unreachable!(),
+        }
+        PortWrite::None
+    }
+
+    /// Advance the timers by one cycle (1 Mhz)
+    pub fn step(&mut self) {
+        if self.t1_counter == 0 {
+            if self.t1_armed {
+                self.ifr |= IRQ_T1;
+            }
+            if self.acr & ACR_T1_CONTINUOUS != 0 {
+                self.t1_counter = self.t1_latch;
+            } else {
+                self.t1_armed = false;
+                self.t1_counter = 0xffff;
+            }
+        } else {
+            self.t1_counter -= 1;
+        }
+
+        // Timer 2 is only a one shot interval timer here (pulse counting on PB6 is not wired
+        // on the Mockingboard)
+        if self.t2_counter == 0 && self.t2_armed {
+            self.ifr |= IRQ_T2;
+            self.t2_armed = false;
+        }
+        self.t2_counter = self.t2_counter.wrapping_sub(1);
+    }
+
+    /// Set an interrupt flag from the outside, e.g. CA1 driven by the SSI-263
+    pub fn set_interrupt_flag(&mut self, flag: u8) {
+        self.ifr |= flag;
+    }
+
+    fn ifr_value(&self) -> u8 {
+        if self.irq() { self.ifr | IRQ_ANY } else { self.ifr }
+    }
+
+    /// The IRQ output: true if one of the enabled interrupts is active
+    pub fn irq(&self) -> bool {
+        self.ifr & self.ier & 0x7f != 0
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+
+/// A keyframe is stored every KEYFRAME_INTERVAL snapshots, the ones in between are stored
+/// as a difference with that keyframe
+const KEYFRAME_INTERVAL: usize = 32;
+
+/// Snapshots (see [crate::snapshot]) taken at regular intervals, so that the machine can be
+/// rewound to any of them.
+///
+/// Consecutive snapshots are almost identical, so each one is xor'ed with the most recent
+/// keyframe, which turns the unchanged bytes into zeros, then the runs of zeros are compressed.
+/// When the buffer grows over its maximum size, the oldest keyframe and the snapshots depending
+/// on it are dropped.
+pub struct RewindBuffer {
+    points: VecDeque<RewindPoint>,
+    /// Uncompressed content of the most recent keyframe
+    keyframe: Vec<u8>,
+    /// Number of snapshots pushed since the most recent keyframe
+    since_keyframe: usize,
+    max_bytes: usize,
+    /// Size of the compressed snapshots
+    pub(crate) total_bytes: usize,
+}
+
+struct RewindPoint {
+    /// Value of the cycle counter when the snapshot was taken
+    cycle: u64,
+    is_keyframe: bool,
+    /// Size of the uncompressed snapshot
+    len: usize,
+    data: Vec<u8>,
+}
+
+impl RewindBuffer {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            points: VecDeque::new(),
+            keyframe: Vec::new(),
+            since_keyframe: KEYFRAME_INTERVAL,
+            max_bytes,
+            total_bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize { self.points.len() }
+
+    pub fn cycle(&self, index: usize) -> Option<u64> {
+        self.points.get(index).map(|p| p.cycle)
+    }
+
+    pub fn push(&mut self, cycle: u64, snapshot: &[u8]) {
+        let point = if self.since_keyframe >= KEYFRAME_INTERVAL || snapshot.len() != self.keyframe.len() {
+            self.keyframe = snapshot.to_vec();
+            self.since_keyframe = 0;
+            RewindPoint { cycle, is_keyframe: true, len: snapshot.len(), data: compress(snapshot) }
+        } else {
+            self.since_keyframe += 1;
+            let delta: Vec<u8> = snapshot.iter().zip(&self.keyframe).map(|(a, b)| a ^ b).collect();
+            RewindPoint { cycle, is_keyframe: false, len: snapshot.len(), data: compress(&delta) }
+        };
+        self.total_bytes += point.data.len();
+        self.points.push_back(point);
+
+        // Drop the oldest group, but always keep the most recent one
+        while self.total_bytes > self.max_bytes {
+            match self.points.iter().skip(1).position(|p| p.is_keyframe) {
+                Some(next_keyframe) => {
+                    for p in self.points.drain(..next_keyframe + 1) {
+                        self.total_bytes -= p.data.len();
+                    }
+                }
+                None => { break; }
+            }
+        }
+    }
+
+    /// The uncompressed snapshot at `index` (0 is the oldest)
+    pub fn get(&self, index: usize) -> Option<Vec<u8>> {
+        let point = self.points.get(index)?;
+        let keyframe_index = (0..=index).rev().find(|i| self.points[*i].is_keyframe)?;
+        let keyframe = &self.points[keyframe_index];
+        let mut result = decompress(&keyframe.data, keyframe.len)?;
+        if ! point.is_keyframe {
+            let delta = decompress(&point.data, point.len)?;
+            for (r, d) in result.iter_mut().zip(delta) {
+                *r ^= d;
+            }
+        }
+        Some(result)
+    }
+
+    /// Discard the snapshots after `len`, e.g. when the machine resumes from an older snapshot
+    pub fn truncate(&mut self, len: usize) {
+        while self.points.len() > len {
+            if let Some(p) = self.points.pop_back() {
+                self.total_bytes -= p.data.len();
+            }
+        }
+        // The keyframe might be gone, start a new one
+        self.since_keyframe = KEYFRAME_INTERVAL;
+    }
+}
+
+/// Run length encoding of the zeros: a sequence of (zero count, literal count, literals),
+/// the counts being LEB128 varints
+fn compress(data: &[u8]) -> Vec<u8> {
+    fn varint(result: &mut Vec<u8>, mut v: usize) {
+        while v >= 0x80 {
+            result.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        result.push(v as u8);
+    }
+
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..].iter().take_while(|b| **b == 0).count();
+        i += zeros;
+        // The literals end when there are at least 4 zeros, shorter runs are cheaper as literals
+        let start = i;
+        while i < data.len() && ! data[i..].starts_with(&[0, 0, 0, 0]) {
+            i += 1;
+        }
+        varint(&mut result, zeros);
+        varint(&mut result, i - start);
+        result.extend_from_slice(&data[start..i]);
+    }
+    result
+}
+
+fn decompress(data: &[u8], len: usize) -> Option<Vec<u8>> {
+    fn varint(data: &[u8], i: &mut usize) -> Option<usize> {
+        let mut result = 0;
+        let mut shift = 0;
+        loop {
+            let b = *data.get(*i)?;
+            *i += 1;
+            result |= ((b & 0x7f) as usize) << shift;
+            if b & 0x80 == 0 { return Some(result); }
+            shift += 7;
+        }
+    }
+
+    let mut result = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = varint(data, &mut i)?;
+        let literals = varint(data, &mut i)?;
+        result.resize(result.len() + zeros, 0);
+        result.extend_from_slice(data.get(i..i + literals)?);
+        i += literals;
+    }
+    if result.len() == len { Some(result) } else { None }
+}
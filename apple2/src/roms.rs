@@ -67,24 +67,284 @@ pub(crate) const DISK2_ROM: [u8; 256] = [
     0x00, 
 ];
 
-/// Smartport: $C700
+/// Disk ][ with the 13 sector boot PROM, see [crate::card::CardType::Disk2Sector13]. This is
+/// not a dump of the P5A PROM that came with DOS 3.1 and 3.2 but it boots the same way: it
+/// reads sector 0 of track 0 (address field D5 AA B5, 5 and 3 encoded data) in $0300-$03FF and
+/// jumps to $0301 with the slot * 16 in X. It uses $0200-$02FF for the table of the nibbles and
+/// $0800-$09FF for the raw values, see [crate::disk::d13::D13::encode_5_and_3]. Source:
+///
+/// ```text
+///         LDY #$20        ; $Cn01, $Cn03, $Cn05 and $Cn07 hold the Disk ][ signature 20 00 03 3C
+///         LDX #$00        ; Build the table of the 5 and 3 nibbles in $02AB-$02FF: the bytes
+///         AND #$03        ; without two consecutive zero bits, except $AA and $D5
+/// BUILD:  STX $3C
+///         TXA
+///         LSR A
+///         ORA $3C
+///         CMP #$FF
+///         BNE NEXT
+///         CPX #$D5
+///         BEQ NEXT
+///         DEY
+///         TYA
+///         STA $0200,X
+/// NEXT:   DEX
+///         TYA
+///         BNE BUILD
+///         JSR $FF58       ; Find the slot from the return address
+///         TSX
+///         LDA $0100,X
+///         ASL A
+///         ASL A
+///         ASL A
+///         ASL A
+///         STA $2B
+///         TAX
+///         LDA $C08E,X     ; Read mode, drive 1, motor on
+///         LDA $C08A,X
+///         LDA $C089,X
+///         LDY #$50        ; Move the head to track 0
+/// SEEK:   LDA $C080,X
+///         TYA
+///         AND #$03
+///         ASL A
+///         ORA $2B
+///         TAX
+///         LDA $C081,X
+///         LDA #$56
+///         JSR $FCA8
+///         DEY
+///         BPL SEEK
+/// READ:   LDA #$B5        ; Look for the address field of sector 0
+/// FIND:   STA $3C         ; D5 AA followed by $3C
+/// SYNC:   LDA $C08C,X
+///         BPL SYNC
+/// S1:     CMP #$D5
+///         BNE SYNC
+/// S2:     LDA $C08C,X
+///         BPL S2
+///         CMP #$AA
+///         BNE S1
+/// S3:     LDA $C08C,X
+///         BPL S3
+///         CMP $3C
+///         BNE S1
+///         CMP #$AD
+///         BEQ DATA
+///         LDY #$03        ; Volume, track and sector in 4 and 4
+/// ADDR:   LDA $C08C,X
+///         BPL ADDR
+///         ROL A
+///         STA $3D
+/// ADDR2:  LDA $C08C,X
+///         BPL ADDR2
+///         AND $3D
+///         DEY
+///         BNE ADDR
+///         TAY
+///         BNE READ
+///         LDA #$AD        ; Then for its data field
+///         BNE FIND
+/// DATA:   LDY #$9A        ; 154 values with the low 3 bits in $0800-$0899, backwards
+///         LDA #$00
+/// DATA1:  DEY
+///         STY $3C
+/// DATA2:  LDY $C08C,X
+///         BPL DATA2
+///         EOR $0200,Y
+///         LDY $3C
+///         STA $0800,Y
+///         BNE DATA1
+/// DATA3:  STY $3C         ; 256 values with the high 5 bits in $0900-$09FF
+/// DATA4:  LDY $C08C,X
+///         BPL DATA4
+///         EOR $0200,Y
+///         LDY $3C
+///         STA $0900,Y
+///         INY
+///         BNE DATA3
+/// DATA5:  LDY $C08C,X     ; Checksum
+///         BPL DATA5
+///         EOR $0200,Y
+///         BNE READ
+///         TAX             ; X: offset in $0300
+///         LDA $0899       ; The last byte is handled as the first one of a 52nd group
+///         ASL A
+///         ASL A
+///         STA $08FF
+///         LDY #$32        ; Y: 51 groups of 5 bytes, backwards
+/// GROUP:  LDA #$18        ; Negative for bytes 3 and 4, zero after byte 4
+///         STA $3D
+/// BYTE:   BPL THREE
+///         LDA $3A         ; Bytes 3 and 4: the bits collected from bytes 0-2
+///         PHA
+///         LDA $3B
+///         STA $3A
+///         PLA
+///         AND #$07
+///         BPL HIGH
+/// THREE:  LDA $0800,Y     ; Bytes 0-2: 3 bits, and one bit of bytes 3 and 4
+///         LSR A
+///         ROL $3B
+///         LSR A
+///         ROL $3A
+/// HIGH:   STA $3C
+///         LDA $0900,Y
+///         ASL A
+///         ASL A
+///         ASL A
+///         ORA $3C
+///         STA $0300,X
+///         INX
+///         BEQ DONE
+///         TYA             ; Next byte of the group is 51 further, 5 * 51 = -1
+///         ADC #$33
+///         TAY
+///         ASL $3D
+///         BNE BYTE
+///         BEQ GROUP
+/// DONE:   LDX $2B
+///         JMP $0301
+/// ```
+pub(crate) const DISK2_13_SECTOR_ROM: [u8; 256] = [
+    0xA0, 0x20, 0xA2, 0x00, 0x29, 0x03, 0x86, 0x3C, 0x8A, 0x4A, 0x05, 0x3C, 0xC9, 0xFF, 0xD0, 0x09,
+    0xE0, 0xD5, 0xF0, 0x05, 0x88, 0x98, 0x9D, 0x00, 0x02, 0xCA, 0x98, 0xD0, 0xE9, 0x20, 0x58, 0xFF,
+    0xBA, 0xBD, 0x00, 0x01, 0x0A, 0x0A, 0x0A, 0x0A, 0x85, 0x2B, 0xAA, 0xBD, 0x8E, 0xC0, 0xBD, 0x8A,
+    0xC0, 0xBD, 0x89, 0xC0, 0xA0, 0x50, 0xBD, 0x80, 0xC0, 0x98, 0x29, 0x03, 0x0A, 0x05, 0x2B, 0xAA,
+    0xBD, 0x81, 0xC0, 0xA9, 0x56, 0x20, 0xA8, 0xFC, 0x88, 0x10, 0xEB, 0xA9, 0xB5, 0x85, 0x3C, 0xBD,
+    0x8C, 0xC0, 0x10, 0xFB, 0xC9, 0xD5, 0xD0, 0xF7, 0xBD, 0x8C, 0xC0, 0x10, 0xFB, 0xC9, 0xAA, 0xD0,
+    0xF3, 0xBD, 0x8C, 0xC0, 0x10, 0xFB, 0xC5, 0x3C, 0xD0, 0xEA, 0xC9, 0xAD, 0xF0, 0x1B, 0xA0, 0x03,
+    0xBD, 0x8C, 0xC0, 0x10, 0xFB, 0x2A, 0x85, 0x3D, 0xBD, 0x8C, 0xC0, 0x10, 0xFB, 0x25, 0x3D, 0x88,
+    0xD0, 0xEE, 0xA8, 0xD0, 0xC6, 0xA9, 0xAD, 0xD0, 0xC4, 0xA0, 0x9A, 0xA9, 0x00, 0x88, 0x84, 0x3C,
+    0xBC, 0x8C, 0xC0, 0x10, 0xFB, 0x59, 0x00, 0x02, 0xA4, 0x3C, 0x99, 0x00, 0x08, 0xD0, 0xEE, 0x84,
+    0x3C, 0xBC, 0x8C, 0xC0, 0x10, 0xFB, 0x59, 0x00, 0x02, 0xA4, 0x3C, 0x99, 0x00, 0x09, 0xC8, 0xD0,
+    0xEE, 0xBC, 0x8C, 0xC0, 0x10, 0xFB, 0x59, 0x00, 0x02, 0xD0, 0x90, 0xAA, 0xAD, 0x99, 0x08, 0x0A,
+    0x0A, 0x8D, 0xFF, 0x08, 0xA0, 0x32, 0xA9, 0x18, 0x85, 0x3D, 0x10, 0x0C, 0xA5, 0x3A, 0x48, 0xA5,
+    0x3B, 0x85, 0x3A, 0x68, 0x29, 0x07, 0x10, 0x09, 0xB9, 0x00, 0x08, 0x4A, 0x26, 0x3B, 0x4A, 0x26,
+    0x3A, 0x85, 0x3C, 0xB9, 0x00, 0x09, 0x0A, 0x0A, 0x0A, 0x05, 0x3C, 0x9D, 0x00, 0x03, 0xE8, 0xF0,
+    0x0A, 0x98, 0x69, 0x33, 0xA8, 0x06, 0x3D, 0xD0, 0xD1, 0xF0, 0xCB, 0xA6, 0x2B, 0x4C, 0x01, 0x03,
+];
+
+/// The firmware of the hard drive card, see [crate::smartport::SmartPort]: a ProDOS block
+/// driver at $Cn0A and a SmartPort entry point at $Cn0D. Both hand their parameters to the
+/// card, which does the actual work. Assembled for slot 7, see [smartport_rom] for the other
+/// slots. Source:
+///
+/// ```text
+///         LDA #$20
+///         LDA #$00
+///         LDA #$03
+///         LDA #$00        ; $Cn07 = 0: SmartPort
+///         BEQ BOOT
+///         SEC             ; $Cn0A: ProDOS block entry
+///         BCS ENTRY
+///         CLC             ; $Cn0D: SmartPort entry
+/// ENTRY:  BCS BLOCK
+///         PLA             ; Return address - 1, the parameters follow
+///         STA $C0F9
+///         PLA
+///         STA $C0FA
+///         LDA $C0FB       ; Execute the SmartPort call
+///         LDA $C0FA       ; Return address, past the parameters
+///         PHA
+///         LDA $C0F9
+///         PHA
+/// DONE:   LDX $C0FC
+///         LDY $C0FD
+///         LDA $C0FE       ; Error code
+///         ROR $C0F1       ; Error flag -> carry
+///         RTS
+/// BLOCK:  LDA $42         ; Command
+///         STA $C0F2
+///         LDA $43         ; Unit
+///         STA $C0F3
+///         LDA $44         ; Buffer
+///         STA $C0F4
+///         LDA $45
+///         STA $C0F5
+///         LDA $46         ; Block
+///         STA $C0F6
+///         LDA $47
+///         STA $C0F7
+///         LDA $C0F0       ; Execute
+///         BNE DONE
+///         LDA $42
+///         CMP #$01
+///         BNE DONE
+///         LDY #$00        ; READ: copy the block to the buffer
+/// LOOP1:  LDA $C0F8
+///         STA ($44),Y
+///         INY
+///         BNE LOOP1
+///         INC $45
+/// LOOP2:  LDA $C0F8
+///         STA ($44),Y
+///         INY
+///         BNE LOOP2
+///         DEC $45
+///         JMP DONE
+/// BOOT:   LDA #$00        ; STATUS of drive 1
+///         STA $C0F2
+///         LDA #$70
+///         STA $C0F3
+///         LDA $C0F0
+///         BNE NOBOOT
+///         LDA #$70        ; Read block 0 of drive 1 in $800
+///         STA $43
+///         LDA #$00
+///         STA $44
+///         STA $46
+///         STA $47
+///         LDA #$08
+///         STA $45
+///         LDA #$01
+///         STA $42
+///         JSR BLOCK
+///         BCS NOBOOT
+///         BIT $C061       ; Open Apple: skip
+///         BMI NOBOOT
+///         LDX #$70
+///         JMP $0801
+/// NOBOOT: JMP $C600
+/// ```
 pub(crate) const SMARTPORT_ROM: [u8; 256] = [
 //  0     1     2     3     4     5     6     7     8     9     A     B     C     D     E     F
-    0xA9, 0x20, 0xA9, 0x00, 0xA9, 0x03, 0xA9, 0x3C, 0xD0, 0x08, 0x38, 0xB0, 0x01, 0x18, 0xB0, 0x77,
-    0x90, 0x15, 0xA9, 0x00, 0x8D, 0xF2, 0xC0, 0xA9, 0x70, 0x8D, 0xF3, 0xC0, 0xAD, 0xF0, 0xC0, 0x6E,
-    0xF1, 0xC0, 0x90, 0x40, 0x4C, 0x00, 0xC6, 0x68, 0x85, 0x46, 0x69, 0x03, 0xA8, 0x68, 0x85, 0x47,
-    0x69, 0x00, 0x48, 0x98, 0x48, 0xA0, 0x01, 0xB1, 0x46, 0x85, 0x42, 0xC8, 0xD0, 0x0B, 0x00, 0x00,
-    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x38, 0xB0, 0xC5, 0xB1, 0x46, 0x85, 0x45, 0xC8, 0xB1, 0x46,
-    0x85, 0x46, 0xA0, 0x01, 0xB1, 0x45, 0x85, 0x43, 0xC8, 0xB1, 0x45, 0x85, 0x44, 0xC8, 0xD0, 0x75,
-    0x00, 0x38, 0xB0, 0xAA, 0xA9, 0x70, 0x85, 0x43, 0xA9, 0x00, 0x85, 0x44, 0x85, 0x46, 0x85, 0x47,
-    0xA9, 0x08, 0x85, 0x45, 0xA9, 0x01, 0x85, 0x42, 0x20, 0x87, 0xC7, 0xB0, 0xA7, 0x2C, 0x61, 0xC0,
-    0x30, 0xA2, 0xA2, 0x70, 0x4C, 0x01, 0x08, 0x18, 0xA5, 0x42, 0x8D, 0xF2, 0xC0, 0xA5, 0x43, 0x8D,
-    0xF3, 0xC0, 0xA5, 0x44, 0x8D, 0xF4, 0xC0, 0xA5, 0x45, 0x8D, 0xF5, 0xC0, 0xA5, 0x46, 0x8D, 0xF6,
-    0xC0, 0xA5, 0x47, 0x8D, 0xF7, 0xC0, 0xAD, 0xF0, 0xC0, 0x48, 0xA5, 0x42, 0xC9, 0x01, 0xD0, 0x03,
-    0x20, 0xB8, 0xC7, 0x6E, 0xF1, 0xC0, 0x68, 0x60, 0x98, 0x48, 0xA0, 0x00, 0xAD, 0xF8, 0xC0, 0x91,
-    0x44, 0xC8, 0xD0, 0xF8, 0xE6, 0x45, 0xA0, 0x00, 0xAD, 0xF8, 0xC0, 0x91, 0x44, 0xC8, 0xD0, 0xF8,
-    0xC6, 0x45, 0x68, 0xA8, 0x60, 0xB1, 0x45, 0x48, 0xC8, 0xB1, 0x45, 0x48, 0xC8, 0xB1, 0x45, 0x85,
-    0x47, 0x68, 0x85, 0x46, 0x68, 0x85, 0x45, 0xC8, 0xD0, 0x9D, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x7F, 0xD7, 0x0A,
+    0xA9, 0x20, 0xA9, 0x00, 0xA9, 0x03, 0xA9, 0x00, 0xF0, 0x68, 0x38, 0xB0, 0x01, 0x18, 0xB0, 0x20,
+    0x68, 0x8D, 0xF9, 0xC0, 0x68, 0x8D, 0xFA, 0xC0, 0xAD, 0xFB, 0xC0, 0xAD, 0xFA, 0xC0, 0x48, 0xAD,
+    0xF9, 0xC0, 0x48, 0xAE, 0xFC, 0xC0, 0xAC, 0xFD, 0xC0, 0xAD, 0xFE, 0xC0, 0x6E, 0xF1, 0xC0, 0x60,
+    0xA5, 0x42, 0x8D, 0xF2, 0xC0, 0xA5, 0x43, 0x8D, 0xF3, 0xC0, 0xA5, 0x44, 0x8D, 0xF4, 0xC0, 0xA5,
+    0x45, 0x8D, 0xF5, 0xC0, 0xA5, 0x46, 0x8D, 0xF6, 0xC0, 0xA5, 0x47, 0x8D, 0xF7, 0xC0, 0xAD, 0xF0,
+    0xC0, 0xD0, 0xD0, 0xA5, 0x42, 0xC9, 0x01, 0xD0, 0xCA, 0xA0, 0x00, 0xAD, 0xF8, 0xC0, 0x91, 0x44,
+    0xC8, 0xD0, 0xF8, 0xE6, 0x45, 0xAD, 0xF8, 0xC0, 0x91, 0x44, 0xC8, 0xD0, 0xF8, 0xC6, 0x45, 0x4C,
+    0x23, 0xC7, 0xA9, 0x00, 0x8D, 0xF2, 0xC0, 0xA9, 0x70, 0x8D, 0xF3, 0xC0, 0xAD, 0xF0, 0xC0, 0xD0,
+    0x23, 0xA9, 0x70, 0x85, 0x43, 0xA9, 0x00, 0x85, 0x44, 0x85, 0x46, 0x85, 0x47, 0xA9, 0x08, 0x85,
+    0x45, 0xA9, 0x01, 0x85, 0x42, 0x20, 0x30, 0xC7, 0xB0, 0x0A, 0x2C, 0x61, 0xC0, 0x30, 0x05, 0xA2,
+    0x70, 0x4C, 0x01, 0x08, 0x4C, 0x00, 0xC6, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0xD7, 0x0A,
 ];
 
+/// Offsets of the bytes of [SMARTPORT_ROM] that depend on the slot: the low bytes of the
+/// $C0F0-$C0FE I/O locations, the high bytes of the $C7xx addresses and the #$70 units (DSSS0000)
+const SMARTPORT_ROM_IO: [usize; 21] = [0x12, 0x16, 0x19, 0x1c, 0x20, 0x24, 0x27, 0x2a, 0x2d, 0x33, 0x38,
+    0x3d, 0x42, 0x47, 0x4c, 0x4f, 0x5c, 0x66, 0x75, 0x7a, 0x7d];
+const SMARTPORT_ROM_PAGE: [usize; 2] = [0x71, 0x97];
+const SMARTPORT_ROM_UNIT: [usize; 3] = [0x78, 0x82, 0xa0];
+
+/// [SMARTPORT_ROM] for a card in `slot` (1-7)
+pub(crate) fn smartport_rom(slot: usize) -> [u8; 256] {
+    let mut result = SMARTPORT_ROM;
+    for i in SMARTPORT_ROM_IO {
+        result[i] = 0x80 + ((slot as u8) << 4) + (result[i] & 0xf);
+    }
+    for i in SMARTPORT_ROM_PAGE {
+        result[i] = 0xc0 + slot as u8;
+    }
+    for i in SMARTPORT_ROM_UNIT {
+        result[i] = (slot as u8) << 4;
+    }
+    result
+}
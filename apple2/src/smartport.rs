@@ -1,44 +1,325 @@
-use std::{fs, io};
-use std::fs::File;
-use std::io::Read;
-use crate::ui::iced::shared::Shared;
+use std::any::Any;
+use std::sync::Arc;
+use crate::card::{Bus, Card};
+use crate::constants::HARD_DRIVE_COUNT;
+use crate::disk::hard_drive::{HardDriveImage, BLOCK_SIZE};
+use crate::roms::smartport_rom;
+use crate::context::EmulatorContext;
+use crate::ui_log;
 
-/// Implementation of the SmartPort interface. Make sure that $C0F8 returns the correct bytes
-/// from the currently loaded hard drive / block number.
+/// The commands of the ProDOS block driver, written in $C0n2
+pub(crate) const STATUS: u8 = 0;
+pub(crate) const READ: u8 = 1;
+pub(crate) const WRITE: u8 = 2;
+pub(crate) const FORMAT: u8 = 3;
+
+/// The SmartPort commands that follow the `JSR $Cn0D`. STATUS, READ_BLOCK, WRITE_BLOCK and
+/// FORMAT have the same values as the block driver's.
+pub(crate) const READ_BLOCK: u8 = 1;
+pub(crate) const WRITE_BLOCK: u8 = 2;
+pub(crate) const CONTROL: u8 = 4;
+pub(crate) const INIT: u8 = 5;
+/// Added to the command for the extended calls, whose pointers and block numbers are 32 bits
+pub(crate) const EXTENDED: u8 = 0x40;
+
+/// The STATUS codes
+pub(crate) const STATUS_DEVICE: u8 = 0;
+pub(crate) const STATUS_DIB: u8 = 3;
+
+/// The CONTROL codes
+const CONTROL_RESET: u8 = 0;
+const CONTROL_EJECT: u8 = 4;
+
+/// The error codes returned in A (with carry set)
+pub(crate) const NO_ERROR: u8 = 0;
+pub(crate) const BAD_COMMAND: u8 = 0x01;
+pub(crate) const BAD_PARAMETER_COUNT: u8 = 0x04;
+pub(crate) const BUS_ERROR: u8 = 0x06;
+pub(crate) const BAD_UNIT: u8 = 0x11;
+pub(crate) const BAD_CONTROL: u8 = 0x21;
+pub(crate) const IO_ERROR: u8 = 0x27;
+pub(crate) const NO_DEVICE: u8 = 0x28;
+pub(crate) const WRITE_PROTECTED: u8 = 0x2b;
+pub(crate) const BAD_BLOCK: u8 = 0x2d;
+pub(crate) const OFFLINE: u8 = 0x2f;
+
+/// Device type and subtype returned in the DIB: hard disk, supports the extended calls
+const DEVICE_TYPE: u8 = 0x02;
+const DEVICE_SUBTYPE: u8 = 0x80;
+/// Length of the device name in the DIB
+const DIB_NAME_LENGTH: usize = 16;
+/// The virtual volumes are written back to the host after a second without writes, in card
+/// steps (2 per cycle)
+const FLUSH_DELAY: u32 = 2_000_000;
+
+/// Implementation of the hard drive card, which holds up to [HARD_DRIVE_COUNT] units. Its ROM
+/// hands the parameters of the calls to the registers below and reads $C0n0 or $C0nB to
+/// execute them. The error code is then returned in A and the error flag in carry.
+///
+/// The ProDOS block driver ($Cn0A) copies its parameters ($42-$47) to:
+/// - $C0n2: command (STATUS, READ, WRITE, FORMAT)
+/// - $C0n3: unit number, DSSS0000, D is the drive (unit 1 or 2)
+/// - $C0n4-$C0n5: buffer
+/// - $C0n6-$C0n7: block number
+///
+/// then reads $C0n0 to execute the command. After a READ, each read of $C0n8 returns the
+/// next byte of the block.
+///
+/// The SmartPort entry point ($Cn0D) writes its return address in $C0n9-$C0nA and reads $C0nB,
+/// which reads the command and the parameter list from memory and performs the transfer.
+/// $C0n9-$C0nA then contain the return address past the parameters, and $C0nC-$C0nD the
+/// number of bytes transferred (X and Y).
 pub struct SmartPort {
-    /// Content of the .hdv file
-    file_content: Vec<u8>,
+    /// Content of the image in each unit, loaded the first time it's accessed
+    images: [Option<HardDriveImage>; HARD_DRIVE_COUNT],
+
+    command: u8,
+    unit: u8,
+    buffer: u16,
+    block_number: u16,
+    /// Error code of the last command
+    error: u8,
+    /// SmartPort calls: address of the last byte of the JSR
+    return_address: u16,
+    /// Returned in X and Y
+    count: u16,
 
-    /// The current block we are returning bytes from whenever $C0F8 is invoked
-    block_content: [u8; 512],
+    /// The block read by the last READ, returned one byte at a time by $C0n8
+    block_content: [u8; BLOCK_SIZE],
     block_content_index: usize,
 
-    /// Used to figure out if we need to load a new block
-    last_block_read: Option<u16>,
+    /// Card steps left before the images are flushed, 0 if they're up to date
+    flush_countdown: u32,
+
+    /// The firmware, patched for the slot of the card
+    rom: [u8; 256],
+
+    /// Where the hard drives come from
+    context: Arc<EmulatorContext>,
 }
 
-impl Default for SmartPort {
-    fn default() -> Self {
+impl SmartPort {
+    pub fn new(slot: usize, context: Arc<EmulatorContext>) -> Self {
         Self {
-            last_block_read: None,
-            block_content: [0; 512],
+            images: Default::default(),
+            command: 0,
+            unit: 0,
+            buffer: 0,
+            block_number: 0,
+            error: NO_ERROR,
+            return_address: 0,
+            count: 0,
+            block_content: [0; BLOCK_SIZE],
             block_content_index: 0,
-            file_content: vec![],
+            flush_countdown: 0,
+            rom: smartport_rom(slot),
+            context,
         }
     }
-}
 
-impl SmartPort {
-    /// Invoked whenever $C0F8 is read
-    pub fn next_byte(&mut self, block_number: u16) -> io::Result<u8> {
-        // Load the block if we haven't read any or if the current block is different from
-        // the one requested
-        if self.last_block_read.map_or(true, |bn| bn != block_number) {
-            self.read_block(block_number)?;
+    /// The image in `unit` (0-3), reloaded if the user changed it
+    fn image(&mut self, unit: usize) -> Result<&mut HardDriveImage, u8> {
+        let path = self.context.hard_drive(unit).ok_or(NO_DEVICE)?.path;
+        if self.images[unit].as_ref().is_none_or(|image| image.path != path) {
+            match HardDriveImage::new(&path) {
+                Ok(image) => { self.images[unit] = Some(image); }
+                Err(e) => {
+                    ui_log(&e);
+                    self.images[unit] = None;
+                    return Err(IO_ERROR);
+                }
+            }
+        }
+        Ok(self.images[unit].as_mut().unwrap())
+    }
+
+    /// Number of units reported to the SmartPort STATUS call: up to the last one with an image
+    fn unit_count(&self) -> usize {
+        (0..HARD_DRIVE_COUNT).rev().find(|unit| self.context.hard_drive(*unit).is_some())
+            .map_or(0, |unit| unit + 1)
+    }
+
+    /// Invoked when $C0n0 is read: run the ProDOS block driver command, return its error code
+    fn execute(&mut self, bus: &mut dyn Bus) -> u8 {
+        let unit = (self.unit >> 7) as usize;
+        let block_number = self.block_number as usize;
+        self.count = 0;
+        let result = match self.command {
+            STATUS => self.image(unit).map(|image| {
+                image.block_count().min(0xffff) as u16
+            }).map(|count| self.count = count),
+            READ => self.read_block(unit, block_number).map(|block| {
+                self.block_content = block;
+                self.block_content_index = 0;
+            }),
+            WRITE => match dma_read(bus, self.buffer as usize, BLOCK_SIZE) {
+                Ok(bytes) => self.write_block(unit, block_number, &bytes),
+                Err(_) => Err(IO_ERROR),
+            },
+            FORMAT => self.format(unit),
+            _ => Err(IO_ERROR),
+        };
+        // The block driver reports a bad block as an I/O error
+        self.error = match result {
+            Ok(_) => NO_ERROR,
+            Err(BAD_BLOCK) => IO_ERROR,
+            Err(e) => e,
+        };
+        self.error
+    }
+
+    /// Invoked when $C0nB is read: run the SmartPort call that follows the JSR
+    fn execute_smartport(&mut self, bus: &mut dyn Bus) -> u8 {
+        let call = self.return_address.wrapping_add(1);
+        let command = bus.read(call);
+        let extended = command & EXTENDED != 0;
+        let parameters = word(bus, call.wrapping_add(1));
+        self.return_address = self.return_address.wrapping_add(if extended { 5 } else { 3 });
+
+        let result = self.smartport_call(command & ! EXTENDED, extended, parameters, bus);
+        self.count = *result.as_ref().unwrap_or(&0);
+        self.error = result.err().unwrap_or(NO_ERROR);
+        self.error
+    }
+
+    /// Return the number of bytes transferred
+    fn smartport_call(&mut self, command: u8, extended: bool, parameters: u16, bus: &mut dyn Bus)
+        -> Result<u16, u8>
+    {
+        let mut parameter = |i: u16| bus.read(parameters.wrapping_add(i));
+        let expected_count = match command {
+            STATUS | READ_BLOCK | WRITE_BLOCK | CONTROL => 3,
+            FORMAT | INIT => 1,
+            _ => { return Err(BAD_COMMAND); }
+        };
+        if parameter(0) != expected_count {
+            return Err(BAD_PARAMETER_COUNT);
+        }
+        let unit = parameter(1) as usize;
+        if unit > HARD_DRIVE_COUNT {
+            return Err(BAD_UNIT);
+        }
+        // The buffer (or control list) pointer, followed by the block number or the code
+        let buffer = parameter(2) as usize | (parameter(3) as usize) << 8;
+        let next = if extended { 6 } else { 4 };
+        let code = parameter(next);
+        let block_number = parameter(next) as usize | (parameter(next + 1) as usize) << 8
+            | (parameter(next + 2) as usize) << 16
+            | if extended { (parameter(next + 3) as usize) << 24 } else { 0 };
+
+        if unit == 0 {
+            // The SmartPort itself
+            return match (command, code) {
+                (STATUS, STATUS_DEVICE) => {
+                    // Number of units, no interrupt, vendor, version
+                    let status = [self.unit_count() as u8, 0x40, 0, 0, 0, 1, 0, 0];
+                    dma_write(bus, buffer, &status)
+                }
+                (STATUS, _) => Err(BAD_CONTROL),
+                (CONTROL, _) | (INIT, _) => Ok(0),
+                _ => Err(BAD_UNIT),
+            };
+        }
+
+        let unit = unit - 1;
+        match command {
+            STATUS => {
+                let status = self.device_status(unit);
+                match code {
+                    STATUS_DEVICE => dma_write(bus, buffer, &status),
+                    STATUS_DIB => {
+                        // ASCII only, the file name can contain any character
+                        let name: Vec<u8> = self.images[unit].as_ref().filter(|_| status[0] & 0x10 != 0)
+                            .map_or(Vec::new(), |image| image.name().bytes()
+                                .map(|b| if b.is_ascii() { b.to_ascii_uppercase() } else { b'?' })
+                                .take(DIB_NAME_LENGTH)
+                                .collect());
+                        let mut dib = status.to_vec();
+                        dib.push(name.len() as u8);
+                        dib.extend(&name);
+                        dib.resize(dib.len() + DIB_NAME_LENGTH - name.len(), b' ');
+                        dib.extend([DEVICE_TYPE, DEVICE_SUBTYPE, 0x01, 0x00]);
+                        dma_write(bus, buffer, &dib)
+                    }
+                    _ => Err(BAD_CONTROL),
+                }
+            }
+            READ_BLOCK => {
+                let block = self.read_block(unit, block_number).map_err(offline)?;
+                dma_write(bus, buffer, &block)
+            }
+            WRITE_BLOCK => {
+                let bytes = dma_read(bus, buffer, BLOCK_SIZE)?;
+                self.write_block(unit, block_number, &bytes).map_err(offline)?;
+                Ok(BLOCK_SIZE as u16)
+            }
+            FORMAT => self.format(unit).map_err(offline).map(|_| 0),
+            CONTROL => match code {
+                CONTROL_RESET | CONTROL_EJECT => Ok(0),
+                _ => Err(BAD_CONTROL),
+            },
+            _ => Err(BAD_COMMAND),
         }
+    }
+
+    /// The general status byte followed by the number of blocks
+    fn device_status(&mut self, unit: usize) -> [u8; 4] {
+        // Block device, write allowed, read allowed, format allowed
+        let mut status = 0xe8;
+        let mut blocks = 0;
+        if let Ok(image) = self.image(unit) {
+            // Online
+            status |= 0x10;
+            if image.is_write_protected() {
+                status |= 0x04;
+            }
+            blocks = image.block_count();
+        }
+        [status, blocks as u8, (blocks >> 8) as u8, (blocks >> 16) as u8]
+    }
 
-        if self.block_content_index >= 512 {
-            // Not sure what the behavior is supposed to be if the caller reads $C0F8
+    fn read_block(&mut self, unit: usize, block_number: usize) -> Result<[u8; BLOCK_SIZE], u8> {
+        let image = self.image(unit)?;
+        let block: [u8; BLOCK_SIZE] = image.read_block(block_number)
+            .and_then(|b| b.try_into().ok()).ok_or(BAD_BLOCK)?;
+        self.context.set_block_number(unit, block_number as u16);
+        Ok(block)
+    }
+
+    fn write_block(&mut self, unit: usize, block_number: usize, bytes: &[u8]) -> Result<(), u8> {
+        let image = self.image(unit)?;
+        if image.is_write_protected() {
+            return Err(WRITE_PROTECTED);
+        }
+        if block_number >= image.block_count() {
+            return Err(BAD_BLOCK);
+        }
+        image.write_block(block_number, bytes).map_err(|e| { ui_log(&e); IO_ERROR })?;
+        self.context.set_block_number(unit, block_number as u16);
+        self.flush_countdown = FLUSH_DELAY;
+        Ok(())
+    }
+
+    fn format(&mut self, unit: usize) -> Result<(), u8> {
+        let image = self.image(unit)?;
+        if image.is_write_protected() {
+            return Err(WRITE_PROTECTED);
+        }
+        image.format().map_err(|e| { ui_log(&e); IO_ERROR })
+    }
+
+    /// Write the virtual volumes back to their host directory
+    pub fn flush(&mut self) {
+        self.flush_countdown = 0;
+        for image in self.images.iter_mut().flatten() {
+            image.flush();
+        }
+    }
+
+    /// Invoked whenever $C0n8 is read
+    fn next_byte(&mut self) -> u8 {
+        if self.block_content_index >= BLOCK_SIZE {
+            // Not sure what the behavior is supposed to be if the caller reads $C0n8
             // more than 512 times without loading another block. Assume we just loop
             // around the same buffer
             self.block_content_index = 0;
@@ -46,29 +327,88 @@ impl SmartPort {
 
         let result = self.block_content[self.block_content_index];
         self.block_content_index += 1;
+        result
+    }
+}
+
+/// SmartPort calls report a missing image as offline
+fn offline(error: u8) -> u8 {
+    if error == NO_DEVICE { OFFLINE } else { error }
+}
 
-        Ok(result)
+fn word(bus: &mut dyn Bus, address: u16) -> u16 {
+    bus.read(address) as u16 | (bus.read(address.wrapping_add(1)) as u16) << 8
+}
+
+/// Copy `bytes` to the caller's buffer, return the number of bytes copied. The buffer can't
+/// go past $FFFF.
+fn dma_write(bus: &mut dyn Bus, buffer: usize, bytes: &[u8]) -> Result<u16, u8> {
+    if buffer + bytes.len() > 0x10000 {
+        return Err(BUS_ERROR);
+    }
+    for (i, byte) in bytes.iter().enumerate() {
+        bus.write((buffer + i) as u16, *byte);
     }
+    Ok(bytes.len() as u16)
+}
 
-    /// Read the block_number in our holding 512 byte buffer.
-    /// That buffer will then be returned one byte at a time each time $C0F8 is read
-    fn read_block(&mut self, block_number: u16) -> io::Result<()> {
-        if let Some(disk_info) = &Shared::get_hard_drive(0) {
-            if self.file_content.is_empty() {
-                let mut file = File::open(&disk_info.path)?;
-                let metadata = fs::metadata(&disk_info.path)?;
-                self.file_content = vec![0; metadata.len() as usize];
-                file.read_exact(&mut self.file_content)?;
+/// The `len` bytes of the caller's buffer
+fn dma_read(bus: &mut dyn Bus, buffer: usize, len: usize) -> Result<Vec<u8>, u8> {
+    if buffer + len > 0x10000 {
+        return Err(BUS_ERROR);
+    }
+    Ok((buffer..buffer + len).map(|address| bus.read(address as u16)).collect())
+}
+
+impl Card for SmartPort {
+    fn name(&self) -> String {
+        "SmartPort".to_string()
+    }
+
+    /// The card reads the SmartPort parameters and transfers the blocks through `bus`
+    fn io(&mut self, offset: u8, value: u8, read: bool, bus: &mut dyn Bus) -> u8 {
+        match (offset, read) {
+            (0, true) => self.execute(bus),
+            (1, true) => (self.error != NO_ERROR) as u8,
+            (2, false) => { self.command = value; 0 }
+            (3, false) => { self.unit = value; 0 }
+            (4, false) => { self.buffer = (self.buffer & 0xff00) | value as u16; 0 }
+            (5, false) => { self.buffer = (self.buffer & 0xff) | ((value as u16) << 8); 0 }
+            (6, false) => { self.block_number = (self.block_number & 0xff00) | value as u16; 0 }
+            (7, false) => { self.block_number = (self.block_number & 0xff) | ((value as u16) << 8); 0 }
+            (8, true) => self.next_byte(),
+            (9, true) => self.return_address as u8,
+            (9, false) => { self.return_address = (self.return_address & 0xff00) | value as u16; 0 }
+            (0xa, true) => (self.return_address >> 8) as u8,
+            (0xa, false) => {
+                self.return_address = (self.return_address & 0xff) | ((value as u16) << 8);
+                0
             }
+            (0xb, true) => self.execute_smartport(bus),
+            (0xc, true) => self.count as u8,
+            (0xd, true) => (self.count >> 8) as u8,
+            (0xe, true) => self.error,
+            _ => 0,
+        }
+    }
 
-            let offset = block_number as usize * 512;
-            self.block_content.copy_from_slice(&self.file_content[offset..(512 + offset)]);
-            self.last_block_read = Some(block_number);
-            self.block_content_index = 0;
+    fn rom(&self) -> Option<&[u8]> {
+        Some(&self.rom)
+    }
 
-            Shared::set_block_number(0, block_number);
+    fn step(&mut self) {
+        if self.flush_countdown > 0 {
+            self.flush_countdown -= 1;
+            if self.flush_countdown == 0 {
+                self.flush();
+            }
         }
+    }
 
-        Ok(())
+    fn reset(&mut self) {
+        self.flush();
     }
-}
\ No newline at end of file
+
+    fn as_any(&self) -> &dyn Any { self }
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
@@ -0,0 +1,220 @@
+use std::fs;
+use cpu::cpu::{Cpu, Interrupts};
+use crate::memory::Apple2Memory;
+
+/// Save states ("snapshots") of the whole machine: CPU (including its interrupt lines), memory, language card, soft switches,
+/// cards (including the disk controller and the bit streams of the disks that were written to).
+///
+/// Format: magic, version, FNV-1a hash of the payload, payload length, payload.
+/// The payload is a sequence of little endian values written by [SnapshotWriter] in the order
+/// each component decides. Whenever that order or the unit of a value changes (e.g. the clock
+/// of the disk controller's LSS, or the flux positions), [SNAPSHOT_VERSION] must be bumped:
+/// snapshots with a different version are rejected instead of being misread.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"MA2S";
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+/// Extension of the snapshot files
+pub const SNAPSHOT_EXTENSION: &str = "a2s";
+
+const HEADER_SIZE: usize = 4 + 4 + 8 + 8;
+
+#[derive(Default)]
+pub struct SnapshotWriter {
+    buffer: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn u8(&mut self, v: u8) { self.buffer.push(v); }
+    pub fn bool(&mut self, v: bool) { self.buffer.push(v as u8); }
+    pub fn u16(&mut self, v: u16) { self.buffer.extend_from_slice(&v.to_le_bytes()); }
+    pub fn u32(&mut self, v: u32) { self.buffer.extend_from_slice(&v.to_le_bytes()); }
+    pub fn u64(&mut self, v: u64) { self.buffer.extend_from_slice(&v.to_le_bytes()); }
+    pub fn u128(&mut self, v: u128) { self.buffer.extend_from_slice(&v.to_le_bytes()); }
+    pub fn usize(&mut self, v: usize) { self.u64(v as u64); }
+    pub fn f32(&mut self, v: f32) { self.u32(v.to_bits()); }
+
+    /// Fixed size content, the reader needs to know the size (see [SnapshotReader::bytes_into])
+    pub fn bytes(&mut self, v: &[u8]) { self.buffer.extend_from_slice(v); }
+
+    /// Variable size content, prefixed with its length
+    pub fn sized_bytes(&mut self, v: &[u8]) {
+        self.usize(v.len());
+        self.bytes(v);
+    }
+
+    pub fn string(&mut self, v: &str) { self.sized_bytes(v.as_bytes()); }
+
+    /// A bit stream (one bit per byte, see [crate::disk::bit_stream::BitStream]), packed eight
+    /// bits per byte
+    pub fn bits(&mut self, bits: &[u8]) {
+        self.usize(bits.len());
+        for chunk in bits.chunks(8) {
+            let mut byte = 0;
+            for (i, bit) in chunk.iter().enumerate() {
+                if *bit != 0 { byte |= 0x80 >> i; }
+            }
+            self.u8(byte);
+        }
+    }
+
+    /// The content written by `f`, prefixed with its length so that a reader can skip it
+    pub fn section(&mut self, f: impl FnOnce(&mut SnapshotWriter)) {
+        let mut section = SnapshotWriter::default();
+        f(&mut section);
+        self.sized_bytes(&section.buffer);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> { self.buffer }
+}
+
+pub struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if count > self.bytes.len() - self.position {
+            return Err(format!("Snapshot truncated at offset {}", self.position));
+        }
+        let result = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(result)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> { Ok(self.take(1)?[0]) }
+    pub fn bool(&mut self) -> Result<bool, String> { Ok(self.u8()? != 0) }
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn u128(&mut self) -> Result<u128, String> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+    pub fn usize(&mut self) -> Result<usize, String> {
+        usize::try_from(self.u64()?).map_err(|e| e.to_string())
+    }
+    pub fn f32(&mut self) -> Result<f32, String> { Ok(f32::from_bits(self.u32()?)) }
+
+    pub fn bytes_into(&mut self, destination: &mut [u8]) -> Result<(), String> {
+        destination.copy_from_slice(self.take(destination.len())?);
+        Ok(())
+    }
+
+    pub fn sized_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.usize()?;
+        self.take(len)
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.sized_bytes()?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    pub fn bits(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.usize()?;
+        let packed = self.take(len.div_ceil(8))?;
+        Ok((0..len).map(|i| (packed[i / 8] >> (7 - i % 8)) & 1).collect())
+    }
+
+    /// Read a section written by [SnapshotWriter::section]
+    pub fn section(&mut self) -> Result<SnapshotReader<'a>, String> {
+        Ok(SnapshotReader::new(self.sized_bytes()?))
+    }
+}
+
+/// 64 bit FNV-1a, detects truncated or corrupted snapshots before anything gets restored
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| (h ^ *b as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+pub fn save_snapshot(cpu: &Cpu<Apple2Memory>) -> Vec<u8> {
+    let mut w = SnapshotWriter::default();
+    w.u8(cpu.a);
+    w.u8(cpu.x);
+    w.u8(cpu.y);
+    w.u16(cpu.pc);
+    w.u8(cpu.p.value());
+    w.u8(cpu.s);
+    w.u128(cpu.cycles);
+    let interrupts = &cpu.interrupts;
+    w.u32(interrupts.irq_sources);
+    w.bool(interrupts.nmi_line);
+    w.bool(interrupts.nmi_pending);
+    w.bool(interrupts.reset_pending);
+    w.u8(interrupts.delayed_i.map_or(0xff, |i| i as u8));
+    w.u64(cpu.memory.cycles);
+    cpu.memory.save_state(&mut w);
+    let payload = w.into_bytes();
+
+    let mut result = Vec::with_capacity(HEADER_SIZE + payload.len());
+    result.extend_from_slice(&SNAPSHOT_MAGIC);
+    result.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    result.extend_from_slice(&hash(&payload).to_le_bytes());
+    result.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    result.extend_from_slice(&payload);
+    result
+}
+
+/// Return the payload of the snapshot if its header is valid
+fn check_header(bytes: &[u8]) -> Result<&[u8], String> {
+    let mut r = SnapshotReader::new(bytes);
+    let mut magic = [0; 4];
+    r.bytes_into(&mut magic).map_err(|_| "Not a snapshot".to_string())?;
+    if magic != SNAPSHOT_MAGIC {
+        return Err("Not a snapshot".to_string());
+    }
+    let version = r.u32()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(format!("Unsupported snapshot version {version}, expected {SNAPSHOT_VERSION}"));
+    }
+    let expected_hash = r.u64()?;
+    let payload = r.sized_bytes()?;
+    if hash(payload) != expected_hash {
+        return Err("Corrupted snapshot".to_string());
+    }
+    Ok(payload)
+}
+
+/// Restore the machine saved by [save_snapshot]. The header is verified before anything is
+/// modified, so an invalid snapshot leaves the machine untouched.
+pub fn load_snapshot(cpu: &mut Cpu<Apple2Memory>, bytes: &[u8]) -> Result<(), String> {
+    let mut r = SnapshotReader::new(check_header(bytes)?);
+    let (a, x, y, pc, p, s) = (r.u8()?, r.u8()?, r.u8()?, r.u16()?, r.u8()?, r.u8()?);
+    let cpu_cycles = r.u128()?;
+    let interrupts = Interrupts {
+        irq_sources: r.u32()?,
+        nmi_line: r.bool()?,
+        nmi_pending: r.bool()?,
+        reset_pending: r.bool()?,
+        delayed_i: match r.u8()? { 0xff => None, i => Some(i != 0) },
+    };
+    let cycles = r.u64()?;
+    cpu.memory.load_state(&mut r, cycles)?;
+    cpu.a = a;
+    cpu.x = x;
+    cpu.y = y;
+    cpu.pc = pc;
+    cpu.p.set_value(p);
+    cpu.s = s;
+    cpu.cycles = cpu_cycles;
+    cpu.interrupts = interrupts;
+    Ok(())
+}
+
+pub fn save_snapshot_to_file(cpu: &Cpu<Apple2Memory>, path: &str) -> Result<(), String> {
+    fs::write(path, save_snapshot(cpu)).map_err(|e| format!("Couldn't save {path}: {e}"))
+}
+
+pub fn load_snapshot_from_file(cpu: &mut Cpu<Apple2Memory>, path: &str) -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|e| format!("Couldn't read {path}: {e}"))?;
+    load_snapshot(cpu, &bytes)
+}
@@ -1,279 +1,274 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::thread;
-use std::time::{Duration, Instant};
-
-// use cpal::{Device, FromSample, Sample, Stream, StreamConfig, traits::{DeviceTrait, HostTrait, StreamTrait}};
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+#[cfg(feature = "audio")]
+use std::sync::Arc;
+#[cfg(feature = "audio")]
+use std::time::Duration;
+
+#[cfg(feature = "audio")]
 use rodio::{OutputStream, Sink, Source};
-use rodio::source::SineWave;
-use splines::{Interpolation, Key, Spline};
-
-use crate::constants::SAMPLE_RATE;
-use crate::ui::iced::shared::Shared;
 
-pub struct Speaker2 {
-    sink: Sink,
-    /// The last time we detected an access to C03X
-    last_cycle: u64,
+use crate::constants::{CPU_FREQUENCY_HZ, SAMPLE_RATE, SPEAKER_VOLUME};
+#[cfg(feature = "audio")]
+use crate::context::EmulatorContext;
+
+/// Width of the band-limited step, in samples. This is also the latency of the synthesizer.
+const STEP_WIDTH: usize = 16;
+
+/// Number of sub-sample positions a toggle can be rendered at
+const PHASES: usize = 64;
+
+/// Cutoff of the low-pass filter built into the band-limited step, as a fraction
+/// of the sample rate (0.5 = Nyquist)
+const CUTOFF: f64 = 0.45;
+
+/// Coefficient of the high-pass filter that removes the DC offset (~15 Hz at 48 kHz).
+/// This is also what brings the speaker back to rest when it stops being toggled.
+const DC_BLOCKER: f32 = 0.998;
+
+/// The dynamic resampling never changes the rate by more than this ratio (0.5%),
+/// which is not audible
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+/// Turns the speaker toggles ($C030), timestamped in CPU cycles, into samples at SAMPLE_RATE.
+/// Wall-clock time is never used: the same toggles always produce the same samples, which
+/// makes it usable offline, e.g. in tests (see [render_toggles]).
+///
+/// Each toggle is rendered as a band-limited step (an integrated windowed sinc) positioned
+/// with a sub-sample precision, so fast toggles don't alias into crackles.
+pub struct SpeakerSynth {
+    /// For each phase, the derivative of the band-limited step, STEP_WIDTH + 2 samples long
+    steps: Vec<[f32; STEP_WIDTH + 2]>,
+
+    /// Level of the speaker: SPEAKER_VOLUME or -SPEAKER_VOLUME
+    level: f32,
+    /// Derivative of the output, the front is the next sample to be produced
+    impulses: VecDeque<f32>,
+    /// Integral of the impulses, i.e. the band-limited speaker level
+    integrator: f32,
+    /// DC blocker state
+    previous_input: f32,
+    previous_output: f32,
+
+    /// The cycle (with a fractional part) of the next sample to be produced is
+    /// `origin_cycle + sample_count * cycles_per_sample`. Multiplying instead of accumulating
+    /// avoids drifting because of rounding errors.
+    origin_cycle: f64,
+    sample_count: u64,
+    /// Nominal number of CPU cycles per sample
+    base_cycles_per_sample: f64,
+    /// Number of CPU cycles per sample, after the dynamic adjustment
+    cycles_per_sample: f64,
 }
 
-// impl Speaker2 {
-//     pub fn new() -> Self {
-//         let (controller, mixer) = dynamic_mixer::mixer::<f32>(1, 44_100);
-//         let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-//         let sink = Sink::try_new(&stream_handle).unwrap();
-//         let source = AStream{};
-//         sink.append(source);
-//         Speaker2 { sink, last_cycle: 0, speaker_on: false, }
-//     }
-// 
-//     pub fn run(&self) {
-//         // controller.add(source);
-//         self.sink.set_volume(2.0);
-// 
-//         // Sleep the thread until sink is empty.
-//         self.sink.play();
-//         self.sink.sleep_until_end();
-// 
-//         // loop {
-//         //     std::thread::sleep(std::time::Duration::from_millis(100));
-//         // }
-//         println!("Exiting Speaker2");
-//     }
-// 
-//     pub fn play() {
-//         let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-//         let sink = Sink::try_new(&stream_handle).unwrap();
-//         let source = AStream{};
-//         sink.append(source);
-//         sink.sleep_until_end();
-//     }
-// 
-//     /// cycles is guaranteed to not be empty
-//     pub fn cycles_to_samples(&mut self, cycles: Vec<u64>, sample_frequency: u64) -> Vec<f32> {
-//         let mut result: Vec<f32> = Vec::new();
-//         // let duration_ms: f32 = (cycles[cycles.len() - 1] as f32 - cycles[0] as f32) * 1000.0
-//         //     / cpu_frequency as f32;
-// 
-//         // The value used to downsample. The cycles are recorded at CPU speed (1Mhz)
-//         // but the sample rate is around 44kHz, so this number is 1M / 44k ~= 23.
-//         // 23 CPU samples turn into one sound sample
-//         // let sample_frequency = self.config.sample_rate.0;
-//         let cpu_frequency = 1_000_000;
-//         let sampling = cpu_frequency / sample_frequency as u64;
-//         if (cycles[0] - self.last_cycle) > 100_000 {
-//             println!("Long silence");
-//         }
-//         if self.last_cycle == 0 || (cycles[0] - self.last_cycle) > 100_000 {
-//             self.last_cycle = cycles[0];
-//         }
-//         let mut intervals: Vec<u64> = Vec::new();
-//         intervals.push(cycles[0] - self.last_cycle);
-//         for i in 1..cycles.len() {
-//             intervals.push(cycles[i] - cycles[i - 1]);
-//         }
-//         self.last_cycle = cycles[cycles.len() - 1];
-//         for i in intervals {
-//             let sound_sample_count = i as f32 / sampling as f32;
-//             let value = if self.speaker_on {
-//                 0.2
-//             } else {
-//                 -0.2
-//             };
-//             // alog(&format!("Interval: {i}, adding #{} of sample {}", sound_sample_count.round(), value));
-//             for j in 0..sound_sample_count as usize {
-//                 result.push(value);
-//             }
-//             self.speaker_on = ! self.speaker_on;
-//         }
-//         // for cycle in cycles {
-//         //         let total_cycles = cycle - self.last_cycle;
-//         //         self.last_cycle = cycle;
-//         //         let sound_sample_count = total_cycles as f32 / sampling as f32;
-//         //             // if sound_sample_count < 1.0 {
-//         //             //     println!("Low count");
-//         //             // }
-//         //         let mut just_transitioned = false;
-//         //         for _ in 0..sound_sample_count.round() as usize {
-//         //             // let value = if just_transitioned {
-//         //             //     just_transitioned = false;
-//         //             //     sampling as f32 / 2.0
-//         //             // } else
-//         //             let value = if self.speaker_on {
-//         //                 1.0
-//         //             } else {
-//         //                 -1.0
-//         //             };
-//         //             Shared::add_sound_sample(value);
-//         //         }
-//         //         // alog(&format!("Speaker: count:{sound_sample_count} sample:{}, duration:{duration_ms}",
-//         //         //     if self.speaker_on { sampling } else { 0 }));
-//         //         self.speaker_on = ! self.speaker_on;
-//         //         just_transitioned = true;
-//         // }
-// 
-//         result
-//     }
-// 
-// }
-
-#[derive(Default)]
-pub struct Samples {
-    last_cycle: u64,
-    speaker_on: bool,
-}
+impl SpeakerSynth {
+    /// `cpu_frequency` is the number of cycles the emulator runs per second
+    pub fn new(cpu_frequency: u64) -> Self {
+        let base = cpu_frequency as f64 / SAMPLE_RATE as f64;
+        Self {
+            steps: create_steps(),
+            level: -SPEAKER_VOLUME,
+            impulses: VecDeque::new(),
+            integrator: -SPEAKER_VOLUME,
+            previous_input: -SPEAKER_VOLUME,
+            previous_output: 0.0,
+            origin_cycle: 0.0,
+            sample_count: 0,
+            base_cycles_per_sample: base,
+            cycles_per_sample: base,
+        }
+    }
+
+    pub fn set_cpu_frequency(&mut self, cpu_frequency: u64) {
+        self.base_cycles_per_sample = cpu_frequency as f64 / SAMPLE_RATE as f64;
+        self.set_cycles_per_sample(self.base_cycles_per_sample);
+    }
+
+    fn next_sample_cycle(&self) -> f64 {
+        self.origin_cycle + self.sample_count as f64 * self.cycles_per_sample
+    }
+
+    fn set_cycles_per_sample(&mut self, cycles_per_sample: f64) {
+        self.origin_cycle = self.next_sample_cycle();
+        self.sample_count = 0;
+        self.cycles_per_sample = cycles_per_sample;
+    }
+
+    /// Drop the pending samples and restart the sample clock at `cycle`, e.g. after the
+    /// cycle count has been changed by loading a snapshot
+    pub fn reset(&mut self, cycle: u64) {
+        self.impulses.clear();
+        self.origin_cycle = cycle as f64;
+        self.sample_count = 0;
+    }
 
-impl Samples {
-    /// cycles is guaranteed to not be empty
-    pub fn cycles_to_samples(&mut self, cycles: Vec<u64>, sample_rate: u32) -> Vec<f32> {
-        let max = 0.1;
-        let min = -0.1;
-        let mut result: Vec<f32> = Vec::new();
-        let cpu_frequency = 1_000_000;
-        let sampling = cpu_frequency / sample_rate;
-        if self.last_cycle == 0 || (cycles[0] - self.last_cycle) > 250_000 {
-            self.last_cycle = cycles[0];
+    /// Invoked on each access to $C030
+    pub fn toggle(&mut self, cycle: u64) {
+        // Toggles can't be placed before the next sample: it's already been committed
+        let position = ((cycle as f64 - self.next_sample_cycle()) / self.cycles_per_sample).max(0.0);
+        let index = position as usize;
+        let phase = (((position - index as f64) * PHASES as f64) as usize).min(PHASES - 1);
+
+        let delta = -2.0 * self.level;
+        self.level = -self.level;
+
+        let needed = index + STEP_WIDTH + 2;
+        if self.impulses.len() < needed {
+            self.impulses.resize(needed, 0.0);
         }
-        let mut intervals: Vec<u64> = Vec::new();
-        intervals.push(cycles[0] - self.last_cycle);
-        for i in 1..cycles.len() {
-            intervals.push(cycles[i] - cycles[i - 1]);
+        for (i, s) in self.steps[phase].iter().enumerate() {
+            self.impulses[index + i] += delta * s;
         }
-        self.last_cycle = cycles[cycles.len() - 1];
-        for i in intervals {
-            let value = if self.speaker_on {
-                max
-            } else {
-                min
-            };
-            let mut sound_sample_count = i as f32 / sampling as f32;
-            if sound_sample_count < 1.0 { sound_sample_count = 1.0; }
-            // alog(&format!("Interval: {i}, adding #{} of sample {}", sound_sample_count.round(), value));
-            for _ in 0..sound_sample_count.round() as usize {
-                result.push(value as f32);
-            }
-            // Smooth the transition between the two extreme values
-            if ! result.is_empty() {
-                self.speaker_on = !self.speaker_on;
-            }
+    }
+
+    /// Produce all the samples that start before `cycle` and append them to `output`
+    pub fn render(&mut self, cycle: u64, output: &mut Vec<f32>) {
+        while self.next_sample_cycle() + self.cycles_per_sample <= cycle as f64 {
+            self.integrator += self.impulses.pop_front().unwrap_or(0.0);
+            let sample = self.integrator - self.previous_input + DC_BLOCKER * self.previous_output;
+            self.previous_input = self.integrator;
+            self.previous_output = sample;
+            output.push(sample);
+            self.sample_count += 1;
         }
+    }
+
+    /// Dynamic resampling: adjust the rate so that the number of samples waiting to be played
+    /// (`buffered`) stays around `target`. If the buffer fills up, produce slightly fewer
+    /// samples per cycle, and slightly more if it drains.
+    pub fn adjust_rate(&mut self, buffered: usize, target: usize) {
+        let error = (buffered as f64 - target as f64) / target.max(1) as f64;
+        let adjustment = (error * MAX_RATE_ADJUSTMENT).clamp(-MAX_RATE_ADJUSTMENT, MAX_RATE_ADJUSTMENT);
+        self.set_cycles_per_sample(self.base_cycles_per_sample * (1.0 + adjustment));
+    }
+}
 
-        result
+impl Default for SpeakerSynth {
+    fn default() -> Self {
+        SpeakerSynth::new(CPU_FREQUENCY_HZ as u64)
     }
+}
 
+/// Offline rendering: turn a list of toggle cycles into the samples up to `end_cycle`
+pub fn render_toggles(toggles: &[u64], end_cycle: u64, cpu_frequency: u64) -> Vec<f32> {
+    let mut synth = SpeakerSynth::new(cpu_frequency);
+    let mut result = Vec::new();
+    for cycle in toggles {
+        synth.toggle(*cycle);
+    }
+    synth.render(end_cycle, &mut result);
+    result
 }
 
-/// We want to go from s to 0.0 smoothly
-pub fn decay(s: f32) {
-    let rate = 100.0;
-    let increment = -s / rate;
-    let mut decay = s;
-    for _ in 0..rate as usize {
-        decay += increment;
-        if f32::abs(decay) > 0.1 {
-            println!("Decay is too big");
+/// Build the derivative of a step filtered by a Blackman windowed sinc, for each phase.
+/// `step(x)` goes from 0 (x <= 0) to 1 (x >= STEP_WIDTH). For a toggle happening at `phase`
+/// (a fraction of a sample), sample `i` gets `step(i - phase) - step(i - 1 - phase)`.
+fn create_steps() -> Vec<[f32; STEP_WIDTH + 2]> {
+    let size = STEP_WIDTH * PHASES;
+    let mut integral = vec![0.0_f64; size + 1];
+    let mut sum = 0.0;
+    for (i, value) in integral.iter_mut().enumerate() {
+        let x = i as f64 / PHASES as f64 - STEP_WIDTH as f64 / 2.0;
+        let sinc = if x == 0.0 { 1.0 } else {
+            let t = 2.0 * PI * CUTOFF * x;
+            t.sin() / t
+        };
+        let w = i as f64 / size as f64;
+        let blackman = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+        sum += sinc * blackman;
+        *value = sum;
+    }
+    for v in integral.iter_mut() {
+        *v /= sum;
+    }
+    let step = |index: isize| -> f64 {
+        if index <= 0 { 0.0 } else if index as usize >= size { 1.0 } else { integral[index as usize] }
+    };
+
+    let mut result = Vec::new();
+    for phase in 0..PHASES {
+        let mut deltas = [0.0_f32; STEP_WIDTH + 2];
+        for (i, d) in deltas.iter_mut().enumerate() {
+            let x = (i * PHASES) as isize - phase as isize;
+            *d = (step(x) - step(x - PHASES as isize)) as f32;
         }
-        println!("Adding decay {decay}");
-        Shared::add_sound_sample(decay);
+        result.push(deltas);
     }
-    Shared::add_sound_sample(0.0);
+    result
 }
 
+/// Plays the speaker and the Mockingboard with rodio, only compiled with the `audio` feature
+#[cfg(feature = "audio")]
 pub struct Speaker {
+    /// Where the samples come from
+    context: Arc<EmulatorContext>,
 }
 
+#[cfg(feature = "audio")]
 impl Speaker {
-    pub fn new() -> Self {
-
-        // let source = AStream::default();
-        // controller.add(source);
-
+    pub fn new(context: Arc<EmulatorContext>) -> Self {
         Self {
+            context,
         }
     }
 
+    /// Play the samples produced by the emulator until the end of times
     pub fn run(&self) {
-        loop {
-            self.play_rodio();
-            thread::sleep(Duration::from_millis(40));
-        }
-    }
-
-    pub fn play_rodio(&self) {
         let (_stream, stream_handle) = OutputStream::try_default().unwrap();
         let sink = Sink::try_new(&stream_handle).unwrap();
 
-        // Append the dynamic mixer to the sink to play a C major 6th chord.
-        let stream = AStream::default();
-        sink.append(stream); // mixer);
+        // The Mockingboard samples are mixed with the speaker's
+        let stream = AStream { context: self.context.clone(), last_sample: 0.0 }
+            .mix(MockingboardStream { context: self.context.clone() });
+        sink.append(stream);
 
-        // Sleep the thread until sink is empty.
+        // The stream never ends
         sink.sleep_until_end();
     }
 }
 
-pub fn file_to_samples(path: &str, sample_rate: u32) -> Vec<f32> {
-    let file = File::open(path).unwrap();
-    let reader = BufReader::new(file);
-    let mut cycles: Vec<u64> = Vec::new();
-    for line in reader.lines() {
-        let l = line.unwrap_or_default();
-        if let Ok(n) = str::parse::<u64>(&l) {
-            if n != 0 {
-                cycles.push(n);
-            }
-        }
-    }
-
-    // println!("Samples: {} {}", samples[0], samples[1]);
-
-    Samples::default().cycles_to_samples(cycles, sample_rate)
+/// Samples produced by the Mockingboard, see [crate::mockingboard::mockingboard::Mockingboard]
+#[cfg(feature = "audio")]
+struct MockingboardStream {
+    context: Arc<EmulatorContext>,
 }
 
-pub fn play_file_rodio(path: &str) {
-    let s = SineWave::new(400.0)
-        ;
-    let start = Key::new(0., 0., Interpolation::Cosine);
-    let end = Key::new(1., 10., Interpolation::default());
-    let spline = Spline::from_vec(vec![start, end]);
-
-    // generate and print 21 values with equal distances
-    // that is: 0.0, 0.05, 0.10, ..., 0.95, 1.00
-    for i in 0..10 {
-        println!("value: {:?}", spline.sample(i as f32 / 10.0))
+#[cfg(feature = "audio")]
+impl Source for MockingboardStream {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
     }
 
-
-    let samples = file_to_samples(path, SAMPLE_RATE);
-    for s in samples {
-        Shared::add_sound_sample(s);
+    fn channels(&self) -> u16 {
+        1
     }
 
-    // let (controller, mixer) = dynamic_mixer::mixer::<f32>(1, 48_100);
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&stream_handle).unwrap();
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
 
-    let source = AStream::default();
-    // controller.add(source);
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
 
-    // Append the dynamic mixer to the sink to play a C major 6th chord.
-    sink.append(source); // mixer);
+#[cfg(feature = "audio")]
+impl Iterator for MockingboardStream {
+    type Item = f32;
 
-    // Sleep the thread until sink is empty.
-    sink.sleep_until_end();
+    fn next(&mut self) -> Option<Self::Item> {
+        self.context.next_mockingboard_sample()
+    }
 }
 
+/// Samples produced by [SpeakerSynth]
+#[cfg(feature = "audio")]
 struct AStream {
+    context: Arc<EmulatorContext>,
     last_sample: f32,
-    last_sample_time: Instant,
-}
-
-impl Default for AStream {
-    fn default() -> Self {
-        Self { last_sample: 0.0, last_sample_time: Instant::now() }
-    }
 }
 
+#[cfg(feature = "audio")]
 impl Source for AStream {
     fn current_frame_len(&self) -> Option<usize> {
         None
@@ -292,23 +287,16 @@ impl Source for AStream {
     }
 }
 
+#[cfg(feature = "audio")]
 impl Iterator for AStream {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // println!(" RETURNING NEXT");
-        match Shared::get_next_sound_sample_maybe() {
-            None => {
-                None
-            }
-            Some(s) => {
-                self.last_sample_time = Instant::now();
-                if s != 0.0 {
-                    self.last_sample = s;
-                    Shared::set_last_sample_played(Some((Instant::now(), s)));
-                }
-                Some(s)
-            }
-        }
+        // On underrun (e.g. the emulator is paused), fade the last sample instead of
+        // ending the stream, which would cause a click
+        let sample = self.context.next_sound_sample()
+            .unwrap_or(self.last_sample * DC_BLOCKER);
+        self.last_sample = sample;
+        Some(sample)
     }
 }
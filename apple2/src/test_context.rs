@@ -0,0 +1,89 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use cpu::constants::DEFAULT_EMULATOR_SPEED_HZ;
+use crate::apple2_cpu::EmulatorConfigMsg;
+use crate::constants::{REWIND_POINTS_PER_SECOND, START};
+use crate::context::{EmulatorContext, EmulatorEvent, EmulatorObserver};
+use crate::create_apple2;
+use crate::disk::disk_info::DiskInfo;
+
+#[derive(Default)]
+struct Recorder {
+    events: Mutex<Vec<EmulatorEvent>>,
+}
+
+impl EmulatorObserver for Recorder {
+    fn on_event(&self, event: &EmulatorEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+/// Create a machine with its own context, run it for `cycles` and return what its observer saw
+fn run(disk: Option<&str>, cycles: u64, rewind: bool) -> (Arc<EmulatorContext>, Vec<EmulatorEvent>) {
+    START.get_or_init(Instant::now);
+    let context = Arc::new(EmulatorContext::default());
+    let recorder = Arc::new(Recorder::default());
+    context.subscribe(recorder.clone());
+    let mut apple2 = create_apple2(None, None, None, [disk.map(DiskInfo::n), None],
+        Box::<EmulatorConfigMsg>::default(), context.clone());
+    if rewind {
+        apple2.enable_rewind();
+    }
+    for _ in 0..cycles {
+        apple2.cpu.step();
+    }
+    assert_eq!(apple2.cpu.cpu.memory.cycles, cycles);
+    let events = recorder.events.lock().unwrap().clone();
+    (context, events)
+}
+
+#[test]
+pub fn observers_see_the_drive_activity() {
+    let (context, events) = run(Some("files/master.dsk"), 1_000_000, false);
+    assert!(context.drive(0).is_some());
+    assert!(context.drive(1).is_none());
+    assert!(events.iter().any(|e| matches!(e, EmulatorEvent::DiskChanged(0, Some(_)))));
+    // DOS is being loaded: the head moved
+    assert!(events.iter().any(|e| matches!(e, EmulatorEvent::PhaseChanged(0, p) if *p > 0)));
+}
+
+#[test]
+pub fn machines_are_independent() {
+    let with_disk = std::thread::spawn(|| run(Some("files/master.dsk"), 500_000, false));
+    let (context, events) = run(None, 500_000, false);
+    let (other_context, other_events) = with_disk.join().unwrap();
+
+    assert!(context.drive(0).is_none());
+    assert!(! events.iter().any(|e| matches!(e, EmulatorEvent::DiskChanged(_, Some(_)))));
+    assert!(other_context.drive(0).is_some());
+    assert!(other_events.iter().any(|e| matches!(e, EmulatorEvent::PhaseChanged(0, _))));
+}
+
+#[test]
+pub fn controller_input() {
+    let context = EmulatorContext::default();
+    let recorder = Arc::new(Recorder::default());
+    context.subscribe(recorder.clone());
+    context.set_controller_values([1, 2, 3, 4]);
+    context.set_controller_button(1, true);
+    let state = context.controller();
+    assert_eq!(state.values, [1, 2, 3, 4]);
+    assert_eq!(state.buttons, [false, true, false, false]);
+    let events = recorder.events.lock().unwrap();
+    assert!(matches!(events.last(), Some(EmulatorEvent::ControllerChanged(s)) if *s == state));
+}
+
+#[test]
+pub fn rewind_is_opt_in() {
+    let rewind_counts = |events: &[EmulatorEvent]| -> Vec<usize> {
+        events.iter().filter_map(|e| match e {
+            EmulatorEvent::RewindChanged(status) => Some(status.count),
+            _ => None,
+        }).collect()
+    };
+    let (_, events) = run(None, 300_000, false);
+    assert!(rewind_counts(&events).is_empty());
+    // One second at the speed of the emulator
+    let (_, events) = run(None, DEFAULT_EMULATOR_SPEED_HZ, true);
+    assert_eq!(rewind_counts(&events).last(), Some(&REWIND_POINTS_PER_SECOND));
+}
@@ -150,7 +150,7 @@ fn test_encoded_data() {
 fn test_boot_sequence() {
     let mut computer = create_apple2(
         None, None, None, [None, None],
-        Box::new(EmulatorConfigMsg::default()));
+        Box::new(EmulatorConfigMsg::default()), Default::default());
     println!("Created computer");
     computer.cpu.cpu.pc = 0xc65c;
     computer.cpu.cpu.x = 0x60;
@@ -171,7 +171,8 @@ fn test_boot_sequence() {
 
 pub(crate) fn test_bit_buffer() {
     let disk_info = DiskInfo::n("D:\\PD\\Apple disks\\Apple DOS 3.3.dsk");
-    let mut dc = DiskController::new_with_filename(6, &[Some(disk_info), None], None);
+    let mut dc = DiskController::new_with_filename(6, &[Some(disk_info), None], None,
+        Default::default());
     // dc.set_track(4);
     // dc.set_bit_position(22759);
     println!("Next byte: {:02X}", dc.next_byte());
@@ -0,0 +1,60 @@
+use crate::headless::*;
+
+#[test]
+pub fn boot_master_dsk() {
+    let options = HeadlessOptions {
+        disks: vec!["files/master.dsk".to_string()],
+        max_cycles: 20_000_000,
+        until: Some("]".to_string()),
+        ..Default::default()
+    };
+    let result = run_machine(&options).unwrap();
+    assert!(result.found, "Screen:\n{}", result.text.join("\n"));
+    assert!(result.cycles < options.max_cycles);
+    assert_eq!(result.text.len(), 24);
+    assert_eq!((result.screen.width, result.screen.height), (560, 384));
+    assert_eq!(result.screen.hash().len(), 8);
+}
+
+#[test]
+pub fn missing_disk() {
+    let options = HeadlessOptions {
+        disks: vec!["files/does_not_exist.dsk".to_string()],
+        max_cycles: 1000,
+        ..Default::default()
+    };
+    assert!(run_machine(&options).is_err());
+    assert_eq!(run_headless(&options), EXIT_ERROR);
+}
+
+#[test]
+pub fn text_screen() {
+    let mut memory = vec![0; 0x10000];
+    let mut aux_memory = vec![0; 0x10000];
+    // Normal "]", inverse "A", flashing "B"
+    memory[0x400..0x403].copy_from_slice(&[0xdd, 0x01, 0x42]);
+    memory[0x7d0] = 0xe1;
+    let text = screen_text(&memory, &aux_memory);
+    assert_eq!(text.len(), 24);
+    assert!(text[0].starts_with("]AB"));
+    assert!(text[23].starts_with('a'));
+
+    // 80 columns: aux first
+    memory[crate::memory_constants::EIGHTY_COLUMNS_STATUS as usize] = 0x80;
+    aux_memory[0x400] = 0xda;
+    let text = screen_text(&memory, &aux_memory);
+    assert_eq!(text[0].len(), 80);
+    assert!(text[0].starts_with("Z]"));
+}
+
+#[test]
+pub fn png() {
+    let screen = Screen { width: 2, height: 1, pixels: vec![0xff, 0, 0, 0, 0xff, 0] };
+    let png = screen.to_png();
+    assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+    assert_eq!(&png[12..16], b"IHDR");
+    // Width and height, big endian
+    assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 1]);
+    assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+}
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+use crate::Apple2;
+use crate::config_file::ConfigFile;
+use crate::constants::CYCLES_PER_FRAME;
+use crate::context::EmulatorContext;
+use crate::mockingboard::mockingboard::Mockingboard;
+
+fn boot_master_dsk() -> Apple2 {
+    let mut apple2 = Apple2::new(ConfigFile::default(), [None, None],
+        Arc::new(EmulatorContext::default()));
+    apple2.insert_disk(0, "files/master.dsk").unwrap();
+    assert!(apple2.disks()[0].is_some());
+    while ! apple2.text_screen()[23].starts_with(']') {
+        assert!(apple2.cycles() < 20_000_000, "Didn't boot:\n{}", apple2.text_screen().join("\n"));
+        apple2.step_frame();
+    }
+    // Let DOS finish running HELLO
+    apple2.step_frames(60);
+    apple2
+}
+
+#[test]
+pub fn type_into_basic() {
+    let mut apple2 = boot_master_dsk();
+    apple2.type_text("PRINT 40+2\r");
+    apple2.step_frames(10);
+    let text = apple2.text_screen();
+    assert!(text.iter().any(|line| line.trim_end() == "42"), "Screen:\n{}", text.join("\n"));
+}
+
+#[test]
+pub fn step_and_inspect() {
+    let mut apple2 = Apple2::new(ConfigFile::default(), [None, None],
+        Arc::new(EmulatorContext::default()));
+    apple2.step_frames(2);
+    assert_eq!(apple2.cycles(), 2 * CYCLES_PER_FRAME);
+    assert!(! apple2.audio_samples().is_empty());
+    assert!(apple2.audio_samples().is_empty());
+
+    apple2.poke(0x300, 0x42);
+    assert_eq!(apple2.peek(0x300), 0x42);
+    assert_eq!(apple2.main_memory()[0x300], 0x42);
+    apple2.press_key(b'A');
+    assert_eq!(apple2.peek(0xc000), 0xc1);
+
+    let screen = apple2.screen();
+    assert_eq!(screen.pixels.len(), screen.width * screen.height * 3);
+    assert!(apple2.insert_disk(0, "files/does_not_exist.dsk").is_err());
+}
+
+#[test]
+pub fn slot_4_is_empty_by_default() {
+    let apple2 = Apple2::new(ConfigFile::default(), [None, None],
+        Arc::new(EmulatorContext::default()));
+    assert!(apple2.cpu.cpu.memory.slots.find::<Mockingboard>().is_none());
+}
@@ -3,10 +3,11 @@ use crate::memory::*;
 use crate::memory_constants::*;
 use crate::{clear_soft_switch, set_soft_switch};
 use crate::roms::RomType;
+use crate::card::default_slots;
 
 // #[test]
 pub fn test_set_and_reset_switches() {
-    let mut m = Apple2Memory::new([None, None], [None, None], None);
+    let mut m = Apple2Memory::new([None, None], Default::default(), &default_slots(), None, Default::default());
     struct Test {
         on: u16,
         off: u16,
@@ -86,7 +87,7 @@ pub fn test_high_ram() {
 
     for (index, test) in tests.iter().enumerate() {
         let mut m = {
-            let mut m = Apple2Memory::new([None, None], [None, None], None);
+            let mut m = Apple2Memory::new([None, None], Default::default(), &default_slots(), None, Default::default());
             m.memories[0][D] = 0x53;
             m.memories[0][F] = 0x60;
             m.high_ram[0].banks[0][D - 0xd000] = 0x11;
@@ -135,7 +136,7 @@ pub fn test_high_ram() {
 
 // #[test]
 pub fn test_lang_card() {
-    let mut m = Apple2Memory::new([None, None], [None, None], None);
+    let mut m = Apple2Memory::new([None, None], Default::default(), &default_slots(), None, Default::default());
     m.get(0xc08b);
     m.get(0xc08b);
     m.set(D as u16, 0x44);
@@ -198,7 +199,7 @@ pub fn test_aux_mem() {
 
     fn create_mem() -> Apple2Memory {
         // Initialize aux to $3 and main to $1
-        let mut m = Apple2Memory::new([None, None], [None, None], None);
+        let mut m = Apple2Memory::new([None, None], Default::default(), &default_slots(), None, Default::default());
         m.load_roms(RomType::Apple2Enhanced);
         m
     }
@@ -375,3 +376,62 @@ pub fn test_aux_mem() {
     }
 }
 
+
+#[test]
+pub fn test_slot_roms() {
+    use std::any::Any;
+    use crate::card::{Bus, Card, EXPANSION_ROM_SIZE};
+    use crate::roms::DISK2_ROM;
+
+    struct TestCard {
+        rom: [u8; 0x100],
+        expansion_rom: [u8; EXPANSION_ROM_SIZE],
+        last_write: Option<(u8, u8)>,
+    }
+
+    impl Card for TestCard {
+        fn name(&self) -> String { "Test".to_string() }
+        fn io(&mut self, offset: u8, value: u8, read: bool, _bus: &mut dyn Bus) -> u8 {
+            if read { 0x40 | offset } else { self.last_write = Some((offset, value)); 0 }
+        }
+        fn rom(&self) -> Option<&[u8]> { Some(&self.rom) }
+        fn expansion_rom(&self) -> Option<&[u8]> { Some(&self.expansion_rom) }
+        fn as_any(&self) -> &dyn Any { self }
+        fn as_any_mut(&mut self) -> &mut dyn Any { self }
+    }
+
+    let mut m = Apple2Memory::new([None, None], Default::default(), &default_slots(), None, Default::default());
+    m.slots.insert(4, Box::new(TestCard {
+        rom: [0x44; 0x100],
+        expansion_rom: [0x88; EXPANSION_ROM_SIZE],
+        last_write: None,
+    }));
+
+    // Slot ROMs
+    assert_eq!(m.get(0xc600), DISK2_ROM[0]);
+    assert_eq!(m.get(0xc6ff), DISK2_ROM[0xff]);
+    assert_eq!(m.get(0xc410), 0x44);
+
+    // I/O: slot 4 is $C0C0-$C0CF
+    assert_eq!(m.get(0xc0c3), 0x43);
+    m.set(0xc0c5, 0x12);
+    assert_eq!(m.slots.find::<TestCard>().unwrap().last_write, Some((5, 0x12)));
+
+    // $C800-$CFFF belongs to the last card accessed, until $CFFF is accessed
+    m.get(0xcfff);
+    assert_eq!(m.get(0xc800), 0);
+    m.get(0xc400);
+    assert_eq!(m.get(0xc800), 0x88);
+    m.get(0xcfff);
+    assert_eq!(m.get(0xc800), 0);
+}
+
+#[test]
+pub fn load_disk_without_controller() {
+    use crate::card::SLOT_COUNT;
+    use crate::disk::disk_info::DiskInfo;
+
+    let mut m = Apple2Memory::new([None, None], Default::default(), &[None; SLOT_COUNT], None, Default::default());
+    let error = m.load_disk_from_file(false, 0, DiskInfo::n("files/master.dsk")).unwrap_err();
+    assert!(error.contains("No Disk ][ controller"), "{error}");
+}
@@ -0,0 +1,71 @@
+use crate::rewind::RewindBuffer;
+
+/// 64 KB of pseudo random content, with `changes` bytes modified
+fn snapshot(changes: usize) -> Vec<u8> {
+    let mut result: Vec<u8> = (0..0x10000_u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    for i in 0..changes {
+        result[i * 97 % 0x10000] = i as u8;
+    }
+    result
+}
+
+#[test]
+pub fn snapshots_are_restored() {
+    let mut buffer = RewindBuffer::new(usize::MAX);
+    for i in 0..100 {
+        buffer.push(i as u64 * 1000, &snapshot(i));
+    }
+    assert_eq!(buffer.len(), 100);
+    for i in [0, 1, 31, 32, 33, 64, 99] {
+        assert_eq!(buffer.get(i).unwrap(), snapshot(i), "Snapshot {i}");
+        assert_eq!(buffer.cycle(i), Some(i as u64 * 1000));
+    }
+    assert!(buffer.get(100).is_none());
+
+    // Only the first snapshot of each group is big
+    assert!(buffer.total_bytes < 5 * snapshot(0).len(), "Size: {}", buffer.total_bytes);
+}
+
+#[test]
+pub fn snapshots_of_different_sizes() {
+    let mut buffer = RewindBuffer::new(usize::MAX);
+    buffer.push(0, &snapshot(0));
+    buffer.push(1, &[1, 2, 0, 0, 0, 0, 0, 3]);
+    buffer.push(2, &snapshot(5));
+    assert_eq!(buffer.get(0).unwrap(), snapshot(0));
+    assert_eq!(buffer.get(1).unwrap(), vec![1, 2, 0, 0, 0, 0, 0, 3]);
+    assert_eq!(buffer.get(2).unwrap(), snapshot(5));
+}
+
+#[test]
+pub fn oldest_snapshots_are_dropped() {
+    let max = 3 * snapshot(0).len();
+    let mut buffer = RewindBuffer::new(max);
+    for i in 0..500 {
+        buffer.push(i as u64, &snapshot(i));
+    }
+    assert!(buffer.total_bytes <= max);
+    let len = buffer.len();
+    assert!(len > 32 && len < 500, "Length: {len}");
+    // The most recent snapshots are still there and still correct
+    assert_eq!(buffer.cycle(len - 1), Some(499));
+    assert_eq!(buffer.get(0).unwrap(), snapshot(500 - len));
+    assert_eq!(buffer.get(len - 1).unwrap(), snapshot(499));
+}
+
+#[test]
+pub fn truncate() {
+    let mut buffer = RewindBuffer::new(usize::MAX);
+    for i in 0..40 {
+        buffer.push(i as u64, &snapshot(i));
+    }
+    // Resume from snapshot 35, then more snapshots are taken
+    buffer.truncate(36);
+    assert_eq!(buffer.len(), 36);
+    buffer.push(1000, &snapshot(1000));
+    buffer.push(1001, &snapshot(1001));
+    assert_eq!(buffer.get(35).unwrap(), snapshot(35));
+    assert_eq!(buffer.get(36).unwrap(), snapshot(1000));
+    assert_eq!(buffer.get(37).unwrap(), snapshot(1001));
+    assert_eq!(buffer.cycle(37), Some(1001));
+}
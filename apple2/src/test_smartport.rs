@@ -0,0 +1,318 @@
+use std::fs;
+use std::sync::Arc;
+use cpu::memory::Memory;
+use crate::card::{Bus, Card, SLOT_COUNT};
+use crate::constants::HARD_DRIVE_COUNT;
+use crate::context::EmulatorContext;
+use crate::disk::disk_info::DiskInfo;
+use crate::disk::hard_drive::{HardDriveImage, BLOCK_SIZE};
+use crate::memory::{Apple2Memory, AUX, MAIN};
+use crate::memory_constants::*;
+use crate::smartport::*;
+use crate::test_util::{temp_dir, temp_path};
+
+const BLOCKS: usize = 8;
+
+/// The content of an image whose block n is filled with n
+fn blocks() -> Vec<u8> {
+    (0..BLOCKS).flat_map(|b| vec![b as u8; BLOCK_SIZE]).collect()
+}
+
+/// A file in the temp directory
+fn create_file(name: &str, content: &[u8]) -> String {
+    let path = temp_path(name);
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn create_image(name: &str) -> String {
+    create_file(&format!("{name}.hdv"), &blocks())
+}
+
+/// A 2MG header followed by the blocks and a comment
+fn create_2mg(name: &str, locked: bool) -> String {
+    let mut content = b"2IMGMPLE".to_vec();
+    content.extend_from_slice(&64_u16.to_le_bytes());
+    content.extend_from_slice(&1_u16.to_le_bytes());
+    content.extend_from_slice(&1_u32.to_le_bytes());
+    content.extend_from_slice(&(if locked { 0x8000_0000_u32 } else { 0 }).to_le_bytes());
+    content.extend_from_slice(&(BLOCKS as u32).to_le_bytes());
+    content.extend_from_slice(&64_u32.to_le_bytes());
+    content.extend_from_slice(&((BLOCKS * BLOCK_SIZE) as u32).to_le_bytes());
+    content.resize(64, 0);
+    content.extend(blocks());
+    content.extend_from_slice(b"A comment");
+    create_file(&format!("{name}.2mg"), &content)
+}
+
+/// A flat memory, without soft switches
+impl Bus for Vec<u8> {
+    fn read(&mut self, address: u16) -> u8 {
+        self[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self[address as usize] = value;
+    }
+}
+
+/// Send a ProDOS block driver command like the ROM does, return (A, carry)
+fn command(sp: &mut SmartPort, memory: &mut Vec<u8>, command: u8, unit: u8, buffer: u16, block: u16)
+    -> (u8, bool)
+{
+    for (offset, value) in [(2, command), (3, unit), (4, buffer as u8), (5, (buffer >> 8) as u8),
+        (6, block as u8), (7, (block >> 8) as u8)]
+    {
+        sp.io(offset, value, false, memory);
+    }
+    let a = sp.io(0, 0, true, memory);
+    let carry = sp.io(1, 0, true, memory) & 1 != 0;
+    (a, carry)
+}
+
+/// Where the calls are made from and where their parameters are
+const JSR: u16 = 0x1000;
+const PARAMETERS: u16 = 0x1100;
+
+/// Make a SmartPort call like the ROM does, return (A, carry, X/Y)
+fn call(sp: &mut SmartPort, memory: &mut Vec<u8>, command: u8, parameters: &[u8]) -> (u8, bool, u16) {
+    memory[JSR as usize..JSR as usize + 3].copy_from_slice(&[0x20, 0x0d, 0xc7]);
+    memory[JSR as usize + 3] = command;
+    memory[JSR as usize + 4..JSR as usize + 8].copy_from_slice(&[PARAMETERS as u8, (PARAMETERS >> 8) as u8, 0, 0]);
+    memory[PARAMETERS as usize..PARAMETERS as usize + parameters.len()].copy_from_slice(parameters);
+    let pushed = JSR + 2;
+    sp.io(9, pushed as u8, false, memory);
+    sp.io(0xa, (pushed >> 8) as u8, false, memory);
+    let a = sp.io(0xb, 0, true, memory);
+    assert_eq!(a, sp.io(0xe, 0, true, memory));
+    let return_address = sp.io(9, 0, true, memory) as u16 | (sp.io(0xa, 0, true, memory) as u16) << 8;
+    assert_eq!(return_address, pushed + if command & EXTENDED != 0 { 5 } else { 3 });
+    let count = sp.io(0xc, 0, true, memory) as u16 | (sp.io(0xd, 0, true, memory) as u16) << 8;
+    let carry = sp.io(1, 0, true, memory) & 1 != 0;
+    (a, carry, count)
+}
+
+fn smartport(paths: &[Option<&str>]) -> SmartPort {
+    let context = Arc::new(EmulatorContext::default());
+    for (i, path) in paths.iter().enumerate() {
+        context.set_hard_drive(i, path.map(DiskInfo::n));
+    }
+    SmartPort::new(7, context)
+}
+
+#[test]
+pub fn read_and_write_both_drives() {
+    let (path1, path2) = (create_image("drive1"), create_image("drive2"));
+    let mut sp = smartport(&[Some(&path1), Some(&path2)]);
+    let mut memory = vec![0; 0x10000];
+
+    assert_eq!(command(&mut sp, &mut memory, STATUS, 0x70, 0, 0), (NO_ERROR, false));
+    assert_eq!(command(&mut sp, &mut memory, STATUS, 0xf0, 0, 0), (NO_ERROR, false));
+    assert_eq!(sp.io(0xc, 0, true, &mut memory), BLOCKS as u8);
+
+    assert_eq!(command(&mut sp, &mut memory, READ, 0x70, 0x2000, 3), (NO_ERROR, false));
+    assert_eq!(sp.io(8, 0, true, &mut memory), 3);
+
+    // Write block 5 of drive 2 from $2000
+    memory[0x2000..0x2000 + BLOCK_SIZE].fill(0xaa);
+    assert_eq!(command(&mut sp, &mut memory, WRITE, 0xf0, 0x2000, 5), (NO_ERROR, false));
+    let content = fs::read(&path2).unwrap();
+    assert!(content[5 * BLOCK_SIZE..6 * BLOCK_SIZE].iter().all(|b| *b == 0xaa));
+    assert_eq!(content[4 * BLOCK_SIZE], 4);
+    assert_eq!(fs::read(&path1).unwrap()[5 * BLOCK_SIZE], 5);
+
+    assert_eq!(command(&mut sp, &mut memory, READ, 0xf0, 0x2000, 5), (NO_ERROR, false));
+    assert_eq!(sp.io(8, 0, true, &mut memory), 0xaa);
+
+    assert_eq!(command(&mut sp, &mut memory, FORMAT, 0x70, 0, 0), (NO_ERROR, false));
+    assert!(fs::read(&path1).unwrap().iter().all(|b| *b == 0));
+
+    let _ = fs::remove_file(path1);
+    let _ = fs::remove_file(path2);
+}
+
+#[test]
+pub fn errors() {
+    let path = create_image("errors");
+    let mut sp = smartport(&[Some(&path), None]);
+    let mut memory = vec![0; 0x10000];
+
+    assert_eq!(command(&mut sp, &mut memory, STATUS, 0xf0, 0, 0), (NO_DEVICE, true));
+    assert_eq!(command(&mut sp, &mut memory, READ, 0x70, 0x2000, BLOCKS as u16), (IO_ERROR, true));
+    assert_eq!(command(&mut sp, &mut memory, READ, 0x70, 0x2000, 0), (NO_ERROR, false));
+
+    let read_only = create_image("read_only");
+    let mut permissions = fs::metadata(&read_only).unwrap().permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&read_only, permissions.clone()).unwrap();
+    let mut sp = smartport(&[Some(&read_only)]);
+    assert_eq!(command(&mut sp, &mut memory, WRITE, 0x70, 0x2000, 1), (WRITE_PROTECTED, true));
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    fs::set_permissions(&read_only, permissions).unwrap();
+
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(read_only);
+}
+
+#[test]
+pub fn smartport_calls() {
+    let (path1, path4) = (create_image("unit1"), create_2mg("unit4", false));
+    let mut sp = smartport(&[Some(&path1), None, None, Some(&path4)]);
+    let mut memory = vec![0; 0x10000];
+
+    // STATUS of the SmartPort: 4 units
+    assert_eq!(call(&mut sp, &mut memory, STATUS, &[3, 0, 0x00, 0x20, STATUS_DEVICE]), (NO_ERROR, false, 8));
+    assert_eq!(memory[0x2000], 4);
+
+    // DIB of unit 4
+    assert_eq!(call(&mut sp, &mut memory, STATUS, &[3, 4, 0x00, 0x20, STATUS_DIB]), (NO_ERROR, false, 25));
+    assert_eq!(memory[0x2000] & 0x10, 0x10);
+    assert_eq!(&memory[0x2001..0x2004], &[BLOCKS as u8, 0, 0]);
+    let name = format!("MAPLE2-{}-UNIT4", std::process::id());
+    assert_eq!(memory[0x2004] as usize, name.len().min(16));
+    assert!(name.as_bytes().starts_with(&memory[0x2005..0x2005 + memory[0x2004] as usize]));
+    assert_eq!(memory[0x2015], 0x02);
+
+    // Unit 2 is empty
+    assert_eq!(call(&mut sp, &mut memory, STATUS, &[3, 2, 0x00, 0x20, STATUS_DEVICE]), (NO_ERROR, false, 4));
+    assert_eq!(memory[0x2000] & 0x10, 0);
+    assert_eq!(call(&mut sp, &mut memory, READ_BLOCK, &[3, 2, 0x00, 0x20, 0, 0, 0]).0, OFFLINE);
+
+    // READBLOCK and WRITEBLOCK on the 2MG image, whose blocks start after the header
+    assert_eq!(call(&mut sp, &mut memory, READ_BLOCK, &[3, 4, 0x00, 0x20, 6, 0, 0]), (NO_ERROR, false, 512));
+    assert!(memory[0x2000..0x2200].iter().all(|b| *b == 6));
+    memory[0x3000..0x3200].fill(0x55);
+    assert_eq!(call(&mut sp, &mut memory, WRITE_BLOCK | EXTENDED,
+        &[3, 4, 0x00, 0x30, 0, 0, 2, 0, 0, 0]), (NO_ERROR, false, 512));
+    let content = fs::read(&path4).unwrap();
+    assert!(content[64 + 2 * BLOCK_SIZE..64 + 3 * BLOCK_SIZE].iter().all(|b| *b == 0x55));
+    assert!(content.ends_with(b"A comment"));
+
+    // Errors
+    assert_eq!(call(&mut sp, &mut memory, READ_BLOCK, &[3, 1, 0x00, 0x20, BLOCKS as u8, 0, 0]),
+        (BAD_BLOCK, true, 0));
+    assert_eq!(call(&mut sp, &mut memory, READ_BLOCK, &[3, 5, 0x00, 0x20, 0, 0, 0]).0, BAD_UNIT);
+    assert_eq!(call(&mut sp, &mut memory, READ_BLOCK, &[2, 1, 0x00, 0x20, 0, 0, 0]).0,
+        BAD_PARAMETER_COUNT);
+    assert_eq!(call(&mut sp, &mut memory, 0x09, &[4, 1]).0, BAD_COMMAND);
+    assert_eq!(call(&mut sp, &mut memory, CONTROL, &[3, 1, 0x00, 0x20, 0]), (NO_ERROR, false, 0));
+    assert_eq!(call(&mut sp, &mut memory, CONTROL, &[3, 1, 0x00, 0x20, 0x80]).0, BAD_CONTROL);
+    assert_eq!(call(&mut sp, &mut memory, FORMAT, &[1, 1]), (NO_ERROR, false, 0));
+    assert!(fs::read(&path1).unwrap().iter().all(|b| *b == 0));
+
+    let _ = fs::remove_file(path1);
+    let _ = fs::remove_file(path4);
+}
+
+#[test]
+pub fn non_ascii_dib_name() {
+    // The 16th byte of the name is the first byte of "é"
+    let directory = temp_dir("dib");
+    let path = format!("{directory}/abcdefghijklmnoé.hdv");
+    fs::write(&path, blocks()).unwrap();
+    let mut sp = smartport(&[Some(&path), None, None, None]);
+    let mut memory = vec![0; 0x10000];
+    assert_eq!(call(&mut sp, &mut memory, STATUS, &[3, 1, 0x00, 0x20, STATUS_DIB]), (NO_ERROR, false, 25));
+    assert_eq!(&memory[0x2004..0x2015], b"\x10ABCDEFGHIJKLMNO?");
+    let _ = fs::remove_dir_all(directory);
+}
+
+#[test]
+pub fn two_mg_header() {
+    let path = create_2mg("locked", true);
+    let image = HardDriveImage::new(&path).unwrap();
+    assert!(image.is_write_protected());
+    assert_eq!(image.block_count(), BLOCKS);
+    assert_eq!(image.read_block(1).unwrap()[0], 1);
+    assert!(image.read_block(BLOCKS).is_none());
+    let mut sp = smartport(&[Some(&path)]);
+    let mut memory = vec![0; 0x10000];
+    assert_eq!(call(&mut sp, &mut memory, WRITE_BLOCK, &[3, 1, 0x00, 0x20, 0, 0, 0]),
+        (WRITE_PROTECTED, true, 0));
+    let _ = fs::remove_file(path);
+
+    let mut dos_order = fs::read(create_2mg("dos", false)).unwrap();
+    dos_order[12] = 0;
+    assert!(HardDriveImage::new_with_content("dos.2mg", dos_order, false).is_err());
+    assert!(HardDriveImage::new_with_content("short.2mg", b"2IMG".to_vec(), false).is_err());
+    let _ = fs::remove_file(temp_path("dos.2mg"));
+}
+
+#[test]
+pub fn dma_through_soft_switches() {
+    let path = create_image("dma");
+    let mut drives: [Option<DiskInfo>; HARD_DRIVE_COUNT] = Default::default();
+    drives[0] = Some(DiskInfo::n(&path));
+    let mut m = Apple2Memory::new([None, None], drives, &[None; SLOT_COUNT], None, Default::default());
+    m.slots.insert(7, Box::new(SmartPort::new(7, m.context.clone())));
+
+    // WRITE takes the block from aux memory when RAMRD is set
+    m.memories[MAIN][0x2000..0x2000 + BLOCK_SIZE].fill(0x11);
+    m.memories[AUX][0x2000..0x2000 + BLOCK_SIZE].fill(0x77);
+    m.set(READ_AUX_MEM_ON, 0);
+    for (address, value) in [(0xc0f2, WRITE), (0xc0f3, 0x70), (0xc0f4, 0x00), (0xc0f5, 0x20),
+        (0xc0f6, 1), (0xc0f7, 0)]
+    {
+        m.set(address, value);
+    }
+    assert_eq!(m.get(0xc0f0), NO_ERROR);
+    assert!(fs::read(&path).unwrap()[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|b| *b == 0x77));
+    m.set(READ_AUX_MEM_OFF, 0);
+
+    // A SmartPort READBLOCK, see `call`
+    let read_block = |m: &mut Apple2Memory, block: u8, buffer: u16| {
+        m.memories[MAIN][JSR as usize + 3..JSR as usize + 6].copy_from_slice(&[READ_BLOCK, PARAMETERS as u8,
+            (PARAMETERS >> 8) as u8]);
+        m.memories[MAIN][PARAMETERS as usize..PARAMETERS as usize + 7].copy_from_slice(&[3, 1, buffer as u8,
+            (buffer >> 8) as u8, block, 0, 0]);
+        m.set(0xc0f9, (JSR + 2) as u8);
+        m.set(0xc0fa, ((JSR + 2) >> 8) as u8);
+        m.get(0xc0fb)
+    };
+
+    // Into the language card, bank 1 read and write enabled
+    m.get(0xc08b);
+    m.get(0xc08b);
+    assert_eq!(read_block(&mut m, 3, 0xd000), NO_ERROR);
+    assert_eq!(m.high_ram[MAIN].banks[0][0], 3);
+    assert_eq!(m.memories[MAIN][0xd000], 0);
+    m.get(0xc082);
+
+    // Into the aux text page with 80STORE and PAGE2
+    m.set(EIGHTY_STORE_ON, 0);
+    m.get(0xc055);
+    assert_eq!(read_block(&mut m, 2, 0x400), NO_ERROR);
+    assert_eq!((m.memories[AUX][0x400], m.memories[MAIN][0x400]), (2, 0));
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+pub fn rom_in_other_slots() {
+    use crate::roms::{smartport_rom, SMARTPORT_ROM};
+    // Patching the slot 7 ROM for slot 7 doesn't change it, so the offsets are right
+    assert_eq!(smartport_rom(7), SMARTPORT_ROM);
+    let rom = smartport_rom(5);
+    assert!(! rom.windows(2).any(|w| w[1] == 0xc0 && (0xf0..=0xff).contains(&w[0])));
+    assert!(! rom.contains(&0xc7));
+    // STA $C0D9, JSR $C530, LDX #$50
+    assert_eq!(&rom[0x11..0x14], &[0x8d, 0xd9, 0xc0]);
+    assert_eq!(&rom[0x95..0x98], &[0x20, 0x30, 0xc5]);
+    assert_eq!(&rom[0x9f..0xa1], &[0xa2, 0x50]);
+}
+
+#[test]
+pub fn inserted_with_any_unit() {
+    use crate::card::default_slots;
+    let path = create_image("unit3");
+    let mut drives: [Option<DiskInfo>; HARD_DRIVE_COUNT] = Default::default();
+    drives[2] = Some(DiskInfo::n(&path));
+    let context = Arc::new(EmulatorContext::default());
+    context.set_boot_from_hard_drive(true);
+    let m = Apple2Memory::new([None, None], drives, &default_slots(), None, context.clone());
+    assert!(m.slots.find::<SmartPort>().is_some());
+    // No image in any unit
+    let m = Apple2Memory::new([None, None], Default::default(), &default_slots(), None, context);
+    assert!(m.slots.find::<SmartPort>().is_none());
+    let _ = fs::remove_file(path);
+}
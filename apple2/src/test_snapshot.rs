@@ -0,0 +1,160 @@
+use cpu::config::Config;
+use cpu::cpu::Cpu;
+use cpu::memory::Memory;
+use crate::card::{CardType, SLOT_COUNT};
+use crate::disk::disk::Disk;
+use crate::disk::disk_controller::DiskController;
+use crate::disk::disk_info::DiskInfo;
+use crate::memory::Apple2Memory;
+use crate::mockingboard::mockingboard::Mockingboard;
+use crate::mockingboard::via::{IER, IRQ_T1, T1C_H, T1C_L};
+use crate::snapshot::*;
+use crate::test_util::temp_path;
+
+fn slots(disk2: bool) -> [Option<CardType>; SLOT_COUNT] {
+    let mut result = [None; SLOT_COUNT];
+    result[4] = Some(CardType::Mockingboard);
+    if disk2 { result[6] = Some(CardType::Disk2); }
+    result
+}
+
+fn new_cpu(disk2: bool) -> Cpu<Apple2Memory> {
+    let memory = Apple2Memory::new([None, None], Default::default(), &slots(disk2), None, Default::default());
+    Cpu::new(memory, None, Config::default())
+}
+
+/// A machine with registers, main and aux memory, language card and Mockingboard in a known state
+fn modified_cpu() -> Cpu<Apple2Memory> {
+    let mut cpu = new_cpu(true);
+    cpu.a = 0x12;
+    cpu.x = 0x34;
+    cpu.y = 0x56;
+    cpu.pc = 0x789a;
+    cpu.s = 0xbc;
+    cpu.p.set_value(0xb5);
+    cpu.cycles = 123_456;
+    cpu.set_irq(4, true);
+    cpu.set_nmi(true);
+    let m = &mut cpu.memory;
+    m.memories[0][0x1234] = 0xde;
+    m.memories[1][0x2345] = 0xad;
+    // Read and write the language card, bank 2
+    m.get(0xc083);
+    m.get(0xc083);
+    m.set(0xd000, 0x42);
+    m.set(0xe000, 0x43);
+    let mb = m.slots.find_mut::<Mockingboard>().unwrap();
+    mb.write(IER, 0x80 | IRQ_T1);
+    mb.write(T1C_L, 0x10);
+    mb.write(T1C_H, 0x20);
+    cpu
+}
+
+#[test]
+pub fn round_trip() {
+    let cpu = modified_cpu();
+    let snapshot = save_snapshot(&cpu);
+
+    let mut restored = new_cpu(true);
+    load_snapshot(&mut restored, &snapshot).unwrap();
+    assert_eq!((restored.a, restored.x, restored.y, restored.pc, restored.s), (0x12, 0x34, 0x56, 0x789a, 0xbc));
+    assert_eq!(restored.p.value(), 0xb5);
+    assert_eq!(restored.cycles, 123_456);
+    assert_eq!(restored.interrupts, cpu.interrupts);
+    assert!(restored.is_irq_asserted());
+    let m = &mut restored.memory;
+    assert_eq!(m.memories[0][0x1234], 0xde);
+    assert_eq!(m.memories[1][0x2345], 0xad);
+    // The language card flags were restored too: $D000 reads from bank 2
+    assert_eq!(m.get(0xd000), 0x42);
+    assert_eq!(m.get(0xe000), 0x43);
+    let mb = m.slots.find_mut::<Mockingboard>().unwrap();
+    assert_eq!(mb.vias[0].t1_counter, 0x2010);
+    assert_eq!(mb.read(IER), 0x80 | IRQ_T1);
+
+    // Saving the restored machine produces the same snapshot
+    assert_eq!(save_snapshot(&restored), snapshot);
+}
+
+#[test]
+pub fn version_is_checked() {
+    let mut snapshot = save_snapshot(&modified_cpu());
+    snapshot[4..8].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+    let mut cpu = new_cpu(true);
+    let error = load_snapshot(&mut cpu, &snapshot).unwrap_err();
+    assert!(error.contains("version"), "{error}");
+    // Nothing was restored
+    assert_eq!(cpu.a, 0);
+    assert_eq!(cpu.memory.memories[0][0x1234], 0);
+
+    assert!(load_snapshot(&mut cpu, b"WOZ2 this is not a snapshot").is_err());
+    assert!(load_snapshot(&mut cpu, &[]).is_err());
+}
+
+#[test]
+pub fn corrupted_snapshots_are_rejected() {
+    let snapshot = save_snapshot(&modified_cpu());
+    let mut cpu = new_cpu(true);
+
+    let mut corrupted = snapshot.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xff;
+    assert!(load_snapshot(&mut cpu, &corrupted).is_err());
+    assert!(load_snapshot(&mut cpu, &snapshot[..snapshot.len() / 2]).is_err());
+    assert_eq!(cpu.memory.memories[0][0x1234], 0);
+}
+
+#[test]
+pub fn cards_must_match() {
+    let snapshot = save_snapshot(&modified_cpu());
+    let mut cpu = new_cpu(false);
+    let error = load_snapshot(&mut cpu, &snapshot).unwrap_err();
+    assert!(error.contains("Slot 6"), "{error}");
+    assert_eq!(cpu.memory.memories[0][0x1234], 0);
+}
+
+#[test]
+pub fn missing_disks_leave_the_machine_untouched() {
+    let path = temp_path("snapshot.dsk");
+    std::fs::copy("files/master.dsk", &path).unwrap();
+    let mut cpu = modified_cpu();
+    cpu.memory.slots.find_mut::<DiskController>().unwrap().load_disk_from_file(0, DiskInfo::n(&path));
+    let snapshot = save_snapshot(&cpu);
+    std::fs::remove_file(&path).unwrap();
+
+    // The disk controller comes after the Mockingboard, which must not be restored either
+    let mut cpu = new_cpu(true);
+    let error = load_snapshot(&mut cpu, &snapshot).unwrap_err();
+    assert!(error.contains(&path), "{error}");
+    assert_eq!(cpu.a, 0);
+    assert_eq!(cpu.memory.memories[0][0x1234], 0);
+    let mb = cpu.memory.slots.find_mut::<Mockingboard>().unwrap();
+    assert_eq!(mb.read(IER), 0x80);
+    assert!(cpu.memory.slots.find_mut::<DiskController>().unwrap().left_disk().is_none());
+}
+
+#[test]
+pub fn written_disks_keep_their_bit_streams() {
+    let mut disk = Disk::new("files/master.dsk", false, None).unwrap();
+    let mut w = SnapshotWriter::default();
+    disk.save_state(&mut w);
+    let untouched_size = w.into_bytes().len();
+
+    disk.set_write_protected(false);
+    disk.bit_position = 100;
+    for _ in 0..16 {
+        disk.set_bit_and_advance(0, 1);
+    }
+    let expected = disk.get_stream(0).bits().to_vec();
+    let mut w = SnapshotWriter::default();
+    disk.save_state(&mut w);
+    let bytes = w.into_bytes();
+    assert!(bytes.len() > untouched_size);
+
+    let restored = Disk::new_with_state(&mut SnapshotReader::new(&bytes), None).unwrap();
+    assert_eq!(restored.bit_position, 116);
+    assert_eq!(restored.get_stream(0).bits(), expected);
+    // The file wasn't modified: the other disk still has the original bits
+    let original = Disk::new("files/master.dsk", false, None).unwrap();
+    assert_ne!(original.get_stream(0).bits(), expected);
+}
@@ -0,0 +1,86 @@
+use crate::constants::{SAMPLE_RATE, SPEAKER_VOLUME};
+use crate::speaker::{render_toggles, SpeakerSynth};
+
+const CPU_FREQUENCY: u64 = 1_000_000;
+
+/// A square wave of `frequency` Hz for `duration_cycles` cycles
+fn square_wave(frequency: u64, duration_cycles: u64) -> Vec<u64> {
+    let half_period = CPU_FREQUENCY / frequency / 2;
+    (1..duration_cycles / half_period).map(|i| i * half_period).collect()
+}
+
+#[test]
+pub fn silence() {
+    let samples = render_toggles(&[], CPU_FREQUENCY / 10, CPU_FREQUENCY);
+    assert_eq!(samples.len(), SAMPLE_RATE as usize / 10);
+    assert!(samples.iter().all(|s| *s == 0.0));
+}
+
+#[test]
+pub fn single_toggle() {
+    // Toggle after 100 samples
+    let cycle = 100 * CPU_FREQUENCY / SAMPLE_RATE as u64;
+    let samples = render_toggles(&[cycle], CPU_FREQUENCY / 100, CPU_FREQUENCY);
+    assert!(samples[..100].iter().all(|s| *s == 0.0));
+    // The step is band-limited: it takes a few samples to rise, rings a little,
+    // then reaches the new level and decays slowly toward 0 (DC blocker)
+    let peak = samples.iter().cloned().fold(0.0, f32::max);
+    assert!(peak > 2.0 * SPEAKER_VOLUME && peak < 2.0 * SPEAKER_VOLUME * 1.1, "Peak: {peak}");
+    assert!(samples[100..130].windows(2).any(|w| w[1] > w[0]));
+    let after = samples[140];
+    assert!(after > 1.8 * SPEAKER_VOLUME && after < 2.0 * SPEAKER_VOLUME, "After: {after}");
+    assert!(samples[400] < after);
+}
+
+#[test]
+pub fn square_wave_pattern() {
+    let end = CPU_FREQUENCY / 10;
+    let samples = render_toggles(&square_wave(1_000, end), end, CPU_FREQUENCY);
+
+    // Same input, same output
+    assert_eq!(samples, render_toggles(&square_wave(1_000, end), end, CPU_FREQUENCY));
+
+    // 1 kHz, counted over the last 80ms once the DC blocker has settled: ~160 zero crossings
+    let crossings = samples[SAMPLE_RATE as usize / 50..].windows(2)
+        .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+        .count();
+    assert!((158..=162).contains(&crossings), "Crossings: {crossings}");
+    assert!(samples.iter().all(|s| s.abs() < 2.5 * SPEAKER_VOLUME));
+}
+
+#[test]
+pub fn frames_dont_change_the_output() {
+    // Rendering in several frames produces the same samples as rendering all at once
+    let end = CPU_FREQUENCY / 20;
+    let toggles = square_wave(440, end);
+    let expected = render_toggles(&toggles, end, CPU_FREQUENCY);
+
+    let mut synth = SpeakerSynth::new(CPU_FREQUENCY);
+    let mut samples = Vec::new();
+    let mut toggles = toggles.iter().peekable();
+    for frame_end in (4096..=end).step_by(4096).chain([end]) {
+        while let Some(cycle) = toggles.next_if(|c| **c < frame_end) {
+            synth.toggle(*cycle);
+        }
+        synth.render(frame_end, &mut samples);
+    }
+    assert_eq!(samples, expected);
+}
+
+#[test]
+pub fn dynamic_rate() {
+    let count = |buffered: usize| {
+        let mut synth = SpeakerSynth::new(CPU_FREQUENCY);
+        synth.adjust_rate(buffered, 1000);
+        let mut samples = Vec::new();
+        synth.render(CPU_FREQUENCY, &mut samples);
+        samples.len()
+    };
+    let nominal = count(1000);
+    assert_eq!(nominal, SAMPLE_RATE as usize);
+    // Buffer filling up: produce fewer samples, draining: produce more, by at most 0.5%
+    assert!(count(2000) < nominal);
+    assert!(count(0) > nominal);
+    assert!(count(100_000) >= nominal * 995 / 1000);
+    assert!(count(0) <= nominal * 1005 / 1000 + 1);
+}
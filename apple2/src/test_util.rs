@@ -0,0 +1,22 @@
+//! Helpers shared by the tests
+
+use std::fs;
+use crate::disk::disk_controller::SECTOR_SIZE_BYTES;
+
+/// A path in the temp directory, unique to this run of the tests
+pub(crate) fn temp_path(name: &str) -> String {
+    std::env::temp_dir().join(format!("maple2-{}-{name}", std::process::id())).to_str().unwrap().to_string()
+}
+
+/// An empty directory in the temp directory, see [temp_path]
+pub(crate) fn temp_dir(name: &str) -> String {
+    let path = temp_path(name);
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// `size` bytes made of sectors that are all different from each other
+pub(crate) fn different_sectors(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i / SECTOR_SIZE_BYTES * 7 + i) as u8).collect()
+}
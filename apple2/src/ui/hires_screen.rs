@@ -95,6 +95,11 @@ impl AColor {
             _ => { panic!("Should never happen"); }
         }
     }
+
+    /// Lo-res uses the same 16 colors as double hires, in the same order
+    pub fn to_lores_color(color: u8) -> Self {
+        AColor::to_double_hires_color(color)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -323,6 +328,41 @@ impl HiresScreen {
         result
     }
 
+    /// Lo-res graphics use the text page: each byte is two blocks, the low nibble on top
+    /// and the high nibble at the bottom, so the screen is 40x48 blocks of 7x4 pixels.
+    /// In double lo-res, the screen is 80x48 blocks of 3.5x4 pixels and the even columns
+    /// come from aux memory.
+    pub(crate) fn calculate_lores(&mut self,
+            memory: &[u8], aux_memory: &[u8],
+            mag: u16, is_double: bool, mixed: bool, page2: bool)
+            -> Vec<DrawCommand>
+    {
+        let mut result: Vec<DrawCommand> = Vec::new();
+        let rows = if mixed { TEXT_HEIGHT - 4 } else { TEXT_HEIGHT };
+        let columns = if is_double { TEXT_WIDTH * 2 } else { TEXT_WIDTH };
+        let block_width = if is_double { 3.5 } else { 7.0 } * mag as f32;
+        let block_height = 4.0 * mag as f32;
+        for y in 0..rows {
+            for x in 0..columns {
+                let (byte, is_aux) = if is_double {
+                    let address = text_coordinates_to_address(x / 2, y, page2) as usize;
+                    if x % 2 == 0 { (aux_memory[address], true) } else { (memory[address], false) }
+                } else {
+                    (memory[text_coordinates_to_address(x, y, page2) as usize], false)
+                };
+                for (i, nibble) in [byte & 0xf, byte >> 4].into_iter().enumerate() {
+                    let color = if is_aux { rotate_aux_nibble(nibble) } else { nibble };
+                    let x0 = x as f32 * block_width;
+                    let y0 = (y as usize * 2 + i) as f32 * block_height;
+                    result.push(Rectangle(x0, y0, x0 + block_width, y0 + block_height,
+                        AColor::to_lores_color(color)));
+                }
+            }
+        }
+
+        result
+    }
+
     pub fn calculate_double_hires2(&mut self,
             memory: &Vec<u8>, aux_memory: &Vec<u8>,
             mag: u16, page2: bool) -> Vec<DrawCommand> {
@@ -663,6 +703,17 @@ impl HiresScreen {
                     draw_commands = self.calculate_hires(memory,
                         HIRES_HEIGHT, mag, page2);
                 }
+            } else {
+                // Lo-res, double lo-res if 80 columns and AN3 are on
+                let an3 = memory[AN3_STATUS as usize] & 0b0010_0000 != 0;
+                let is_double = is_80 && an3;
+                draw_commands = self.calculate_lores(memory, aux_memory, mag, is_double,
+                    is_mixed, page2);
+                if is_mixed {
+                    draw_commands.append(&mut self.calculate_text(memory, aux_memory,
+                        mag, is_80,
+                        true /* mixed */, page2));
+                }
             }
         }
 
@@ -715,6 +766,12 @@ fn correct_color(high_bit: u8, even: bool, previous: u8, current: u8, next: u8)
     result
 }
 
+/// In double lo-res, the color bits of the aux nibbles are rotated by one compared to main memory
+/// since the aux pixels are displayed half a color cycle earlier
+pub fn rotate_aux_nibble(nibble: u8) -> u8 {
+    ((nibble << 1) & 0xf) | (nibble >> 3)
+}
+
 pub fn text_coordinates_to_address(x: u8, y: u8, page2: bool) -> u16 {
     let mut result = TEXT_MODE_ADDRESSES[y as usize] + x as u16;
     if page2 { result += 0x400 };
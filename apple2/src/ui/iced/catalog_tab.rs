@@ -0,0 +1,283 @@
+use std::fs;
+use std::path::Path;
+use iced::{Alignment, Element, Font, Length};
+use iced::widget::{button, column, container, scrollable, text, Column, Row};
+use rfd::FileDialog;
+use crate::disk::archive::{self, split_path};
+use crate::disk::convert::{read_blocks, read_dos_sectors, write_blocks, write_dos_sectors};
+use crate::disk::disk_info::DiskInfo;
+use crate::disk::dos33::{host_name, parse_host_name, Dos33};
+use crate::disk::hard_drive::is_hard_drive_image;
+use crate::disk::prodos::{parse_suffix, prodos_date, suffixed_name, to_prodos_name, FileAttributes, ProDos,
+    BINARY_TYPE, DEFAULT_ACCESS};
+use crate::ui::iced::message::{InternalUiMessage, InternalUiMessage::*};
+use crate::ui::iced::shared::Shared;
+use crate::ui::iced::style::{m_button, m_group, MColor};
+use crate::ui::iced::tab::Tab;
+
+/// The file systems the catalog tab can browse
+enum Volume {
+    Dos33(Dos33),
+    ProDos(ProDos),
+}
+
+/// The catalog of the disk last inserted in drive 1 or in hard drive 1: DOS 3.3 or ProDOS
+#[derive(Default)]
+pub struct CatalogTab {
+    is_hard_drive: bool,
+    /// The path of the disk being shown
+    path: Option<String>,
+    volume: Option<Result<Volume, String>>,
+    /// The ProDOS directory being shown, "" for the volume directory
+    directory: String,
+    /// The result of the last export, add or delete
+    status: Option<Result<String, String>>,
+}
+
+/// The disk loaded in drive 1 or hard drive 1
+fn drive(is_hard_drive: bool) -> Result<DiskInfo, String> {
+    let disk_info = if is_hard_drive { Shared::get_hard_drive(0) } else { Shared::get_drive(0) };
+    disk_info.ok_or_else(|| format!("No disk in {}", if is_hard_drive { "hard drive 1" } else { "drive 1" }))
+}
+
+/// The file holding the content of a disk: its overlay once it's been written. Hard drives only
+/// have one if they're inside an archive
+fn content_path(disk_info: &DiskInfo) -> String {
+    archive_overlay(disk_info).or(disk_info.overlay()).filter(|o| Path::new(o).exists()).unwrap_or(disk_info.path())
+}
+
+fn archive_overlay(disk_info: &DiskInfo) -> Option<String> {
+    split_path(&disk_info.path()).map(|_| DiskInfo::overlay_path(&disk_info.path()))
+}
+
+/// The file to edit: archives can't be written to, their disks are copied to their overlay first
+fn editable_path(disk_info: &DiskInfo) -> Result<String, String> {
+    if let Some(overlay) = archive_overlay(disk_info).filter(|o| ! Path::new(o).exists()) {
+        let content = archive::read(&disk_info.path()).map_err(|e| format!("Couldn't read {}: {e}", disk_info.path()))?;
+        fs::write(&overlay, content).map_err(|e| format!("Couldn't write {overlay}: {e}"))?;
+    }
+    Ok(content_path(disk_info))
+}
+
+fn read_volume(path: &str) -> Result<Volume, String> {
+    if ! is_hard_drive_image(path) {
+        if let Ok(dos) = read_dos_sectors(path).and_then(Dos33::new) {
+            return Ok(Volume::Dos33(dos));
+        }
+    }
+    ProDos::new(read_blocks(path)?).map(Volume::ProDos)
+        .map_err(|_| "Neither a DOS 3.3 nor a ProDOS disk".to_string())
+}
+
+fn write_volume(path: &str, volume: &Volume) -> Result<(), String> {
+    match volume {
+        Volume::Dos33(dos) => write_dos_sectors(path, dos.image()),
+        Volume::ProDos(prodos) => write_blocks(path, prodos.image()),
+    }
+}
+
+/// "SUB/FILE" -> "SUB", "FILE" -> ""
+fn parent(path: &str) -> String {
+    path.rsplit_once('/').map_or(String::new(), |(parent, _)| parent.to_string())
+}
+
+impl CatalogTab {
+    pub fn update(&mut self, message: InternalUiMessage) {
+        match message {
+            Init(config_file) => {
+                self.path = config_file.drive_1();
+                self.volume = self.path.as_ref().map(|p| read_volume(p));
+            }
+            DiskInserted(is_hard_drive, _, Some(disk_info)) => {
+                // The same disk is reloaded after each change, stay in the same directory
+                if is_hard_drive != self.is_hard_drive || self.path.as_ref() != Some(&disk_info.path()) {
+                    self.directory = String::new();
+                }
+                self.is_hard_drive = is_hard_drive;
+                self.path = Some(disk_info.path());
+                self.volume = Some(read_volume(&content_path(&disk_info)));
+            }
+            DiskInserted(is_hard_drive, _, None) if is_hard_drive == self.is_hard_drive => {
+                self.path = None;
+                self.volume = None;
+            }
+            CatalogOpen(directory) => {
+                self.directory = directory;
+            }
+            CatalogStatus(status) => {
+                self.status = Some(status);
+            }
+            _ => {}
+        }
+    }
+
+    /// Ask where to save the file at `path`, suggesting a host name that keeps its type
+    pub fn pick_export_file(is_hard_drive: bool, path: &str) -> Option<String> {
+        let suggested = drive(is_hard_drive).and_then(|d| read_volume(&content_path(&d))).and_then(|volume| {
+            match volume {
+                Volume::Dos33(dos) => {
+                    let entry = dos.find(path)?;
+                    Ok(host_name(&entry, &dos.extract(&entry)?))
+                }
+                Volume::ProDos(prodos) => Ok(suffixed_name(&prodos.find(path)?)),
+            }
+        }).unwrap_or(path.to_string());
+        FileDialog::new()
+            .set_title(format!("Export {path}"))
+            .set_file_name(suggested)
+            .save_file()
+            .and_then(|path_buf| path_buf.to_str().map(|s| s.to_string()))
+    }
+
+    pub fn pick_file_to_add() -> Option<String> {
+        FileDialog::new()
+            .set_title("Add a file to the disk")
+            .pick_file()
+            .and_then(|path_buf| path_buf.to_str().map(|s| s.to_string()))
+    }
+
+    /// Save the content of the file at `path` to `host_path`, without the DOS 3.3 header
+    pub fn export(is_hard_drive: bool, path: &str, host_path: &str) -> Result<String, String> {
+        let data = match read_volume(&content_path(&drive(is_hard_drive)?))? {
+            Volume::Dos33(dos) => dos.extract(&dos.find(path)?)?.data,
+            Volume::ProDos(prodos) => prodos.read_file(path)?,
+        };
+        fs::write(host_path, data).map_err(|e| format!("Couldn't write {host_path}: {e}"))?;
+        Ok(format!("Exported {path} to {host_path}"))
+    }
+
+    /// Add the host file to `directory`, its type and address come from the `#ttaaaa` suffix
+    /// of its name. Return the path of the disk, which needs to be reloaded
+    pub fn add(is_hard_drive: bool, directory: &str, host_path: &str) -> Result<(String, String), String> {
+        let disk_info = drive(is_hard_drive)?;
+        let data = fs::read(host_path).map_err(|e| format!("Couldn't read {host_path}: {e}"))?;
+        let file_name = Path::new(host_path).file_name().map_or(String::new(), |n| n.to_string_lossy().to_string());
+        let path = editable_path(&disk_info)?;
+        let mut volume = read_volume(&path)?;
+        let name = match &mut volume {
+            Volume::Dos33(dos) => {
+                let (name, file_type, address) = parse_host_name(&file_name);
+                dos.add_file(&name, file_type, &data, address)?;
+                name
+            }
+            Volume::ProDos(prodos) => {
+                let (name, file_type, aux_type) = parse_suffix(&file_name).unwrap_or((file_name, BINARY_TYPE, 0));
+                let name = to_prodos_name(&name);
+                let modified = prodos_date(std::time::SystemTime::now());
+                prodos.add_file(directory, &name,
+                    &FileAttributes { file_type, aux_type, access: DEFAULT_ACCESS, modified }, &data)?;
+                name
+            }
+        };
+        write_volume(&path, &volume)?;
+        Ok((disk_info.path(), format!("Added {name}")))
+    }
+
+    /// Delete the file at `path`, or the ProDOS directory if it's empty. Return the path of
+    /// the disk, which needs to be reloaded
+    pub fn delete(is_hard_drive: bool, path: &str) -> Result<(String, String), String> {
+        let disk_info = drive(is_hard_drive)?;
+        let disk_path = editable_path(&disk_info)?;
+        let mut volume = read_volume(&disk_path)?;
+        match &mut volume {
+            Volume::Dos33(dos) => dos.delete_file(path)?,
+            Volume::ProDos(prodos) => prodos.delete(path)?,
+        }
+        write_volume(&disk_path, &volume)?;
+        Ok((disk_info.path(), format!("Deleted {path}")))
+    }
+
+    /// The title line and the rows of the entries
+    fn catalog(&self, volume: &Volume) -> Result<(String, Vec<Element<'static, InternalUiMessage>>), String> {
+        let is_hard_drive = self.is_hard_drive;
+        match volume {
+            Volume::Dos33(dos) => {
+                let rows = dos.catalog()?.into_iter().map(|e| {
+                    entry_row(is_hard_drive, e.to_string(), e.name.clone(), false, e.locked)
+                }).collect();
+                Ok((format!("DOS 3.3, disk volume {}, {} free sectors", dos.volume(), dos.free_sector_count()), rows))
+            }
+            Volume::ProDos(prodos) => {
+                let mut rows = Vec::new();
+                if ! self.directory.is_empty() {
+                    rows.push(Row::new()
+                        .spacing(5)
+                        .align_items(Alignment::Center)
+                        .push(icon_button("⬆", Some(CatalogOpen(parent(&self.directory)))))
+                        .push(text("..").font(Font::MONOSPACE).size(14))
+                        .into());
+                }
+                for entry in prodos.list(&self.directory)? {
+                    let path = if self.directory.is_empty() { entry.name.clone() }
+                        else { format!("{}/{}", self.directory, entry.name) };
+                    rows.push(entry_row(is_hard_drive, entry.to_string(), path, entry.is_directory(),
+                        entry.is_locked()));
+                }
+                Ok((format!("/{}/{}, {} free blocks of {}", prodos.volume_name(), self.directory,
+                    prodos.free_block_count(), prodos.block_count()), rows))
+            }
+        }
+    }
+}
+
+fn icon_button(icon: &str, message: Option<InternalUiMessage>) -> Element<'static, InternalUiMessage> {
+    button(text(icon.to_string()).size(12)).padding(2).on_press_maybe(message).into()
+}
+
+/// A file of the catalog with its buttons. Directories are opened instead of exported
+fn entry_row(is_hard_drive: bool, label: String, path: String, is_directory: bool, locked: bool)
+    -> Element<'static, InternalUiMessage>
+{
+    Row::new()
+        .spacing(5)
+        .align_items(Alignment::Center)
+        .push(if is_directory {
+            icon_button("📂", Some(CatalogOpen(path.clone())))
+        } else {
+            icon_button("💾", Some(CatalogExport(is_hard_drive, path.clone())))
+        })
+        .push(icon_button("🗑", if locked { None } else { Some(CatalogDelete(is_hard_drive, path)) }))
+        .push(text(label).font(Font::MONOSPACE).color(MColor::yellow()).size(14))
+        .into()
+}
+
+impl Tab for CatalogTab {
+    type Message = InternalUiMessage;
+
+    fn title(&self) -> String {
+        String::from("Catalog")
+    }
+
+    fn content(&self) -> Element<'_, InternalUiMessage> {
+        let catalog = match &self.volume {
+            None => Err("No disk in drive 1 or hard drive 1".to_string()),
+            Some(Err(e)) => Err(e.clone()),
+            Some(Ok(volume)) => self.catalog(volume),
+        };
+        let content: Element<InternalUiMessage> = match catalog {
+            Err(e) => text(e).color(MColor::gray1()).into(),
+            Ok((title, rows)) => {
+                column![
+                    Row::new()
+                        .spacing(10)
+                        .padding(10)
+                        .align_items(Alignment::Center)
+                        .push(text(title).width(Length::Fill))
+                        .push(m_button("Add file…", CatalogAdd(self.is_hard_drive, self.directory.clone()))),
+                    m_group("Files".into(), scrollable(Column::with_children(rows).padding(5)).into())
+                        .width(Length::Fill)
+                        .height(Length::Fill),
+                ].into()
+            }
+        };
+        let status = match &self.status {
+            Some(Ok(s)) => text(s.clone()).color(MColor::green1()),
+            Some(Err(e)) => text(e.clone()).color(MColor::red()),
+            None => text(""),
+        };
+        container(column![content, status].spacing(5).padding(5))
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .into()
+    }
+}
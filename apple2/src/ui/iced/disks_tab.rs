@@ -8,7 +8,11 @@ use iced::widget::button::Status;
 use ignore::{DirEntry, Walk};
 use rfd::FileDialog;
 use crate::config_file::ConfigFile;
-use crate::constants::{BUGGY_DISKS, DISKS_SUFFIXES};
+use crate::constants::{BUGGY_DISKS, DISKS_SUFFIXES, HARD_DRIVE_COUNT};
+use crate::disk::archive::{is_archive, members};
+use crate::disk::disk_controller::DSK_SIZE_BYTES;
+use crate::disk::hard_drive::is_hard_drive_image;
+use crate::disk::disk_info::DiskInfo;
 use crate::ui::iced::message::InternalUiMessage;
 use crate::ui::iced::message::InternalUiMessage::{LoadDrive, LoadHardDrive};
 use crate::ui::iced::shared::Shared;
@@ -84,6 +88,14 @@ impl DisksTab {
         }
     }
 
+    /// A directory of the host to mount as a virtual ProDOS volume
+    pub fn pick_volume_directory() -> Option<String> {
+        FileDialog::new()
+            .set_title("Mount a directory as a ProDOS volume")
+            .pick_folder()
+            .and_then(|path_buf| path_buf.to_str().map(|s| s.to_string()))
+    }
+
     /// The user changed some filtering so recalculate the list of disks we're showing.
     fn recalculate_disks(&mut self, directories: &[String]) {
         self.all_disks = Self::read_disks_directories(directories);
@@ -111,9 +123,13 @@ impl DisksTab {
             let builder = Walk::new(path).filter(|f| {
                 if let Ok(de) = f {
                     let name = de.file_name().to_str().unwrap().to_lowercase();
-                    let mut result = false;
+                    // Overlays are picked up when their original disk is loaded
+                    if DiskInfo::is_overlay_path(&name) {
+                        return false;
+                    }
+                    let mut result = is_archive(&name);
                     for suffix in DISKS_SUFFIXES.clone().into_iter() {
-                        if name.ends_with(&suffix) {
+                        if name.ends_with(&format!(".{suffix}")) {
                             result = true;
                             break
                         }
@@ -125,7 +141,12 @@ impl DisksTab {
             });
             for file in builder {
                 if let Ok(f) = file {
-                    result.push(DisplayedDisk::new(f));
+                    let path = f.path().to_str().unwrap().to_string();
+                    if is_archive(&path) {
+                        result.extend(Self::archive_disks(&path));
+                    } else {
+                        result.push(DisplayedDisk::new(f));
+                    }
                 } else {
                     ui_log(&format!("Error in file {:?}", file));
                 }
@@ -137,6 +158,33 @@ impl DisksTab {
         result.dedup_by(|a, b| a.file_name == b.file_name);
         result
     }
+
+    /// The disks inside an archive, their path is the path of the archive followed by their name
+    fn archive_disks(path: &str) -> Vec<DisplayedDisk> {
+        match members(path) {
+            Ok(members) => members.into_iter()
+                .filter(|m| DISKS_SUFFIXES.iter().any(|suffix| m.name.to_lowercase().ends_with(&format!(".{suffix}"))))
+                .map(|m| {
+                    let path = format!("{path}/{}", m.name);
+                    // Same as is_hard_drive_image(), without reading the archive again
+                    let is_hard_drive = if m.name.to_lowercase().ends_with(".po") {
+                        m.size > DSK_SIZE_BYTES
+                    } else {
+                        is_hard_drive_image(&path)
+                    };
+                    DisplayedDisk {
+                        file_name: m.name.rsplit('/').next().unwrap_or_default().to_string(),
+                        is_hard_drive,
+                        path,
+                    }
+                })
+                .collect(),
+            Err(e) => {
+                ui_log(&format!("Couldn't read the archive {path}: {e}"));
+                Vec::new()
+            }
+        }
+    }
 }
 
 fn drive_button_style(theme: &Theme, status: Status) -> button::Style {
@@ -171,11 +219,12 @@ fn drive_button(label: String, highlight: bool, message: InternalUiMessage)
 
 fn drive_buttons(disk: &DisplayedDisk, highlight_1: bool, highlight_2: bool) -> Element<InternalUiMessage> {
     let path = disk.path.clone();
-    if disk.file_name.ends_with("hdv") {
-        row![
-            container(drive_button("HD1".into(), highlight_1, LoadHardDrive(0, path.clone()))),
-            container(drive_button("HD2".into(), highlight_2, LoadHardDrive(1, path))),
-        ]
+    if disk.is_hard_drive {
+        Row::with_children((0..HARD_DRIVE_COUNT).map(|i| {
+            let highlight = Shared::get_hard_drive(i).map_or(false, |d| d.path == path);
+            container(drive_button(format!("HD{}", i + 1), highlight, LoadHardDrive(i, path.clone())))
+                .into()
+        }))
     } else {
         row![
             container(drive_button(" 1 ".into(), highlight_1, LoadDrive(0, path.clone()))),
@@ -264,13 +313,17 @@ impl Tab for DisksTab {
 pub(crate) struct DisplayedDisk {
     pub file_name: String,
     pub path: String,
+    /// Computed once, it can take reading the archive the disk is in
+    pub is_hard_drive: bool,
 }
 
 impl DisplayedDisk {
     fn new(d: DirEntry) -> DisplayedDisk {
+        let path = d.path().to_str().unwrap().to_string();
         DisplayedDisk {
             file_name: d.file_name().to_str().unwrap().to_string(),
-            path: d.path().to_str().unwrap().to_string(),
+            is_hard_drive: is_hard_drive_image(&path),
+            path,
         }
     }
 }
@@ -1,16 +1,153 @@
-use iced::{Element, Length, Renderer, Theme};
+use std::fmt::{Display, Formatter};
+use iced::{Alignment, Element, Length, Renderer, Theme};
 use iced::alignment::{Horizontal, Vertical};
-use iced::widget::{button, Column, container, Container, row, Space, text};
+use iced::widget::{button, Column, container, Container, pick_list, row, Row, Space, text, text_input};
+use rfd::FileDialog;
+use crate::disk::convert::{create_disk, NewDisk};
 use crate::disk::disk_info::DiskInfo;
+use crate::disk::disk_controller::DSK_SIZE_BYTES;
+use crate::disk::hard_drive::BLOCK_SIZE;
 use crate::ui::iced::main_window::MainWindow;
 use crate::ui::iced::message::InternalUiMessage;
-use crate::ui::iced::message::InternalUiMessage::Eject;
+use crate::ui::iced::message::InternalUiMessage::{CommitOverlay, DiscardOverlay, Eject,
+    EnableOverlay, MountDirectory, NewDiskCancel, NewDiskCreate,
+    NewDiskFormatSelected, NewDiskNameChanged, NewDiskSizeSelected, OpenNewDisk, WriteProtect};
 use crate::ui::iced::shared::Shared;
-use crate::ui::iced::style::{m_group, MColor};
+use crate::ui::iced::style::{m_button, m_group, MColor};
+
+/// What goes on a new disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NewDiskFormat {
+    Unformatted,
+    Dos33,
+    ProDos,
+}
+
+impl Display for NewDiskFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NewDiskFormat::Unformatted => "Unformatted",
+            NewDiskFormat::Dos33 => "DOS 3.3",
+            NewDiskFormat::ProDos => "ProDOS",
+        })
+    }
+}
+
+const ALL_FORMATS: [NewDiskFormat; 3] = [NewDiskFormat::Unformatted, NewDiskFormat::Dos33, NewDiskFormat::ProDos];
+
+/// The size of a new hard drive, in blocks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HardDriveSize(pub usize);
+
+impl Display for HardDriveSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.0 < 2048 {
+            write!(f, "{}K", self.0 / 2)
+        } else {
+            write!(f, "{}M", self.0.div_ceil(2048))
+        }
+    }
+}
+
+const HARD_DRIVE_SIZES: [HardDriveSize; 4] = [HardDriveSize(1600), HardDriveSize(10_240), HardDriveSize(32_768),
+    HardDriveSize(65_535)];
+
+/// The "New disk…" form shown instead of the drives: the disk is created once its file is
+/// picked, then inserted in `drive_number`
+pub struct NewDiskForm {
+    pub is_hard_drive: bool,
+    pub drive_number: usize,
+    format: NewDiskFormat,
+    /// The name of ProDOS volumes
+    name: String,
+    size: HardDriveSize,
+    pub error: Option<String>,
+}
+
+impl NewDiskForm {
+    pub fn new(is_hard_drive: bool, drive_number: usize) -> Self {
+        Self {
+            is_hard_drive, drive_number,
+            format: if is_hard_drive { NewDiskFormat::ProDos } else { NewDiskFormat::Dos33 },
+            name: "BLANK".to_string(),
+            size: HARD_DRIVE_SIZES[3],
+            error: None,
+        }
+    }
+
+    pub fn update(&mut self, message: InternalUiMessage) {
+        match message {
+            NewDiskFormatSelected(format) => { self.format = format; }
+            NewDiskNameChanged(name) => { self.name = name; }
+            NewDiskSizeSelected(size) => { self.size = size; }
+            _ => {}
+        }
+    }
+
+    /// Ask where to save the new disk: .dsk, .po or .woz for floppies, .hdv for hard drives
+    pub fn pick_path(is_hard_drive: bool) -> Option<String> {
+        let (extensions, file_name): (&[&str], &str) = if is_hard_drive {
+            (&["hdv"], "new.hdv")
+        } else {
+            (&["dsk", "po", "woz"], "new.dsk")
+        };
+        FileDialog::new()
+            .set_title("New disk")
+            .add_filter("Disk images", extensions)
+            .set_file_name(file_name)
+            .save_file()
+            .and_then(|path_buf| path_buf.to_str().map(|s| s.to_string()))
+    }
+
+    /// `path` comes from [Self::pick_path], whose dialog already asked before replacing a file
+    pub fn create(&self, path: &str) -> Result<(), String> {
+        let content = match self.format {
+            NewDiskFormat::Unformatted => NewDisk::Unformatted,
+            NewDiskFormat::Dos33 => NewDisk::Dos33(254),
+            NewDiskFormat::ProDos => NewDisk::ProDos(self.name.clone()),
+        };
+        create_disk(path, &content, if self.is_hard_drive { self.size.0 } else { DSK_SIZE_BYTES / BLOCK_SIZE }, true)
+    }
+
+    fn view(&self) -> Element<InternalUiMessage> {
+        // DOS 3.3 only knows about floppies
+        let formats: Vec<NewDiskFormat> = ALL_FORMATS.into_iter()
+            .filter(|f| ! self.is_hard_drive || *f != NewDiskFormat::Dos33)
+            .collect();
+        let mut form = Row::new()
+            .spacing(10)
+            .align_items(Alignment::Center)
+            .push(pick_list(formats, Some(self.format), NewDiskFormatSelected));
+        if self.format == NewDiskFormat::ProDos {
+            form = form.push(text_input("Volume name", &self.name)
+                .on_input(NewDiskNameChanged)
+                .width(Length::Fixed(120.0)));
+        }
+        if self.is_hard_drive {
+            form = form.push(pick_list(HARD_DRIVE_SIZES, Some(self.size), NewDiskSizeSelected));
+        }
+        form = form
+            .push(m_button("Create…", NewDiskCreate))
+            .push(m_button("Cancel", NewDiskCancel));
+        let drive = format!("{} {}", if self.is_hard_drive { "New hard drive" } else { "New disk in drive" },
+            self.drive_number + 1);
+        let mut content = Column::new().spacing(5).push(form);
+        if let Some(error) = &self.error {
+            content = content.push(text(error.clone()).color(MColor::red()));
+        }
+        m_group(drive, content.into()).into()
+    }
+}
 
 impl MainWindow {
     pub fn drives_window(&self) -> Container<InternalUiMessage> {
-        container(if Shared::get_show_drives() { self.floppies() } else { self.hard_drives() })
+        container(if let Some(form) = &self.new_disk {
+            form.view()
+        } else if Shared::get_show_drives() {
+            self.floppies()
+        } else {
+            self.hard_drives()
+        })
     }
 
     fn hard_drives(&self) -> Element<InternalUiMessage> {
@@ -80,17 +217,48 @@ impl MainWindow {
                 }
             })
         };
+        // Hard drives can also mount a directory of the host
+        let mount = is_hard_drive.then(|| button(text("\u{1f4c1}")
+                .size(20.0)
+                .shaping(text::Shaping::Advanced))
+            .style(button::text)
+            .on_press(MountDirectory(drive_number)));
+        // Floppies can be write protected and written to a copy-on-write overlay
+        let floppy = disk_info.as_ref().filter(|_| ! is_hard_drive);
+        let lock = floppy.map(|di| {
+            let protected = di.is_write_protected();
+            Self::icon_button(if protected { "\u{1f512}" } else { "\u{1f513}" },
+                WriteProtect(drive_number, ! protected))
+        });
+        let overlay = floppy.map(|di| {
+            if di.overlay().is_some() {
+                row![
+                    Self::icon_button("\u{2714}", CommitOverlay(drive_number)),
+                    Self::icon_button("\u{2716}", DiscardOverlay(drive_number)),
+                ]
+            } else {
+                row![Self::icon_button("\u{1f4cb}", EnableOverlay(drive_number))]
+            }
+        });
         row![
             track_sector,
             container(disk_name)
                 .align_x(Horizontal::Center)
                 .align_y(Vertical::Top)
                 .width(Length::Fill),
-            button(text("\u{23cf}")
+        ].push_maybe(mount).push_maybe(lock).push_maybe(overlay)
+            .push(Self::icon_button("\u{1f195}", OpenNewDisk(is_hard_drive, drive_number)))
+            .push(Self::icon_button("\u{23cf}", Eject(is_hard_drive, drive_number)))
+            .spacing(5).into()
+    }
+
+    fn icon_button<'a>(icon: &'a str, message: InternalUiMessage)
+        -> button::Button<'a, InternalUiMessage, Theme, Renderer>
+    {
+        button(text(icon)
                 .size(20.0)
                 .shaping(text::Shaping::Advanced))
             .style(button::text)
-            .on_press(Eject(is_hard_drive, drive_number))
-        ].spacing(5).into()
+            .on_press(message)
     }
 }
\ No newline at end of file
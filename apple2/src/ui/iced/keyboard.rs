@@ -68,6 +68,8 @@ pub fn special_named_key(named: Named) -> Option<SpecialKeyMsg> {
     match named {
         Named::Alt => { Some(SpecialKeyMsg::AltLeft) }
         Named::AltGraph => { Some(SpecialKeyMsg::AltRight) }
+        Named::F5 => { Some(SpecialKeyMsg::Rewind) }
+        Named::F6 => { Some(SpecialKeyMsg::ResumeRewind) }
         _ => { None }
     }
 }
@@ -3,10 +3,10 @@ use std::time::Instant;
 
 use crossbeam::channel::Sender;
 use gilrs::{Axis, Button, Gilrs};
-use iced::{Color, Element, keyboard, Length, Padding, Point, Rectangle, Renderer, Size, Theme};
+use iced::{Alignment, Color, Element, keyboard, Length, Padding, Point, Rectangle, Renderer, Size, Theme};
 use iced::keyboard::Key::Named;
 use iced::mouse::Cursor;
-use iced::widget::{container, Space};
+use iced::widget::{container, slider, text, Space};
 use iced::widget::{Column, row, Row};
 use iced::widget::button::danger;
 use iced::widget::Canvas;
@@ -15,19 +15,20 @@ use iced_aw::Tabs;
 
 use crate::{InternalUiMessage, InternalUiMessage::*};
 use crate::config_file::ConfigFile;
-use crate::constants::{CPU_REFRESH_MS, HIRES_HEIGHT, HIRES_WIDTH, SAMPLE_RATE};
+use crate::constants::{CPU_REFRESH_MS, HIRES_HEIGHT, HIRES_WIDTH, REWIND_POINTS_PER_SECOND};
 use crate::disk::drive::DriveStatus;
 use crate::joystick::Joystick;
 use crate::messages::{CpuDumpMsg, DrawCommand, SetMemoryMsg, ToCpu, ToMiniFb};
 use crate::send_message;
-use crate::speaker::Samples;
 use crate::ui::hires_screen::{AColor, HiresScreen};
 use crate::ui::iced::debug_tab::DebugTab;
 use crate::ui::iced::disk_tab::DriveTab;
 use crate::ui::iced::disks_tab::DisksTab;
+use crate::ui::iced::drives_view::NewDiskForm;
 use crate::ui::iced::keyboard::special_named_key;
 use crate::ui::iced::message::SpecialKeyMsg;
 use crate::ui::iced::nibbles_tab::NibblesTab;
+use crate::ui::iced::catalog_tab::CatalogTab;
 use crate::ui::iced::shared::*;
 use crate::ui::iced::style::{m_button, MColor};
 use crate::ui::iced::tab::Tab;
@@ -78,10 +79,13 @@ pub struct MainWindow {
     sender_minifb: Option<Sender<ToMiniFb>>,
     /// 0 or 1
     pub selected_drive: usize,
+    /// Shown instead of the drives while a new disk is being created
+    pub new_disk: Option<NewDiskForm>,
 
     /// Tabs
     active_tab: TabId,
     disks_tab: DisksTab,
+    catalog_tab: CatalogTab,
     nibbles_tab: NibblesTab,
     drive_tab: DriveTab,
     debug_tab: DebugTab,
@@ -93,7 +97,6 @@ pub struct MainWindow {
     draw_commands: Vec<DrawCommand>,
     hires_screen: HiresScreen,
 
-    samples: Samples,
     joystick: Joystick,
 }
 
@@ -108,18 +111,20 @@ impl MainWindow {
             draw_commands: vec![],
             last_update: Instant::now(),
             selected_drive: 0,
+            new_disk: None,
             sender, sender_minifb,
 
             disks_tab: Default::default(),
+            catalog_tab: Default::default(),
             nibbles_tab: Default::default(),
             drive_tab: Default::default(),
             debug_tab: Default::default(),
             cache: Default::default(),
             hires_screen: Default::default(),
-            samples: Samples::default(),
             joystick: Joystick::default(),
         };
         result.disks_tab.update(Init(config_file.clone()));
+        result.catalog_tab.update(Init(config_file.clone()));
         result.nibbles_tab.update(Init(config_file.clone()));
 
         result
@@ -141,32 +146,30 @@ impl MainWindow {
         // Controller
         //
         // self.joystick.main_loop();
-
-        //
-        // Speaker
-        //
-        let cycles: Vec<u64> = Shared::get_speaker_events().iter().map(|e| e.cycle).collect();
-        if ! cycles.is_empty() {
-            let samples = self.samples.cycles_to_samples(cycles, SAMPLE_RATE);
-            for s in samples {
-                Shared::add_sound_sample(s);
-            }
-        }
-
-        //
-        // Speaker decay
-        //
-        // if let Some((i, s)) = Shared::get_last_sample_played() {
-        //     if f32::abs(s) > 0.01 && i.elapsed().as_millis() > 250 {
-        //         speaker_decay(s);
-        //         Shared::set_last_sample_played(None);
-        //     }
-        // }
     }
 
     fn cpu(&self) -> CpuDumpMsg {
         Shared::get_cpu()
     }
+
+    /// Slider to go back in time through the rewind snapshots. Also available with F5 (back one
+    /// second) and F6 (resume)
+    fn rewind_view(&self) -> Element<InternalUiMessage> {
+        let rewind = Shared::get_rewind();
+        let last = rewind.count.saturating_sub(1) as u32;
+        let position = rewind.position.map_or(last, |p| p as u32);
+        let seconds = (last - position) as f32 / REWIND_POINTS_PER_SECOND as f32;
+        let mut result = Row::new()
+            .spacing(10)
+            .align_items(Alignment::Center)
+            .push(text("Rewind"))
+            .push(slider(0..=last, position, RewindTo).width(Length::Fixed(300.0)))
+            .push(text(format!("-{seconds:.1}s")));
+        if rewind.position.is_some() {
+            result = result.push(m_button("Resume", InternalUiMessage::ResumeRewind));
+        }
+        container(result).padding([0, 10]).into()
+    }
 }
 
 impl Window for MainWindow {
@@ -186,6 +189,10 @@ impl Window for MainWindow {
             .push(m_button("Debug", InternalUiMessage::OpenDebugger))
             .push(Space::with_height(15.0))
             .push(m_button("Swap", InternalUiMessage::Swap))
+            .push(Space::with_height(15.0))
+            .push(m_button("Save", InternalUiMessage::SaveState))
+            .push(Space::with_height(5.0))
+            .push(m_button("Load", InternalUiMessage::LoadState))
             .padding(Padding::from([0.0, 10.0, 0.0, 10.0]))
             .push(Space::with_height(5.0))
             .push(if Shared::get_show_drives() {
@@ -197,6 +204,7 @@ impl Window for MainWindow {
 
         let tabs: Element<'_, InternalUiMessage> = Tabs::new(InternalUiMessage::TabSelected)
             .push(TabId::DisksTab, self.disks_tab.tab_label(), self.disks_tab.view())
+            .push(TabId::CatalogTab, self.catalog_tab.tab_label(), self.catalog_tab.view())
             .push(TabId::NibblesTab, self.nibbles_tab.tab_label(), self.nibbles_tab.view())
             .push(TabId::DriveTab, self.drive_tab.tab_label(), self.drive_tab.view())
             // .push(TabId::DebugTab, self.debug_tab.tab_label(), self.debug_tab.view())
@@ -214,6 +222,8 @@ impl Window for MainWindow {
                 .padding(0)
                 .push(self.drives_window())
                 .push(Space::with_height(10.0))
+                .push(self.rewind_view())
+                .push(Space::with_height(10.0))
                 .push(container(tabs).padding([0, 10, 0, 10]))
             )
             ;
@@ -233,6 +243,7 @@ impl Window for MainWindow {
             }
             DiskInserted(is_hard_drive, drive, disk_info) => {
                 if drive == 0 {
+                    self.catalog_tab.update(DiskInserted(is_hard_drive, drive, disk_info.clone()));
                     self.nibbles_tab.update(DiskInserted(is_hard_drive, drive, disk_info.clone()));
                 }
                 self.drive_tab.update2(DiskInserted(is_hard_drive, drive, disk_info));
@@ -285,6 +296,20 @@ impl Window for MainWindow {
                 }
                 self.config_file.set_drive(is_hard_drive, drive_number, None);
             }
+            CatalogOpen(_) | CatalogStatus(_) => {
+                self.catalog_tab.update(message);
+            }
+            OpenNewDisk(is_hard_drive, drive_number) => {
+                self.new_disk = Some(NewDiskForm::new(is_hard_drive, drive_number));
+            }
+            NewDiskFormatSelected(_) | NewDiskNameChanged(_) | NewDiskSizeSelected(_) => {
+                if let Some(form) = &mut self.new_disk {
+                    form.update(message);
+                }
+            }
+            NewDiskCancel => {
+                self.new_disk = None;
+            }
             FirstRead(_, _) | ClearDiskGraph => {
                 self.drive_tab.update2(message);
             }
@@ -1,6 +1,7 @@
 use iced::window;
 use crate::config_file::ConfigFile;
 use crate::disk::disk_info::DiskInfo;
+use crate::ui::iced::drives_view::{HardDriveSize, NewDiskFormat};
 use crate::ui::iced::memory_view::MemoryType;
 use crate::ui::iced::ui_iced::TabId;
 
@@ -8,6 +9,10 @@ use crate::ui::iced::ui_iced::TabId;
 pub enum SpecialKeyMsg {
     AltLeft,
     AltRight,
+    /// Go back one second in the rewind buffer
+    Rewind,
+    /// Resume from the rewind snapshot being displayed
+    ResumeRewind,
 }
 
 /// Messages received from the CPU are of type ToUi, but we need to have our own
@@ -19,6 +24,13 @@ pub enum InternalUiMessage {
     Load,
     Reboot,
     Swap,
+    /// Save the machine to the quick save file, see [crate::snapshot]
+    SaveState,
+    /// Restore the machine from the quick save file
+    LoadState,
+    /// The rewind slider moved to this snapshot
+    RewindTo(u32),
+    ResumeRewind,
     OpenDebugger,
     // bool: true if is_hard_drive
     DiskInserted(bool, usize, Option<DiskInfo>),
@@ -33,6 +45,9 @@ pub enum InternalUiMessage {
     LoadDrive(usize, String),
     /// Load hard drive (0, 1) with the hard drive found at the path
     LoadHardDrive(usize, String),
+    /// Pick a host directory to mount in this hard drive as a ProDOS volume
+    MountDirectory(usize),
+    DirectoryPicked(usize, Option<String>),
     /// New filter typed on the Disks tab
     FilterUpdated(String),
     Init(ConfigFile),
@@ -77,9 +92,35 @@ pub enum InternalUiMessage {
     ShowHardDrives,
     // bool: is_hard_drive, usize: drive_number
     Eject(bool, usize),
+    // usize: drive_number, bool: write protect the disk
+    WriteProtect(usize, bool),
+    // Copy-on-write overlay of the disk in this drive: start, commit or discard it
+    EnableOverlay(usize),
+    CommitOverlay(usize),
+    DiscardOverlay(usize),
     Exit,
+    /// Show the "New disk…" form for this drive, bool: true if is_hard_drive
+    OpenNewDisk(bool, usize),
+    NewDiskFormatSelected(NewDiskFormat),
+    NewDiskNameChanged(String),
+    NewDiskSizeSelected(HardDriveSize),
+    /// Pick the file of the new disk, then create it
+    NewDiskCreate,
+    NewDiskPicked(Option<String>),
+    NewDiskCancel,
     // First read from this drive on this phase_160
     FirstRead(usize, u8),
     // Disk tab: clear the graph
     ClearDiskGraph,
+    /// Catalog tab, bool: true for hard drive 1, false for drive 1. Export the file at this path
+    CatalogExport(bool, String),
+    CatalogExportTo(bool, String, Option<String>),
+    /// Catalog tab: pick a host file and add it to this directory
+    CatalogAdd(bool, String),
+    CatalogAddPicked(bool, String, Option<String>),
+    CatalogDelete(bool, String),
+    /// Catalog tab: show this ProDOS directory
+    CatalogOpen(String),
+    /// The result of the last catalog operation, shown in the Catalog tab
+    CatalogStatus(Result<String, String>),
 }
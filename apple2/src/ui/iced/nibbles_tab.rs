@@ -6,7 +6,7 @@ use iced::{Alignment, Border, Color, Element, Font, Length, Renderer, Theme};
 use iced::alignment::{Horizontal};
 use iced::widget::{button, Column};
 use iced::widget::button::Status;
-use crate::disk::bit_stream::{AreaType, TrackType};
+use crate::disk::bit_stream::{AnalyzedSector, AreaType, TrackType};
 use crate::Disk;
 use crate::disk::disk_info::DiskInfo;
 use crate::ui::iced::shared::Shared;
@@ -17,6 +17,8 @@ use crate::ui::iced::style::{m_container, m_group, MColor};
 #[derive(Default)]
 pub struct NibblesTab {
     current_disk: Option<Disk>,
+    /// The type of each of the 160 phases of `current_disk`, analyzed once when it's inserted
+    track_types: Vec<TrackType>,
 }
 
 /// Highlight the current phase
@@ -36,6 +38,60 @@ fn tttl(track_type: &TrackType) -> Text<'static> {
     }
 }
 
+/// A cell of the sector table
+fn cell(label: Text<'static>, width: f32) -> Element<'static, InternalUiMessage> {
+    label.font(Font::MONOSPACE)
+        .shaping(text::Shaping::Advanced)
+        .size(14)
+        .width(Length::Fixed(width))
+        .into()
+}
+
+const SECTOR_COLUMNS: [(&str, f32); 9] = [
+    ("Sector", 60.0), ("Volume", 60.0), ("Track", 50.0), ("Address", 70.0), ("Epilogue", 80.0),
+    ("Data", 50.0), ("Epilogue", 80.0), ("Sync", 60.0), ("Bytes", 200.0),
+];
+
+/// The fields of a sector as they were found on the track, what isn't standard in red
+fn sector_row(sector: &AnalyzedSector) -> Row<'static, InternalUiMessage> {
+    fn checksum(ok: bool) -> Text<'static> {
+        if ok { text("\u{2713}").color(MColor::green()) } else { text("\u{2717}").color(MColor::red()) }
+    }
+    fn epilogue(bytes: &[u8; 3]) -> Text<'static> {
+        let color = if bytes.starts_with(&[0xde, 0xaa]) { MColor::white() } else { MColor::red() };
+        text(format!("{:02X} {:02X} {:02X}", bytes[0], bytes[1], bytes[2])).color(color)
+    }
+    let hex = |value: u8| text(format!("{value:02X}"));
+    let mut cells = vec![
+        hex(sector.sector),
+        hex(sector.volume),
+        hex(sector.track),
+        checksum(sector.address_checksum_ok),
+        epilogue(&sector.address_epilogue),
+    ];
+    match &sector.data {
+        Some(data) => {
+            let bytes: Vec<String> = data.bytes[..8].iter().map(|b| format!("{b:02X}")).collect();
+            cells.extend([
+                checksum(data.checksum_ok),
+                epilogue(&data.epilogue),
+                text(format!("{}/{}", sector.address_sync_bits, data.sync_bits)),
+                text(bytes.join(" ")),
+            ]);
+        }
+        None => {
+            cells.extend([
+                text("-").color(MColor::red()),
+                text(""),
+                text(format!("{}/-", sector.address_sync_bits)),
+                text(""),
+            ]);
+        }
+    }
+    Row::with_children(cells.into_iter().zip(SECTOR_COLUMNS)
+        .map(|(t, (_, width))| cell(t, width)))
+}
+
 impl NibblesTab {
     pub fn update(&mut self, message: InternalUiMessage) {
         fn to_disk(di: Option<DiskInfo>) -> Option<Disk> {
@@ -47,14 +103,22 @@ impl NibblesTab {
 
         match message {
             Init(config_file) => {
-                self.current_disk = to_disk(config_file.drive_1().map(|p| DiskInfo::n(&p)));
+                self.set_disk(to_disk(config_file.drive_1().map(|p| DiskInfo::n(&p))));
             }
             DiskInserted(_, _, disk_info) => {
-                self.current_disk = to_disk(disk_info);
+                self.set_disk(to_disk(disk_info));
             }
             _ => {}
         }
     }
+
+    fn set_disk(&mut self, disk: Option<Disk>) {
+        self.track_types = match &disk {
+            Some(disk) => (0..160).map(|phase| disk.analyze_track(phase).track_type).collect(),
+            None => vec![TrackType::Empty; 160],
+        };
+        self.current_disk = disk;
+    }
 }
 
 impl Tab for NibblesTab {
@@ -115,10 +179,8 @@ impl Tab for NibblesTab {
         // Track map
         //
         let disk = self.current_disk.clone();
-        let t2 = if let Some(ref disk) = &disk {
-            (0..160).map(|phase| {
-                disk.analyze_track(phase).track_type
-            }).collect::<Vec<TrackType>>()
+        let t2 = if self.track_types.len() == 160 {
+            self.track_types.clone()
         } else {
             vec![TrackType::Empty; 160]
         };
@@ -143,9 +205,14 @@ impl Tab for NibblesTab {
         let SYNC_BITS: Color = MColor::blue2();
 
         let mut column: Column<InternalUiMessage> = Column::new();
+        let mut sectors: Column<InternalUiMessage> = Column::new().push(Row::with_children(
+            SECTOR_COLUMNS.map(|(title, width)| cell(text(title).color(MColor::gray1()), width))));
         if let Some(ref disk) = &disk {
             let mut address = 0_u16;
             let analyzed_track = disk.analyze_track(Shared::get_phase_160(0) as usize);
+            for sector in &analyzed_track.sectors {
+                sectors = sectors.push(sector_row(sector));
+            }
             let nibbles = &analyzed_track.nibbles;
             let mut index = 0_usize;
             while index < nibbles.len() {
@@ -192,6 +259,10 @@ impl Tab for NibblesTab {
             scrollable(column).into())
                 .width(Length::Fill)
                 .height(Length::Fill);
+        // Sync: the sync bits before the address field / before the data field
+        let sectors = m_group("Sectors".into(),
+            scrollable(sectors.spacing(5)).into())
+                .height(Length::Fill);
 
         //
         // Finally, layout the tab
@@ -199,7 +270,7 @@ impl Tab for NibblesTab {
         let content = iced::widget::column![
             current_track,
             track_map,
-            container,
+            iced::widget::row![sectors, container],
         ].spacing(10).
             into();
 
@@ -1,15 +1,14 @@
-use std::collections::VecDeque;
 use std::ops::DerefMut;
 use std::string::ToString;
-use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use std::sync::RwLock;
 
 use once_cell::sync::Lazy;
 
 use cpu::cpu::RunStatus;
 
+use crate::constants::HARD_DRIVE_COUNT;
+use crate::context::{ControllerState, EmulatorEvent, EmulatorObserver, RewindStatus};
 use crate::disk::disk_info::DiskInfo;
-use crate::joystick::Joystick;
 use crate::messages::CpuDumpMsg;
 
 #[derive(Default)]
@@ -30,9 +29,11 @@ static DRIVES: [RwLock<Lazy<Drive>>; 2] = [
     RwLock::new(Lazy::new(|| Drive::default()))
 ];
 
-static HARD_DRIVES: [RwLock<Lazy<HardDrive>>; 2] = [
-    RwLock::new(Lazy::new(|| HardDrive::default())),
-    RwLock::new(Lazy::new(|| HardDrive::default()))
+static HARD_DRIVES: [RwLock<Lazy<HardDrive>>; HARD_DRIVE_COUNT] = [
+    RwLock::new(Lazy::new(HardDrive::default)),
+    RwLock::new(Lazy::new(HardDrive::default)),
+    RwLock::new(Lazy::new(HardDrive::default)),
+    RwLock::new(Lazy::new(HardDrive::default))
 ];
 
 static BREAKPOINT_WAS_HIT: RwLock<bool> = RwLock::new(false);
@@ -43,27 +44,14 @@ struct CpuHolder {
 
 static CPU: RwLock<Lazy<CpuHolder>> = RwLock::new(Lazy::new(|| CpuHolder { cpu: CpuDumpMsg::default() }));
 
-#[derive(Copy, Clone)]
-pub struct SpeakerEvent {
-    pub cycle: u64,
-    pub timestamp: u64,
-}
-
-static SPEAKER_EVENTS: RwLock<Vec<SpeakerEvent>> = RwLock::new(Vec::new());
-static SOUND_SAMPLES: RwLock<VecDeque<f32>> = RwLock::new(VecDeque::new());
-static LAST_SAMPLE_PLAYED: RwLock<Option<(Instant, f32)>> = RwLock::new(None);
 static SHOW_DRIVES: RwLock<bool> = RwLock::new(true);
 
-#[derive(Default)]
-struct SharedJoystick {
-    reset: [u64; 4],
-    values: [u8; 4],
-    buttons: [bool; 4],
-}
+static REWIND: RwLock<RewindStatus> = RwLock::new(RewindStatus { count: 0, position: None });
 
-static JOYSTICK: RwLock<Lazy<Joystick>> = RwLock::new(Lazy::new(|| Joystick::default()));
-static SHARED_JOYSTICK: RwLock<Lazy<SharedJoystick>> = RwLock::new(Lazy::new(|| SharedJoystick::default()));
+static CONTROLLER: RwLock<ControllerState> = RwLock::new(ControllerState { values: [0; 4], buttons: [false; 4] });
 
+/// What the iced frontend knows about the machine. The emulator doesn't access these, they
+/// are updated from its events by [SharedObserver] and read by the views.
 pub struct Shared;
 
 impl Shared {
@@ -136,31 +124,12 @@ impl Shared {
         HARD_DRIVES[drive_index].write().unwrap().deref_mut().disk_info = disk_info;
     }
 
-    pub fn add_speaker_event(event: SpeakerEvent) { SPEAKER_EVENTS.write().unwrap().push(event); }
-    pub fn get_speaker_events() -> Vec<SpeakerEvent> {
-        let result = SPEAKER_EVENTS.read().unwrap().clone();
-        SPEAKER_EVENTS.write().unwrap().clear();
-        result
-    }
-
-    pub fn get_next_sound_sample_maybe() -> Option<f32> {
-        SOUND_SAMPLES.write().unwrap().pop_front()
-    }
-
-    pub fn has_samples() -> bool {
-        ! SOUND_SAMPLES.read().unwrap().is_empty()
-    }
-
-    pub fn add_sound_sample(s: f32) {
-        SOUND_SAMPLES.write().unwrap().push_back(s);
+    pub fn get_rewind() -> RewindStatus {
+        *REWIND.read().unwrap()
     }
 
-    pub fn set_last_sample_played(s: Option<(Instant, f32)>) {
-        *LAST_SAMPLE_PLAYED.write().unwrap() = s;
-    }
-
-    pub fn get_last_sample_played() -> Option<(Instant, f32)>{
-        *LAST_SAMPLE_PLAYED.read().unwrap()
+    pub fn set_rewind(status: RewindStatus) {
+        *REWIND.write().unwrap() = status;
     }
 
     pub fn get_show_drives() -> bool {
@@ -171,27 +140,34 @@ impl Shared {
         *SHOW_DRIVES.write().unwrap() = b;
     }
 
-    pub fn reset_joystick(cycle: u64) {
-        JOYSTICK.write().unwrap().reset_cycles(cycle);
-    }
-
-    pub fn get_controller_value(index: usize, cycle: u64) -> u8 {
-        JOYSTICK.write().unwrap().get_value_for_paddle(index, cycle)
-    }
-
-    pub fn update_controller_raw_values(values: [u8; 4]) {
-        SHARED_JOYSTICK.write().unwrap().values = values;
-    }
-
     pub fn get_controller_raw_values() -> [u8; 4] {
-        SHARED_JOYSTICK.read().unwrap().values.clone()
+        CONTROLLER.read().unwrap().values
     }
 
     pub fn get_controller_button_value(index: usize) -> bool {
-        SHARED_JOYSTICK.read().unwrap().buttons[index]
+        CONTROLLER.read().unwrap().buttons[index]
     }
+}
 
-    pub fn set_controller_button_value(index: usize, v: bool) {
-        SHARED_JOYSTICK.write().unwrap().buttons[index] = v;
+/// Mirror the events of the emulator into [Shared]
+pub struct SharedObserver;
+
+impl EmulatorObserver for SharedObserver {
+    fn on_event(&self, event: &EmulatorEvent) {
+        use EmulatorEvent::*;
+        match event {
+            DiskChanged(drive_index, disk_info) => { Shared::set_drive(*drive_index, disk_info.clone()); }
+            HardDriveChanged(drive_index, disk_info) => {
+                Shared::set_hard_drive(*drive_index, disk_info.clone());
+            }
+            PhaseChanged(drive_index, phase_160) => { Shared::set_phase_160(*drive_index, *phase_160); }
+            SectorChanged(drive_index, sector) => { Shared::set_sector(*drive_index, *sector); }
+            BlockChanged(drive_index, block) => { Shared::set_block_number(*drive_index, *block); }
+            ControllerChanged(state) => { *CONTROLLER.write().unwrap() = *state; }
+            Cpu(cpu) => { Shared::set_cpu(cpu.clone()); }
+            RunStatusChanged(run_status) => { Shared::set_run_status(*run_status); }
+            BreakpointHit(hit) => { Shared::set_breakpoint_was_hit(*hit); }
+            RewindChanged(status) => { Shared::set_rewind(*status); }
+        }
     }
 }
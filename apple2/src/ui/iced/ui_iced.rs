@@ -10,13 +10,16 @@ use cpu::cpu::{RunStatus, StopReason};
 
 use crate::{DiskInfo, send_message, ui_log};
 use crate::config_file::ConfigFile;
-use crate::constants::{HIRES_HEIGHT, HIRES_WIDTH};
+use crate::constants::{HIRES_HEIGHT, HIRES_WIDTH, REWIND_POINTS_PER_SECOND};
+use crate::context::EmulatorContext;
 use crate::messages::{CpuStateMsg, SetMemoryMsg, ToCpu, ToMiniFb, ToUi};
 use crate::messages::ToCpu::*;
 use crate::ui::iced::message::InternalUiMessage::*;
 use crate::ui::hires_screen::AColor;
 use crate::ui::iced::debugger_window::{DebuggerWindow, MemoryViewState};
+use crate::ui::iced::catalog_tab::CatalogTab;
 use crate::ui::iced::disks_tab::DisksTab;
+use crate::ui::iced::drives_view::NewDiskForm;
 use crate::ui::iced::keyboard;
 use crate::ui::iced::main_window::MainWindow;
 use crate::ui::iced::memory_view::MemoryType;
@@ -30,6 +33,7 @@ type UiMessage = ToUi;
 pub enum TabId {
     #[default]
     DisksTab,
+    CatalogTab,
     NibblesTab,
     DriveTab,
     DebugTab,
@@ -38,7 +42,8 @@ pub enum TabId {
 pub fn main_iced(sender: Option<Sender<ToCpu>>,
                  receiver: Receiver<ToUi>,
                  sender_minifb: Option<Sender<ToMiniFb>>,
-                 config_file: ConfigFile) -> iced::Result
+                 config_file: ConfigFile,
+                 context: Arc<EmulatorContext>) -> iced::Result
 {
     let mut window_settings = window::Settings::default();
     let mag = config_file.magnification();
@@ -72,6 +77,7 @@ pub fn main_iced(sender: Option<Sender<ToCpu>>,
                 sender: sender.clone(),
                 sender_minifb: sender_minifb.clone(),
                 config_file: config_file.clone(),
+                context: context.clone(),
                 ..Default::default()
             };
             result
@@ -92,6 +98,8 @@ struct EmulatorApp {
     sender: Option<Sender<ToCpu>>,
     sender_minifb: Option<Sender<ToMiniFb>>,
     config_file: ConfigFile,
+    /// The frontend's input to the emulator, its output arrives through [Shared]
+    context: Arc<EmulatorContext>,
     debugger_window: Option<DebuggerWindow>,
     debugger_id: Option<window::Id>,
     main_window: Option<MainWindow>,
@@ -120,6 +128,7 @@ impl Default for EmulatorApp {
             main_id: None,
             sender_minifb: None,
             config_file: Default::default(),
+            context: Default::default(),
             opening_debugger: false,
             emulator_speed: 0.0,
             exit: false,
@@ -154,6 +163,7 @@ impl EmulatorApp {
             if let Some(ref mut w) = &mut self.main_window {
                 Shared::set_show_drives(! is_hard_drive);
             }
+            self.context.set_boot_from_hard_drive(is_hard_drive);
             send_message!(&self.sender, LoadDisk(is_hard_drive, drive_index, disk_info));
         };
 
@@ -161,14 +171,31 @@ impl EmulatorApp {
             SpecialKey(key, pressed) => {
                 match key {
                     SpecialKeyMsg::AltLeft => {
-                        Shared::set_controller_button_value(0, pressed);
+                        self.context.set_controller_button(0, pressed);
                     }
                     SpecialKeyMsg::AltRight => {
-                        Shared::set_controller_button_value(1, pressed);
+                        self.context.set_controller_button(1, pressed);
                 }
+                    SpecialKeyMsg::Rewind if pressed => {
+                        let rewind = Shared::get_rewind();
+                        if rewind.count > 0 {
+                            let position = rewind.position.unwrap_or(rewind.count - 1);
+                            send_message!(&self.sender,
+                                Rewind(position.saturating_sub(REWIND_POINTS_PER_SECOND)));
+                        }
+                    }
+                    SpecialKeyMsg::ResumeRewind if pressed => {
+                        send_message!(&self.sender, ToCpu::ResumeRewind);
+                    }
                     _ => {}
                 };
             }
+            RewindTo(index) => {
+                send_message!(&self.sender, Rewind(index as usize));
+            }
+            InternalUiMessage::ResumeRewind => {
+                send_message!(&self.sender, ToCpu::ResumeRewind);
+            }
             Key(key) => {
                 for i in 0..16 {
                 send_message!(&self.sender, SetMemory(SetMemoryMsg {
@@ -188,9 +215,6 @@ impl EmulatorApp {
                 | Tick
                 | Reboot
                 | DriveSelected(_)
-                | ShowDrives
-                | ShowHardDrives
-                | Eject(_, _)
                 | FirstRead(_, _)
                 | ClearDiskGraph
                 =>
@@ -199,6 +223,35 @@ impl EmulatorApp {
                     main_window.update(message.clone());
                 }
             }
+            ShowDrives | ShowHardDrives | Eject(_, _) => {
+                match message {
+                    ShowDrives => { self.context.set_boot_from_hard_drive(false); }
+                    ShowHardDrives => { self.context.set_boot_from_hard_drive(true); }
+                    Eject(is_hard_drive, drive_number) => {
+                        send_message!(&self.sender, EjectDisk(is_hard_drive, drive_number));
+                    }
+                    _ => {}
+                }
+                if let Some(ref mut main_window) = &mut self.main_window {
+                    main_window.update(message.clone());
+                }
+            }
+            WriteProtect(drive_number, protected) => {
+                if protected {
+                    send_message!(&self.sender, LockDisk(drive_number));
+                } else {
+                    send_message!(&self.sender, UnlockDisk(drive_number));
+                }
+            }
+            EnableOverlay(drive_number) => {
+                send_message!(&self.sender, EnableDiskOverlay(drive_number));
+            }
+            CommitOverlay(drive_number) => {
+                send_message!(&self.sender, CommitDiskOverlay(drive_number));
+            }
+            DiscardOverlay(drive_number) => {
+                send_message!(&self.sender, DiscardDiskOverlay(drive_number));
+            }
             DiskInserted(is_hard_drive, drive_index, ref di) => {
                 let di2 = di.clone();
                 self.config_file.set_drive(is_hard_drive, drive_index, di2.map(|d| d.path.clone()));
@@ -218,6 +271,16 @@ impl EmulatorApp {
                     println!("No sender to send Swap to");
                 }
             }
+            SaveState => {
+                if let Some(path) = ConfigFile::quick_save_path() {
+                    send_message!(&self.sender, ToCpu::SaveState(path));
+                }
+            }
+            LoadState => {
+                if let Some(path) = ConfigFile::quick_save_path() {
+                    send_message!(&self.sender, ToCpu::LoadState(path));
+                }
+            }
             OpenDebugger => {
                 self.opening_debugger = true;
                 let w = window::open(window::Settings {
@@ -256,6 +319,84 @@ impl EmulatorApp {
             LoadHardDrive(drive_index, path) => {
                 load_drive(path, drive_index, true);
             }
+            MountDirectory(drive_index) => {
+                result.push(Task::perform(
+                    async move {
+                        DisksTab::pick_volume_directory()
+                    },
+                    move |path| DirectoryPicked(drive_index, path)));
+            }
+            DirectoryPicked(drive_index, Some(path)) => {
+                load_drive(path, drive_index, true);
+            }
+            CatalogExport(is_hard_drive, path) => {
+                result.push(Task::perform(
+                    async move {
+                        let host_path = CatalogTab::pick_export_file(is_hard_drive, &path);
+                        (path, host_path)
+                    },
+                    move |(path, host_path)| CatalogExportTo(is_hard_drive, path, host_path)));
+            }
+            CatalogExportTo(is_hard_drive, path, Some(host_path)) => {
+                result.push(Task::done(CatalogStatus(CatalogTab::export(is_hard_drive, &path, &host_path))));
+            }
+            CatalogAdd(is_hard_drive, directory) => {
+                result.push(Task::perform(
+                    async move {
+                        CatalogTab::pick_file_to_add()
+                    },
+                    move |host_path| CatalogAddPicked(is_hard_drive, directory.clone(), host_path)));
+            }
+            CatalogAddPicked(is_hard_drive, _, Some(_)) | CatalogDelete(is_hard_drive, _) => {
+                let edited = match message {
+                    CatalogAddPicked(_, ref directory, Some(ref host_path)) =>
+                        CatalogTab::add(is_hard_drive, directory, host_path),
+                    CatalogDelete(_, ref path) => CatalogTab::delete(is_hard_drive, path),
+                    _ => unreachable!(),
+                };
+                // Reload the disk so the emulator and the catalog see the new content
+                let status = edited.map(|(path, status)| {
+                    load_drive(path, 0, is_hard_drive);
+                    status
+                });
+                result.push(Task::done(CatalogStatus(status)));
+            }
+            CatalogOpen(_) | CatalogStatus(_) | OpenNewDisk(_, _) | NewDiskFormatSelected(_)
+                | NewDiskNameChanged(_) | NewDiskSizeSelected(_) | NewDiskCancel => {
+                if let Some(ref mut main_window) = &mut self.main_window {
+                    main_window.update(message.clone());
+                }
+            }
+            NewDiskCreate => {
+                if let Some(form) = self.main_window.as_ref().and_then(|w| w.new_disk.as_ref()) {
+                    let is_hard_drive = form.is_hard_drive;
+                    result.push(Task::perform(
+                        async move {
+                            NewDiskForm::pick_path(is_hard_drive)
+                        },
+                        NewDiskPicked));
+                }
+            }
+            NewDiskPicked(Some(path)) => {
+                if let Some(main_window) = &mut self.main_window {
+                    if let Some(mut form) = main_window.new_disk.take() {
+                        match form.create(&path) {
+                            Ok(()) => {
+                                result.push(Task::done(if form.is_hard_drive {
+                                    LoadHardDrive(form.drive_number, path)
+                                } else {
+                                    LoadDrive(form.drive_number, path)
+                                }));
+                            }
+                            Err(e) => {
+                                // Keep the form open with the error
+                                form.error = Some(e);
+                                main_window.new_disk = Some(form);
+                            }
+                        }
+                    }
+                }
+            }
             RegisterA(a) => {
                 println!("New value for A: {a}");
             }
@@ -300,7 +441,9 @@ impl EmulatorApp {
                     }
                 }
             }
-            Load | TabClosed(_) | Init(_) | DebuggerPause | EditBreakPoint(_) => {
+            Load | TabClosed(_) | Init(_) | DebuggerPause | EditBreakPoint(_)
+                | DirectoryPicked(_, None) | CatalogExportTo(_, _, None) | CatalogAddPicked(_, _, None)
+                | NewDiskPicked(None) => {
                 // ignored
             }
             // _ => {
@@ -0,0 +1,85 @@
+use crate::memory_constants::*;
+use crate::messages::DrawCommand;
+use crate::messages::DrawCommand::Rectangle;
+use crate::ui::hires_screen::{rotate_aux_nibble, AColor, HiresScreen};
+use crate::ui::hires_screen::AColor::*;
+
+/// Memory in lo-res mode: TEXT and HIRES off
+fn lores_memory() -> Vec<u8> {
+    vec![0; 0x10000]
+}
+
+fn rectangle(command: &DrawCommand) -> (f32, f32, f32, f32, AColor) {
+    let Rectangle(x0, y0, x1, y1, color) = *command;
+    (x0, y0, x1, y1, color)
+}
+
+#[test]
+pub fn test_lores_page_1_and_2() {
+    let mut memory = lores_memory();
+    let aux_memory = lores_memory();
+    // First byte of the first line: white on top, dark red at the bottom
+    memory[0x400] = 0x1f;
+    // Last byte of the last line
+    memory[0x7f7] = 0xd0;
+    memory[0x800] = 0x9c;
+
+    let commands = HiresScreen::new().get_draw_commands(&memory, &aux_memory, 1);
+    assert_eq!(commands.len(), 40 * 48);
+    assert_eq!(rectangle(&commands[0]), (0.0, 0.0, 7.0, 4.0, DHWhite));
+    assert_eq!(rectangle(&commands[1]), (0.0, 4.0, 7.0, 8.0, DHDarkRed));
+    assert_eq!(rectangle(&commands[2]), (7.0, 0.0, 14.0, 4.0, DHBlack));
+    let last = commands.len() - 1;
+    assert_eq!(rectangle(&commands[last - 1]), (273.0, 184.0, 280.0, 188.0, DHBlack));
+    assert_eq!(rectangle(&commands[last]), (273.0, 188.0, 280.0, 192.0, DHYellow));
+
+    memory[PAGE_2_STATUS as usize] = 0x80;
+    let commands = HiresScreen::new().get_draw_commands(&memory, &aux_memory, 2);
+    assert_eq!(rectangle(&commands[0]), (0.0, 0.0, 14.0, 8.0, DHLightGreen));
+    assert_eq!(rectangle(&commands[1]), (0.0, 8.0, 14.0, 16.0, DHOrange));
+}
+
+#[test]
+pub fn test_lores_mixed() {
+    let mut memory = lores_memory();
+    let aux_memory = lores_memory();
+    memory[MIXED_STATUS as usize] = 0x80;
+    let commands = HiresScreen::new().get_draw_commands(&memory, &aux_memory, 1);
+    let lores_count = 40 * 40;
+    assert_eq!(rectangle(&commands[lores_count - 1]).3, 160.0);
+    // The bottom four lines are text
+    assert!(commands.len() > lores_count);
+    assert!(commands[lores_count..].iter().all(|c| rectangle(c).1 >= 160.0));
+}
+
+#[test]
+pub fn test_double_lores() {
+    let mut memory = lores_memory();
+    let mut aux_memory = lores_memory();
+    memory[EIGHTY_COLUMNS_STATUS as usize] = 0x80;
+    memory[AN3_STATUS as usize] = 0b0010_0000;
+    aux_memory[0x400] = 0x21;
+    memory[0x400] = 0x21;
+
+    let commands = HiresScreen::new().get_draw_commands(&memory, &aux_memory, 2);
+    assert_eq!(commands.len(), 80 * 48);
+    // Aux column: the nibbles are rotated (1 -> 2, 2 -> 4)
+    assert_eq!(rectangle(&commands[0]), (0.0, 0.0, 7.0, 8.0, DHDarkBlue));
+    assert_eq!(rectangle(&commands[1]), (0.0, 8.0, 7.0, 16.0, DHDarkGreen));
+    // Main column
+    assert_eq!(rectangle(&commands[2]), (7.0, 0.0, 14.0, 8.0, DHDarkRed));
+    assert_eq!(rectangle(&commands[3]), (7.0, 8.0, 14.0, 16.0, DHDarkBlue));
+
+    // Without AN3, it's regular lo-res
+    memory[AN3_STATUS as usize] = 0;
+    assert_eq!(HiresScreen::new().get_draw_commands(&memory, &aux_memory, 2).len(), 40 * 48);
+}
+
+#[test]
+pub fn test_rotate_aux_nibble() {
+    assert_eq!(rotate_aux_nibble(0), 0);
+    assert_eq!(rotate_aux_nibble(1), 2);
+    assert_eq!(rotate_aux_nibble(8), 1);
+    assert_eq!(rotate_aux_nibble(0xf), 0xf);
+    assert_eq!(rotate_aux_nibble(0b0101), 0b1010);
+}
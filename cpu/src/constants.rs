@@ -3,9 +3,16 @@
 
 pub const DEFAULT_EMULATOR_SPEED_HZ: u64 = 1_300_000;
 
+pub(crate) const NMI_VECTOR_L: u16 = 0xfffa;
+pub(crate) const NMI_VECTOR_H: u16 = 0xfffb;
+pub(crate) const RESET_VECTOR_L: u16 = 0xfffc;
+pub(crate) const RESET_VECTOR_H: u16 = 0xfffd;
 pub(crate) const IRQ_VECTOR_L: u16 = 0xfffe;
 pub(crate) const IRQ_VECTOR_H: u16 = 0xffff;
 
+/// Number of cycles it takes to enter an interrupt handler (IRQ, NMI, RESET)
+pub const INTERRUPT_CYCLES: u8 = 7;
+
 pub const BRK: u8 = 0x00;
 pub const ORA_IND_X: u8 = 0x01;
 pub const JAM_02: u8 = 0x02;
@@ -157,9 +157,28 @@ pub struct Cpu<T: Memory> {
     started: bool,
     pub(crate) is_65c02: bool,
 
+    /// Interrupt lines, see [Cpu::set_irq], [Cpu::set_nmi] and [Cpu::reset]
+    pub interrupts: Interrupts,
+
     logging_sender: Option<Sender<ToLogging>>,
 }
 
+/// State of the external interrupt lines
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Interrupts {
+    /// IRQ is level triggered and can be asserted by several devices at the same time,
+    /// one bit per source. The IRQ is serviced as long as at least one bit is set.
+    pub irq_sources: u32,
+    /// Current level of the NMI line (true = asserted)
+    pub nmi_line: bool,
+    /// NMI is edge triggered: set when the line goes from released to asserted
+    pub nmi_pending: bool,
+    pub reset_pending: bool,
+    /// CLI, SEI and PLP change the I flag after the CPU has polled the interrupts, so the
+    /// instruction that follows still sees the old value. Contains that old value.
+    pub delayed_i: Option<bool>,
+}
+
 impl<T: Memory> Display for Cpu<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.write_str(&format!("A={:02X} X={:02X} Y={:02X} P={:02X} S={:02X} PC={:04X}",
@@ -231,11 +250,77 @@ impl <T: Memory> Cpu<T> {
             started: false,
             is_65c02: config.is_65c02,
             operands: if config.is_65c02 { OPERANDS_65C02 } else { OPERANDS_6502 },
+            interrupts: Interrupts::default(),
             logging_sender,
         }
     }
 
+    /// Assert or release the IRQ line for the given source (0..32). IRQ is level triggered:
+    /// it will be serviced before each instruction for as long as one source asserts it
+    /// and the I flag is clear, so the device needs to release it once acknowledged.
+    pub fn set_irq(&mut self, source: u8, asserted: bool) {
+        assert!((source as u32) < u32::BITS, "IRQ source {source} is out of range (0..32)");
+        let bit = 1 << source;
+        if asserted {
+            self.interrupts.irq_sources |= bit;
+        } else {
+            self.interrupts.irq_sources &= !bit;
+        }
+    }
+
+    pub fn is_irq_asserted(&self) -> bool {
+        self.interrupts.irq_sources != 0
+    }
+
+    /// Set the level of the NMI line. NMI is edge triggered: an interrupt is only
+    /// generated when the line goes from released to asserted, and the I flag is ignored.
+    pub fn set_nmi(&mut self, asserted: bool) {
+        if asserted && ! self.interrupts.nmi_line {
+            self.interrupts.nmi_pending = true;
+        }
+        self.interrupts.nmi_line = asserted;
+    }
+
+    /// Pull the RESET line. The reset sequence runs before the next instruction:
+    /// the PC is loaded from $FFFC, the I flag is set (and D cleared on the 65C02) and
+    /// the stack pointer is decremented three times without anything being written.
+    pub fn reset(&mut self) {
+        self.interrupts.reset_pending = true;
+    }
+
+    /// If an interrupt needs to be serviced, enter its handler and return true
+    fn poll_interrupts(&mut self) -> bool {
+        let i = self.interrupts.delayed_i.take().unwrap_or(self.p.i());
+        if self.interrupts.reset_pending {
+            // The devices driving IRQ and NMI are in charge of releasing their lines
+            self.interrupts.reset_pending = false;
+            self.interrupts.nmi_pending = false;
+            self.s = self.s.wrapping_sub(3);
+            self.p.set_i(true);
+            if self.is_65c02 {
+                self.p.set_d(false);
+            }
+            self.pc = self.memory.word(RESET_VECTOR_L);
+            true
+        } else if self.interrupts.nmi_pending {
+            self.interrupts.nmi_pending = false;
+            self.handle_interrupt(false, NMI_VECTOR_H, NMI_VECTOR_L);
+            true
+        } else if self.is_irq_asserted() && ! i {
+            self.handle_interrupt(false, IRQ_VECTOR_H, IRQ_VECTOR_L);
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn step(&mut self, config: &Config, breakpoints: &HashSet<u16>) {
+        if self.poll_interrupts() {
+            self.run_status = RunStatus::Continue(INTERRUPT_CYCLES);
+            self.cycles = self.cycles + self.run_status.cycles();
+            return;
+        }
+
         if breakpoints.contains(&self.pc) {
             self.run_status = RunStatus::Stop(BreakpointHit, 1);
             return;
@@ -289,13 +374,6 @@ impl <T: Memory> Cpu<T> {
         let max = 10;
         let mut i = 0;
 
-        /// Necessary for SmartPort. This is when we're about to return from the read routine
-        /// in SmartPort and since we simulate it, there can never be any error, so make sure
-        /// we clear the carry.
-        if pc == 0xc7b7 {
-            self.p.set_c(false);
-        }
-
         let opcode = self.memory.get(pc);
 
         #[cfg(feature = "log_prodos")]
@@ -382,7 +460,17 @@ impl <T: Memory> Cpu<T> {
             BCS => { cycles += self.branch(pc.wrapping_add(1), self.p.c()) },
             BVC => { cycles += self.branch(pc.wrapping_add(1), ! self.p.v()) },
             BVS => { cycles += self.branch(pc.wrapping_add(1), self.p.v()) },
-            BRK => self.handle_interrupt(true, IRQ_VECTOR_H, IRQ_VECTOR_L),
+            BRK => {
+                if self.interrupts.nmi_pending && ! self.is_65c02 {
+                    // On the NMOS 6502, an NMI occurring during BRK hijacks it: B is pushed
+                    // but the CPU jumps to the NMI vector and the BRK is lost.
+                    // The 65C02 completes the BRK and services the NMI afterward.
+                    self.interrupts.nmi_pending = false;
+                    self.handle_interrupt(true, NMI_VECTOR_H, NMI_VECTOR_L);
+                } else {
+                    self.handle_interrupt(true, IRQ_VECTOR_H, IRQ_VECTOR_L);
+                }
+            },
             CMP_IMM => {
                 let value = self.memory.get(pc.wrapping_add(1));
                 self.cmp(self.a, value)
@@ -440,8 +528,14 @@ impl <T: Memory> Cpu<T> {
             },
             CLC => self.p.set_c(false),
             SEC => self.p.set_c(true),
-            CLI => self.p.set_i(false),
-            SEI => self.p.set_i(true),
+            CLI => {
+                self.interrupts.delayed_i = Some(self.p.i());
+                self.p.set_i(false)
+            },
+            SEI => {
+                self.interrupts.delayed_i = Some(self.p.i());
+                self.p.set_i(true)
+            },
             CLD => self.p.set_d(false),
             SED => self.p.set_d(true),
             CLV => self.p.set_v(false),
@@ -660,6 +754,7 @@ impl <T: Memory> Cpu<T> {
                 self.push_byte(self.p.value());
             },
             PLP => {
+                self.interrupts.delayed_i = Some(self.p.i());
                 let b = self.pop_byte();
                 self.p.set_value(b);
             },
@@ -1089,14 +1184,21 @@ impl <T: Memory> Cpu<T> {
         self.p.set_n(tmp < 0);
     }
 
+    /// `brk` is true for the BRK instruction, false for IRQ and NMI
     fn handle_interrupt(&mut self, brk: bool, vector_high: u16, vector_low: u16) {
-        self.p.set_b(brk);
-        // Klaus functional tests require to increment the PC by 1
-        // but the exhaustive 6502 tests require 2
-        self.push_word((self.pc.wrapping_add(1)));
-        self.push_byte(self.p.value());
+        if brk {
+            self.p.set_b(true);
+            // Klaus functional tests require to increment the PC by 1
+            // but the exhaustive 6502 tests require 2
+            self.push_word((self.pc.wrapping_add(1)));
+            self.push_byte(self.p.value());
+        } else {
+            // Hardware interrupts return to the next instruction and push B clear
+            self.push_word(self.pc);
+            self.push_byte(self.p.value() & !0x10);
+        }
         if self.is_65c02 {
-            // BRK clears the D flag on 65C02
+            // BRK and interrupts clear the D flag on 65C02
             self.p.set_d(false);
         }
         self.p.set_i(true);
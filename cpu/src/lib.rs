@@ -8,6 +8,7 @@ mod addressing_type;
 pub mod operand;
 
 mod test;
+mod test_interrupts;
 pub mod disassembly;
 mod log_file;
 pub mod logging_thread;
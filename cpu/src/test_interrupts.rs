@@ -0,0 +1,150 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use crate::config::Config;
+    use crate::constants::*;
+    use crate::cpu::{Cpu, RunStatus};
+    use crate::memory::{DefaultMemory, Memory};
+
+    const IRQ_HANDLER: u16 = 0x2000;
+    const NMI_HANDLER: u16 = 0x3000;
+    const RESET_HANDLER: u16 = 0x4000;
+
+    /// A CPU at $1000 with a program made of `program` followed by NOPs.
+    /// The interrupt handlers are made of NOPs as well.
+    fn create_cpu(is_65c02: bool, program: &[u8]) -> (Cpu<DefaultMemory>, Config) {
+        let config = Config { is_65c02, ..Default::default() };
+        let mut memory = DefaultMemory::new();
+        for start in [0x1000, IRQ_HANDLER, NMI_HANDLER, RESET_HANDLER] {
+            for i in 0..0x100 {
+                memory.set(start + i, NOP);
+            }
+        }
+        for (i, byte) in program.iter().enumerate() {
+            memory.set(0x1000 + i as u16, *byte);
+        }
+        for (vector, handler) in [(0xfffe, IRQ_HANDLER), (0xfffa, NMI_HANDLER), (0xfffc, RESET_HANDLER)] {
+            memory.set(vector, handler as u8);
+            memory.set(vector + 1, (handler >> 8) as u8);
+        }
+        let mut cpu = Cpu::new(memory, None, config.clone());
+        cpu.pc = 0x1000;
+        (cpu, config)
+    }
+
+    fn step(cpu: &mut Cpu<DefaultMemory>, config: &Config) {
+        cpu.step(config, &HashSet::new());
+    }
+
+    #[test]
+    fn irq_respects_i_flag() {
+        let (mut cpu, config) = create_cpu(false, &[SEI, NOP, NOP, CLI, NOP]);
+        step(&mut cpu, &config);  // SEI
+        step(&mut cpu, &config);  // NOP
+        cpu.set_irq(0, true);
+        step(&mut cpu, &config);  // NOP: IRQ masked
+        assert_eq!(cpu.pc, 0x1003);
+        step(&mut cpu, &config);  // CLI
+        // The I flag is polled before CLI takes effect, so one more instruction runs
+        step(&mut cpu, &config);
+        assert_eq!(cpu.pc, 0x1005);
+        let cycles = cpu.cycles;
+        step(&mut cpu, &config);
+        assert_eq!(cpu.pc, IRQ_HANDLER);
+        assert_eq!(cpu.run_status, RunStatus::Continue(INTERRUPT_CYCLES));
+        assert_eq!(cpu.cycles - cycles, INTERRUPT_CYCLES as u128);
+        assert!(cpu.p.i());
+
+        // Return address is the next instruction and B is clear in the pushed flags
+        let p = cpu.memory.get(0x100 + cpu.s as u16 + 1);
+        assert_eq!(p & 0x10, 0);
+        assert_eq!(cpu.memory.word(0x100 + cpu.s as u16 + 2), 0x1005);
+
+        // Level triggered: still asserted but masked by the handler's I flag
+        step(&mut cpu, &config);
+        assert_eq!(cpu.pc, IRQ_HANDLER + 1);
+    }
+
+    #[test]
+    fn nmi_is_edge_triggered() {
+        let (mut cpu, config) = create_cpu(false, &[SEI]);
+        step(&mut cpu, &config);
+        cpu.set_nmi(true);
+        step(&mut cpu, &config);
+        assert_eq!(cpu.pc, NMI_HANDLER);
+        step(&mut cpu, &config);
+        // Line still asserted: no new NMI
+        step(&mut cpu, &config);
+        assert_eq!(cpu.pc, NMI_HANDLER + 2);
+        cpu.set_nmi(false);
+        cpu.set_nmi(true);
+        step(&mut cpu, &config);
+        assert_eq!(cpu.pc, NMI_HANDLER);
+    }
+
+    #[test]
+    fn decimal_flag() {
+        for is_65c02 in [false, true] {
+            let (mut cpu, config) = create_cpu(is_65c02, &[CLI, SED]);
+            step(&mut cpu, &config);
+            step(&mut cpu, &config);
+            cpu.set_irq(3, true);
+            step(&mut cpu, &config);
+            assert_eq!(cpu.pc, IRQ_HANDLER);
+            assert_eq!(cpu.p.d(), ! is_65c02);
+        }
+    }
+
+    #[test]
+    fn nmi_hijacks_brk_on_nmos() {
+        for is_65c02 in [false, true] {
+            let (mut cpu, config) = create_cpu(is_65c02, &[BRK]);
+            let s = cpu.s;
+            cpu.p.set_i(true);
+            // The NMI arrives while BRK is executing: run the BRK without polling first
+            cpu.set_nmi(true);
+            cpu.pc = 0x1001;
+            cpu.next_instruction(0x1000, &config, &HashSet::new());
+            let expected_pc = if is_65c02 { IRQ_HANDLER } else { NMI_HANDLER };
+            assert_eq!(cpu.pc, expected_pc, "65C02: {is_65c02}");
+            // B is set in both cases since the BRK was executing
+            assert_eq!(cpu.memory.get(0x100 + s as u16 - 2) & 0x10, 0x10);
+            step(&mut cpu, &config);
+            let expected_pc = if is_65c02 { NMI_HANDLER } else { NMI_HANDLER + 1 };
+            assert_eq!(cpu.pc, expected_pc, "65C02: {is_65c02}");
+        }
+    }
+
+    #[test]
+    fn irq_sources() {
+        let (mut cpu, _) = create_cpu(false, &[]);
+        cpu.set_irq(0, true);
+        cpu.set_irq(31, true);
+        cpu.set_irq(0, false);
+        // The line stays asserted until every source releases it
+        assert!(cpu.is_irq_asserted());
+        cpu.set_irq(31, false);
+        assert!(! cpu.is_irq_asserted());
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn irq_source_out_of_range() {
+        let (mut cpu, _) = create_cpu(false, &[]);
+        cpu.set_irq(32, true);
+    }
+
+    #[test]
+    fn reset() {
+        let (mut cpu, config) = create_cpu(true, &[SED]);
+        step(&mut cpu, &config);
+        let s = cpu.s;
+        cpu.reset();
+        step(&mut cpu, &config);
+        assert_eq!(cpu.pc, RESET_HANDLER);
+        assert_eq!(cpu.s, s.wrapping_sub(3));
+        assert!(cpu.p.i());
+        assert!(! cpu.p.d());
+        assert_eq!(cpu.run_status, RunStatus::Continue(INTERRUPT_CYCLES));
+    }
+}
@@ -0,0 +1,45 @@
+//! Disk image tools: `maple2-disk convert Game.woz Game.dsk`
+
+use std::process::exit;
+use clap::{Parser, Subcommand};
+use maple_2::disk::convert::convert;
+
+#[derive(Parser)]
+#[command(name = "maple2-disk")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a disk image to another format, given by the suffixes of the files:
+    /// dsk, do, po, hdv, d13, nib, woz, 2mg, and a2r as a source only
+    Convert {
+        input: String,
+        output: String,
+
+        /// Convert even if something is lost, e.g. nonstandard tracks or bad checksums
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+fn main() {
+    match Args::parse().command {
+        Command::Convert { input, output, force } => {
+            match convert(&input, &output, force) {
+                Ok(lost) => {
+                    for l in lost {
+                        eprintln!("Lost: {l}");
+                    }
+                    println!("Wrote {output}");
+                }
+                Err(e) => {
+                    eprintln!("{e}");
+                    exit(1);
+                }
+            }
+        }
+    }
+}
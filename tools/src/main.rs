@@ -1,10 +1,42 @@
+use std::process::exit;
+use clap::{Parser, Subcommand};
 use crate::log_analyzer::analyze_logs;
+use crate::prodos::ProDosCommand;
 
 pub mod log_analyzer;
 mod compress;
 mod csv;
+mod prodos;
+
+#[derive(Parser)]
+#[command(name = "tools")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compare two execution traces, the default
+    Logs,
+    /// Browse and edit the ProDOS volume of a disk image (.po, .hdv, .2mg, .dsk, .woz, ...)
+    #[command(subcommand)]
+    Prodos(ProDosCommand),
+}
 
 fn main() {
-    // compress();
-    analyze_logs();
-}
\ No newline at end of file
+    match Args::parse().command {
+        None | Some(Command::Logs) => {
+            // compress();
+            if let Err(e) = analyze_logs() {
+                eprintln!("{e}");
+            }
+        }
+        Some(Command::Prodos(command)) => {
+            if let Err(e) = prodos::run(command) {
+                eprintln!("{e}");
+                exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Browse and edit the ProDOS volume of a disk image: `tools prodos ls Games.po /GAMES`
+//!
+//! Files keep their type and aux type on the host in a `#ttaaaa` suffix (`HELLO#fc0801`), the
+//! files added without this suffix are BIN.
+
+use std::fs;
+use std::path::Path;
+use clap::Subcommand;
+use maple_2::disk::convert::{read_blocks, write_blocks};
+use maple_2::disk::prodos::{parse_suffix, prodos_date, suffixed_name, to_prodos_name, FileAttributes,
+    ProDos, BINARY_TYPE, DEFAULT_ACCESS};
+
+#[derive(Subcommand)]
+pub enum ProDosCommand {
+    /// List a directory of the volume, the volume directory by default
+    Ls {
+        image: String,
+        #[arg(default_value = "")]
+        path: String,
+    },
+    /// Copy a file of the volume to the host, in the current directory by default
+    Get {
+        image: String,
+        path: String,
+        destination: Option<String>,
+    },
+    /// Copy a host file to a directory of the volume, the volume directory by default
+    Put {
+        image: String,
+        file: String,
+        #[arg(default_value = "")]
+        directory: String,
+    },
+    /// Delete a file or an empty directory
+    Rm {
+        image: String,
+        path: String,
+    },
+    /// Create a directory
+    Mkdir {
+        image: String,
+        path: String,
+    },
+    /// Create a disk image holding an empty volume: 280 blocks for a floppy, up to 65535
+    New {
+        image: String,
+        name: String,
+        #[arg(default_value_t = 280)]
+        blocks: usize,
+    },
+}
+
+pub fn run(command: ProDosCommand) -> Result<(), String> {
+    match command {
+        ProDosCommand::Ls { image, path } => {
+            let volume = open(&image)?;
+            println!("/{}/{}", volume.volume_name(), path.trim_matches('/'));
+            for entry in volume.list(&path)? {
+                println!("{entry}");
+            }
+            println!("Blocks free: {}  used: {}  total: {}", volume.free_block_count(),
+                volume.block_count() - volume.free_block_count(), volume.block_count());
+        }
+        ProDosCommand::Get { image, path, destination } => {
+            let volume = open(&image)?;
+            let entry = volume.find(&path)?;
+            let destination = match destination {
+                Some(d) if Path::new(&d).is_dir() => Path::new(&d).join(suffixed_name(&entry)),
+                Some(d) => Path::new(&d).to_path_buf(),
+                None => Path::new(&suffixed_name(&entry)).to_path_buf(),
+            };
+            fs::write(&destination, volume.read_file(&path)?)
+                .map_err(|e| format!("Couldn't write {destination:?}: {e}"))?;
+            println!("Wrote {}", destination.display());
+        }
+        ProDosCommand::Put { image, file, directory } => {
+            let mut volume = open(&image)?;
+            let content = fs::read(&file).map_err(|e| format!("Couldn't read {file}: {e}"))?;
+            let host_name = Path::new(&file).file_name().map_or(String::new(), |n| n.to_string_lossy().to_string());
+            let (name, file_type, aux_type) = parse_suffix(&host_name).unwrap_or((host_name, BINARY_TYPE, 0));
+            let modified = prodos_date(fs::metadata(&file).and_then(|m| m.modified())
+                .unwrap_or(std::time::UNIX_EPOCH));
+            let name = to_prodos_name(&name);
+            volume.add_file(&directory, &name, &FileAttributes { file_type, aux_type, access: DEFAULT_ACCESS, modified },
+                &content)?;
+            write_blocks(&image, volume.image())?;
+            println!("Added {name}");
+        }
+        ProDosCommand::Rm { image, path } => {
+            let mut volume = open(&image)?;
+            volume.delete(&path)?;
+            write_blocks(&image, volume.image())?;
+        }
+        ProDosCommand::Mkdir { image, path } => {
+            let mut volume = open(&image)?;
+            let (parent, name) = path.trim_matches('/').rsplit_once('/').unwrap_or(("", path.trim_matches('/')));
+            volume.add_directory(parent, name)?;
+            write_blocks(&image, volume.image())?;
+        }
+        ProDosCommand::New { image, name, blocks } => {
+            if Path::new(&image).exists() {
+                return Err(format!("{image} already exists"));
+            }
+            write_blocks(&image, ProDos::format(&name, blocks)?.image())?;
+            println!("Wrote {image}");
+        }
+    }
+    Ok(())
+}
+
+fn open(image: &str) -> Result<ProDos, String> {
+    ProDos::new(read_blocks(image)?)
+}